@@ -1,8 +1,13 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use fw2tar::analysis::find_linux_filesystems::KEY_DIRS;
+use fw2tar::analysis::directory_executables::get_dir_executable_info;
+use fw2tar::analysis::find_linux_filesystems::{find_linux_filesystems, CRITICAL_FILES, KEY_DIRS};
+use fw2tar::analysis::scoring::ScoringProfile;
 use std::cmp::{Ord, Ordering};
+use std::fs;
 use std::ops::Deref;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
+use walkdir::WalkDir;
 
 fn find_key_dirs_intersection() -> usize {
     let root = Path::new("/");
@@ -62,6 +67,57 @@ fn find_key_dirs_filesystem() -> usize {
     key_dir_count
 }
 
+/// Build a binwalk-style tree of `levels` root filesystems, each nested in the previous one
+/// under a `.extracted` and `jffs2-root` directory pair, which the counts do not leave out.
+fn create_nested_rootfs_tree(dir: &Path, levels: usize) {
+    let mut root = dir.to_owned();
+
+    for _ in 0..levels {
+        for key_dir in KEY_DIRS {
+            for sub in 0..10 {
+                let sub = root.join(key_dir).join(format!("dir{sub}"));
+                fs::create_dir_all(&sub).unwrap();
+
+                for i in 0..20 {
+                    let file = sub.join(format!("file{i}"));
+                    fs::write(&file, format!("#!/bin/sh\n{:64}", "")).unwrap();
+                    fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+                }
+            }
+        }
+        fs::write(root.join("bin/sh"), "").unwrap();
+        fs::write(root.join("etc/passwd"), "").unwrap();
+
+        root = root.join("usr/share/_firmware.bin.extracted/jffs2-root");
+    }
+}
+
+/// The search before it was done in one pass: find candidates top-down, then walk the
+/// subtree of each one again to count its files.
+fn find_linux_filesystems_rewalking(start_dir: &Path) -> usize {
+    let min_required = (KEY_DIRS.len() + CRITICAL_FILES.len()) / 2;
+    let mut executables = 0;
+
+    for entry in WalkDir::new(start_dir)
+        .max_depth(15)
+        .into_iter()
+        .filter_entry(|entry| entry.file_type().is_dir())
+    {
+        let root = entry.unwrap().into_path();
+        let matches = KEY_DIRS
+            .iter()
+            .chain(CRITICAL_FILES)
+            .filter(|path| root.join(path).exists())
+            .count();
+
+        if matches >= min_required {
+            executables += get_dir_executable_info(&root).total_executables;
+        }
+    }
+
+    executables
+}
+
 pub fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("find key dirs by interesection", |b| {
         b.iter(find_key_dirs_intersection)
@@ -70,6 +126,19 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("find key dirs by filesystem", |b| {
         b.iter(find_key_dirs_filesystem)
     });
+
+    let tree = tempfile::tempdir().unwrap();
+    create_nested_rootfs_tree(tree.path(), 12);
+
+    let mut group = c.benchmark_group("find linux filesystems in a deep tree");
+    group.sample_size(20);
+    group.bench_function("walking each candidate again", |b| {
+        b.iter(|| find_linux_filesystems_rewalking(tree.path()))
+    });
+    group.bench_function("in one pass", |b| {
+        b.iter(|| find_linux_filesystems(tree.path(), &ScoringProfile::default(), "bench"))
+    });
+    group.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,368 @@
+//! CPU architecture, ABI and C library of a root filesystem.
+//!
+//! Every ELF executable and shared library in the tree votes for the `e_machine`, class and
+//! byte order it was built for, and the most common combination wins. The ABI details are
+//! decoded from the `e_flags` of the winning binaries, and the C library is told from the
+//! dynamic loader they ask for or, failing that, from the library file names present.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use super::elf::{ElfHeader, ElfType};
+use crate::extractors::native::Endian;
+
+const EM_SPARC: u16 = 2;
+const EM_386: u16 = 3;
+const EM_68K: u16 = 4;
+const EM_MIPS: u16 = 8;
+const EM_MIPS_RS3_LE: u16 = 10;
+const EM_PPC: u16 = 20;
+const EM_PPC64: u16 = 21;
+const EM_S390: u16 = 22;
+const EM_ARM: u16 = 40;
+const EM_SH: u16 = 42;
+const EM_SPARCV9: u16 = 43;
+const EM_X86_64: u16 = 62;
+const EM_OPENRISC: u16 = 92;
+const EM_ARC_COMPACT: u16 = 93;
+const EM_XTENSA: u16 = 94;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+const EM_LOONGARCH: u16 = 258;
+
+const EF_ARM_EABIMASK: u32 = 0xff00_0000;
+const EF_ARM_ABI_FLOAT_SOFT: u32 = 0x200;
+const EF_ARM_ABI_FLOAT_HARD: u32 = 0x400;
+
+const EF_MIPS_ARCH: u32 = 0xf000_0000;
+const EF_MIPS_ABI: u32 = 0x0000_f000;
+const EF_MIPS_ABI2: u32 = 0x20;
+const EF_MIPS_NAN2008: u32 = 0x400;
+
+const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+
+/// Byte order the binaries were built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl From<Endian> for Endianness {
+    fn from(endian: Endian) -> Self {
+        match endian {
+            Endian::Little => Endianness::Little,
+            Endian::Big => Endianness::Big,
+        }
+    }
+}
+
+/// How floating point arguments are passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FloatAbi {
+    /// In integer registers, whether or not the CPU has an FPU
+    Soft,
+    /// In FPU registers
+    Hard,
+}
+
+/// C library the rootfs was built against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Libc {
+    Glibc,
+    Uclibc,
+    Musl,
+}
+
+/// How many binaries were built for one architecture
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ArchitectureVote {
+    pub name: &'static str,
+    pub machine: u16,
+    pub bits: u8,
+    pub endianness: Endianness,
+    pub binaries: usize,
+}
+
+/// What the binaries of a rootfs were built for
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Architecture {
+    /// Name of the architecture, such as `mips` or `aarch64`, or `unknown` for an `e_machine`
+    /// xfs has no name for
+    pub name: &'static str,
+    /// ELF `e_machine`
+    pub machine: u16,
+    /// 32 or 64, from the ELF class
+    pub bits: u8,
+    pub endianness: Endianness,
+    /// Most common ELF `e_flags` among the binaries built for the architecture
+    pub flags: u32,
+    /// ARM EABI version, 0 for the old ABI
+    pub arm_eabi: Option<u32>,
+    /// ARM and RISC-V float ABI, when the flags or the loader name tell
+    pub float_abi: Option<FloatAbi>,
+    /// MIPS ISA level, such as `mips32r2`
+    pub mips_isa: Option<&'static str>,
+    /// MIPS calling convention: `o32`, `n32`, `n64`, `o64`, `eabi32` or `eabi64`
+    pub mips_abi: Option<&'static str>,
+    /// Whether MIPS binaries use the IEEE 754-2008 NaN encoding
+    pub mips_nan2008: Option<bool>,
+    /// Dynamic loader most binaries ask for (`PT_INTERP`), `null` if all are static
+    pub interpreter: Option<String>,
+    pub libc: Option<Libc>,
+    /// ELF executables and shared libraries examined
+    pub binaries: usize,
+    /// Every architecture any binary was built for, most common first
+    pub votes: Vec<ArchitectureVote>,
+}
+
+fn machine_name(machine: u16, bits: u8) -> &'static str {
+    match (machine, bits) {
+        (EM_386, _) => "x86",
+        (EM_X86_64, _) => "x86_64",
+        (EM_ARM, _) => "arm",
+        (EM_AARCH64, _) => "aarch64",
+        (EM_MIPS | EM_MIPS_RS3_LE, 64) => "mips64",
+        (EM_MIPS | EM_MIPS_RS3_LE, _) => "mips",
+        (EM_PPC, _) => "powerpc",
+        (EM_PPC64, _) => "powerpc64",
+        (EM_RISCV, 64) => "riscv64",
+        (EM_RISCV, _) => "riscv32",
+        (EM_SPARC, _) => "sparc",
+        (EM_SPARCV9, _) => "sparc64",
+        (EM_S390, _) => "s390",
+        (EM_SH, _) => "sh",
+        (EM_68K, _) => "m68k",
+        (EM_OPENRISC, _) => "openrisc",
+        (EM_XTENSA, _) => "xtensa",
+        (EM_ARC_COMPACT, _) => "arc",
+        (EM_LOONGARCH, _) => "loongarch",
+        _ => "unknown",
+    }
+}
+
+fn mips_isa(flags: u32) -> Option<&'static str> {
+    Some(match (flags & EF_MIPS_ARCH) >> 28 {
+        0 => "mips1",
+        1 => "mips2",
+        2 => "mips3",
+        3 => "mips4",
+        4 => "mips5",
+        5 => "mips32",
+        6 => "mips64",
+        7 => "mips32r2",
+        8 => "mips64r2",
+        9 => "mips32r6",
+        10 => "mips64r6",
+        _ => return None,
+    })
+}
+
+fn mips_abi(flags: u32, bits: u8) -> Option<&'static str> {
+    Some(match flags & EF_MIPS_ABI {
+        0x1000 => "o32",
+        0x2000 => "o64",
+        0x3000 => "eabi32",
+        0x4000 => "eabi64",
+        _ if flags & EF_MIPS_ABI2 != 0 => "n32",
+        _ if bits == 64 => "n64",
+        // Old toolchains leave the ABI out of o32 binaries
+        _ => "o32",
+    })
+}
+
+/// The C library a dynamic loader belongs to, from its file name
+fn libc_of_loader(interp: &str) -> Option<Libc> {
+    let name = Path::new(interp).file_name()?.to_str()?;
+
+    if name.starts_with("ld-musl-") {
+        Some(Libc::Musl)
+    } else if name.starts_with("ld-uClibc") {
+        Some(Libc::Uclibc)
+    } else if name.starts_with("ld-linux")
+        || name.starts_with("ld.so")
+        || name.starts_with("ld64.so")
+    {
+        Some(Libc::Glibc)
+    } else {
+        None
+    }
+}
+
+/// The C library a file in a library directory belongs to, from its soname
+fn libc_of_library(name: &str) -> Option<Libc> {
+    if name.starts_with("libc.musl-") || name.starts_with("ld-musl-") {
+        Some(Libc::Musl)
+    } else if name.starts_with("libuClibc") || name.starts_with("ld-uClibc") || name == "libc.so.0"
+    {
+        Some(Libc::Uclibc)
+    } else if name == "libc.so.6" || name.starts_with("ld-linux") {
+        Some(Libc::Glibc)
+    } else {
+        None
+    }
+}
+
+/// The value `values` holds most often, the smallest one on ties
+fn most_common<T: Ord>(values: impl IntoIterator<Item = T>) -> Option<T> {
+    let mut counts = BTreeMap::new();
+    for value in values {
+        *counts.entry(value).or_insert(0) += 1;
+    }
+
+    let max = *counts.values().max()?;
+    counts
+        .into_iter()
+        .find(|&(_, count)| count == max)
+        .map(|(value, _)| value)
+}
+
+/// Work out what the rootfs at `root` was built for. `None` if it holds no ELF executables or
+/// shared libraries.
+pub fn detect(root: &Path) -> Option<Architecture> {
+    let mut binaries = Vec::new();
+    let mut libraries = Vec::new();
+
+    for entry in WalkDir::new(root) {
+        let Ok(entry) = entry else { continue };
+        let name = entry.file_name().to_string_lossy();
+
+        // Library symlinks are named after the soname, so they count as much as the files
+        libraries.extend(libc_of_library(&name));
+
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        if let Ok(Some(elf)) = ElfHeader::read(entry.path()) {
+            if matches!(elf.elf_type, ElfType::Executable | ElfType::Shared) {
+                binaries.push(elf);
+            }
+        }
+    }
+
+    let key = |elf: &ElfHeader| {
+        let bits = if elf.is_64 { 64 } else { 32 };
+        (elf.machine, bits, Endianness::from(elf.endian))
+    };
+
+    let mut votes: BTreeMap<(u16, u8, Endianness), usize> = BTreeMap::new();
+    for elf in &binaries {
+        *votes.entry(key(elf)).or_insert(0) += 1;
+    }
+
+    let mut votes: Vec<_> = votes
+        .into_iter()
+        .map(|((machine, bits, endianness), count)| ArchitectureVote {
+            name: machine_name(machine, bits),
+            machine,
+            bits,
+            endianness,
+            binaries: count,
+        })
+        .collect();
+    votes.sort_by_key(|vote| std::cmp::Reverse(vote.binaries));
+
+    let winner = votes.first()?;
+    let winning = || {
+        binaries
+            .iter()
+            .filter(|elf| key(elf) == (winner.machine, winner.bits, winner.endianness))
+    };
+
+    let flags = most_common(winning().map(|elf| elf.flags)).unwrap_or_default();
+    let interpreter = most_common(winning().filter_map(|elf| elf.interp.clone()));
+
+    let libc = interpreter
+        .as_deref()
+        .and_then(libc_of_loader)
+        .or_else(|| most_common(libraries));
+
+    let (arm_eabi, float_abi) = match winner.machine {
+        EM_ARM => {
+            let float_abi = if flags & EF_ARM_ABI_FLOAT_HARD != 0 {
+                Some(FloatAbi::Hard)
+            } else if flags & EF_ARM_ABI_FLOAT_SOFT != 0 {
+                Some(FloatAbi::Soft)
+            } else {
+                // EABI4 and older binaries leave it to the loader name
+                interpreter.as_deref().map(|interp| {
+                    if interp.contains("armhf") {
+                        FloatAbi::Hard
+                    } else {
+                        FloatAbi::Soft
+                    }
+                })
+            };
+
+            (Some((flags & EF_ARM_EABIMASK) >> 24), float_abi)
+        }
+        EM_RISCV => {
+            let float_abi = if flags & EF_RISCV_FLOAT_ABI == 0 {
+                FloatAbi::Soft
+            } else {
+                FloatAbi::Hard
+            };
+
+            (None, Some(float_abi))
+        }
+        _ => (None, None),
+    };
+
+    let mips = matches!(winner.machine, EM_MIPS | EM_MIPS_RS3_LE);
+
+    Some(Architecture {
+        name: winner.name,
+        machine: winner.machine,
+        bits: winner.bits,
+        endianness: winner.endianness,
+        flags,
+        arm_eabi,
+        float_abi,
+        mips_isa: mips.then(|| mips_isa(flags)).flatten(),
+        mips_abi: mips.then(|| mips_abi(flags, winner.bits)).flatten(),
+        mips_nan2008: mips.then_some(flags & EF_MIPS_NAN2008 != 0),
+        interpreter,
+        libc,
+        binaries: binaries.len(),
+        votes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_mips_flags() {
+        // A typical big endian router binary: mips32r2, o32, PIC
+        let flags = 0x7000_1007;
+
+        assert_eq!(mips_isa(flags), Some("mips32r2"));
+        assert_eq!(mips_abi(flags, 32), Some("o32"));
+        assert_eq!(mips_abi(EF_MIPS_ABI2 | 0x8000_0000, 64), Some("n32"));
+        assert_eq!(mips_abi(0x8000_0000, 64), Some("n64"));
+    }
+
+    #[test]
+    fn tells_libc_from_loader() {
+        assert_eq!(libc_of_loader("/lib/ld-uClibc.so.0"), Some(Libc::Uclibc));
+        assert_eq!(
+            libc_of_loader("/lib/ld-musl-mipsel-sf.so.1"),
+            Some(Libc::Musl)
+        );
+        assert_eq!(
+            libc_of_loader("/lib/ld-linux-armhf.so.3"),
+            Some(Libc::Glibc)
+        );
+        assert_eq!(libc_of_loader("/lib/ld.so.1"), Some(Libc::Glibc));
+        assert_eq!(libc_of_loader("/system/bin/linker"), None);
+    }
+}
@@ -1,7 +1,14 @@
+use std::fs::{File, Metadata};
+use std::io::{Seek, SeekFrom};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::Serialize;
 use walkdir::{DirEntry, WalkDir};
 
+use super::elf::{read_up_to, ElfHeader, ElfType};
+
 const EXECUTABLE_MASK: u32 = libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH;
 
 static BAD_SUFFIXES: &[&str] = &[
@@ -13,17 +20,140 @@ static BAD_SUFFIXES: &[&str] = &[
     "0.tar",
 ];
 
-#[derive(Debug, Clone)]
+/// What a regular file holds, going by its contents rather than its mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// An ELF executable, position-independent or not
+    Executable,
+    SharedLibrary,
+    KernelModule,
+    /// Any other ELF file, such as a core dump or an object file
+    OtherElf,
+    /// A file starting with `#!`
+    Script,
+    Other,
+}
+
+/// Tell what the file at `path` is from its first bytes and, for ELF files, its headers.
+pub fn classify(path: &Path) -> FileKind {
+    let Ok(mut file) = File::open(path) else {
+        return FileKind::Other;
+    };
+
+    match ElfHeader::read_from(&mut file) {
+        Ok(Some(elf)) => match elf.elf_type {
+            ElfType::Executable => FileKind::Executable,
+            // PIE executables are shared objects too, but ask for a dynamic loader or, when
+            // statically linked, are flagged DF_1_PIE
+            ElfType::Shared if elf.interp.is_some() || elf.pie => FileKind::Executable,
+            ElfType::Shared => FileKind::SharedLibrary,
+            ElfType::Relocatable if path.extension().is_some_and(|ext| ext == "ko") => {
+                FileKind::KernelModule
+            }
+            _ => FileKind::OtherElf,
+        },
+        Ok(None) => {
+            let mut magic = [0; 2];
+            let shebang = file.seek(SeekFrom::Start(0)).is_ok()
+                && read_up_to(&mut file, &mut magic).is_ok_and(|len| len == 2)
+                && magic == *b"#!";
+
+            if shebang {
+                FileKind::Script
+            } else {
+                FileKind::Other
+            }
+        }
+        Err(_) => FileKind::Other,
+    }
+}
+
+/// Number of files of each [`FileKind`] that holds code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct FileKinds {
+    /// ELF executables, including position-independent ones
+    pub executables: usize,
+    pub shared_libraries: usize,
+    pub kernel_modules: usize,
+    /// ELF files of any other type
+    pub other_elf: usize,
+    /// Files starting with `#!`
+    pub scripts: usize,
+}
+
+impl FileKinds {
+    fn count(&mut self, kind: FileKind) {
+        match kind {
+            FileKind::Executable => self.executables += 1,
+            FileKind::SharedLibrary => self.shared_libraries += 1,
+            FileKind::KernelModule => self.kernel_modules += 1,
+            FileKind::OtherElf => self.other_elf += 1,
+            FileKind::Script => self.scripts += 1,
+            FileKind::Other => {}
+        }
+    }
+
+    fn add(&mut self, other: &FileKinds) {
+        self.executables += other.executables;
+        self.shared_libraries += other.shared_libraries;
+        self.kernel_modules += other.kernel_modules;
+        self.other_elf += other.other_elf;
+        self.scripts += other.scripts;
+    }
+
+    pub fn elf_files(&self) -> usize {
+        self.executables + self.shared_libraries + self.kernel_modules + self.other_elf
+    }
+
+    /// Files that run or are loaded by something that runs, whatever their mode
+    pub fn programs(&self) -> usize {
+        self.executables + self.shared_libraries + self.scripts
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct ExecutableInfo {
     pub total_size: u64,
     pub total_files: usize,
+    /// Files with any exec bit set, which extractors running unprivileged may lose or invent
     pub total_executables: usize,
+    /// What the files are by their contents
+    pub kinds: FileKinds,
+}
+
+impl ExecutableInfo {
+    /// Count the regular file at `path`
+    pub fn add_file(&mut self, path: &Path, metadata: &Metadata) {
+        self.total_files += 1;
+
+        if metadata.permissions().mode() & EXECUTABLE_MASK != 0 {
+            self.total_executables += 1;
+        }
+
+        self.kinds.count(classify(path));
+
+        self.total_size += metadata.len();
+    }
+
+    /// Count everything in a subdirectory
+    pub fn add_dir(&mut self, other: &ExecutableInfo) {
+        self.total_size += other.total_size;
+        self.total_files += other.total_files;
+        self.total_executables += other.total_executables;
+        self.kinds.add(&other.kinds);
+    }
+}
+
+/// Whether an entry named `name` is another extractor's output rather than part of the
+/// filesystem around it, so it is left out of that filesystem's counts.
+pub fn is_extraction_artifact(name: &str) -> bool {
+    BAD_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) || name.starts_with("squashfs-root-")
 }
 
+/// Count the files under `dir` on their own. Searches for filesystems get these counts for
+/// every directory at once from [`find_linux_filesystems`](super::find_linux_filesystems).
 pub fn get_dir_executable_info(dir: &Path) -> ExecutableInfo {
-    let mut total_size = 0;
-    let mut total_files = 0;
-    let mut total_executables = 0;
+    let mut info = ExecutableInfo::default();
 
     let ignore_extraction_artifacts = |entry: &DirEntry| {
         if entry.path() == dir {
@@ -33,23 +163,8 @@ pub fn get_dir_executable_info(dir: &Path) -> ExecutableInfo {
         entry
             .path()
             .file_name()
-            .map(|name| {
-                name.to_str().map(|name| {
-                    for suffix in BAD_SUFFIXES {
-                        if name.ends_with(suffix) {
-                            return false;
-                        }
-                    }
-
-                    if name.starts_with("squashfs-root-") {
-                        return false;
-                    }
-
-                    true
-                })
-            })
-            .flatten()
-            .unwrap_or(true)
+            .and_then(|name| name.to_str())
+            .is_none_or(|name| !is_extraction_artifact(name))
     };
 
     for entry in WalkDir::new(dir)
@@ -62,21 +177,37 @@ pub fn get_dir_executable_info(dir: &Path) -> ExecutableInfo {
         };
 
         if metadata.is_file() {
-            total_files += 1;
-
-            if metadata.permissions().mode() & EXECUTABLE_MASK != 0 {
-                total_executables += 1;
-            }
-
-            total_size += metadata.len();
+            info.add_file(entry.path(), &metadata);
         }
     }
 
-    log::info!("{dir:?}: {total_size}, {total_files}, {total_executables}");
+    log::info!(
+        "{dir:?}: {}, {}, {}",
+        info.total_size,
+        info.total_files,
+        info.total_executables
+    );
+
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_by_contents() {
+        let tmp = tempfile::tempdir().unwrap();
+        let script = tmp.path().join("rc");
+        let text = tmp.path().join("passwd");
+        std::fs::write(&script, "#!/bin/sh\necho\n").unwrap();
+        std::fs::write(&text, "root:x:0:0::/root:/bin/sh\n").unwrap();
+
+        // The test binary itself is an ELF executable
+        let exe = std::env::current_exe().unwrap();
 
-    ExecutableInfo {
-        total_size,
-        total_files,
-        total_executables,
+        assert_eq!(classify(&exe), FileKind::Executable);
+        assert_eq!(classify(&script), FileKind::Script);
+        assert_eq!(classify(&text), FileKind::Other);
     }
 }
@@ -0,0 +1,235 @@
+//! Just enough ELF parsing to tell what a file in an extracted tree is.
+//!
+//! Only the file header, the program headers and the dynamic section are read, so classifying
+//! a file costs a few small reads however large it is.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::extractors::native::Endian;
+
+pub const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const DT_NULL: u64 = 0;
+const DT_FLAGS_1: u64 = 0x6fff_fffb;
+const DF_1_PIE: u64 = 0x0800_0000;
+
+/// Longest interpreter path read, real ones are far shorter
+const MAX_INTERP_LEN: u64 = 4096;
+
+/// Most program headers read, to bound the work done on corrupt files
+const MAX_PROGRAM_HEADERS: u16 = 256;
+
+/// Most dynamic section entries read, real ones have a few dozen
+const MAX_DYNAMIC_ENTRIES: u64 = 512;
+
+/// What the ELF file header says the file is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfType {
+    /// A relocatable object, which in a rootfs is a kernel module
+    Relocatable,
+    /// A position-dependent executable
+    Executable,
+    /// A shared library, or an executable built as position-independent
+    Shared,
+    Other(u16),
+}
+
+/// The parts of an ELF file's headers used to classify it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfHeader {
+    /// `true` for ELFCLASS64
+    pub is_64: bool,
+    pub endian: Endian,
+    pub elf_type: ElfType,
+    /// `e_machine`
+    pub machine: u16,
+    /// `e_flags`, whose meaning depends on the machine
+    pub flags: u32,
+    /// Path of the dynamic loader named by `PT_INTERP`
+    pub interp: Option<String>,
+    /// `DF_1_PIE` is set, marking an `ET_DYN` file as a position-independent executable even
+    /// when it is statically linked and so has no loader
+    pub pie: bool,
+}
+
+impl ElfHeader {
+    /// Read the headers of the file at `path`. `Ok(None)` if it is not an ELF file.
+    pub fn read(path: &Path) -> io::Result<Option<Self>> {
+        Self::read_from(&mut File::open(path)?)
+    }
+
+    /// Read the headers of an open file, from its start
+    pub fn read_from(file: &mut File) -> io::Result<Option<Self>> {
+        let mut header = [0; 64];
+        file.seek(SeekFrom::Start(0))?;
+        let len = read_up_to(file, &mut header)?;
+        if len < 52 || header[..4] != *ELF_MAGIC {
+            return Ok(None);
+        }
+
+        let is_64 = match header[4] {
+            1 => false,
+            2 if len == 64 => true,
+            _ => return Ok(None),
+        };
+        let endian = match header[5] {
+            1 => Endian::Little,
+            2 => Endian::Big,
+            _ => return Ok(None),
+        };
+
+        let elf_type = match endian.u16(&header[16..]) {
+            ET_REL => ElfType::Relocatable,
+            ET_EXEC => ElfType::Executable,
+            ET_DYN => ElfType::Shared,
+            other => ElfType::Other(other),
+        };
+        let machine = endian.u16(&header[18..]);
+
+        let (phoff, flags, phentsize, phnum) = if is_64 {
+            (
+                endian.u64(&header[32..]),
+                endian.u32(&header[48..]),
+                endian.u16(&header[54..]),
+                endian.u16(&header[56..]),
+            )
+        } else {
+            (
+                endian.u32(&header[28..]) as u64,
+                endian.u32(&header[36..]),
+                endian.u16(&header[42..]),
+                endian.u16(&header[44..]),
+            )
+        };
+
+        let (interp, pie) = if elf_type == ElfType::Relocatable {
+            (None, false)
+        } else {
+            read_program_headers(file, is_64, endian, phoff, phentsize, phnum)?
+        };
+
+        Ok(Some(Self {
+            is_64,
+            endian,
+            elf_type,
+            machine,
+            flags,
+            interp,
+            pie,
+        }))
+    }
+}
+
+/// Read the loader path `PT_INTERP` points to and whether the dynamic section sets `DF_1_PIE`
+fn read_program_headers(
+    file: &mut File,
+    is_64: bool,
+    endian: Endian,
+    phoff: u64,
+    phentsize: u16,
+    phnum: u16,
+) -> io::Result<(Option<String>, bool)> {
+    let min_entry = if is_64 { 56 } else { 32 };
+    if phoff == 0 || (phentsize as usize) < min_entry {
+        return Ok((None, false));
+    }
+
+    let mut interp = None;
+    let mut dynamic = None;
+    let mut entry = vec![0; phentsize as usize];
+    for i in 0..phnum.min(MAX_PROGRAM_HEADERS) as u64 {
+        file.seek(SeekFrom::Start(phoff.saturating_add(i * phentsize as u64)))?;
+        if read_up_to(file, &mut entry)? < entry.len() {
+            break;
+        }
+
+        let (offset, size) = if is_64 {
+            (endian.u64(&entry[8..]), endian.u64(&entry[32..]))
+        } else {
+            (
+                endian.u32(&entry[4..]) as u64,
+                endian.u32(&entry[16..]) as u64,
+            )
+        };
+
+        match endian.u32(&entry) {
+            PT_INTERP if interp.is_none() => interp = Some(read_interp(file, offset, size)?),
+            PT_DYNAMIC if dynamic.is_none() => dynamic = Some((offset, size)),
+            _ => {}
+        }
+    }
+
+    let pie = match dynamic {
+        Some((offset, size)) => read_flags_1(file, is_64, endian, offset, size)? & DF_1_PIE != 0,
+        None => false,
+    };
+
+    Ok((interp, pie))
+}
+
+fn read_interp(file: &mut File, offset: u64, size: u64) -> io::Result<String> {
+    let mut interp = Vec::new();
+    file.seek(SeekFrom::Start(offset))?;
+    file.by_ref()
+        .take(size.min(MAX_INTERP_LEN))
+        .read_to_end(&mut interp)?;
+
+    let end = interp.iter().position(|&b| b == 0).unwrap_or(interp.len());
+    Ok(String::from_utf8_lossy(&interp[..end]).into_owned())
+}
+
+/// Find `DT_FLAGS_1` in the dynamic section, 0 if it is not there
+fn read_flags_1(
+    file: &mut File,
+    is_64: bool,
+    endian: Endian,
+    offset: u64,
+    size: u64,
+) -> io::Result<u64> {
+    let entry_size = if is_64 { 16 } else { 8 };
+    let mut entry = [0; 16];
+    let entry = &mut entry[..entry_size];
+
+    file.seek(SeekFrom::Start(offset))?;
+    for _ in 0..(size / entry_size as u64).min(MAX_DYNAMIC_ENTRIES) {
+        if read_up_to(file, entry)? < entry_size {
+            break;
+        }
+
+        let (tag, value) = if is_64 {
+            (endian.u64(entry), endian.u64(&entry[8..]))
+        } else {
+            (endian.u32(entry) as u64, endian.u32(&entry[4..]) as u64)
+        };
+
+        match tag {
+            DT_NULL => break,
+            DT_FLAGS_1 => return Ok(value),
+            _ => {}
+        }
+    }
+
+    Ok(0)
+}
+
+/// Fill as much of `buf` as the file has, returning how much that was
+pub(crate) fn read_up_to(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+
+    Ok(len)
+}
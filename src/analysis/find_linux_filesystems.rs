@@ -1,16 +1,18 @@
-use std::cmp::Reverse;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
-use super::directory_executables::{get_dir_executable_info, ExecutableInfo};
+use super::directory_executables::{is_extraction_artifact, ExecutableInfo, FileKinds};
+use super::find_secondary_filesystems::is_unpacked_root;
+use super::safety::resolve_in;
+use super::scoring::{Features, Score, ScoringProfile};
 
-const DEFAULT_MIN_EXECUTABLES: usize = 10;
 const MAX_EXPLORE_DEPTH: usize = 15;
 
 pub const KEY_DIRS: &[&str] = &["bin", "etc", "lib", "usr", "var"];
 pub const CRITICAL_FILES: &[&str] = &["bin/sh", "etc/passwd"];
 
-const MIN_REQUIRED: usize = (KEY_DIRS.len() + CRITICAL_FILES.len()) / 2;
+/// Where busybox is installed, the applets being symlinks to it
+const BUSYBOX_PATHS: &[&str] = &["bin/busybox", "sbin/busybox", "usr/bin/busybox"];
 
 #[derive(Debug, Clone)]
 pub struct PrimaryFilesystem {
@@ -18,71 +20,193 @@ pub struct PrimaryFilesystem {
     pub size: u64,
     pub num_files: usize,
     pub key_file_count: usize,
+    /// Files with an exec bit
     pub executables: usize,
+    /// What the files are by their contents
+    pub kinds: FileKinds,
+    pub score: Score,
 }
 
+/// A directory whose subtree is still being walked
+struct Pending {
+    path: PathBuf,
+    depth: usize,
+    /// Position in the walk, to keep candidates that score the same in walk order
+    order: usize,
+    /// Left out of its parent's counts, see [`is_extraction_artifact`]
+    artifact: bool,
+    info: ExecutableInfo,
+    /// Index of the first symlink walked inside it
+    symlinks_start: usize,
+}
+
+/// Take the innermost directory off `stack`, adding its counts into its parent's.
+fn pop(stack: &mut Vec<Pending>) -> Pending {
+    let dir = stack.pop().expect("popped an empty stack");
+
+    if let Some(parent) = stack.last_mut() {
+        if !dir.artifact {
+            parent.info.add_dir(&dir.info);
+        }
+    }
+
+    dir
+}
+
+/// Number of [`KEY_DIRS`] and [`CRITICAL_FILES`] present in `root`. They are resolved inside
+/// it, so symlinks like bin/sh -> /bin/busybox are not checked against the host.
+fn key_file_count(root: &Path) -> usize {
+    KEY_DIRS
+        .iter()
+        .chain(CRITICAL_FILES)
+        .filter(|path| resolve_in(root, Path::new(path)).is_some())
+        .count()
+}
+
+/// Directories between `dir`, `depth` below the extractor's output, and the root of the image
+/// it was unpacked from. Extractors nest their output differently, so counting from the output
+/// directory would favour whichever nests least.
+fn nesting_depth(dir: &Path, depth: usize) -> usize {
+    dir.ancestors()
+        .take(depth)
+        .position(is_unpacked_root)
+        .unwrap_or(depth)
+}
+
+/// Find the root filesystems under `start_dir` that meet the minimums of `profile`, best
+/// scoring first.
+///
+/// The tree is walked once: the counts of every directory are added into its parent as the
+/// walk leaves it, so nested candidates do not walk their subtrees again.
 pub fn find_linux_filesystems(
     start_dir: &Path,
-    min_executables: Option<usize>,
+    profile: &ScoringProfile,
     extractor_name: &str,
 ) -> Vec<PrimaryFilesystem> {
     let mut filesystems = Vec::new();
-    let min_executables = min_executables.unwrap_or(DEFAULT_MIN_EXECUTABLES);
+    let min_executables = profile.min_executables;
 
     log::info!("Searching {start_dir:?}");
 
-    for entry in WalkDir::new(start_dir)
-        .max_depth(MAX_EXPLORE_DEPTH)
-        .into_iter()
-        .filter_entry(|entry| entry.file_type().is_dir())
-    {
-        let Ok(entry) = entry else { continue };
+    // Every symlink walked so far, those inside a directory following its `symlinks_start`
+    let mut symlinks: Vec<PathBuf> = Vec::new();
 
-        //log::trace!("looking at {:?}", entry.path());
-
-        let mut total_matches = 0;
-        let root = entry.path();
-
-        for dir in KEY_DIRS {
-            if root.join(dir).exists() {
-                //log::trace!("{dir} found in {root:?}");
-                total_matches += 1;
-            }
+    let mut finish = |dir: Pending, symlinks: &[PathBuf]| {
+        if dir.depth > MAX_EXPLORE_DEPTH {
+            return;
         }
 
-        for file in CRITICAL_FILES {
-            if root.join(file).exists() {
-                //log::trace!("{file} found in {root:?}");
-                total_matches += 1;
-            }
-        }
+        let root = &dir.path;
+        let total_matches = key_file_count(root);
 
-        if total_matches >= MIN_REQUIRED {
+        if total_matches >= profile.min_key_files {
             let ExecutableInfo {
                 total_executables,
                 total_size,
                 total_files,
-            } = get_dir_executable_info(root);
-
-            if total_executables >= min_executables {
-                log::info!("{root:?}: {total_executables}, {total_size}, {total_files}");
-
-                filesystems.push(PrimaryFilesystem {
-                    path: root.to_owned(),
-                    size: total_size,
-                    num_files: total_files,
-                    key_file_count: total_matches,
-                    executables: total_executables,
-                })
+                kinds,
+            } = dir.info;
+
+            // Extractors running unprivileged lose or invent exec bits, so what counts is what
+            // the files hold
+            let programs = kinds.programs();
+            if programs >= min_executables {
+                log::info!("{root:?}: {programs} ({total_executables} by mode), {total_size}, {total_files}");
+
+                let symlinks = &symlinks[dir.symlinks_start..];
+                let features = Features {
+                    key_files: total_matches,
+                    elf_files: kinds.executables + kinds.shared_libraries,
+                    init: resolve_in(root, Path::new("sbin/init")).is_some(),
+                    busybox: BUSYBOX_PATHS
+                        .iter()
+                        .any(|path| resolve_in(root, Path::new(path)).is_some()),
+                    symlinks: symlinks.len(),
+                    broken_symlinks: symlinks
+                        .iter()
+                        .filter(|link| {
+                            let link = link.strip_prefix(root).unwrap_or(link);
+                            resolve_in(root, link).is_none()
+                        })
+                        .count(),
+                    nesting_depth: nesting_depth(root, dir.depth),
+                };
+
+                filesystems.push((
+                    dir.order,
+                    PrimaryFilesystem {
+                        size: total_size,
+                        num_files: total_files,
+                        key_file_count: total_matches,
+                        executables: total_executables,
+                        kinds,
+                        score: profile.score(&features),
+                        path: dir.path,
+                    },
+                ))
             } else {
-                log::warn!("Extractor {extractor_name} did not find enough executables ({total_executables} < {min_executables})")
+                log::warn!("Extractor {extractor_name} did not find enough executables ({programs} < {min_executables})")
             }
         } else if total_matches > 0 {
             log::info!("Directory {} had {total_matches}", root.display());
         }
+    };
+
+    // The directories from the start directory down to the current entry
+    let mut stack: Vec<Pending> = Vec::new();
+
+    for (order, entry) in WalkDir::new(start_dir).into_iter().enumerate() {
+        let Ok(entry) = entry else { continue };
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+
+        // Everything at this depth or deeper has been walked
+        let depth = entry.depth();
+        while stack.last().is_some_and(|dir| dir.depth >= depth) {
+            finish(pop(&mut stack), &symlinks);
+        }
+
+        let artifact = depth > 0
+            && entry
+                .file_name()
+                .to_str()
+                .is_some_and(is_extraction_artifact);
+
+        if metadata.is_dir() {
+            stack.push(Pending {
+                path: entry.into_path(),
+                depth,
+                order,
+                artifact,
+                info: ExecutableInfo::default(),
+                symlinks_start: symlinks.len(),
+            });
+        } else if metadata.is_symlink() {
+            symlinks.push(entry.into_path());
+        } else if metadata.is_file() && !artifact {
+            if let Some(parent) = stack.last_mut() {
+                parent.info.add_file(entry.path(), &metadata);
+            }
+        }
+    }
+
+    while !stack.is_empty() {
+        finish(pop(&mut stack), &symlinks);
     }
 
-    filesystems.sort_by_key(|fs| Reverse((fs.executables, fs.size, fs.key_file_count)));
+    // Candidates that score the same stay in walk order, outer directories first
+    filesystems.sort_by_key(|(order, _)| *order);
+    let mut filesystems: Vec<_> = filesystems.into_iter().map(|(_, fs)| fs).collect();
+    filesystems.sort_by(|a, b| {
+        b.score.total.total_cmp(&a.score.total).then_with(|| {
+            (b.kinds.programs(), b.size, b.key_file_count).cmp(&(
+                a.kinds.programs(),
+                a.size,
+                a.key_file_count,
+            ))
+        })
+    });
 
     filesystems
 }
@@ -96,6 +220,71 @@ mod tests {
         assert!(KEY_DIRS.is_sorted());
     }
 
+    #[test]
+    fn counts_match_a_walk_of_each_candidate() {
+        use super::super::directory_executables::get_dir_executable_info;
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let mut root = tmp.path().join("image_extract");
+
+        // A rootfs holding a nested one, both next to extraction artifacts that are left out
+        for level in 0..3 {
+            for dir in KEY_DIRS {
+                fs::create_dir_all(root.join(dir)).unwrap();
+            }
+            fs::write(root.join("etc/passwd"), "root:x:0:0::/root:/bin/sh\n").unwrap();
+            for i in 0..12 {
+                let bin = root.join(format!("bin/tool{i}"));
+                let script = format!("#!/bin/sh\n{}", "#".repeat(level * 100 + i));
+                fs::write(&bin, script).unwrap();
+                fs::set_permissions(&bin, fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            fs::create_dir_all(root.join("blob.unknown")).unwrap();
+            fs::write(root.join("blob.unknown/data"), "data").unwrap();
+            fs::write(root.join("chunk.unknown"), "chunk").unwrap();
+            fs::write(root.join("rootfs0.tar"), "tar").unwrap();
+
+            root = root.join("nested/squashfs-root");
+        }
+
+        let filesystems = find_linux_filesystems(tmp.path(), &ScoringProfile::default(), "test");
+        assert_eq!(filesystems.len(), 3);
+
+        for fs in filesystems {
+            let info = get_dir_executable_info(&fs.path);
+            assert_eq!(
+                (fs.size, fs.num_files, fs.executables, fs.kinds),
+                (
+                    info.total_size,
+                    info.total_files,
+                    info.total_executables,
+                    info.kinds
+                ),
+                "{:?}",
+                fs.path
+            );
+        }
+    }
+
+    #[test]
+    fn nesting_depth_counts_from_the_unpacked_image() {
+        // unblob, binwalk and native layouts of a rootfs at the root of its image
+        for dir in [
+            "firmware.bin_extract/0-1000.squashfs_extract",
+            "_firmware.bin.extracted/squashfs-root",
+            "squashfs-0x40",
+        ] {
+            let depth = Path::new(dir).components().count();
+            assert_eq!(nesting_depth(Path::new(dir), depth), 0, "{dir}");
+        }
+
+        assert_eq!(nesting_depth(Path::new("squashfs-root/opt/rootfs"), 3), 2);
+        // Outside anything recognisably unpacked, the depth in the output is all there is
+        assert_eq!(nesting_depth(Path::new("a/b"), 2), 2);
+    }
+
     #[test]
     fn test_walkdir() {
         for entry in WalkDir::new(".") {
@@ -0,0 +1,186 @@
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use super::find_linux_filesystems::KEY_DIRS;
+use super::safety::resolve_in;
+use crate::extractors::fstree::Manifest;
+
+const MAX_EXPLORE_DEPTH: usize = 15;
+
+/// Extensions of files that usually hold device configuration
+const CONFIG_EXTENSIONS: &[&str] = &["cfg", "conf", "ini", "json", "nvram", "xml"];
+
+/// Extensions of files served by a web interface
+const WEB_EXTENSIONS: &[&str] = &[
+    "asp", "cgi", "css", "gif", "htm", "html", "ico", "jpg", "js", "php", "png", "svg",
+];
+
+/// Top level directories of a partition holding web assets
+const WEB_DIRS: &[&str] = &["htdocs", "web", "webroot", "www"];
+
+/// Top level directories of an overlay's upper layer
+const OVERLAY_DIRS: &[&str] = &["upper", "work"];
+
+/// What a non-root filesystem appears to hold, from its layout and file names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SecondaryKind {
+    /// NVRAM dumps and configuration partitions
+    Config,
+    /// Writable layers mounted over the rootfs, such as `/jffs2` partitions
+    Overlay,
+    /// Web interface assets
+    Web,
+    /// Anything else, such as vendor data blobs
+    Data,
+}
+
+#[derive(Debug, Clone)]
+pub struct SecondaryFilesystem {
+    pub path: PathBuf,
+    pub size: u64,
+    pub num_files: usize,
+    pub kind: SecondaryKind,
+}
+
+/// Whether `dir` is the root of something an extractor unpacked, going by how each extractor
+/// names its output: native extractors write `<format>-<offset>` directories with a manifest
+/// next to them, binwalk `<format>-root` and unblob `<name>_extract`.
+pub(super) fn is_unpacked_root(dir: &Path) -> bool {
+    if Manifest::path_for(dir).is_file() {
+        return true;
+    }
+
+    let Some(name) = dir.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+
+    let native = name.split_once("-0x").is_some_and(|(format, offset)| {
+        !format.is_empty() && offset.chars().all(|c| c.is_ascii_hexdigit())
+    });
+    let binwalk = name.ends_with("-root")
+        || name
+            .rsplit_once("-root-")
+            .is_some_and(|(_, n)| n.chars().all(|c| c.is_ascii_digit()));
+
+    native || binwalk || name.ends_with("_extract")
+}
+
+fn classify(dir: &Path) -> (SecondaryKind, u64, usize) {
+    let mut size = 0;
+    let mut num_files = 0;
+    let mut config_files = 0;
+    let mut web_files = 0;
+    let mut nvram = false;
+
+    for entry in WalkDir::new(dir).min_depth(1) {
+        let Ok(entry) = entry else { continue };
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+
+        if !metadata.is_file() {
+            continue;
+        }
+
+        num_files += 1;
+        size += metadata.len();
+
+        let name = entry.file_name().to_string_lossy().to_ascii_lowercase();
+        let extension = Path::new(&name)
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned());
+
+        nvram |= name.contains("nvram");
+        if name.contains("config")
+            || extension
+                .as_deref()
+                .is_some_and(|ext| CONFIG_EXTENSIONS.contains(&ext))
+        {
+            config_files += 1;
+        }
+        if extension
+            .as_deref()
+            .is_some_and(|ext| WEB_EXTENSIONS.contains(&ext))
+        {
+            web_files += 1;
+        }
+    }
+
+    let has_any = |dirs: &[&str]| {
+        dirs.iter()
+            .any(|name| resolve_in(dir, Path::new(name)).is_some_and(|path| path.is_dir()))
+    };
+
+    let kind = if has_any(KEY_DIRS) || has_any(OVERLAY_DIRS) {
+        SecondaryKind::Overlay
+    } else if nvram || config_files * 2 >= num_files {
+        SecondaryKind::Config
+    } else if has_any(WEB_DIRS) || web_files * 2 >= num_files {
+        SecondaryKind::Web
+    } else {
+        SecondaryKind::Data
+    };
+
+    (kind, size, num_files)
+}
+
+/// Find filesystems under `start_dir` that are not a root filesystem, such as configuration
+/// partitions, overlays and web asset partitions. `primaries` are the root filesystems already
+/// found, which are skipped along with everything inside them. Configuration comes first, then
+/// larger filesystems of each kind.
+pub fn find_secondary_filesystems(
+    start_dir: &Path,
+    primaries: &[&Path],
+    extractor_name: &str,
+) -> Vec<SecondaryFilesystem> {
+    let mut filesystems = Vec::new();
+
+    let mut entries = WalkDir::new(start_dir)
+        .min_depth(1)
+        .max_depth(MAX_EXPLORE_DEPTH)
+        .into_iter();
+
+    while let Some(entry) = entries.next() {
+        let Ok(entry) = entry else { continue };
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+
+        let dir = entry.path();
+        if primaries.iter().any(|primary| dir.starts_with(primary)) {
+            entries.skip_current_dir();
+            continue;
+        }
+
+        // Containers of a root filesystem are searched for siblings of it instead
+        if !is_unpacked_root(dir) || primaries.iter().any(|primary| primary.starts_with(dir)) {
+            continue;
+        }
+
+        // Anything unpacked from inside this one is archived along with it
+        entries.skip_current_dir();
+
+        let (kind, size, num_files) = classify(dir);
+        if num_files == 0 {
+            continue;
+        }
+
+        log::info!("{extractor_name}: secondary {kind:?} filesystem {dir:?}: {size}, {num_files}");
+
+        filesystems.push(SecondaryFilesystem {
+            path: dir.to_owned(),
+            size,
+            num_files,
+            kind,
+        });
+    }
+
+    filesystems.sort_by_key(|fs| (fs.kind, Reverse(fs.size)));
+
+    filesystems
+}
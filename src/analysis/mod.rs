@@ -1,20 +1,35 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use std::time::Instant;
-use std::{env, fs, io};
+use std::{env, io};
 
 use sha1::{Digest, Sha1};
 use tempfile::TempDir;
 use thiserror::Error;
 
+pub mod architecture;
 pub mod directory_executables;
+pub mod elf;
 pub mod find_linux_filesystems;
+pub mod find_secondary_filesystems;
+pub mod safety;
+pub mod scoring;
 
 use crate::archive::tar_fs;
-use crate::extractors::{ExtractError, Extractor};
+use crate::extractors::{quota, ExtractError, ExtractStatus, Extractor};
 use crate::metadata::Metadata;
+use crate::progress::{EventKind, Stage};
+use crate::results::ExtractorResult;
+use crate::ExtractionJob;
+use architecture::Architecture;
+use directory_executables::FileKinds;
 use find_linux_filesystems::find_linux_filesystems;
+use find_secondary_filesystems::{find_secondary_filesystems, SecondaryKind};
+use scoring::{Score, ScoringProfile};
+
+/// Directory inside the output directory that candidate archives are staged in
+pub const CANDIDATES_DIR: &str = ".xfs-candidates";
 
 #[derive(Debug, Clone)]
 pub struct ExtractionResult {
@@ -22,11 +37,19 @@ pub struct ExtractionResult {
     pub index: usize,
     pub size: u64,
     pub num_files: usize,
+    pub key_file_count: usize,
+    pub executables: usize,
+    pub kinds: FileKinds, // What the files are by their contents
+    pub architecture: Option<Architecture>, // What the best rootfs of each extractor was built for
+    pub score: Option<Score>, // How a root filesystem scored, None for secondary filesystems
     pub primary: bool,
     pub archive_hash: String,
     pub file_node_count: usize,
     pub path: PathBuf,
     pub rootfs_path: PathBuf, // Path to the rootfs directory
+    pub extract_dir: PathBuf, // Output directory of the extractor that found it
+    pub status: ExtractStatus, // Whether the extractor ran to completion
+    pub kind: Option<SecondaryKind>, // What a secondary filesystem holds, None for root filesystems
 }
 
 #[derive(Error, Debug)]
@@ -41,21 +64,25 @@ pub enum ExtractProcessError {
     FailToFind,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn extract_and_process(
     extractor: &dyn Extractor,
     in_file: &Path,
     output_dir: &Path,
     extract_dir_base: &Path,
     save_scratch: bool,
-    _copy_rootfs: bool,
+    copy_rootfs: bool,
     _rootfs_dir_path: &Path,
+    kept_temp_dirs: &Mutex<Vec<TempDir>>,
     verbose: bool,
     primary_limit: usize,
-    _secondary_limit: usize,
+    secondary_limit: usize,
     results: &Mutex<Vec<ExtractionResult>>,
+    reports: &Mutex<BTreeMap<&'static str, ExtractorResult>>,
     metadata: &Metadata,
     removed_devices: Option<&Mutex<HashSet<PathBuf>>>,
-    args: &crate::args::Args,
+    scoring: &ScoringProfile,
+    job: &ExtractionJob,
 ) -> Result<(), ExtractProcessError> {
     let extractor_name = extractor.name();
 
@@ -83,7 +110,7 @@ pub fn extract_and_process(
     };
 
     // Only create log file if --logs flag is specified
-    let log_file = if args.logs {
+    let log_file = if job.logs {
         output_dir.join(format!("{extractor_name}.log"))
     } else {
         // Use a temporary path that will be discarded
@@ -92,95 +119,98 @@ pub fn extract_and_process(
 
     let start_time = Instant::now();
 
-    // Print extraction status if progress flag is enabled
-    if args.progress {
-        println!("xfs: [STAGE 1/4] {} - extraction: starting...", extractor_name);
-    } else if verbose {
-        // Only print extraction status in verbose mode for non-progress output
-        print!("xfs: {} - extraction: ", extractor_name);
-    }
+    job.emit(Some(extractor_name), EventKind::ExtractorStarted);
     
+    quota::track(extractor_name, actual_extract_dir);
     let extraction_result = extractor
         .extract(in_file, actual_extract_dir, &log_file, verbose);
+    quota::untrack(actual_extract_dir);
     
-    if extraction_result.is_ok() {
-        if args.progress {
-            println!("xfs: [STAGE 1/4] {} - extraction: completed ✓", extractor_name);
-        } else if verbose {
-            println!("✓");
+    let status = match extraction_result {
+        Ok(status) => {
+            job.emit(Some(extractor_name), EventKind::ExtractorFinished { status });
+            status
         }
-    } else {
-        if args.progress || verbose {
-            println!("✗");
+        Err(e) => {
+            job.emit(Some(extractor_name), EventKind::ExtractorFailed { error: e.to_string() });
+            reports
+                .lock()
+                .unwrap()
+                .insert(extractor_name, ExtractorResult::failed(&e, start_time.elapsed()));
+            return Err(ExtractProcessError::ExtractFail(e));
         }
-        return Err(ExtractProcessError::ExtractFail(extraction_result.err().unwrap()));
-    }
+    };
 
-    let elapsed = start_time.elapsed().as_secs_f32();
-    log::info!("{extractor_name} took {elapsed:.2} seconds");
+    let elapsed = start_time.elapsed();
+    log::info!("{extractor_name} took {:.2} seconds", elapsed.as_secs_f32());
 
-    // Print rootfs finding status if progress flag is enabled
-    if args.progress {
-        println!("xfs: [STAGE 2/4] {} - identify rootfs: searching...", extractor_name);
-    } else if verbose {
-        // Only print rootfs finding status in verbose mode for non-progress output
-        print!("xfs: {} - identify rootfs: ", extractor_name);
+    // Deal with anything pointing out of the tree before it is searched, archived or copied
+    let safety = safety::check_tree(actual_extract_dir);
+    if !safety.is_empty() {
+        log::warn!(
+            "{extractor_name} output has {} escaping symlinks, {} symlink loops and {} external hardlinks (removed)",
+            safety.escaping_symlink_count,
+            safety.symlink_loop_count,
+            safety.removed_hardlink_count
+        );
     }
+    reports
+        .lock()
+        .unwrap()
+        .insert(extractor_name, ExtractorResult::finished(status, elapsed, safety));
+
+    job.emit(Some(extractor_name), EventKind::StageStarted { stage: Stage::Identify });
     
-    let rootfs_choices = find_linux_filesystems(actual_extract_dir, None, extractor_name);
+    let rootfs_choices = find_linux_filesystems(actual_extract_dir, scoring, extractor_name);
+
+    for (i, fs) in rootfs_choices.iter().enumerate() {
+        // Output the relative path to the identified rootfs directory
+        let relative_rootfs_path = if save_scratch {
+            let relative_base = extract_dir_base.strip_prefix(output_dir).unwrap_or(extract_dir_base);
+            Path::new(".").join(relative_base).join(extractor_name).join(fs.path.strip_prefix(actual_extract_dir).unwrap_or(&fs.path))
+        } else {
+            // If not saving scratch, just show the temp path info
+            fs.path.clone()
+        };
+
+        job.emit(Some(extractor_name), EventKind::CandidateFound {
+            index: i,
+            path: relative_rootfs_path.display().to_string(),
+            size: fs.size,
+            executables: fs.executables,
+            elf_executables: fs.kinds.executables,
+            score: fs.score.total,
+        });
+    }
+    job.emit(Some(extractor_name), EventKind::CandidatesFound { count: rootfs_choices.len() });
 
-    if rootfs_choices.is_empty() {
-        // if args.progress || verbose {
-        //     println!("✗");
-        // }
-        println!("\txfs: [STAGE 2/4] {} - identify rootfs: No Linux rootfs found ✗", extractor_name);
+    // Secondary filesystems are still worth archiving when no root filesystem is found
+    if rootfs_choices.is_empty() && secondary_limit == 0 {
         // log::error!("No Linux filesystems found extracting {in_file:?} with {extractor_name}");
+        job.emit(Some(extractor_name), EventKind::StageFinished { stage: Stage::Identify });
         return Err(ExtractProcessError::FailToFind);
-    } else {
-        if args.progress {
-            println!("\txfs: [STAGE 2/4] {} - identify rootfs: found ✓", extractor_name);
-        } else if verbose {
-            println!("✓");
-        }
     }
 
     for (i, fs) in rootfs_choices.iter().enumerate() {
         if i >= primary_limit {
-            if args.progress {
-                println!(
-                    "xfs: [STAGE 2/4] WARNING: skipping {n} filesystems, if files are missing you may need to set --primary-limit higher",
-                    n=rootfs_choices.len() - primary_limit
-                );
-            } else if verbose {
-                println!(
-                    "xfs: WARNING: skipping {n} filesystems, if files are missing you may need to set --primary-limit higher",
-                    n=rootfs_choices.len() - primary_limit
-                );
-            }
+            job.emit(Some(extractor_name), EventKind::CandidatesSkipped {
+                count: rootfs_choices.len() - primary_limit,
+            });
             break;
         }
-
-        // Output the relative path to the identified rootfs directory
-        let _relative_rootfs_path = if save_scratch {
-            let relative_base = extract_dir_base.strip_prefix(output_dir).unwrap_or(extract_dir_base);
-            relative_base.join(extractor_name).join(fs.path.strip_prefix(actual_extract_dir).unwrap_or(&fs.path))
-        } else {
-            // If not saving scratch, just show the temp path info
-            fs.path.clone()
-        };
         
         // Only print rootfs path for the best extractor later
 
-        let tar_path = if i == 0 {
-            output_dir.join("rootfs.tar.gz")
-        } else {
-            output_dir.join(format!("rootfs.{i}.tar.gz"))
-        };
+        // Staged under a name of its own, as extractors run concurrently; the best extractor's
+        // archives are promoted once all of them are done
+        let tar_path = output_dir
+            .join(CANDIDATES_DIR)
+            .join(format!("{extractor_name}.{i}.tar.gz"));
 
         // We'll copy the rootfs directory later if needed, after determining the best extractor
 
         // XXX: improve error handling here
-        let file_node_count = tar_fs(&fs.path, &tar_path, metadata, removed_devices).unwrap();
+        let file_node_count = tar_fs(&fs.path, actual_extract_dir, &tar_path, metadata, removed_devices).unwrap();
         let archive_hash = sha1_file(&tar_path).unwrap();
 
         results.lock().unwrap().push(ExtractionResult {
@@ -188,15 +218,75 @@ pub fn extract_and_process(
             index: i,
             size: fs.size,
             num_files: fs.num_files,
+            key_file_count: fs.key_file_count,
+            executables: fs.executables,
+            kinds: fs.kinds,
+            // Read now, as the tree is gone by the time the rootfs is selected without scratch
+            architecture: if i == 0 { architecture::detect(&fs.path) } else { None },
+            score: Some(fs.score.clone()),
             primary: true,
             archive_hash,
             file_node_count,
             path: tar_path,
             rootfs_path: fs.path.clone(),
+            extract_dir: actual_extract_dir.to_owned(),
+            status,
+            kind: None,
         });
     }
 
-    drop(temp_dir);
+    let mut secondary_count = 0;
+    if secondary_limit > 0 {
+        let primaries: Vec<&Path> = rootfs_choices.iter().map(|fs| fs.path.as_path()).collect();
+        let secondary_choices = find_secondary_filesystems(actual_extract_dir, &primaries, extractor_name);
+
+        if secondary_choices.len() > secondary_limit {
+            job.emit(Some(extractor_name), EventKind::SecondarySkipped {
+                count: secondary_choices.len() - secondary_limit,
+            });
+        }
+
+        secondary_count = secondary_choices.len().min(secondary_limit);
+        for (i, fs) in secondary_choices.iter().take(secondary_limit).enumerate() {
+            let tar_path = output_dir
+                .join(CANDIDATES_DIR)
+                .join(format!("{extractor_name}.secondary.{i}.tar.gz"));
+
+            let file_node_count = tar_fs(&fs.path, actual_extract_dir, &tar_path, metadata, removed_devices).unwrap();
+            let archive_hash = sha1_file(&tar_path).unwrap();
+
+            results.lock().unwrap().push(ExtractionResult {
+                extractor: extractor_name,
+                index: i,
+                size: fs.size,
+                num_files: fs.num_files,
+                key_file_count: 0,
+                executables: 0,
+                kinds: FileKinds::default(),
+                architecture: None,
+                score: None,
+                primary: false,
+                archive_hash,
+                file_node_count,
+                path: tar_path,
+                rootfs_path: fs.path.clone(),
+                extract_dir: actual_extract_dir.to_owned(),
+                status,
+                kind: Some(fs.kind),
+            });
+        }
+    }
+
+    job.emit(Some(extractor_name), EventKind::StageFinished { stage: Stage::Identify });
+    if rootfs_choices.is_empty() && secondary_count == 0 {
+        return Err(ExtractProcessError::FailToFind);
+    }
+
+    // Without scratch, the rootfs is copied out of the temporary directory once the best
+    // extractor is picked, so it has to outlive this
+    if let Some(temp_dir) = temp_dir.filter(|_| copy_rootfs) {
+        kept_temp_dirs.lock().unwrap().push(temp_dir);
+    }
 
     Ok(())
 }
@@ -210,17 +300,3 @@ pub fn sha1_file(file: &Path) -> io::Result<String> {
 
     Ok(format!("{result:x}"))
 }
-
-pub fn copy_dir_all(src: &Path, dst: &Path) -> io::Result<()> {
-    fs::create_dir_all(dst)?;
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let ty = entry.file_type()?;
-        if ty.is_dir() {
-            copy_dir_all(&entry.path(), &dst.join(entry.file_name()))?;
-        } else {
-            fs::copy(entry.path(), dst.join(entry.file_name()))?;
-        }
-    }
-    Ok(())
-}
@@ -0,0 +1,274 @@
+//! Post-extraction safety pass over extracted trees.
+//!
+//! Extractors write whatever the firmware contains, so an extracted tree can hold symlinks to
+//! host paths (`/etc/shadow`), relative symlinks climbing out of the extraction directory,
+//! symlink loops, or hardlinks to host files created by an extractor that trusted the image.
+//! [`check_tree`] finds those before anything else looks at the tree: hardlinks to files
+//! outside the tree are removed, as their contents are host data; symlinks are only reported,
+//! since they are legitimate inside the rootfs and nothing in xfs follows them on the host.
+//! [`resolve_in`] resolves paths inside a tree the way the firmware would see them.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+/// Symlinks followed before giving up, as `MAXSYMLINKS` in Linux
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Paths listed per kind of hazard; the rest are only counted
+const MAX_LISTED: usize = 100;
+
+/// Hazards found in one extraction directory. Paths are relative to it, and at most
+/// 100 are listed per kind.
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct SafetyReport {
+    /// Relative symlinks that resolve outside the extraction directory
+    pub escaping_symlinks: Vec<PathBuf>,
+    pub escaping_symlink_count: usize,
+    /// Symlink chains that loop
+    pub symlink_loops: Vec<PathBuf>,
+    pub symlink_loop_count: usize,
+    /// Files hard-linked to a file outside the extraction directory, which were removed
+    pub removed_hardlinks: Vec<PathBuf>,
+    pub removed_hardlink_count: usize,
+    /// Symlinks to absolute paths. Fine inside the rootfs, but they point at the host when
+    /// followed outside of it.
+    pub absolute_symlink_count: usize,
+}
+
+impl SafetyReport {
+    pub fn is_empty(&self) -> bool {
+        self.escaping_symlink_count == 0
+            && self.symlink_loop_count == 0
+            && self.removed_hardlink_count == 0
+    }
+}
+
+fn note(list: &mut Vec<PathBuf>, count: &mut usize, path: &Path) {
+    *count += 1;
+    if list.len() < MAX_LISTED {
+        list.push(path.to_owned());
+    }
+}
+
+/// Where a path inside a tree ends up
+#[derive(Debug, PartialEq, Eq)]
+enum Resolved {
+    /// Host path of the final entry, which may not exist
+    Inside(PathBuf),
+    Outside,
+    Loop,
+}
+
+/// A path component, owned so symlink targets can be spliced into the pending path
+enum Part {
+    Root,
+    Parent,
+    Name(OsString),
+}
+
+fn parts(path: &Path) -> impl DoubleEndedIterator<Item = Part> + '_ {
+    path.components().filter_map(|component| match component {
+        Component::RootDir | Component::Prefix(_) => Some(Part::Root),
+        Component::CurDir => None,
+        Component::ParentDir => Some(Part::Parent),
+        Component::Normal(name) => Some(Part::Name(name.to_owned())),
+    })
+}
+
+/// Follow `rel` from `root` through any symlinks without leaving `root`. With `chroot`,
+/// absolute targets and `..` are relative to `root`, as for the firmware running from it;
+/// otherwise they leave the tree, as they would on the host.
+fn resolve(root: &Path, rel: &Path, chroot: bool) -> Resolved {
+    let mut resolved = root.to_path_buf();
+    let mut depth = 0;
+    let mut pending: Vec<Part> = parts(rel).rev().collect();
+    let mut hops = 0;
+
+    while let Some(part) = pending.pop() {
+        match part {
+            Part::Root => {
+                if !chroot {
+                    return Resolved::Outside;
+                }
+                resolved = root.to_path_buf();
+                depth = 0;
+            }
+            Part::Parent if depth == 0 => {
+                if !chroot {
+                    return Resolved::Outside;
+                }
+            }
+            Part::Parent => {
+                resolved.pop();
+                depth -= 1;
+            }
+            Part::Name(name) => {
+                let path = resolved.join(&name);
+                match fs::symlink_metadata(&path) {
+                    Ok(meta) if meta.file_type().is_symlink() => {
+                        hops += 1;
+                        if hops > MAX_SYMLINK_HOPS {
+                            return Resolved::Loop;
+                        }
+
+                        let Ok(target) = fs::read_link(&path) else {
+                            return Resolved::Outside;
+                        };
+                        pending.extend(parts(&target).rev());
+                    }
+                    _ => {
+                        resolved = path;
+                        depth += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    Resolved::Inside(resolved)
+}
+
+/// Host path `rel` refers to inside the tree at `root`, following symlinks as the firmware
+/// would if `root` were its `/`. `None` if the path does not exist or loops.
+pub fn resolve_in(root: &Path, rel: &Path) -> Option<PathBuf> {
+    match resolve(root, rel, true) {
+        Resolved::Inside(path) => fs::symlink_metadata(&path).is_ok().then_some(path),
+        Resolved::Outside | Resolved::Loop => None,
+    }
+}
+
+/// Check the extraction directory `dir` for entries that escape it, removing hardlinks to
+/// files outside of it. Nothing is removed if part of `dir` could not be read, since the
+/// missing links may be inside it.
+pub fn check_tree(dir: &Path) -> SafetyReport {
+    let mut report = SafetyReport::default();
+    // Multiply-linked files, with the links seen inside the tree
+    let mut links: HashMap<(u64, u64), (u64, Vec<PathBuf>)> = HashMap::new();
+    // Links in entries the walk could not see would look like links outside the tree
+    let mut walk_complete = true;
+
+    for entry in WalkDir::new(dir).min_depth(1) {
+        let Ok(entry) = entry else {
+            walk_complete = false;
+            continue;
+        };
+        let Ok(meta) = entry.metadata() else {
+            walk_complete = false;
+            continue;
+        };
+        let rel = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+
+        if meta.file_type().is_symlink() {
+            if fs::read_link(entry.path()).is_ok_and(|target| target.is_absolute()) {
+                report.absolute_symlink_count += 1;
+                continue;
+            }
+
+            match resolve(dir, rel, false) {
+                Resolved::Inside(_) => {}
+                Resolved::Outside => note(
+                    &mut report.escaping_symlinks,
+                    &mut report.escaping_symlink_count,
+                    rel,
+                ),
+                Resolved::Loop => note(
+                    &mut report.symlink_loops,
+                    &mut report.symlink_loop_count,
+                    rel,
+                ),
+            }
+        } else if meta.is_file() && meta.nlink() > 1 {
+            links
+                .entry((meta.dev(), meta.ino()))
+                .or_insert_with(|| (meta.nlink(), Vec::new()))
+                .1
+                .push(entry.path().to_owned());
+        }
+    }
+
+    for (nlink, paths) in links.into_values() {
+        if paths.len() as u64 >= nlink {
+            continue;
+        }
+
+        if !walk_complete {
+            log::warn!(
+                "Not removing possible external hardlinks in {}: parts of it could not be read",
+                dir.display()
+            );
+            break;
+        }
+
+        for path in paths {
+            let rel = path.strip_prefix(dir).unwrap_or(&path);
+            if let Err(e) = fs::remove_file(&path) {
+                log::warn!("Failed to remove external hardlink {}: {e}", path.display());
+            }
+            note(
+                &mut report.removed_hardlinks,
+                &mut report.removed_hardlink_count,
+                rel,
+            );
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn check_tree_reports_escaping_and_looping_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir(dir.join("bin")).unwrap();
+        fs::write(dir.join("bin/busybox"), b"").unwrap();
+        symlink("busybox", dir.join("bin/sh")).unwrap();
+        symlink("../../../etc/shadow", dir.join("bin/shadow")).unwrap();
+        symlink("/etc/passwd", dir.join("bin/passwd")).unwrap();
+        symlink("b", dir.join("a")).unwrap();
+        symlink("a", dir.join("b")).unwrap();
+
+        let report = check_tree(dir);
+        assert_eq!(report.escaping_symlinks, [PathBuf::from("bin/shadow")]);
+        assert_eq!(report.symlink_loop_count, 2);
+        assert_eq!(report.absolute_symlink_count, 1);
+        assert_eq!(report.removed_hardlink_count, 0);
+        assert!(!report.is_empty());
+
+        // Nothing is removed for symlinks
+        assert!(fs::symlink_metadata(dir.join("bin/shadow")).is_ok());
+        assert_eq!(
+            resolve_in(dir, Path::new("/bin/sh")),
+            Some(dir.join("bin/busybox"))
+        );
+        assert_eq!(resolve_in(dir, Path::new("a")), None);
+    }
+
+    #[test]
+    fn check_tree_removes_only_external_hardlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("extracted");
+        fs::create_dir(&dir).unwrap();
+        fs::write(tmp.path().join("host"), b"secret").unwrap();
+        fs::hard_link(tmp.path().join("host"), dir.join("stolen")).unwrap();
+        fs::write(dir.join("a"), b"").unwrap();
+        fs::hard_link(dir.join("a"), dir.join("b")).unwrap();
+
+        let report = check_tree(&dir);
+        assert_eq!(report.removed_hardlinks, [PathBuf::from("stolen")]);
+        assert!(!dir.join("stolen").exists());
+        assert!(tmp.path().join("host").exists());
+        assert!(dir.join("a").exists() && dir.join("b").exists());
+    }
+}
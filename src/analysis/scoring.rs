@@ -0,0 +1,259 @@
+//! Ranking of root filesystem candidates.
+//!
+//! Every candidate is measured on a few named [`Feature`]s and scored with their weighted sum.
+//! The weights, and the minimums a directory has to meet to be a candidate at all, make up a
+//! [`ScoringProfile`], which can be loaded from a JSON file:
+//!
+//! ```json
+//! {
+//!     "min_key_files": 3,
+//!     "min_executables": 10,
+//!     "weights": {
+//!         "elf_files": 3.0,
+//!         "nesting_depth": -1.0
+//!     }
+//! }
+//! ```
+//!
+//! Anything left out keeps its default.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::find_linux_filesystems::{CRITICAL_FILES, KEY_DIRS};
+
+/// Something about a candidate that makes it more or less likely to be the real rootfs
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Fraction of the key directories and critical files present
+    KeyFiles,
+    /// Number of ELF executables and shared libraries, scored on a log10 scale
+    ElfFiles,
+    /// Whether `/sbin/init` exists
+    Init,
+    /// Whether busybox is installed
+    Busybox,
+    /// Fraction of symlinks whose target does not exist
+    BrokenSymlinks,
+    /// How many directories below the root of the image it was unpacked from the candidate is,
+    /// so the layout of each extractor's output does not count
+    NestingDepth,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 6] = [
+        Feature::KeyFiles,
+        Feature::ElfFiles,
+        Feature::Init,
+        Feature::Busybox,
+        Feature::BrokenSymlinks,
+        Feature::NestingDepth,
+    ];
+
+    pub fn default_weight(self) -> f64 {
+        match self {
+            Feature::KeyFiles => 4.0,
+            Feature::ElfFiles => 2.0,
+            Feature::Init => 1.0,
+            Feature::Busybox => 1.0,
+            Feature::BrokenSymlinks => -3.0,
+            Feature::NestingDepth => -0.25,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::KeyFiles => "key_files",
+            Feature::ElfFiles => "elf_files",
+            Feature::Init => "init",
+            Feature::Busybox => "busybox",
+            Feature::BrokenSymlinks => "broken_symlinks",
+            Feature::NestingDepth => "nesting_depth",
+        }
+    }
+
+    /// Put a measurement on the scale its weight applies to
+    fn scale(self, value: f64) -> f64 {
+        match self {
+            Feature::KeyFiles => value / (KEY_DIRS.len() + CRITICAL_FILES.len()) as f64,
+            Feature::ElfFiles => (1.0 + value).log10(),
+            _ => value,
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What was measured of a candidate
+#[derive(Debug, Clone, Default)]
+pub struct Features {
+    pub key_files: usize,
+    pub elf_files: usize,
+    pub init: bool,
+    pub busybox: bool,
+    pub symlinks: usize,
+    pub broken_symlinks: usize,
+    pub nesting_depth: usize,
+}
+
+impl Features {
+    fn value(&self, feature: Feature) -> f64 {
+        match feature {
+            Feature::KeyFiles => self.key_files as f64,
+            Feature::ElfFiles => self.elf_files as f64,
+            Feature::Init => self.init as u8 as f64,
+            Feature::Busybox => self.busybox as u8 as f64,
+            Feature::BrokenSymlinks if self.symlinks == 0 => 0.0,
+            Feature::BrokenSymlinks => self.broken_symlinks as f64 / self.symlinks as f64,
+            Feature::NestingDepth => self.nesting_depth as f64,
+        }
+    }
+}
+
+/// Weights and minimums used to rank candidates
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ScoringProfile {
+    /// Key directories and critical files a directory needs to be a candidate
+    pub min_key_files: usize,
+    /// ELF executables, shared libraries and scripts a candidate needs, going by their
+    /// contents rather than their mode
+    pub min_executables: usize,
+    /// Weights that differ from [`Feature::default_weight`]
+    pub weights: BTreeMap<Feature, f64>,
+}
+
+impl Default for ScoringProfile {
+    fn default() -> Self {
+        Self {
+            min_key_files: (KEY_DIRS.len() + CRITICAL_FILES.len()) / 2,
+            min_executables: 10,
+            weights: BTreeMap::new(),
+        }
+    }
+}
+
+impl ScoringProfile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let profile: Self = serde_json::from_str(&contents).map_err(|e| e.to_string())?;
+
+        if let Some((feature, _)) = profile.weights.iter().find(|(_, w)| !w.is_finite()) {
+            return Err(format!("weight of {feature:?} is not a number"));
+        }
+
+        Ok(profile)
+    }
+
+    pub fn weight(&self, feature: Feature) -> f64 {
+        self.weights
+            .get(&feature)
+            .copied()
+            .unwrap_or_else(|| feature.default_weight())
+    }
+
+    pub fn score(&self, features: &Features) -> Score {
+        let features: Vec<_> = Feature::ALL
+            .into_iter()
+            .map(|feature| {
+                let value = features.value(feature);
+                let weight = self.weight(feature);
+
+                FeatureScore {
+                    feature,
+                    value,
+                    weight,
+                    points: weight * feature.scale(value),
+                }
+            })
+            .collect();
+
+        Score {
+            total: features.iter().map(|feature| feature.points).sum(),
+            features,
+        }
+    }
+}
+
+/// How a candidate scored, feature by feature
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct Score {
+    /// Sum of the points of every feature
+    pub total: f64,
+    pub features: Vec<FeatureScore>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct FeatureScore {
+    pub feature: Feature,
+    /// What was measured: a count, a fraction, or 0/1 for yes/no features
+    pub value: f64,
+    pub weight: f64,
+    /// The weight times the measurement, after putting it on the feature's scale
+    pub points: f64,
+}
+
+impl Score {
+    /// Why this candidate was or was not picked over `selected`, the one that was
+    pub fn explain(&self, selected: &Score) -> String {
+        let margin = selected.total - self.total;
+        if margin <= 0.0 {
+            return format!(
+                "scored {:.2}, as high as the selected rootfs, which won the tie-break",
+                self.total
+            );
+        }
+
+        // The feature that cost this candidate the most against the selected one
+        let deciding = self
+            .features
+            .iter()
+            .zip(&selected.features)
+            .map(|(ours, theirs)| (ours.feature, theirs.points - ours.points))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        match deciding {
+            Some((feature, deficit)) if deficit > 0.0 => format!(
+                "scored {:.2}, {margin:.2} below the selected rootfs, mostly on {feature} ({deficit:.2})",
+                self.total
+            ),
+            _ => format!(
+                "scored {:.2}, {margin:.2} below the selected rootfs",
+                self.total
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_overrides_some_weights() {
+        let profile: ScoringProfile =
+            serde_json::from_str(r#"{"weights": {"nesting_depth": -2.0}}"#).unwrap();
+
+        assert_eq!(profile.weight(Feature::NestingDepth), -2.0);
+        assert_eq!(
+            profile.weight(Feature::Init),
+            Feature::Init.default_weight()
+        );
+        assert_eq!(
+            profile.min_key_files,
+            ScoringProfile::default().min_key_files
+        );
+    }
+}
@@ -1,8 +1,8 @@
 use std::collections::HashSet;
 use std::fs::{self, File};
-use std::io::{self, Cursor, Write};
+use std::io::{self, Cursor, Read, Write};
 use std::iter;
-use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::path::{Component, Path, PathBuf};
 use std::sync::Mutex;
 
@@ -11,6 +11,7 @@ use flate2::Compression;
 use nix::unistd::{Gid, Group, Uid, User};
 use walkdir::{DirEntry, WalkDir};
 
+use crate::extractors::fstree::{EntryKind, Manifest};
 use crate::metadata::Metadata;
 
 const FIXED_TIMESTAMP: u64 = 1546318800; // Tue Jan 01 2019 05:00:00 GMT+0000
@@ -24,6 +25,7 @@ fn is_blk_or_chr(meta: fs::Metadata) -> bool {
 
 pub fn tar_fs(
     rootfs_dir: &Path,
+    extract_dir: &Path,
     tar_path: &Path,
     fw2tar_metadata: &Metadata,
     removed_devices: Option<&Mutex<HashSet<PathBuf>>>,
@@ -51,7 +53,7 @@ pub fn tar_fs(
         entry
             .path()
             .file_name()
-            .map(|name| {
+            .and_then(|name| {
                 name.to_str().map(|name| {
                     for prefix in BAD_PREFIXES {
                         if name.starts_with(prefix) {
@@ -68,10 +70,35 @@ pub fn tar_fs(
                     true
                 })
             })
-            .flatten()
             .unwrap_or(true)
     };
 
+    // Native extractors record the original ownership and device nodes in a manifest, since
+    // they may not be able to apply them on disk
+    let manifest = Manifest::find(extract_dir, rootfs_dir);
+
+    if let (Some((manifest_root, manifest)), Some(removed_devices)) = (&manifest, removed_devices) {
+        let rootfs_key = rootfs_dir
+            .strip_prefix(manifest_root)
+            .unwrap_or(Path::new(""));
+
+        for (path, entry) in &manifest.entries {
+            let is_device = matches!(entry.kind, EntryKind::Char | EntryKind::Block);
+            let Ok(rel) = Path::new(path).strip_prefix("/") else {
+                continue;
+            };
+
+            if is_device && !entry.applied {
+                if let Ok(rel) = rel.strip_prefix(rootfs_key) {
+                    removed_devices
+                        .lock()
+                        .unwrap()
+                        .insert(Path::new("/").join(rel));
+                }
+            }
+        }
+    }
+
     let file = File::create(tar_path)?;
     let encoder = GzEncoder::new(file, Compression::default());
 
@@ -96,7 +123,14 @@ pub fn tar_fs(
         };
 
         let data = if metadata.is_file() {
-            fs::read(entry.path())?
+            // Never read through a symlink swapped in since the walk looked at the entry
+            let mut data = Vec::new();
+            File::options()
+                .read(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(entry.path())?
+                .read_to_end(&mut data)?;
+            data
         } else {
             Vec::new()
         };
@@ -104,22 +138,39 @@ pub fn tar_fs(
         let mut header = tar::Header::new_gnu();
         header.set_metadata_in_mode(&metadata, tar::HeaderMode::Deterministic);
         header.set_mode(metadata.permissions().mode());
-
-        if entry_path == "./" {
-            header.set_mode(0o755);
-        }
-
         header.set_mtime(FIXED_TIMESTAMP);
 
         if metadata.is_file() {
             header.set_size(data.len() as u64); // buffering to prevent ToKToU
         }
 
-        if let Ok(Some(user)) = User::from_uid(Uid::from_raw(metadata.uid())) {
+        let original = manifest.as_ref().and_then(|(manifest_root, manifest)| {
+            manifest.get(entry.path().strip_prefix(manifest_root).ok()?)
+        });
+
+        let (uid, gid) = match original {
+            Some(original) => {
+                header.set_uid(original.attrs.uid.into());
+                header.set_gid(original.attrs.gid.into());
+
+                if !metadata.is_symlink() {
+                    header.set_mode(original.attrs.mode);
+                }
+
+                (original.attrs.uid, original.attrs.gid)
+            }
+            None => (metadata.uid(), metadata.gid()),
+        };
+
+        if entry_path == "./" {
+            header.set_mode(0o755);
+        }
+
+        if let Ok(Some(user)) = User::from_uid(Uid::from_raw(uid)) {
             header.set_username(&user.name).unwrap();
         }
 
-        if let Ok(Some(user)) = Group::from_gid(Gid::from_raw(metadata.gid())) {
+        if let Ok(Some(user)) = Group::from_gid(Gid::from_raw(gid)) {
             header.set_groupname(&user.name).unwrap();
         }
 
@@ -1,6 +1,8 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use std::path::PathBuf;
 
+use crate::extractors::quota::Quota;
+
 /// Extract firmware images to filesystem archives
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
@@ -19,10 +21,23 @@ pub struct Args {
     #[arg(long)]
     pub copy_rootfs: bool,
 
-    /// Comma-separated list of extractors. Supported values are binwalk, binwalkv3, unblob
+    /// Keep the rootfs archives of the extractors that were not selected, as
+    /// rootfs.<extractor>.tar.gz (default: false)
+    #[arg(long)]
+    pub keep_candidates: bool,
+
+    /// Comma-separated list of extractors. Supported values are binwalk, binwalkv3, unblob, squashfs, cpio, extfs, jffs2, ubi, cramfs, romfs, minix, yaffs, vendor, and any extractor declared with --extractor-config
     #[arg(long)]
     pub extractors: Option<String>,
 
+    /// JSON file declaring additional external extractors
+    #[arg(long)]
+    pub extractor_config: Option<PathBuf>,
+
+    /// JSON file with the weights and minimums used to pick the rootfs
+    #[arg(long)]
+    pub scoring_profile: Option<PathBuf>,
+
     /// Enable loud (verbose) output - shows all extraction and processing steps
 #[arg(long)]
 pub loud: bool,
@@ -58,8 +73,76 @@ pub logs: bool,
     /// Timeout for extractors, measured in seconds
     #[arg(long, default_value_t = 20)]
     pub timeout: u64,
+
+    /// Address space limit for external extractor processes, in MiB
+    #[arg(long)]
+    pub memory_limit: Option<u64>,
+
+    /// CPU time limit for external extractor processes, in seconds
+    #[arg(long)]
+    pub cpu_limit: Option<u64>,
+
+    /// Size limit for any single file written by external extractor processes, in MiB
+    #[arg(long)]
+    pub file_size_limit: Option<u64>,
+
+    /// Run external extractors in a namespace sandbox that only exposes the system
+    /// directories, the firmware (read-only) and their own output directory, with no network
+    #[arg(long)]
+    pub sandbox: bool,
+
+    /// Extra path to expose read-only inside the sandbox, e.g. a Python installation outside
+    /// the system directories (can be repeated)
+    #[arg(long, requires = "sandbox")]
+    pub sandbox_allow: Vec<PathBuf>,
+
+    /// Total size limit for everything written by all extractors together, in MiB
+    #[arg(long)]
+    pub quota_size: Option<u64>,
+
+    /// Limit on the number of files written by all extractors together
+    #[arg(long)]
+    pub quota_files: Option<u64>,
+
+    /// Quota for a single extractor, as <EXTRACTOR>=<MiB>[:<FILES>] (e.g. unblob=2048:100000,
+    /// or binwalk=:50000 for a file count only). Can be repeated.
+    #[arg(long, value_parser = parse_extractor_quota)]
+    pub extractor_quota: Vec<(String, Quota)>,
     
     /// Show detailed progress output with stage information
     #[arg(long)]
     pub progress: bool,
+
+    /// How to report progress: as text, or as one JSON event per line on stdout
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Text)]
+    pub progress_format: ProgressFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProgressFormat {
+    Text,
+    Json,
+}
+
+fn parse_extractor_quota(value: &str) -> Result<(String, Quota), String> {
+    let (name, limits) = value
+        .split_once('=')
+        .ok_or("expected <EXTRACTOR>=<MiB>[:<FILES>]")?;
+    let (size, files) = limits.split_once(':').unwrap_or((limits, ""));
+
+    let parse = |limit: &str| match limit {
+        "" => Ok(None),
+        limit => limit
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|e| format!("invalid limit {limit:?}: {e}")),
+    };
+
+    Ok((
+        name.to_owned(),
+        Quota {
+            bytes: parse(size)?.map(|mib| mib << 20),
+            files: parse(files)?,
+        },
+    ))
 }
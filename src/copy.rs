@@ -0,0 +1,365 @@
+//! Copying an identified rootfs out of the scratch directory (`--copy-rootfs`).
+//!
+//! The copy reproduces the tree as closely as the current privileges allow: symlinks stay
+//! symlinks, hardlinks stay hardlinks, and ownership, modes (including setuid/setgid), extended
+//! attributes and timestamps are carried over. Nothing is ever followed or written through: the
+//! destination is created from scratch and every entry is created exclusively, so names and
+//! links chosen by the firmware cannot make the copy touch anything outside of it.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File, Permissions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    lchown, symlink, DirBuilderExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+};
+use std::path::{Path, PathBuf};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::extractors::fstree::{EntryKind, Manifest};
+
+/// What a rootfs copy could not reproduce.
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct CopyReport {
+    /// Device nodes that could not be created, as absolute paths inside the rootfs
+    pub skipped_devices: Vec<PathBuf>,
+    /// Other entries that could not be copied, and were left out
+    pub failed: Vec<CopyFailure>,
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Copy the extended attributes of `src` to `dst`, without following symlinks. Attributes the
+/// destination refuses (e.g. `security.*` while unprivileged) are skipped.
+fn copy_xattrs(src: &CString, dst: &CString) {
+    // SAFETY: the paths are NUL-terminated and the buffers are sized by the preceding calls
+    unsafe {
+        let len = libc::llistxattr(src.as_ptr(), std::ptr::null_mut(), 0);
+        if len <= 0 {
+            return;
+        }
+        let mut names = vec![0u8; len as usize];
+        let len = libc::llistxattr(src.as_ptr(), names.as_mut_ptr().cast(), names.len());
+        if len <= 0 {
+            return;
+        }
+        names.truncate(len as usize);
+
+        for name in names
+            .split(|&byte| byte == 0)
+            .filter(|name| !name.is_empty())
+        {
+            let Ok(name) = CString::new(name) else {
+                continue;
+            };
+
+            let len = libc::lgetxattr(src.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0);
+            if len < 0 {
+                continue;
+            }
+            let mut value = vec![0u8; len as usize];
+            let len = libc::lgetxattr(
+                src.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            );
+            if len < 0 {
+                continue;
+            }
+
+            libc::lsetxattr(
+                dst.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                len as usize,
+                0,
+            );
+        }
+    }
+}
+
+/// Set the access and modification times of `path` from `meta`, without following symlinks.
+fn copy_times(path: &CString, meta: &fs::Metadata) {
+    let times = [
+        libc::timespec {
+            tv_sec: meta.atime() as libc::time_t,
+            tv_nsec: meta.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec: meta.mtime() as libc::time_t,
+            tv_nsec: meta.mtime_nsec() as _,
+        },
+    ];
+
+    // SAFETY: path is NUL-terminated and times holds the two entries utimensat expects
+    unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        );
+    }
+}
+
+/// An entry that could not be copied.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct CopyFailure {
+    /// Absolute path inside the rootfs
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// State carried across the entries of one copy
+struct Copier {
+    privileged: bool,
+    /// First destination path of every multiply-linked file, to link the others to
+    inodes: HashMap<(u64, u64), PathBuf>,
+    report: CopyReport,
+}
+
+impl Copier {
+    /// Copy the non-directory entry at `path` to `target`.
+    fn copy_entry(
+        &mut self,
+        path: &Path,
+        meta: &fs::Metadata,
+        rel: &Path,
+        target: &Path,
+    ) -> io::Result<()> {
+        let file_type = meta.file_type();
+
+        if file_type.is_file() && meta.nlink() > 1 {
+            if let Some(first) = self.inodes.get(&(meta.dev(), meta.ino())) {
+                return fs::hard_link(first, target);
+            }
+        }
+
+        let c_target = c_path(target)?;
+        if file_type.is_file() {
+            let mut from = File::options()
+                .read(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(path)?;
+            let mut to = File::options()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(target)?;
+            io::copy(&mut from, &mut to)?;
+
+            // Only once the first link exists can the others be linked to it
+            if meta.nlink() > 1 {
+                self.inodes
+                    .insert((meta.dev(), meta.ino()), target.to_owned());
+            }
+        } else if file_type.is_symlink() {
+            symlink(fs::read_link(path)?, target)?;
+        } else if file_type.is_fifo() {
+            // SAFETY: c_target is NUL-terminated
+            check(unsafe { libc::mkfifo(c_target.as_ptr(), 0o600) })?;
+        } else if file_type.is_char_device() || file_type.is_block_device() {
+            // SAFETY: c_target is NUL-terminated
+            let created = self.privileged
+                && unsafe { libc::mknod(c_target.as_ptr(), meta.mode(), meta.rdev()) } == 0;
+            if !created {
+                self.report.skipped_devices.push(Path::new("/").join(rel));
+                return Ok(());
+            }
+        } else {
+            // Sockets are meaningless once copied
+            return Ok(());
+        }
+
+        if self.privileged {
+            let _ = lchown(target, Some(meta.uid()), Some(meta.gid()));
+        }
+        if !file_type.is_symlink() {
+            // After chown, which clears setuid/setgid
+            fs::set_permissions(target, Permissions::from_mode(meta.mode() & 0o7777))?;
+        }
+        copy_xattrs(&c_path(path)?, &c_target);
+        copy_times(&c_target, meta);
+
+        Ok(())
+    }
+
+    /// Apply the attributes of the source directory `src_dir` to `dir`.
+    fn finish_dir(&self, dir: &Path, src_dir: &Path, meta: &fs::Metadata) -> io::Result<()> {
+        if self.privileged {
+            let _ = lchown(dir, Some(meta.uid()), Some(meta.gid()));
+        }
+        // Keep the copy traversable by the user that made it
+        fs::set_permissions(dir, Permissions::from_mode((meta.mode() & 0o7777) | 0o700))?;
+        let c_dir = c_path(dir)?;
+        copy_xattrs(&c_path(src_dir)?, &c_dir);
+        copy_times(&c_dir, meta);
+
+        Ok(())
+    }
+
+    fn fail(&mut self, rel: &Path, error: impl ToString) {
+        let path = Path::new("/").join(rel);
+        let error = error.to_string();
+        log::warn!("Failed to copy {}: {error}", path.display());
+        self.report.failed.push(CopyFailure { path, error });
+    }
+}
+
+/// Copy the tree at `src`, found in the extractor output directory `extract_dir`, to `dst`,
+/// which must not exist yet. Entries that cannot be copied
+/// are listed in the report and skipped; only failing to create `dst` itself is an error.
+pub fn copy_tree(src: &Path, extract_dir: &Path, dst: &Path) -> io::Result<CopyReport> {
+    fs::DirBuilder::new().mode(0o700).create(dst)?;
+
+    let mut copier = Copier {
+        privileged: nix::unistd::geteuid().is_root(),
+        inodes: HashMap::new(),
+        report: CopyReport::default(),
+    };
+    // Directory attributes are applied last, so read-only directories can still be filled
+    let mut dirs = Vec::new();
+
+    let mut walk = WalkDir::new(src).min_depth(1).into_iter();
+    while let Some(entry) = walk.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().unwrap_or(src);
+                copier.fail(path.strip_prefix(src).unwrap_or(path), &e);
+                continue;
+            }
+        };
+        let rel = entry.path().strip_prefix(src).unwrap_or(entry.path());
+        let target = dst.join(rel);
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            Err(e) => {
+                copier.fail(rel, e);
+                continue;
+            }
+        };
+
+        if meta.is_dir() {
+            match fs::DirBuilder::new().mode(0o700).create(&target) {
+                Ok(()) => dirs.push((target, entry.path().to_owned(), meta)),
+                Err(e) => {
+                    copier.fail(rel, e);
+                    walk.skip_current_dir();
+                }
+            }
+        } else if let Err(e) = copier.copy_entry(entry.path(), &meta, rel, &target) {
+            copier.fail(rel, e);
+        }
+    }
+
+    // Deepest first, so parents stay writable until their children are done
+    dirs.sort_by_key(|(path, _, _)| std::cmp::Reverse(path.components().count()));
+    for (dir, src_dir, meta) in dirs {
+        if let Err(e) = copier.finish_dir(&dir, &src_dir, &meta) {
+            let rel = dir.strip_prefix(dst).unwrap_or(&dir).to_owned();
+            copier.fail(&rel, e);
+        }
+    }
+    if let Ok(meta) = fs::metadata(src) {
+        if let Err(e) = copier.finish_dir(dst, src, &meta) {
+            copier.fail(Path::new(""), e);
+        }
+    }
+
+    let mut report = copier.report;
+
+    // Native extractors only record device nodes in their manifest when unprivileged
+    if let Some((manifest_root, manifest)) = Manifest::find(extract_dir, src) {
+        let src_key = src.strip_prefix(&manifest_root).unwrap_or(Path::new(""));
+        for (path, entry) in &manifest.entries {
+            if !matches!(entry.kind, EntryKind::Char | EntryKind::Block) || entry.applied {
+                continue;
+            }
+            let Ok(rel) = Path::new(path).strip_prefix("/") else {
+                continue;
+            };
+            if let Ok(rel) = rel.strip_prefix(src_key) {
+                report.skipped_devices.push(Path::new("/").join(rel));
+            }
+        }
+    }
+
+    report.skipped_devices.sort();
+    report.skipped_devices.dedup();
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_tree_keeps_links_and_modes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        let dst = tmp.path().join("dst");
+        fs::create_dir_all(src.join("bin")).unwrap();
+        fs::write(src.join("bin/busybox"), b"busybox").unwrap();
+        fs::set_permissions(src.join("bin/busybox"), Permissions::from_mode(0o4755)).unwrap();
+        fs::hard_link(src.join("bin/busybox"), src.join("bin/su")).unwrap();
+        symlink("busybox", src.join("bin/sh")).unwrap();
+        symlink("/etc/shadow", src.join("shadow")).unwrap();
+        fs::set_permissions(src.join("bin"), Permissions::from_mode(0o555)).unwrap();
+
+        let report = copy_tree(&src, tmp.path(), &dst).unwrap();
+        assert!(report.failed.is_empty());
+
+        let busybox = fs::metadata(dst.join("bin/busybox")).unwrap();
+        assert_eq!(busybox.mode() & 0o7777, 0o4755);
+        assert_eq!(fs::read(dst.join("bin/busybox")).unwrap(), b"busybox");
+        assert_eq!(
+            fs::metadata(dst.join("bin/su")).unwrap().ino(),
+            busybox.ino()
+        );
+        assert_ne!(
+            busybox.ino(),
+            fs::metadata(src.join("bin/busybox")).unwrap().ino()
+        );
+
+        assert_eq!(
+            fs::read_link(dst.join("bin/sh")).unwrap(),
+            Path::new("busybox")
+        );
+        // Copied as a link, not followed
+        assert_eq!(
+            fs::read_link(dst.join("shadow")).unwrap(),
+            Path::new("/etc/shadow")
+        );
+        assert_eq!(
+            fs::metadata(dst.join("bin")).unwrap().mode() & 0o7777,
+            0o755
+        );
+    }
+
+    #[test]
+    fn copy_tree_refuses_an_existing_destination() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        fs::create_dir(&src).unwrap();
+
+        let err = copy_tree(&src, tmp.path(), tmp.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+}
@@ -4,9 +4,15 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum Fw2tarError {
-    #[error("Invalid extractor {0:?} (valid options: binwalk, binwalkv3, unblob)")]
+    #[error("Invalid extractor {0:?} (valid options: {valid})", valid = valid_extractors())]
     InvalidExtractor(String),
 
+    #[error("Invalid extractor config {0:?}: {1}")]
+    InvalidExtractorConfig(PathBuf, String),
+
+    #[error("Invalid scoring profile {0:?}: {1}")]
+    InvalidScoringProfile(PathBuf, String),
+
     #[error("Provided firmware ({0:?}) is not a file.")]
     FirmwareNotAFile(PathBuf),
 
@@ -16,6 +22,18 @@ pub enum Fw2tarError {
     #[error("Output file or directory ({0:?}) already exists. Use --force to overwrite.")]
     OutputExists(PathBuf),
 
+    #[error("Another xfs process is writing to {0:?}")]
+    OutputLocked(PathBuf),
+
+    #[error("Interrupted, no output was written")]
+    Interrupted,
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 }
+
+fn valid_extractors() -> String {
+    crate::extractors::all_extractor_names()
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -1,4 +1,5 @@
-use super::{ExtractError, Extractor};
+use super::sandbox::Workspace;
+use super::{ExtractError, ExtractStatus, Extractor};
 use std::path::Path;
 use std::process::Command;
 
@@ -15,19 +16,24 @@ impl Extractor for Binwalk3Extractor {
         extract_dir: &Path,
         log_file: &Path,
         verbose: bool,
-    ) -> Result<(), ExtractError> {
+    ) -> Result<ExtractStatus, ExtractError> {
         // TODO: reimplement using binwalk Rust API? Currently a lot of logic in
         //       binwalk 3.1's main.rs I'd need to reimplement...
-        let output = Command::new("binwalk")
+        let mut command = Command::new("binwalk");
+        command
             .arg("-eM")
             .arg("--log")
             .arg(log_file)
             .arg("-q")
             .arg(in_file)
             .arg("-C")
-            .arg(extract_dir)
-            .output()?;
+            .arg(extract_dir);
 
-        self.cmd_output_to_result(output, false, verbose)
+        let workspace = Workspace {
+            input: in_file,
+            extract_dir,
+            log_file,
+        };
+        self.run_command(command, &workspace, verbose)
     }
 }
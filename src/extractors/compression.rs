@@ -0,0 +1,605 @@
+//! Decompressors used by the native extractors.
+//!
+//! Each function takes a complete compressed block and returns the decompressed bytes,
+//! refusing to produce more than `max_len` bytes so corrupt or hostile images cannot make
+//! us allocate unbounded amounts of memory.
+
+use std::io::{self, Read};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+use super::native::invalid_data;
+
+/// Compression algorithms understood by the native extractors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zlib,
+    /// Raw deflate without the zlib header, as used by UBIFS
+    Deflate,
+    Gzip,
+    /// LZMA "alone" format (13 byte header: properties, dictionary size, unpacked size)
+    Lzma,
+    Xz,
+    Lzo,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Zlib => "zlib",
+            Codec::Deflate => "deflate",
+            Codec::Gzip => "gzip",
+            Codec::Lzma => "lzma",
+            Codec::Xz => "xz",
+            Codec::Lzo => "lzo",
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    pub fn decompress(self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Zlib => read_limited(ZlibDecoder::new(data), max_len),
+            Codec::Deflate => read_limited(DeflateDecoder::new(data), max_len),
+            Codec::Gzip => read_limited(GzDecoder::new(data), max_len),
+            Codec::Lzma => lzma(data, max_len),
+            Codec::Xz => xz(data, max_len),
+            Codec::Lzo => lzo1x(data, max_len),
+            Codec::Lz4 => lz4_block(data, max_len),
+            Codec::Zstd => zstd(data, max_len),
+        }
+    }
+}
+
+impl Codec {
+    /// Identify a self-delimiting compressed stream (one with its own header) at the start
+    /// of `data`.
+    pub fn detect_stream(data: &[u8]) -> Option<Codec> {
+        if data.starts_with(&[0x1f, 0x8b, 0x08]) {
+            Some(Codec::Gzip)
+        } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Codec::Xz)
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Codec::Zstd)
+        } else if is_lzma_alone_header(data) {
+            Some(Codec::Lzma)
+        } else {
+            None
+        }
+    }
+
+    /// Cheaply decompress up to `len` bytes from the start of the stream at `data`, without
+    /// decoding the rest. Returns `None` if the data does not decode.
+    pub fn peek(self, data: &[u8], len: usize) -> Option<Vec<u8>> {
+        let mut out = match self {
+            Codec::Gzip => read_prefix(flate2::bufread::GzDecoder::new(data), len)?,
+            Codec::Zlib => read_prefix(flate2::bufread::ZlibDecoder::new(data), len)?,
+            Codec::Zstd => read_prefix(ruzstd::decoding::StreamingDecoder::new(data).ok()?, len)?,
+            Codec::Xz => {
+                // Decode a bounded prefix of the input. The decoder errors out once the
+                // prefix runs out, but the blocks it produced until then are all that is needed.
+                let prefix = &data[..data.len().min(PEEK_INPUT_LEN)];
+                let mut writer = LimitedWriter {
+                    out: Vec::new(),
+                    max_len: PEEK_OUTPUT_LEN,
+                };
+                let _ = lzma_rs::xz_decompress(&mut &prefix[..], &mut writer);
+                writer.out
+            }
+            Codec::Lzma => {
+                // Decode a bounded prefix of the input; garbage almost always fails quickly
+                let prefix = &data[..data.len().min(PEEK_INPUT_LEN)];
+                lzma_alone(prefix, PEEK_OUTPUT_LEN).ok()?.0
+            }
+            _ => self.decompress(data, PEEK_OUTPUT_LEN).ok()?,
+        };
+
+        out.truncate(len);
+        Some(out)
+    }
+
+    /// Decompress the self-delimiting stream at the start of `data`. Returns the output and
+    /// the number of input bytes the stream occupied.
+    pub fn decompress_stream(self, data: &[u8], max_len: usize) -> io::Result<(Vec<u8>, usize)> {
+        let mut input = data;
+
+        let out = match self {
+            Codec::Gzip => read_limited(flate2::bufread::GzDecoder::new(&mut input), max_len)?,
+            Codec::Zlib => read_limited(flate2::bufread::ZlibDecoder::new(&mut input), max_len)?,
+            Codec::Deflate => {
+                read_limited(flate2::bufread::DeflateDecoder::new(&mut input), max_len)?
+            }
+            Codec::Zstd => {
+                let decoder = ruzstd::decoding::StreamingDecoder::new(&mut input)
+                    .map_err(|err| invalid_data(format!("zstd: {err}")))?;
+                read_limited(decoder, max_len)?
+            }
+            Codec::Xz => {
+                // lzma-rs rejects trailing data, so cut the stream at its footer first
+                let len = xz_stream_len(data)
+                    .ok_or_else(|| invalid_data("xz stream footer not found"))?;
+                input = &data[len..];
+                xz(&data[..len], max_len)?
+            }
+            Codec::Lzma => {
+                let (out, len) = lzma_alone(data, max_len)?;
+                input = &data[len..];
+                out
+            }
+            Codec::Lzo | Codec::Lz4 => {
+                return Err(invalid_data(format!(
+                    "raw {} blocks are not self-delimiting",
+                    self.name()
+                )))
+            }
+        };
+
+        Ok((out, data.len() - input.len()))
+    }
+}
+
+const PEEK_INPUT_LEN: usize = 64 * 1024;
+const PEEK_OUTPUT_LEN: usize = 4 * 1024 * 1024;
+
+/// Check for a plausible LZMA "alone" header: the default `lc=3 lp=0 pb=2` properties, a
+/// power of two dictionary size and either an unknown or a sane unpacked size.
+fn is_lzma_alone_header(data: &[u8]) -> bool {
+    if data.len() < 13 || data[0] != 0x5d {
+        return false;
+    }
+
+    let dict_size = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+    let mut unpacked = [0; 8];
+    unpacked.copy_from_slice(&data[5..13]);
+    let unpacked = u64::from_le_bytes(unpacked);
+
+    dict_size.is_power_of_two()
+        && (1 << 12..=1 << 26).contains(&dict_size)
+        && (unpacked == u64::MAX || unpacked < 1 << 32)
+}
+
+/// Decompress the LZMA "alone" stream at the start of `data`, returning the output and the
+/// number of input bytes the stream occupied.
+fn lzma_alone(data: &[u8], max_len: usize) -> io::Result<(Vec<u8>, usize)> {
+    let options = lzma_rs::decompress::Options {
+        memlimit: Some(max_len),
+        allow_incomplete: true,
+        ..Default::default()
+    };
+
+    let decode = |data: &[u8]| {
+        let mut input = data;
+        let mut writer = LimitedWriter {
+            out: Vec::new(),
+            max_len,
+        };
+        let result = lzma_rs::lzma_decompress_with_options(&mut input, &mut writer, &options);
+        (result, writer.out, data.len() - input.len())
+    };
+
+    match decode(data) {
+        (Ok(()), out, len) => Ok((out, len)),
+        // lzma-rs refuses an end marker that is not at the end of its input (and drops the
+        // output), but the input has been consumed exactly up to the end of the stream, so
+        // decode again with the trailing data cut off.
+        (Err(lzma_rs::error::Error::LzmaError(msg)), _, len)
+            if msg.contains("more bytes are available") =>
+        {
+            match decode(&data[..len]) {
+                (Ok(()), out, len) => Ok((out, len)),
+                (Err(err), _, _) => Err(lzma_error(err)),
+            }
+        }
+        (Err(err), _, _) => Err(lzma_error(err)),
+    }
+}
+
+/// Length of the xz stream at the start of `data`, found by looking for a stream footer
+/// whose flags and CRC match.
+fn xz_stream_len(data: &[u8]) -> Option<usize> {
+    const HEADER_LEN: usize = 12;
+    const FOOTER_LEN: usize = 12;
+
+    let flags = data.get(6..8)?;
+
+    (HEADER_LEN + FOOTER_LEN..=data.len())
+        .step_by(4)
+        .find(|&end| {
+            let footer = &data[end - FOOTER_LEN..end];
+            if &footer[10..] != b"YZ" || &footer[8..10] != flags {
+                return false;
+            }
+
+            let mut crc = flate2::Crc::new();
+            crc.update(&footer[4..10]);
+            crc.sum() == u32::from_le_bytes([footer[0], footer[1], footer[2], footer[3]])
+        })
+}
+
+fn read_prefix(reader: impl Read, len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    reader.take(len as u64).read_to_end(&mut out).ok()?;
+    Some(out)
+}
+
+fn read_limited(reader: impl Read, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    reader.take(max_len as u64 + 1).read_to_end(&mut out)?;
+
+    if out.len() > max_len {
+        return Err(too_large(max_len));
+    }
+
+    Ok(out)
+}
+
+fn too_large(max_len: usize) -> io::Error {
+    invalid_data(format!("decompressed data exceeds {max_len} bytes"))
+}
+
+/// Writer that errors out instead of growing past a fixed size.
+struct LimitedWriter {
+    out: Vec<u8>,
+    max_len: usize,
+}
+
+impl io::Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.out.len() + buf.len() > self.max_len {
+            return Err(too_large(self.max_len));
+        }
+
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn lzma_error(err: lzma_rs::error::Error) -> io::Error {
+    match err {
+        lzma_rs::error::Error::IoError(err) | lzma_rs::error::Error::HeaderTooShort(err) => err,
+        err => invalid_data(err.to_string()),
+    }
+}
+
+fn lzma(data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let mut out = LimitedWriter {
+        out: Vec::new(),
+        max_len,
+    };
+
+    let options = lzma_rs::decompress::Options {
+        memlimit: Some(max_len.max(1 << 20) * 2),
+        allow_incomplete: true,
+        ..Default::default()
+    };
+
+    lzma_rs::lzma_decompress_with_options(&mut &data[..], &mut out, &options)
+        .map_err(lzma_error)?;
+
+    Ok(out.out)
+}
+
+/// Decompress a raw LZMA stream without the "alone" header (as used by JFFS2 and some
+/// vendor squashfs patches) by synthesizing one from the given properties.
+pub fn lzma_raw(
+    data: &[u8],
+    lc: u8,
+    lp: u8,
+    pb: u8,
+    dict_size: u32,
+    unpacked_len: usize,
+) -> io::Result<Vec<u8>> {
+    let mut stream = Vec::with_capacity(data.len() + 13);
+    stream.push((pb * 5 + lp) * 9 + lc);
+    stream.extend_from_slice(&dict_size.to_le_bytes());
+    stream.extend_from_slice(&(unpacked_len as u64).to_le_bytes());
+    stream.extend_from_slice(data);
+
+    lzma(&stream, unpacked_len)
+}
+
+fn xz(data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let mut out = LimitedWriter {
+        out: Vec::new(),
+        max_len,
+    };
+
+    lzma_rs::xz_decompress(&mut &data[..], &mut out).map_err(lzma_error)?;
+
+    Ok(out.out)
+}
+
+fn zstd(data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let decoder = ruzstd::decoding::StreamingDecoder::new(data)
+        .map_err(|err| invalid_data(format!("zstd: {err}")))?;
+
+    read_limited(decoder, max_len)
+}
+
+fn copy_match(out: &mut Vec<u8>, distance: usize, len: usize, max_len: usize) -> io::Result<()> {
+    if distance == 0 || distance > out.len() {
+        return Err(invalid_data(format!(
+            "back-reference {distance} bytes before start of output"
+        )));
+    }
+
+    if out.len() + len > max_len {
+        return Err(too_large(max_len));
+    }
+
+    let start = out.len() - distance;
+    for i in 0..len {
+        out.push(out[start + i]);
+    }
+
+    Ok(())
+}
+
+fn copy_literals(
+    out: &mut Vec<u8>,
+    src: &[u8],
+    ip: &mut usize,
+    len: usize,
+    max_len: usize,
+) -> io::Result<()> {
+    let literals = src
+        .get(*ip..*ip + len)
+        .ok_or_else(|| invalid_data("literal run past end of input"))?;
+
+    if out.len() + len > max_len {
+        return Err(too_large(max_len));
+    }
+
+    out.extend_from_slice(literals);
+    *ip += len;
+
+    Ok(())
+}
+
+/// LZO1X decompression (the format produced by `lzo1x_1_compress` and `lzo1x_999_compress`).
+pub fn lzo1x(src: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let byte = |ip: &mut usize| -> io::Result<usize> {
+        let b = *src
+            .get(*ip)
+            .ok_or_else(|| invalid_data("lzo: input overrun"))?;
+        *ip += 1;
+        Ok(b as usize)
+    };
+
+    // Length of a run encoded as a sequence of zero bytes followed by a non-zero byte
+    let zero_run = |ip: &mut usize, base: usize| -> io::Result<usize> {
+        let mut len = base;
+        loop {
+            let b = byte(ip)?;
+            if b != 0 {
+                return Ok(len + b);
+            }
+            len += 255;
+        }
+    };
+
+    let mut out = Vec::with_capacity(max_len.min(1 << 20));
+    let mut ip = 0;
+    let mut state = 0;
+
+    let mut next;
+    let first = *src
+        .first()
+        .ok_or_else(|| invalid_data("lzo: empty input"))? as usize;
+
+    // Pending number of literals to copy after a match (or before the first instruction)
+    let mut trailing = None;
+
+    if first > 17 {
+        ip += 1;
+        let t = first - 17;
+        if t < 4 {
+            trailing = Some(t);
+        } else {
+            copy_literals(&mut out, src, &mut ip, t, max_len)?;
+            state = 4;
+        }
+    }
+
+    loop {
+        if let Some(t) = trailing.take() {
+            copy_literals(&mut out, src, &mut ip, t, max_len)?;
+            state = t;
+        }
+
+        let mut t = byte(&mut ip)?;
+        let distance;
+
+        if t < 16 {
+            if state == 0 {
+                // Literal run
+                let len = if t == 0 { zero_run(&mut ip, 15)? } else { t } + 3;
+                copy_literals(&mut out, src, &mut ip, len, max_len)?;
+                state = 4;
+                continue;
+            } else if state != 4 {
+                // Two byte match, close by
+                next = t & 3;
+                distance = 1 + (t >> 2) + (byte(&mut ip)? << 2);
+                copy_match(&mut out, distance, 2, max_len)?;
+                trailing = Some(next);
+                continue;
+            } else {
+                // Three byte match following a literal run
+                next = t & 3;
+                distance = 1 + 0x800 + (t >> 2) + (byte(&mut ip)? << 2);
+                t = 3;
+            }
+        } else if t >= 64 {
+            next = t & 3;
+            distance = 1 + ((t >> 2) & 7) + (byte(&mut ip)? << 3);
+            t = (t >> 5) + 1;
+        } else if t >= 32 {
+            t &= 31;
+            t = if t == 0 { zero_run(&mut ip, 31)? } else { t } + 2;
+            let word = byte(&mut ip)? | (byte(&mut ip)? << 8);
+            distance = 1 + (word >> 2);
+            next = word & 3;
+        } else {
+            let high = (t & 8) << 11;
+            t &= 7;
+            t = if t == 0 { zero_run(&mut ip, 7)? } else { t } + 2;
+            let word = byte(&mut ip)? | (byte(&mut ip)? << 8);
+            next = word & 3;
+
+            let distance_raw = high + (word >> 2);
+            if distance_raw == 0 {
+                // End of stream marker
+                break;
+            }
+
+            distance = distance_raw + 0x4000;
+        }
+
+        copy_match(&mut out, distance, t, max_len)?;
+        trailing = Some(next);
+    }
+
+    Ok(out)
+}
+
+/// LZ4 block format decompression (no frame header).
+pub fn lz4_block(src: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(max_len.min(1 << 20));
+    let mut ip = 0;
+
+    let byte = |ip: &mut usize| -> io::Result<usize> {
+        let b = *src
+            .get(*ip)
+            .ok_or_else(|| invalid_data("lz4: input overrun"))?;
+        *ip += 1;
+        Ok(b as usize)
+    };
+
+    let extended = |ip: &mut usize, mut len: usize| -> io::Result<usize> {
+        if len == 15 {
+            loop {
+                let b = byte(ip)?;
+                len += b;
+                if b != 255 {
+                    break;
+                }
+            }
+        }
+        Ok(len)
+    };
+
+    while ip < src.len() {
+        let token = byte(&mut ip)?;
+
+        let literals = extended(&mut ip, token >> 4)?;
+        copy_literals(&mut out, src, &mut ip, literals, max_len)?;
+
+        if ip >= src.len() {
+            break;
+        }
+
+        let distance = byte(&mut ip)? | (byte(&mut ip)? << 8);
+        let len = extended(&mut ip, token & 15)? + 4;
+
+        copy_match(&mut out, distance, len, max_len)?;
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn refuses_output_past_max_len() {
+        let zeros = vec![0; 1 << 20];
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&zeros).unwrap();
+        let zlib = encoder.finish().unwrap();
+        assert_eq!(Codec::Zlib.decompress(&zlib, zeros.len()).unwrap(), zeros);
+        assert!(Codec::Zlib.decompress(&zlib, zeros.len() - 1).is_err());
+
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut &zeros[..], &mut xz).unwrap();
+        assert_eq!(Codec::Xz.decompress(&xz, zeros.len()).unwrap(), zeros);
+        assert!(Codec::Xz.decompress(&xz, 4096).is_err());
+    }
+
+    #[test]
+    fn streams_report_their_length() {
+        for (codec, mut stream) in [
+            (Codec::Gzip, gzip(b"gzip stream")),
+            (Codec::Xz, {
+                let mut xz = Vec::new();
+                lzma_rs::xz_compress(&mut &b"xz stream"[..], &mut xz).unwrap();
+                xz
+            }),
+            (Codec::Lzma, {
+                let mut lzma = Vec::new();
+                lzma_rs::lzma_compress(&mut &b"lzma stream"[..], &mut lzma).unwrap();
+                lzma
+            }),
+        ] {
+            assert_eq!(Codec::detect_stream(&stream), Some(codec));
+
+            let len = stream.len();
+            stream.extend_from_slice(&[0; 64]);
+            let (out, stream_len) = codec.decompress_stream(&stream, 1024).unwrap();
+            assert_eq!(stream_len, len, "{}", codec.name());
+            assert!(out.ends_with(b" stream"));
+            assert_eq!(codec.peek(&stream, 4).unwrap().len(), 4);
+        }
+
+        assert_eq!(Codec::detect_stream(b"plain text"), None);
+    }
+
+    #[test]
+    fn lzma_raw_synthesizes_the_header() {
+        let mut lzma = Vec::new();
+        lzma_rs::lzma_compress(&mut &b"raw lzma"[..], &mut lzma).unwrap();
+
+        // lzma-rs writes the default properties and a 8 MiB dictionary
+        assert_eq!(
+            lzma_raw(&lzma[13..], 3, 0, 2, 1 << 23, 8).unwrap(),
+            b"raw lzma"
+        );
+    }
+
+    #[test]
+    fn lzo1x_literals_and_match() {
+        let stream = [21, b'a', b'b', b'c', b'd', 0x6c, 0x00, 0x11, 0x00, 0x00];
+        assert_eq!(lzo1x(&stream, 64).unwrap(), b"abcdabcd");
+    }
+
+    #[test]
+    fn lzo1x_rejects_bad_distance() {
+        let stream = [18, b'a', 0x6c, 0x00, 0x11, 0x00, 0x00];
+        assert!(lzo1x(&stream, 64).is_err());
+    }
+
+    #[test]
+    fn lz4_literals_and_match() {
+        let block = [0x40, b'a', b'b', b'c', b'd', 0x04, 0x00, 0x10, b'e'];
+        assert_eq!(lz4_block(&block, 64).unwrap(), b"abcdabcde");
+        assert!(lz4_block(&block, 8).is_err());
+    }
+}
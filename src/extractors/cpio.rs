@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::compression::Codec;
+use super::fstree::{safe_name, Attrs, EntryKind, TreeWriter};
+use super::native::{self, Endian, Reader, Unpacked};
+use super::{ExtractError, ExtractStatus, Extractor};
+
+const NEWC_MAGIC: &[u8] = b"070701";
+const CRC_MAGIC: &[u8] = b"070702";
+const ODC_MAGIC: &[u8] = b"070707";
+const BINARY_MAGIC_LE: &[u8] = &[0xc7, 0x71];
+const BINARY_MAGIC_BE: &[u8] = &[0x71, 0xc7];
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b, 0x08];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const LZMA_MAGIC: &[u8] = &[0x5d, 0x00, 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+const MAX_NAME_LEN: usize = 4096;
+const MAX_DECOMPRESSED: usize = 1 << 30;
+
+/// In-process reader for cpio archives (newc, crc, odc and old binary), optionally wrapped in
+/// gzip, xz, lzma or zstd compression as used for initramfs images
+pub struct CpioExtractor;
+
+impl Extractor for CpioExtractor {
+    fn name(&self) -> &'static str {
+        "cpio"
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        _verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        native::extract_all(
+            self.name(),
+            in_file,
+            extract_dir,
+            log_file,
+            &[
+                NEWC_MAGIC,
+                CRC_MAGIC,
+                ODC_MAGIC,
+                BINARY_MAGIC_LE,
+                BINARY_MAGIC_BE,
+                GZIP_MAGIC,
+                XZ_MAGIC,
+                LZMA_MAGIC,
+                ZSTD_MAGIC,
+            ],
+            |data, offset, out_dir| {
+                let data = &data[offset..];
+
+                let Some(codec) = Codec::detect_stream(data) else {
+                    return Ok(
+                        unpack_archives(data, out_dir)?.map(|(len, entries)| Unpacked {
+                            len,
+                            entries,
+                            warnings: Vec::new(),
+                        }),
+                    );
+                };
+
+                // Only decompress streams that start with a cpio header
+                let is_cpio = codec
+                    .peek(data, 128)
+                    .is_some_and(|prefix| Header::parse(&prefix, 0, true).is_some());
+                if !is_cpio {
+                    return Ok(None);
+                }
+
+                let (decompressed, len) = codec.decompress_stream(data, MAX_DECOMPRESSED)?;
+                log::debug!("cpio: {} stream of {len} bytes", codec.name());
+
+                Ok(
+                    unpack_archives(&decompressed, out_dir)?.map(|(_, entries)| Unpacked {
+                        len,
+                        entries,
+                        warnings: Vec::new(),
+                    }),
+                )
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Newc,
+    Crc,
+    Odc,
+    Binary(Endian),
+}
+
+#[derive(Debug)]
+struct Header<'a> {
+    format: Format,
+    dev: u64,
+    ino: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    rdev: (u32, u32),
+    check: u32,
+    name: &'a [u8],
+    data: &'a [u8],
+    /// Whether the name or data run past the end of the input
+    truncated: bool,
+    /// Offset of the next header
+    next: usize,
+}
+
+fn parse_ascii(field: &[u8], radix: u32) -> Option<u64> {
+    let field = std::str::from_utf8(field).ok()?;
+    u64::from_str_radix(field, radix).ok()
+}
+
+fn align(pos: usize, to: usize) -> usize {
+    pos.div_ceil(to) * to
+}
+
+/// Fixed-size part of a header, common to all formats
+struct Fields {
+    dev: u64,
+    ino: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    rdev: (u32, u32),
+    check: u32,
+    name_size: usize,
+    file_size: usize,
+}
+
+impl<'a> Header<'a> {
+    /// Parse the header at `pos`. With `header_only`, the name and data are not required to
+    /// be present (used to check a decompressed prefix).
+    fn parse(data: &'a [u8], pos: usize, header_only: bool) -> Option<Self> {
+        let rest = data.get(pos..)?;
+
+        let (format, header_len, alignment) = if rest.starts_with(NEWC_MAGIC) {
+            (Format::Newc, 110, 4)
+        } else if rest.starts_with(CRC_MAGIC) {
+            (Format::Crc, 110, 4)
+        } else if rest.starts_with(ODC_MAGIC) {
+            (Format::Odc, 76, 1)
+        } else if rest.starts_with(BINARY_MAGIC_LE) {
+            (Format::Binary(Endian::Little), 26, 2)
+        } else if rest.starts_with(BINARY_MAGIC_BE) {
+            (Format::Binary(Endian::Big), 26, 2)
+        } else {
+            return None;
+        };
+
+        let header = rest.get(..header_len)?;
+
+        let fields = match format {
+            Format::Newc | Format::Crc => {
+                let field = |i: usize| parse_ascii(&header[6 + i * 8..14 + i * 8], 16);
+                let ino = field(0)?;
+                let mode = field(1)? as u32;
+                let uid = field(2)? as u32;
+                let gid = field(3)? as u32;
+                let nlink = field(4)? as u32;
+                let _mtime = field(5)?;
+                let file_size = field(6)? as usize;
+                let dev = (field(7)? << 32) | field(8)?;
+                let rdev = (field(9)? as u32, field(10)? as u32);
+                let name_size = field(11)? as usize;
+                let check = field(12)? as u32;
+
+                Fields {
+                    dev,
+                    ino,
+                    mode,
+                    uid,
+                    gid,
+                    nlink,
+                    rdev,
+                    check,
+                    name_size,
+                    file_size,
+                }
+            }
+            Format::Odc => {
+                let field = |range: std::ops::Range<usize>| parse_ascii(&header[range], 8);
+
+                Fields {
+                    dev: field(6..12)?,
+                    ino: field(12..18)?,
+                    mode: field(18..24)? as u32,
+                    uid: field(24..30)? as u32,
+                    gid: field(30..36)? as u32,
+                    nlink: field(36..42)? as u32,
+                    rdev: old_rdev(field(42..48)? as u32),
+                    check: 0,
+                    // mtime is 48..59
+                    name_size: field(59..65)? as usize,
+                    file_size: field(65..76)? as usize,
+                }
+            }
+            Format::Binary(endian) => {
+                let mut r = Reader::at(header, 2, endian);
+                let dev = u64::from(r.u16().ok()?);
+                let ino = u64::from(r.u16().ok()?);
+                let mode = u32::from(r.u16().ok()?);
+                let uid = u32::from(r.u16().ok()?);
+                let gid = u32::from(r.u16().ok()?);
+                let nlink = u32::from(r.u16().ok()?);
+                let rdev = u32::from(r.u16().ok()?);
+                let _mtime = (r.u16().ok()?, r.u16().ok()?);
+                let name_size = r.u16().ok()? as usize;
+                let file_size = (r.u16().ok()? as usize) << 16 | r.u16().ok()? as usize;
+
+                Fields {
+                    dev,
+                    ino,
+                    mode,
+                    uid,
+                    gid,
+                    nlink,
+                    rdev: old_rdev(rdev),
+                    check: 0,
+                    name_size,
+                    file_size,
+                }
+            }
+        };
+
+        if fields.name_size == 0 || fields.name_size > MAX_NAME_LEN {
+            return None;
+        }
+
+        let name_start = pos + header_len;
+        let data_start = align(name_start + fields.name_size, alignment);
+        let data_end = data_start.checked_add(fields.file_size)?;
+        let next = align(data_end, alignment);
+
+        // Only the fixed header may be available when peeking at compressed data
+        let name = data.get(name_start..name_start + fields.name_size);
+        let file_data = data.get(data_start..data_end);
+        let truncated = name.is_none() || file_data.is_none();
+
+        let mut header = Self {
+            format,
+            dev: fields.dev,
+            ino: fields.ino,
+            mode: fields.mode,
+            uid: fields.uid,
+            gid: fields.gid,
+            nlink: fields.nlink,
+            rdev: fields.rdev,
+            check: fields.check,
+            name: name.unwrap_or_default(),
+            data: file_data.unwrap_or_default(),
+            truncated,
+            next,
+        };
+
+        if header_only {
+            return Some(header);
+        }
+
+        // The name must be NUL-terminated and the data must be present
+        let name_valid =
+            header.name.last() == Some(&0) && !header.name[..header.name.len() - 1].contains(&0);
+        if !name_valid || header.truncated {
+            return None;
+        }
+
+        header.name = &header.name[..header.name.len() - 1];
+
+        Some(header)
+    }
+
+    fn kind(&self) -> Option<EntryKind> {
+        Some(match self.mode & libc::S_IFMT {
+            libc::S_IFDIR => EntryKind::Dir,
+            libc::S_IFREG => EntryKind::File,
+            libc::S_IFLNK => EntryKind::Symlink,
+            libc::S_IFCHR => EntryKind::Char,
+            libc::S_IFBLK => EntryKind::Block,
+            libc::S_IFIFO => EntryKind::Fifo,
+            libc::S_IFSOCK => EntryKind::Socket,
+            _ => return None,
+        })
+    }
+}
+
+/// Split a 16-bit `dev_t` from the odc and binary formats into major and minor numbers
+fn old_rdev(rdev: u32) -> (u32, u32) {
+    ((rdev >> 8) & 0xff, rdev & 0xff)
+}
+
+/// Turn an archive member name (`./bin/sh`, `bin/sh`, `/bin/sh`) into a relative path
+fn member_path(name: &[u8]) -> io::Result<PathBuf> {
+    let mut path = PathBuf::new();
+
+    for component in name.split(|&b| b == b'/') {
+        if component.is_empty() || component == b"." {
+            continue;
+        }
+        path.push(safe_name(component)?);
+    }
+
+    Ok(path)
+}
+
+/// Hardlink bookkeeping, keyed by `(dev, ino)`. In newc archives only the last link of a
+/// hardlinked file carries its contents, so earlier links wait for it.
+#[derive(Default)]
+struct Links {
+    written: HashMap<(u64, u64), PathBuf>,
+    waiting: HashMap<(u64, u64), Vec<(PathBuf, Attrs)>>,
+}
+
+/// Unpack the cpio archive at the start of `data`, along with any archives concatenated after
+/// it (as the kernel does for initramfs images). Returns the number of bytes consumed and the
+/// number of entries written, or `None` if `data` does not start with a cpio archive.
+fn unpack_archives(data: &[u8], out_dir: &Path) -> io::Result<Option<(usize, usize)>> {
+    match Header::parse(data, 0, false) {
+        Some(first) if first.name != TRAILER && first.kind().is_some() => {}
+        _ => return Ok(None),
+    }
+
+    let mut tree = TreeWriter::new(out_dir, "cpio")?;
+    let mut links = Links::default();
+    let mut pos = 0;
+
+    loop {
+        let Some(header) = Header::parse(data, pos, false) else {
+            log::warn!("cpio: truncated or corrupt header at {pos:#x}");
+            break;
+        };
+
+        pos = header.next;
+
+        if header.name == TRAILER {
+            // Archives may be concatenated, separated by zero padding
+            let next = data[pos.min(data.len())..]
+                .iter()
+                .position(|&b| b != 0)
+                .map(|skip| pos + skip);
+
+            match next {
+                Some(next) if Header::parse(data, next, false).is_some() => {
+                    pos = next;
+                    continue;
+                }
+                _ => break,
+            }
+        }
+
+        if let Err(err) = unpack_member(&mut tree, &mut links, &header) {
+            log::warn!(
+                "cpio: skipping {:?}: {err}",
+                String::from_utf8_lossy(header.name)
+            );
+        }
+    }
+
+    // Hardlinked files whose data-carrying member never showed up are written empty
+    for paths in links.waiting.into_values() {
+        let mut paths = paths.into_iter();
+        let Some((first, attrs)) = paths.next() else {
+            continue;
+        };
+        if let Err(err) = tree.file(&first, attrs, &[]) {
+            log::warn!("cpio: skipping /{}: {err}", first.display());
+            continue;
+        }
+        for (path, _) in paths {
+            if let Err(err) = tree.hardlink(&path, &first) {
+                log::warn!("cpio: skipping /{}: {err}", path.display());
+            }
+        }
+    }
+
+    let entries = tree.finish()?;
+
+    Ok(Some((pos.min(data.len()), entries)))
+}
+
+fn unpack_member(tree: &mut TreeWriter, links: &mut Links, header: &Header) -> io::Result<()> {
+    let rel = member_path(header.name)?;
+    let attrs = Attrs::new(header.mode, header.uid, header.gid);
+
+    let Some(kind) = header.kind() else {
+        return Err(native::invalid_data(format!(
+            "unknown file type {:o}",
+            header.mode & libc::S_IFMT
+        )));
+    };
+
+    if header.format == Format::Crc {
+        let sum = header
+            .data
+            .iter()
+            .fold(0u32, |sum, &b| sum.wrapping_add(u32::from(b)));
+        if sum != header.check {
+            log::warn!("cpio: checksum mismatch for /{}", rel.display());
+        }
+    }
+
+    match kind {
+        EntryKind::Dir => tree.dir(&rel, attrs),
+        EntryKind::File if header.nlink > 1 => {
+            let key = (header.dev, header.ino);
+
+            if let Some(existing) = links.written.get(&key) {
+                return tree.hardlink(&rel, existing);
+            }
+
+            if header.data.is_empty() {
+                // Contents follow with a later link
+                links.waiting.entry(key).or_default().push((rel, attrs));
+                return Ok(());
+            }
+
+            tree.file(&rel, attrs, header.data)?;
+
+            for (path, _) in links.waiting.remove(&key).unwrap_or_default() {
+                tree.hardlink(&path, &rel)?;
+            }
+
+            links.written.insert(key, rel);
+
+            Ok(())
+        }
+        EntryKind::File => tree.file(&rel, attrs, header.data),
+        EntryKind::Symlink => tree.symlink(&rel, attrs, header.data),
+        EntryKind::Char | EntryKind::Block => tree.special(&rel, kind, attrs, Some(header.rdev)),
+        EntryKind::Fifo | EntryKind::Socket => tree.special(&rel, kind, attrs, None),
+        EntryKind::Hardlink => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A newc member, padded as cpio writes it
+    fn newc(name: &str, mode: u32, ino: u32, nlink: u32, data: &[u8]) -> Vec<u8> {
+        let fields = [ino, mode, 0, 0, nlink, 0, data.len() as u32, 0, 0, 0, 0];
+        let mut member = NEWC_MAGIC.to_vec();
+        for field in fields.into_iter().chain([name.len() as u32 + 1, 0]) {
+            member.extend(format!("{field:08x}").bytes());
+        }
+        member.extend(name.bytes());
+        member.push(0);
+        member.resize(align(member.len(), 4), 0);
+        member.extend_from_slice(data);
+        member.resize(align(member.len(), 4), 0);
+        member
+    }
+
+    fn archive(members: &[Vec<u8>]) -> Vec<u8> {
+        let mut archive = members.concat();
+        archive.extend(newc("TRAILER!!!", 0, 0, 1, b""));
+        archive
+    }
+
+    #[test]
+    fn unpacks_concatenated_archives_and_hardlinks() {
+        let mut data = archive(&[
+            newc(".", 0o40755, 1, 2, b""),
+            newc("bin", 0o40755, 2, 2, b""),
+            newc("bin/sh", 0o100755, 3, 2, b""),
+            newc("bin/busybox", 0o100755, 3, 2, b"#!busybox"),
+        ]);
+        data.resize(align(data.len(), 512), 0);
+        data.extend(archive(&[
+            newc("init", 0o120777, 4, 1, b"bin/busybox"),
+            newc(
+                "./etc/passwd",
+                0o100644,
+                5,
+                1,
+                b"root:x:0:0::/root:/bin/sh\n",
+            ),
+        ]));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let (len, entries) = unpack_archives(&data, tmp.path()).unwrap().unwrap();
+        assert_eq!((len, entries), (data.len(), 6));
+
+        let root = tmp.path();
+        assert_eq!(fs::read(root.join("bin/sh")).unwrap(), b"#!busybox");
+        assert!(fs::read_to_string(root.join("etc/passwd"))
+            .unwrap()
+            .starts_with("root:"));
+        assert_eq!(
+            fs::read_link(root.join("init")).unwrap(),
+            Path::new("bin/busybox")
+        );
+    }
+
+    #[test]
+    fn writes_nothing_beneath_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outside = tmp.path().join("target_outside");
+        fs::create_dir(&outside).unwrap();
+
+        let data = archive(&[
+            newc(
+                "lib",
+                0o120777,
+                1,
+                1,
+                outside.as_os_str().as_encoded_bytes(),
+            ),
+            newc("lib/evil", 0o100644, 2, 1, b"evil"),
+            newc("ok", 0o100644, 3, 1, b"ok"),
+        ]);
+
+        let root = tmp.path().join("root");
+        unpack_archives(&data, &root).unwrap().unwrap();
+
+        assert!(!outside.join("evil").exists());
+        assert_eq!(fs::read(root.join("ok")).unwrap(), b"ok");
+    }
+}
@@ -0,0 +1,550 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::compression::Codec;
+use super::fstree::{safe_name, Attrs, EntryKind, TreeWriter};
+use super::native::{self, crc32_le, invalid_data, Endian, Unpacked};
+use super::{ExtractError, ExtractStatus, Extractor};
+
+const MAGIC_LE: &[u8] = &[0x45, 0x3d, 0xcd, 0x28];
+const MAGIC_BE: &[u8] = &[0x28, 0xcd, 0x3d, 0x45];
+const SIGNATURE: &[u8] = b"Compressed ROMFS";
+
+const SUPERBLOCK_LEN: usize = 76;
+const INODE_LEN: usize = 12;
+/// Some images are prefixed with room for a boot loader, which inode offsets account for
+const PAD_LEN: usize = 512;
+const PAGE_SIZE: usize = 4096;
+
+const FLAG_FSID_VERSION_2: u32 = 0x1;
+const FLAG_EXT_BLOCK_POINTERS: u32 = 0x800;
+
+const BLK_FLAG_UNCOMPRESSED: u32 = 1 << 31;
+const BLK_FLAG_DIRECT_PTR: u32 = 1 << 30;
+const BLK_FLAGS: u32 = BLK_FLAG_UNCOMPRESSED | BLK_FLAG_DIRECT_PTR;
+
+/// In-process reader for CramFS images of either endianness, including the linear XIP variant
+/// whose executables are stored uncompressed and page aligned
+pub struct CramfsExtractor;
+
+impl Extractor for CramfsExtractor {
+    fn name(&self) -> &'static str {
+        "cramfs"
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        _verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        native::extract_all(
+            self.name(),
+            in_file,
+            extract_dir,
+            log_file,
+            &[MAGIC_LE, MAGIC_BE],
+            |data, offset, out_dir| {
+                let Some(image) = Image::open(data, offset) else {
+                    return Ok(None);
+                };
+
+                let mut warnings = Vec::new();
+                let entries = image.unpack(out_dir, &mut warnings)?;
+
+                Ok(Some(Unpacked {
+                    len: image.data.len() - image.sb_offset,
+                    entries,
+                    warnings,
+                }))
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Inode {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    /// File size, or the device number of device nodes
+    size: usize,
+    name_len: usize,
+    offset: usize,
+}
+
+impl Inode {
+    fn parse(raw: &[u8], endian: Endian) -> Self {
+        let words = [
+            endian.u32(&raw[0..4]),
+            endian.u32(&raw[4..8]),
+            endian.u32(&raw[8..12]),
+        ];
+
+        // The bitfields are allocated from opposite ends of each word
+        let (mode, uid, size, gid, name_len, offset) = match endian {
+            Endian::Little => (
+                words[0] & 0xffff,
+                words[0] >> 16,
+                words[1] & 0xff_ffff,
+                words[1] >> 24,
+                words[2] & 0x3f,
+                words[2] >> 6,
+            ),
+            Endian::Big => (
+                words[0] >> 16,
+                words[0] & 0xffff,
+                words[1] >> 8,
+                words[1] & 0xff,
+                words[2] >> 26,
+                words[2] & 0x3ff_ffff,
+            ),
+        };
+
+        Self {
+            mode,
+            uid,
+            gid,
+            size: size as usize,
+            name_len: name_len as usize * 4,
+            offset: offset as usize * 4,
+        }
+    }
+
+    fn attrs(&self) -> Attrs {
+        Attrs::new(self.mode, self.uid, self.gid)
+    }
+
+    fn kind(&self) -> Option<EntryKind> {
+        match self.mode & libc::S_IFMT {
+            libc::S_IFDIR => Some(EntryKind::Dir),
+            libc::S_IFREG => Some(EntryKind::File),
+            libc::S_IFLNK => Some(EntryKind::Symlink),
+            libc::S_IFCHR => Some(EntryKind::Char),
+            libc::S_IFBLK => Some(EntryKind::Block),
+            libc::S_IFIFO => Some(EntryKind::Fifo),
+            libc::S_IFSOCK => Some(EntryKind::Socket),
+            _ => None,
+        }
+    }
+}
+
+struct Image<'a> {
+    /// The whole image, starting at the boot loader padding if there is any
+    data: &'a [u8],
+    /// Offset of `data` in the input
+    base: usize,
+    /// Offset of the superblock in `data`
+    sb_offset: usize,
+    endian: Endian,
+    flags: u32,
+    root: Inode,
+}
+
+impl<'a> Image<'a> {
+    /// Parse the superblock at `offset` of `input`, returning `None` if there isn't a valid one
+    fn open(input: &'a [u8], offset: usize) -> Option<Self> {
+        let sb = input.get(offset..offset + SUPERBLOCK_LEN)?;
+        if &sb[16..32] != SIGNATURE {
+            return None;
+        }
+
+        let endian = if sb.starts_with(MAGIC_LE) {
+            Endian::Little
+        } else {
+            Endian::Big
+        };
+        let size = endian.u32(&sb[4..8]) as usize;
+        let flags = endian.u32(&sb[8..12]);
+        let root = Inode::parse(&sb[64..76], endian);
+
+        // Without padding the root directory's entries directly follow the superblock
+        let base = if root.offset >= PAD_LEN + SUPERBLOCK_LEN && offset >= PAD_LEN {
+            offset - PAD_LEN
+        } else {
+            offset
+        };
+
+        // Images from before the size field was introduced leave it zero
+        let end = if size >= SUPERBLOCK_LEN {
+            base.checked_add(size)?
+        } else {
+            input.len()
+        };
+
+        if root.mode & libc::S_IFMT != libc::S_IFDIR {
+            return None;
+        }
+
+        Some(Self {
+            data: input.get(base..end)?,
+            base,
+            sb_offset: offset - base,
+            endian,
+            flags,
+            root,
+        })
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> io::Result<&'a [u8]> {
+        self.data
+            .get(offset..offset.saturating_add(len))
+            .ok_or_else(|| invalid_data(format!("data at {offset:#x} (+{len}) out of bounds")))
+    }
+
+    fn u32(&self, offset: usize) -> io::Result<u32> {
+        Ok(self.endian.u32(self.bytes(offset, 4)?))
+    }
+
+    /// Check the image CRC, which covers everything from the superblock on and is computed
+    /// with the CRC field itself zeroed
+    fn check_crc(&self) -> bool {
+        let mut data = self.data[self.sb_offset..].to_vec();
+        let expected = self.endian.u32(&data[32..36]);
+        data[32..36].fill(0);
+
+        crc32_le(!0, &data) ^ !0 == expected
+    }
+
+    fn read_dir(&self, dir: &Inode) -> io::Result<Vec<(&'a [u8], Inode)>> {
+        let mut entries = Vec::new();
+        let mut pos = dir.offset;
+        let end = dir.offset + dir.size;
+
+        while pos < end {
+            let inode = Inode::parse(self.bytes(pos, INODE_LEN)?, self.endian);
+            let name = self.bytes(pos + INODE_LEN, inode.name_len)?;
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+
+            entries.push((name, inode));
+            pos += INODE_LEN + inode.name_len;
+        }
+
+        Ok(entries)
+    }
+
+    /// Contents of a regular file or symlink, decompressed one page at a time
+    fn file_contents(&self, inode: &Inode) -> io::Result<Vec<u8>> {
+        let blocks = inode.size.div_ceil(PAGE_SIZE);
+        let table = inode.offset;
+        let ext_pointers = self.flags & FLAG_EXT_BLOCK_POINTERS != 0;
+        let mut out = Vec::with_capacity(inode.size);
+
+        for block in 0..blocks {
+            let expected = PAGE_SIZE.min(inode.size - block * PAGE_SIZE);
+            let mut pointer = self.u32(table + block * 4)?;
+            let mut uncompressed = false;
+
+            let (start, len) = if ext_pointers && pointer & BLK_FLAG_DIRECT_PTR != 0 {
+                uncompressed = pointer & BLK_FLAG_UNCOMPRESSED != 0;
+                let start = ((pointer & !BLK_FLAGS) as usize) << 2;
+
+                if uncompressed {
+                    (start, expected)
+                } else {
+                    let len = self.endian.u16(self.bytes(start, 2)?) as usize;
+                    (start + 2, len)
+                }
+            } else {
+                if ext_pointers {
+                    uncompressed = pointer & BLK_FLAG_UNCOMPRESSED != 0;
+                    pointer &= !BLK_FLAGS;
+                }
+
+                // Each pointer holds the end of its block, which starts where the previous
+                // one ended
+                let start = match block {
+                    0 => table + blocks * 4,
+                    _ => self.block_end(table + (block - 1) * 4, ext_pointers)?,
+                };
+                let end = pointer as usize;
+                if end < start {
+                    return Err(invalid_data(format!("block {block} ends before it starts")));
+                }
+
+                (start, end - start)
+            };
+
+            let data = self.bytes(start, len)?;
+            if len == 0 {
+                // A hole
+                out.resize(out.len() + expected, 0);
+            } else if uncompressed {
+                out.extend_from_slice(data);
+            } else {
+                let page = Codec::Zlib.decompress(data, PAGE_SIZE)?;
+                out.extend_from_slice(&page[..page.len().min(expected)]);
+                out.resize(out.len() + expected - page.len().min(expected), 0);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// End of the block whose pointer is at `pointer_offset`
+    fn block_end(&self, pointer_offset: usize, ext_pointers: bool) -> io::Result<usize> {
+        let pointer = self.u32(pointer_offset)?;
+        if !ext_pointers {
+            return Ok(pointer as usize);
+        }
+
+        let start = ((pointer & !BLK_FLAGS) as usize) << 2;
+        Ok(
+            match (
+                pointer & BLK_FLAG_DIRECT_PTR != 0,
+                pointer & BLK_FLAG_UNCOMPRESSED != 0,
+            ) {
+                (true, true) => start + PAGE_SIZE,
+                (true, false) => start + 2 + self.endian.u16(self.bytes(start, 2)?) as usize,
+                (false, _) => (pointer & !BLK_FLAGS) as usize,
+            },
+        )
+    }
+
+    /// Contents of a file in the XIP layout: uncompressed, starting at the first page boundary
+    /// after the inode's offset
+    fn xip_contents(&self, inode: &Inode) -> io::Result<Vec<u8>> {
+        let start = (self.base + inode.offset).next_multiple_of(PAGE_SIZE) - self.base;
+        Ok(self.bytes(start, inode.size)?.to_vec())
+    }
+
+    fn unpack_entry(&self, tree: &mut TreeWriter, rel: &Path, inode: &Inode) -> io::Result<()> {
+        match inode.kind() {
+            Some(EntryKind::File) => tree.file_with(rel, inode.attrs(), inode.size as u64, || {
+                // XIP images mark their uncompressed files with the sticky bit
+                match self.file_contents(inode) {
+                    Err(_) if inode.mode & libc::S_ISVTX != 0 => self.xip_contents(inode),
+                    contents => contents,
+                }
+            }),
+            Some(EntryKind::Symlink) => {
+                tree.symlink(rel, inode.attrs(), &self.file_contents(inode)?)
+            }
+            Some(kind @ (EntryKind::Char | EntryKind::Block)) => {
+                let rdev = ((inode.size as u32 >> 8) & 0xff, inode.size as u32 & 0xff);
+                tree.special(rel, kind, inode.attrs(), Some(rdev))
+            }
+            Some(kind @ (EntryKind::Fifo | EntryKind::Socket)) => {
+                tree.special(rel, kind, inode.attrs(), None)
+            }
+            _ => Err(invalid_data(format!("unknown mode {:#o}", inode.mode))),
+        }
+    }
+
+    fn unpack(&self, out_dir: &Path, warnings: &mut Vec<String>) -> io::Result<usize> {
+        if self.flags & FLAG_FSID_VERSION_2 != 0 && !self.check_crc() {
+            warnings.push("image CRC does not match, the image may be damaged".to_owned());
+        }
+
+        log::debug!(
+            "cramfs: {} endian, {} bytes, flags {:#x}",
+            self.endian.name(),
+            self.data.len(),
+            self.flags
+        );
+
+        let mut tree = TreeWriter::new(out_dir, "cramfs")?;
+        tree.dir(Path::new(""), self.root.attrs())?;
+
+        let mut visited = HashSet::from([self.root.offset]);
+        let mut pending = vec![(PathBuf::new(), self.root)];
+
+        while let Some((dir, dir_inode)) = pending.pop() {
+            let entries = match self.read_dir(&dir_inode) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warnings.push(format!("cannot read directory /{}: {err}", dir.display()));
+                    continue;
+                }
+            };
+
+            for (name, inode) in entries {
+                let name = match safe_name(name) {
+                    Ok(name) => name,
+                    Err(err) => {
+                        warnings.push(format!("skipping entry in /{}: {err}", dir.display()));
+                        continue;
+                    }
+                };
+                let rel = dir.join(name);
+
+                if inode.kind() == Some(EntryKind::Dir) {
+                    // Empty directories have no entries and an offset of zero
+                    if inode.size > 0 && !visited.insert(inode.offset) {
+                        warnings.push(format!("directory loop at /{}", rel.display()));
+                        continue;
+                    }
+
+                    tree.dir(&rel, inode.attrs())?;
+                    pending.push((rel, inode));
+                } else if let Err(err) = self.unpack_entry(&mut tree, &rel, &inode) {
+                    warnings.push(format!("skipping /{}: {err}", rel.display()));
+                }
+            }
+        }
+
+        tree.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    fn put(image: &mut Vec<u8>, endian: Endian, value: u32) {
+        match endian {
+            Endian::Little => image.extend(value.to_le_bytes()),
+            Endian::Big => image.extend(value.to_be_bytes()),
+        }
+    }
+
+    fn inode(
+        image: &mut Vec<u8>,
+        endian: Endian,
+        mode: u32,
+        size: usize,
+        name: &str,
+        offset: usize,
+    ) {
+        let name_len = name.len().next_multiple_of(4);
+        let (size, name_words, offset) = (size as u32, (name_len / 4) as u32, (offset / 4) as u32);
+        let words = match endian {
+            Endian::Little => [mode, size, name_words | offset << 6],
+            Endian::Big => [mode << 16, size << 8, name_words << 26 | offset],
+        };
+        for word in words {
+            put(image, endian, word);
+        }
+
+        image.extend(name.as_bytes());
+        image.resize(image.len() + name_len - name.len(), 0);
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        zlib.write_all(data).unwrap();
+        zlib.finish().unwrap()
+    }
+
+    /// An image with a file, a symlink and a directory containing a link back to the root
+    fn image(endian: Endian) -> Vec<u8> {
+        const ROOT: usize = SUPERBLOCK_LEN;
+        const SUBDIR: usize = ROOT + 3 * (INODE_LEN + 4);
+        const FILE: usize = SUBDIR + INODE_LEN + 4;
+
+        let file = zlib(b"hello cramfs\n");
+        let target = zlib(b"file");
+        // Inode offsets are in units of four bytes
+        let link = (FILE + 4 + file.len()).next_multiple_of(4);
+
+        let mut image = Vec::new();
+        image.extend(match endian {
+            Endian::Little => MAGIC_LE,
+            Endian::Big => MAGIC_BE,
+        });
+        put(&mut image, endian, 0);
+        put(&mut image, endian, FLAG_FSID_VERSION_2);
+        put(&mut image, endian, 0);
+        image.extend(SIGNATURE);
+        image.resize(64, 0);
+        inode(
+            &mut image,
+            endian,
+            libc::S_IFDIR | 0o755,
+            SUBDIR - ROOT,
+            "",
+            ROOT,
+        );
+
+        inode(&mut image, endian, libc::S_IFREG | 0o644, 13, "file", FILE);
+        inode(&mut image, endian, libc::S_IFLNK | 0o777, 4, "link", link);
+        inode(
+            &mut image,
+            endian,
+            libc::S_IFDIR | 0o755,
+            FILE - SUBDIR,
+            "dir",
+            SUBDIR,
+        );
+        inode(
+            &mut image,
+            endian,
+            libc::S_IFDIR | 0o755,
+            SUBDIR - ROOT,
+            "loop",
+            ROOT,
+        );
+
+        // Each file is a table of block end pointers followed by the blocks
+        put(&mut image, endian, (FILE + 4 + file.len()) as u32);
+        image.extend(file);
+        image.resize(link, 0);
+        put(&mut image, endian, (link + 4 + target.len()) as u32);
+        image.extend(target);
+
+        let size = image.len() as u32;
+        let size = match endian {
+            Endian::Little => size.to_le_bytes(),
+            Endian::Big => size.to_be_bytes(),
+        };
+        image[4..8].copy_from_slice(&size);
+
+        let crc = crc32_le(!0, &image) ^ !0;
+        let crc = match endian {
+            Endian::Little => crc.to_le_bytes(),
+            Endian::Big => crc.to_be_bytes(),
+        };
+        image[32..36].copy_from_slice(&crc);
+
+        image
+    }
+
+    fn unpack(image: &[u8]) -> (tempfile::TempDir, Vec<String>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let image = Image::open(image, 0).unwrap();
+        let mut warnings = Vec::new();
+        image.unpack(tmp.path(), &mut warnings).unwrap();
+        (tmp, warnings)
+    }
+
+    #[test]
+    fn unpacks_either_endianness() {
+        for endian in [Endian::Little, Endian::Big] {
+            let (tmp, warnings) = unpack(&image(endian));
+            assert_eq!(warnings, ["directory loop at /dir/loop"]);
+
+            assert_eq!(
+                fs::read(tmp.path().join("file")).unwrap(),
+                b"hello cramfs\n"
+            );
+            assert_eq!(
+                fs::read_link(tmp.path().join("link")).unwrap(),
+                Path::new("file")
+            );
+            assert!(tmp.path().join("dir").is_dir());
+            assert!(!tmp.path().join("dir/loop").exists());
+        }
+    }
+
+    #[test]
+    fn reports_damage() {
+        let mut image = image(Endian::Little);
+
+        // Point the file's only block past the end of the image
+        let pointer = SUPERBLOCK_LEN + 4 * (INODE_LEN + 4);
+        image[pointer..pointer + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let (tmp, warnings) = unpack(&image);
+        assert_eq!(
+            warnings[0],
+            "image CRC does not match, the image may be damaged"
+        );
+        assert!(warnings[1].starts_with("skipping /file: "), "{warnings:?}");
+        assert!(!tmp.path().join("file").exists());
+        assert!(tmp.path().join("link").is_symlink());
+    }
+}
@@ -0,0 +1,991 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::fstree::{safe_name, Attrs, EntryKind, TreeWriter};
+use super::native::{self, invalid_data, Endian, Reader, Unpacked};
+use super::{ExtractError, ExtractStatus, Extractor};
+
+const MAGIC: &[u8] = &[0x53, 0xef];
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+/// Offset of `s_magic` from the start of the filesystem
+const MAGIC_OFFSET: usize = SUPERBLOCK_OFFSET + 56;
+
+const ROOT_INODE: u32 = 2;
+/// Size of `i_block`, which holds the block map, extent root, fast symlink or inline data
+const I_BLOCK_LEN: usize = 60;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_JOURNAL_DEV: u32 = 0x8;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_ENCRYPT: u32 = 0x10000;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+
+const EXTENTS_FL: u32 = 0x80000;
+const INLINE_DATA_FL: u32 = 0x1000_0000;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+const EXTENT_UNINIT: u16 = 32768;
+const MAX_EXTENT_DEPTH: u16 = 5;
+
+const XATTR_MAGIC: u32 = 0xea02_0000;
+const XATTR_BLOCK_HEADER_LEN: usize = 32;
+const XATTR_ENTRY_LEN: usize = 16;
+/// Largest xattr value, as `XATTR_SIZE_MAX` in Linux
+const XATTR_SIZE_MAX: usize = 1 << 16;
+
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+const LE: Endian = Endian::Little;
+
+/// In-process reader for ext2, ext3 and ext4 images
+pub struct ExtfsExtractor;
+
+impl Extractor for ExtfsExtractor {
+    fn name(&self) -> &'static str {
+        "extfs"
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        _verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        native::extract_all_at(
+            self.name(),
+            in_file,
+            extract_dir,
+            log_file,
+            &[MAGIC],
+            MAGIC_OFFSET,
+            |data, offset, out_dir| {
+                let Some(image) = Image::open(&data[offset..]) else {
+                    return Ok(None);
+                };
+
+                let entries = image.unpack(out_dir)?;
+
+                Ok(Some(Unpacked {
+                    len: image.data.len(),
+                    entries,
+                    warnings: Vec::new(),
+                }))
+            },
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Superblock {
+    inodes_count: u32,
+    blocks_count: u64,
+    first_data_block: u32,
+    block_size: usize,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    desc_size: usize,
+    first_meta_bg: u32,
+    feature_incompat: u32,
+    feature_ro_compat: u32,
+}
+
+impl Superblock {
+    fn parse(data: &[u8]) -> io::Result<Option<Self>> {
+        let mut r = Reader::at(data, SUPERBLOCK_OFFSET, LE);
+        let inodes_count = r.u32()?;
+        let blocks_count_lo = r.u32()?;
+        r.skip(12)?;
+        let first_data_block = r.u32()?;
+        let log_block_size = r.u32()?;
+        r.skip(4)?;
+        let blocks_per_group = r.u32()?;
+        r.skip(4)?;
+        let inodes_per_group = r.u32()?;
+        r.skip(12)?;
+        let magic = r.u16()?;
+        r.skip(18)?;
+        let rev_level = r.u32()?;
+        r.skip(4)?;
+        let _first_ino = r.u32()?;
+        let inode_size = r.u16()?;
+        r.skip(2)?;
+        let _feature_compat = r.u32()?;
+        let feature_incompat = r.u32()?;
+        let feature_ro_compat = r.u32()?;
+
+        let mut r = Reader::at(data, SUPERBLOCK_OFFSET + 0xfe, LE);
+        let desc_size = r.u16()?;
+        r.skip(4)?;
+        let first_meta_bg = r.u32()?;
+
+        let mut r = Reader::at(data, SUPERBLOCK_OFFSET + 0x150, LE);
+        let blocks_count_hi = r.u32()?;
+
+        if magic != 0xef53 || log_block_size > 6 || rev_level > 1 || first_data_block > 1 {
+            return Ok(None);
+        }
+
+        let block_size = 1024usize << log_block_size;
+
+        let inode_size = if rev_level == 0 {
+            128
+        } else {
+            inode_size as usize
+        };
+        if inode_size < 128 || !inode_size.is_power_of_two() || inode_size > block_size {
+            return Ok(None);
+        }
+
+        let is_64bit = feature_incompat & INCOMPAT_64BIT != 0;
+        let desc_size = if is_64bit { desc_size as usize } else { 32 };
+        if desc_size < 32 || !desc_size.is_power_of_two() || desc_size > block_size {
+            return Ok(None);
+        }
+
+        let blocks_count = if is_64bit {
+            u64::from(blocks_count_lo) | (u64::from(blocks_count_hi) << 32)
+        } else {
+            u64::from(blocks_count_lo)
+        };
+
+        let max_per_group = 8 * block_size as u32;
+        if blocks_per_group == 0
+            || blocks_per_group > max_per_group
+            || inodes_per_group == 0
+            || inodes_per_group > max_per_group
+            || blocks_count <= u64::from(first_data_block)
+        {
+            return Ok(None);
+        }
+
+        let sb = Self {
+            inodes_count,
+            blocks_count,
+            first_data_block,
+            block_size,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            desc_size,
+            first_meta_bg,
+            feature_incompat,
+            feature_ro_compat,
+        };
+
+        let max_inodes = sb.group_count() * u64::from(inodes_per_group);
+        if inodes_count == 0 || u64::from(inodes_count) > max_inodes {
+            return Ok(None);
+        }
+
+        Ok(Some(sb))
+    }
+
+    fn group_count(&self) -> u64 {
+        (self.blocks_count - u64::from(self.first_data_block))
+            .div_ceil(u64::from(self.blocks_per_group))
+    }
+
+    fn has_feature_incompat(&self, feature: u32) -> bool {
+        self.feature_incompat & feature != 0
+    }
+
+    /// Whether block group `group` starts with a backup of the superblock and descriptors
+    fn group_has_super(&self, group: u64) -> bool {
+        let is_power_of = |base: u64| {
+            let mut n = group;
+            while n > 1 && n.is_multiple_of(base) {
+                n /= base;
+            }
+            n == 1
+        };
+
+        self.feature_ro_compat & RO_COMPAT_SPARSE_SUPER == 0
+            || group <= 1
+            || is_power_of(3)
+            || is_power_of(5)
+            || is_power_of(7)
+    }
+}
+
+#[derive(Debug)]
+struct Inode<'a> {
+    number: u32,
+    mode: u16,
+    attrs: Attrs,
+    size: u64,
+    flags: u32,
+    /// Blocks in use, in 512 byte units
+    blocks: u64,
+    block: &'a [u8],
+    file_acl: u64,
+    /// In-inode extended attributes, after the magic
+    ibody_xattrs: &'a [u8],
+}
+
+impl Inode<'_> {
+    fn kind(&self) -> Option<EntryKind> {
+        match u32::from(self.mode) & libc::S_IFMT {
+            libc::S_IFDIR => Some(EntryKind::Dir),
+            libc::S_IFREG => Some(EntryKind::File),
+            libc::S_IFLNK => Some(EntryKind::Symlink),
+            libc::S_IFCHR => Some(EntryKind::Char),
+            libc::S_IFBLK => Some(EntryKind::Block),
+            libc::S_IFIFO => Some(EntryKind::Fifo),
+            libc::S_IFSOCK => Some(EntryKind::Socket),
+            _ => None,
+        }
+    }
+
+    fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Device number, in either the old 8:8 or the new 12:20 encoding
+    fn rdev(&self) -> (u32, u32) {
+        let old = LE.u32(&self.block[0..4]);
+        if old != 0 {
+            return ((old >> 8) & 0xff, old & 0xff);
+        }
+
+        let new = LE.u32(&self.block[4..8]);
+        ((new & 0xfff00) >> 8, (new & 0xff) | ((new >> 12) & 0xfff00))
+    }
+}
+
+struct DirEntry {
+    inode: u32,
+    name: Vec<u8>,
+}
+
+struct Image<'a> {
+    data: &'a [u8],
+    sb: Superblock,
+    /// Location of the inode table of every block group
+    inode_tables: Vec<u64>,
+}
+
+impl<'a> Image<'a> {
+    fn open(data: &'a [u8]) -> Option<Self> {
+        let sb = Superblock::parse(data).ok()??;
+
+        if sb.has_feature_incompat(INCOMPAT_JOURNAL_DEV) {
+            return None;
+        }
+
+        let len = sb
+            .blocks_count
+            .saturating_mul(sb.block_size as u64)
+            .min(data.len() as u64) as usize;
+
+        let mut image = Self {
+            data: &data[..len],
+            sb,
+            inode_tables: Vec::new(),
+        };
+        image.inode_tables = image.read_group_descriptors().ok()?;
+
+        Some(image)
+    }
+
+    fn block(&self, block: u64) -> io::Result<&'a [u8]> {
+        let bs = self.sb.block_size as u64;
+        block
+            .checked_mul(bs)
+            .and_then(|start| {
+                let start = usize::try_from(start).ok()?;
+                self.data.get(start..start + self.sb.block_size)
+            })
+            .ok_or_else(|| invalid_data(format!("block {block} is out of bounds")))
+    }
+
+    /// Block holding the `index`th block of group descriptors
+    fn descriptor_block(&self, index: u64) -> u64 {
+        let first_data_block = u64::from(self.sb.first_data_block);
+
+        if self.sb.has_feature_incompat(INCOMPAT_META_BG)
+            && index >= u64::from(self.sb.first_meta_bg)
+        {
+            // With meta_bg each descriptor block sits at the start of the first group it covers
+            let group = index * (self.sb.block_size / self.sb.desc_size) as u64;
+            let group_start = group * u64::from(self.sb.blocks_per_group) + first_data_block;
+            group_start + u64::from(self.sb.group_has_super(group))
+        } else {
+            first_data_block + 1 + index
+        }
+    }
+
+    fn read_group_descriptors(&self) -> io::Result<Vec<u64>> {
+        let per_block = (self.sb.block_size / self.sb.desc_size) as u64;
+        let group_count = self.sb.group_count();
+
+        (0..group_count)
+            .map(|group| {
+                let block = self.block(self.descriptor_block(group / per_block))?;
+                let start = (group % per_block) as usize * self.sb.desc_size;
+                let desc = &block[start..start + self.sb.desc_size];
+
+                let mut table = u64::from(LE.u32(&desc[8..12]));
+                if self.sb.desc_size >= 64 {
+                    table |= u64::from(LE.u32(&desc[0x28..0x2c])) << 32;
+                }
+
+                Ok(table)
+            })
+            .collect()
+    }
+
+    fn read_inode(&self, number: u32) -> io::Result<Inode<'a>> {
+        if number == 0 || number > self.sb.inodes_count {
+            return Err(invalid_data(format!("inode {number} out of range")));
+        }
+
+        let index = number - 1;
+        let group = (index / self.sb.inodes_per_group) as usize;
+        let table = self.inode_tables[group];
+
+        let start = table
+            .checked_mul(self.sb.block_size as u64)
+            .and_then(|start| {
+                let offset = (index % self.sb.inodes_per_group) as u64 * self.sb.inode_size as u64;
+                usize::try_from(start.checked_add(offset)?).ok()
+            })
+            .ok_or_else(|| invalid_data(format!("inode {number} is out of bounds")))?;
+        let raw = self
+            .data
+            .get(start..start + self.sb.inode_size)
+            .ok_or_else(|| invalid_data(format!("inode {number} is out of bounds")))?;
+
+        let mode = LE.u16(&raw[0..]);
+        let uid = u32::from(LE.u16(&raw[2..])) | u32::from(LE.u16(&raw[0x78..])) << 16;
+        let gid = u32::from(LE.u16(&raw[0x18..])) | u32::from(LE.u16(&raw[0x7a..])) << 16;
+        let size = u64::from(LE.u32(&raw[4..])) | u64::from(LE.u32(&raw[0x6c..])) << 32;
+        let blocks = u64::from(LE.u32(&raw[0x1c..])) | u64::from(LE.u16(&raw[0x74..])) << 32;
+        let flags = LE.u32(&raw[0x20..]);
+        let file_acl = u64::from(LE.u32(&raw[0x68..])) | u64::from(LE.u16(&raw[0x76..])) << 32;
+
+        let ibody_xattrs = if self.sb.inode_size > 128 {
+            let extra_isize = LE.u16(&raw[0x80..]) as usize;
+            raw.get(128 + extra_isize..)
+                .filter(|area| area.len() >= 4 && LE.u32(area) == XATTR_MAGIC)
+                .map(|area| &area[4..])
+                .unwrap_or_default()
+        } else {
+            &[]
+        };
+
+        Ok(Inode {
+            number,
+            mode,
+            attrs: Attrs::new(u32::from(mode), uid, gid),
+            size,
+            flags,
+            blocks,
+            block: &raw[0x28..0x28 + I_BLOCK_LEN],
+            file_acl,
+            ibody_xattrs,
+        })
+    }
+
+    /// Copy physical block `physical` into `out` as logical block `logical` of a file,
+    /// truncated to the file size. Returns false once `logical` is past the end of the file.
+    fn copy_block(&self, out: &mut [u8], logical: u64, physical: u64) -> io::Result<bool> {
+        let Some(start) = logical
+            .checked_mul(self.sb.block_size as u64)
+            .and_then(|start| usize::try_from(start).ok())
+            .filter(|&start| start < out.len())
+        else {
+            return Ok(false);
+        };
+
+        let end = out.len().min(start + self.sb.block_size);
+        out[start..end].copy_from_slice(&self.block(physical)?[..end - start]);
+
+        Ok(true)
+    }
+
+    fn map_extents(&self, node: &[u8], max_depth: u16, out: &mut [u8]) -> io::Result<()> {
+        let mut r = Reader::new(node, LE);
+        let magic = r.u16()?;
+        let entries = r.u16()?;
+        let _max = r.u16()?;
+        let depth = r.u16()?;
+        let _generation = r.u32()?;
+
+        if magic != EXTENT_MAGIC {
+            return Err(invalid_data(format!("bad extent header magic {magic:#x}")));
+        }
+        if depth > max_depth {
+            return Err(invalid_data(format!("extent tree too deep ({depth})")));
+        }
+
+        for _ in 0..entries {
+            let logical = u64::from(r.u32()?);
+
+            if depth == 0 {
+                let len = r.u16()?;
+                let start = u64::from(r.u16()?) << 32 | u64::from(r.u32()?);
+
+                // Uninitialized extents read as zeros
+                if len > EXTENT_UNINIT {
+                    continue;
+                }
+
+                for i in 0..u64::from(len) {
+                    if !self.copy_block(out, logical + i, start + i)? {
+                        break;
+                    }
+                }
+            } else {
+                let leaf = u64::from(r.u32()?) | u64::from(r.u16()?) << 32;
+                r.skip(2)?;
+
+                self.map_extents(self.block(leaf)?, depth - 1, out)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Follow a (possibly) indirect block pointer of the ext2/3 block map. `depth` is the
+    /// level of indirection and `logical` the first logical block the pointer covers.
+    fn map_indirect(
+        &self,
+        pointer: u32,
+        depth: u32,
+        logical: u64,
+        out: &mut [u8],
+    ) -> io::Result<()> {
+        let Some(start) = logical.checked_mul(self.sb.block_size as u64) else {
+            return Ok(());
+        };
+
+        if pointer == 0 || start >= out.len() as u64 {
+            return Ok(());
+        }
+
+        if depth == 0 {
+            self.copy_block(out, logical, u64::from(pointer))?;
+            return Ok(());
+        }
+
+        let per_block = (self.sb.block_size / 4) as u64;
+        let span = per_block.pow(depth - 1);
+
+        for (i, child) in self.block(u64::from(pointer))?.chunks_exact(4).enumerate() {
+            self.map_indirect(LE.u32(child), depth - 1, logical + i as u64 * span, out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Contents of an inode, `len` bytes long. Holes read as zeros.
+    fn read_data(&self, inode: &Inode, len: u64) -> io::Result<Vec<u8>> {
+        if len > MAX_FILE_SIZE {
+            return Err(invalid_data(format!(
+                "inode {} is too large ({len} bytes)",
+                inode.number
+            )));
+        }
+
+        if inode.has_flag(INLINE_DATA_FL) {
+            let mut out = self.inline_data(inode)?;
+            out.resize(len as usize, 0);
+            return Ok(out);
+        }
+
+        let mut out = vec![0; len as usize];
+
+        if inode.has_flag(EXTENTS_FL) {
+            self.map_extents(inode.block, MAX_EXTENT_DEPTH, &mut out)?;
+        } else {
+            let per_block = (self.sb.block_size / 4) as u64;
+            let pointers: Vec<u32> = inode.block.chunks_exact(4).map(|p| LE.u32(p)).collect();
+
+            for (i, &pointer) in pointers[..12].iter().enumerate() {
+                self.map_indirect(pointer, 0, i as u64, &mut out)?;
+            }
+            self.map_indirect(pointers[12], 1, 12, &mut out)?;
+            self.map_indirect(pointers[13], 2, 12 + per_block, &mut out)?;
+            self.map_indirect(
+                pointers[14],
+                3,
+                12 + per_block + per_block * per_block,
+                &mut out,
+            )?;
+        }
+
+        Ok(out)
+    }
+
+    /// Inline data: the start is stored in `i_block`, the rest in the `system.data` xattr.
+    /// That is never stored in an ea_inode, and following them here could loop back to
+    /// this inode.
+    fn inline_data(&self, inode: &Inode) -> io::Result<Vec<u8>> {
+        let mut out = inode.block.to_vec();
+
+        if let Some((_, rest)) = self
+            .xattrs(inode, false)?
+            .into_iter()
+            .find(|(name, _)| name == "system.data")
+        {
+            out.extend(rest);
+        }
+
+        Ok(out)
+    }
+
+    /// Extended attributes of `inode`. With `follow_ea_inodes`, values stored in separate
+    /// inodes are read, otherwise those attributes are left out.
+    fn xattrs(&self, inode: &Inode, follow_ea_inodes: bool) -> io::Result<Vec<(String, Vec<u8>)>> {
+        let mut out = Vec::new();
+
+        // Value offsets of in-inode attributes are relative to the first entry, those of an
+        // attribute block to the start of the block
+        self.parse_xattrs(inode.ibody_xattrs, 0, follow_ea_inodes, &mut out)?;
+
+        if inode.file_acl != 0 {
+            let block = self.block(inode.file_acl)?;
+            if LE.u32(block) != XATTR_MAGIC {
+                return Err(invalid_data(format!(
+                    "bad xattr block magic for inode {}",
+                    inode.number
+                )));
+            }
+
+            self.parse_xattrs(block, XATTR_BLOCK_HEADER_LEN, follow_ea_inodes, &mut out)?;
+        }
+
+        Ok(out)
+    }
+
+    fn parse_xattrs(
+        &self,
+        area: &[u8],
+        first: usize,
+        follow_ea_inodes: bool,
+        out: &mut Vec<(String, Vec<u8>)>,
+    ) -> io::Result<()> {
+        let mut r = Reader::at(area, first, LE);
+
+        while r.remaining() >= XATTR_ENTRY_LEN {
+            let start = r.pos();
+            let name_len = r.u8()? as usize;
+            let name_index = r.u8()?;
+            let value_offset = r.u16()? as usize;
+            let value_inode = r.u32()?;
+            let value_size = r.u32()? as usize;
+            let _hash = r.u32()?;
+
+            // The entry list ends with four zero bytes
+            if name_len == 0 && name_index == 0 && value_offset == 0 {
+                break;
+            }
+
+            let name = String::from_utf8_lossy(r.bytes(name_len)?);
+            r.skip((start + XATTR_ENTRY_LEN + name_len).next_multiple_of(4) - r.pos())?;
+
+            let prefix = match name_index {
+                1 => "user.",
+                2 => "system.posix_acl_access",
+                3 => "system.posix_acl_default",
+                4 => "trusted.",
+                6 => "security.",
+                7 => "system.",
+                8 => "system.richacl",
+                _ => "",
+            };
+
+            if value_size > XATTR_SIZE_MAX {
+                return Err(invalid_data(format!(
+                    "xattr {prefix}{name} of {value_size} bytes"
+                )));
+            }
+
+            let value = if value_inode != 0 {
+                if !follow_ea_inodes {
+                    continue;
+                }
+
+                // Large values live in a separate inode (ea_inode feature). Reading it only
+                // looks at its own xattrs for inline data, which do not follow ea_inodes.
+                let inode = self.read_inode(value_inode)?;
+                self.read_data(&inode, value_size as u64)?
+            } else {
+                area.get(value_offset..value_offset + value_size)
+                    .ok_or_else(|| {
+                        invalid_data(format!("xattr {prefix}{name} value out of bounds"))
+                    })?
+                    .to_vec()
+            };
+
+            out.push((format!("{prefix}{name}"), value));
+        }
+
+        Ok(())
+    }
+
+    fn parse_dir_block(&self, block: &[u8], out: &mut Vec<DirEntry>) {
+        let has_filetype = self.sb.has_feature_incompat(INCOMPAT_FILETYPE);
+        let mut pos = 0;
+
+        while pos + 8 <= block.len() {
+            let inode = LE.u32(&block[pos..]);
+            let rec_len = match LE.u16(&block[pos + 4..]) {
+                0 | 65535 => 65536,
+                len => len as usize,
+            };
+            let name_len = if has_filetype {
+                block[pos + 6] as usize
+            } else {
+                LE.u16(&block[pos + 6..]) as usize
+            };
+
+            if rec_len < 8 || pos + rec_len > block.len() || 8 + name_len > rec_len {
+                log::warn!("extfs: corrupt directory entry at offset {pos}");
+                break;
+            }
+
+            if inode != 0 && name_len > 0 {
+                out.push(DirEntry {
+                    inode,
+                    name: block[pos + 8..pos + 8 + name_len].to_vec(),
+                });
+            }
+
+            pos += rec_len;
+        }
+    }
+
+    fn read_dir(&self, inode: &Inode) -> io::Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+
+        if inode.has_flag(INLINE_DATA_FL) {
+            // The first four bytes hold the parent inode instead of "." and ".." entries
+            let data = self.inline_data(inode)?;
+            self.parse_dir_block(&data[4..I_BLOCK_LEN], &mut entries);
+            self.parse_dir_block(&data[I_BLOCK_LEN..], &mut entries);
+        } else {
+            let data = self.read_data(inode, inode.size)?;
+            for block in data.chunks(self.sb.block_size) {
+                self.parse_dir_block(block, &mut entries);
+            }
+        }
+
+        entries.retain(|entry| entry.name != b"." && entry.name != b"..");
+
+        Ok(entries)
+    }
+
+    fn symlink_target(&self, inode: &Inode) -> io::Result<Vec<u8>> {
+        // Fast symlinks keep the target in i_block and own no data blocks
+        let xattr_blocks = if inode.file_acl != 0 {
+            (self.sb.block_size / 512) as u64
+        } else {
+            0
+        };
+        let is_fast = !inode.has_flag(INLINE_DATA_FL)
+            && inode.blocks <= xattr_blocks
+            && inode.size < I_BLOCK_LEN as u64;
+
+        if is_fast {
+            Ok(inode.block[..inode.size as usize].to_vec())
+        } else {
+            self.read_data(inode, inode.size)
+        }
+    }
+
+    fn unpack_entry(&self, tree: &mut TreeWriter, rel: &Path, inode: &Inode) -> io::Result<()> {
+        if let Some(existing) = tree.link_target(u64::from(inode.number), rel) {
+            return tree.hardlink(rel, &existing);
+        }
+
+        match inode.kind() {
+            Some(EntryKind::File) => {
+                tree.file_with(rel, inode.attrs, inode.size, || {
+                    self.read_data(inode, inode.size)
+                })?;
+            }
+            Some(EntryKind::Symlink) => {
+                let target = self.symlink_target(inode)?;
+                tree.symlink(rel, inode.attrs, &target)?;
+            }
+            Some(kind @ (EntryKind::Char | EntryKind::Block)) => {
+                tree.special(rel, kind, inode.attrs, Some(inode.rdev()))?;
+            }
+            Some(kind @ (EntryKind::Fifo | EntryKind::Socket)) => {
+                tree.special(rel, kind, inode.attrs, None)?;
+            }
+            _ => {
+                return Err(invalid_data(format!(
+                    "inode {} has unknown mode {:#o}",
+                    inode.number, inode.mode
+                )))
+            }
+        }
+
+        self.record_xattrs(tree, rel, inode);
+
+        Ok(())
+    }
+
+    fn record_xattrs(&self, tree: &mut TreeWriter, rel: &Path, inode: &Inode) {
+        match self.xattrs(inode, true) {
+            Ok(mut xattrs) => {
+                xattrs.retain(|(name, _)| name != "system.data");
+                if !xattrs.is_empty() {
+                    tree.xattrs(rel, &xattrs);
+                }
+            }
+            Err(err) => log::warn!("extfs: cannot read xattrs of /{}: {err}", rel.display()),
+        }
+    }
+
+    fn unpack(&self, out_dir: &Path) -> io::Result<usize> {
+        if self.sb.has_feature_incompat(INCOMPAT_RECOVER) {
+            log::warn!("extfs: journal needs recovery, unreplayed changes are ignored");
+        }
+        if self.sb.has_feature_incompat(INCOMPAT_ENCRYPT) {
+            log::warn!(
+                "extfs: filesystem uses encryption, encrypted names and data are extracted as is"
+            );
+        }
+
+        let root = self.read_inode(ROOT_INODE)?;
+        if root.kind() != Some(EntryKind::Dir) {
+            return Err(invalid_data("root inode is not a directory"));
+        }
+
+        let mut tree = TreeWriter::new(out_dir, "extfs")?;
+        tree.dir(Path::new(""), root.attrs)?;
+        self.record_xattrs(&mut tree, Path::new(""), &root);
+
+        let mut visited = HashSet::from([ROOT_INODE]);
+        let mut pending: Vec<(PathBuf, Inode)> = vec![(PathBuf::new(), root)];
+
+        while let Some((dir, dir_inode)) = pending.pop() {
+            let entries = match self.read_dir(&dir_inode) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    log::warn!("extfs: cannot read directory /{}: {err}", dir.display());
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                let name = match safe_name(&entry.name) {
+                    Ok(name) => name,
+                    Err(err) => {
+                        log::warn!("extfs: skipping entry in /{}: {err}", dir.display());
+                        continue;
+                    }
+                };
+                let rel = dir.join(name);
+
+                let inode = match self.read_inode(entry.inode) {
+                    Ok(inode) => inode,
+                    Err(err) => {
+                        log::warn!("extfs: skipping /{}: {err}", rel.display());
+                        continue;
+                    }
+                };
+
+                if inode.kind() == Some(EntryKind::Dir) {
+                    if !visited.insert(inode.number) {
+                        log::warn!("extfs: directory loop at /{}", rel.display());
+                        continue;
+                    }
+
+                    tree.dir(&rel, inode.attrs)?;
+                    self.record_xattrs(&mut tree, &rel, &inode);
+                    pending.push((rel, inode));
+                    continue;
+                }
+
+                // Damaged entries are common in truncated dumps, so keep going without them
+                if let Err(err) = self.unpack_entry(&mut tree, &rel, &inode) {
+                    log::warn!("extfs: skipping /{}: {err}", rel.display());
+                }
+            }
+        }
+
+        log::debug!(
+            "extfs: {} byte blocks, {} groups, {} byte inodes",
+            self.sb.block_size,
+            self.sb.group_count(),
+            self.sb.inode_size
+        );
+
+        tree.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const BLOCK_SIZE: usize = 1024;
+    const INODE_SIZE: usize = 256;
+    const INODE_TABLE: usize = 4;
+
+    /// Builds ext2 images of one block group with 1 KiB blocks
+    struct Builder {
+        image: Vec<u8>,
+        next_block: usize,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            let mut builder = Self {
+                image: vec![0; 64 * BLOCK_SIZE],
+                next_block: INODE_TABLE + 4,
+            };
+
+            let sb = SUPERBLOCK_OFFSET;
+            for (offset, value) in [(0, 16), (4, 64), (20, 1), (32, 8192), (40, 16), (76, 1)] {
+                builder.put_u32(sb + offset, value);
+            }
+            builder.put_u16(sb + 56, 0xef53);
+            builder.put_u16(sb + 88, INODE_SIZE as u16);
+            builder.put_u32(sb + 96, INCOMPAT_FILETYPE);
+            builder.put_u32(2 * BLOCK_SIZE + 8, INODE_TABLE as u32);
+
+            builder
+        }
+
+        fn put_u16(&mut self, pos: usize, value: u16) {
+            self.image[pos..pos + 2].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn put_u32(&mut self, pos: usize, value: u32) {
+            self.image[pos..pos + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn block(&mut self, contents: &[u8]) -> u32 {
+            let block = self.next_block;
+            self.next_block += 1;
+            self.image[block * BLOCK_SIZE..][..contents.len()].copy_from_slice(contents);
+            block as u32
+        }
+
+        /// Write inode `number`, returning its offset in the image
+        fn inode(
+            &mut self,
+            number: u32,
+            mode: u32,
+            size: usize,
+            flags: u32,
+            i_block: &[u8],
+        ) -> usize {
+            let pos = INODE_TABLE * BLOCK_SIZE + (number as usize - 1) * INODE_SIZE;
+            self.put_u16(pos, mode as u16);
+            self.put_u32(pos + 4, size as u32);
+            self.put_u16(pos + 0x1a, 1);
+            self.put_u32(pos + 0x20, flags);
+            self.image[pos + 0x28..][..i_block.len()].copy_from_slice(i_block);
+            pos
+        }
+
+        fn file(&mut self, number: u32, contents: &[u8]) {
+            let block = self.block(contents);
+            self.inode(
+                number,
+                libc::S_IFREG | 0o644,
+                contents.len(),
+                0,
+                &block.to_le_bytes(),
+            );
+        }
+
+        fn dir(&mut self, number: u32, entries: &[(u32, &str)]) {
+            let mut listing = Vec::new();
+            for (i, (inode, name)) in entries.iter().enumerate() {
+                let start = listing.len();
+                listing.extend(inode.to_le_bytes());
+                listing.extend([0, 0, name.len() as u8, 0]);
+                listing.extend(name.as_bytes());
+                listing.resize(listing.len().next_multiple_of(4), 0);
+
+                let rec_len = if i == entries.len() - 1 {
+                    BLOCK_SIZE - start
+                } else {
+                    listing.len() - start
+                };
+                listing[start + 4..start + 6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+            }
+
+            let block = self.block(&listing);
+            self.inode(
+                number,
+                libc::S_IFDIR | 0o755,
+                BLOCK_SIZE,
+                0,
+                &block.to_le_bytes(),
+            );
+        }
+    }
+
+    #[test]
+    fn unpacks_files_dirs_and_symlinks() {
+        let mut image = Builder::new();
+        image.dir(
+            2,
+            &[
+                (2, "."),
+                (2, ".."),
+                (12, "hello"),
+                (13, "link"),
+                (14, "etc"),
+            ],
+        );
+        image.file(12, b"hello world");
+        image.inode(13, libc::S_IFLNK | 0o777, 5, 0, b"hello");
+        image.dir(14, &[(14, "."), (2, ".."), (15, "passwd")]);
+        image.file(15, b"root:x:0:0::/root:/bin/sh\n");
+
+        let tmp = tempfile::tempdir().unwrap();
+        let image = Image::open(&image.image).unwrap();
+        assert_eq!(image.unpack(tmp.path()).unwrap(), 5);
+
+        assert_eq!(fs::read(tmp.path().join("hello")).unwrap(), b"hello world");
+        assert_eq!(
+            fs::read_link(tmp.path().join("link")).unwrap(),
+            Path::new("hello")
+        );
+        let passwd = fs::read_to_string(tmp.path().join("etc/passwd")).unwrap();
+        assert!(passwd.starts_with("root:"));
+    }
+
+    #[test]
+    fn ea_inodes_cannot_recurse() {
+        let mut image = Builder::new();
+        image.dir(2, &[(2, "."), (2, ".."), (12, "inline")]);
+
+        // Inline data continued in a system.data xattr whose value is stored in the inode
+        // itself
+        let pos = image.inode(12, libc::S_IFREG | 0o644, 70, INLINE_DATA_FL, &[b'a'; 60]);
+        image.put_u16(pos + 0x80, 32);
+        let xattrs = pos + 128 + 32;
+        image.put_u32(xattrs, XATTR_MAGIC);
+        image.image[xattrs + 4..xattrs + 8].copy_from_slice(&[4, 7, 0, 0]);
+        image.put_u32(xattrs + 8, 12);
+        image.put_u32(xattrs + 12, 10);
+        image.image[xattrs + 20..xattrs + 24].copy_from_slice(b"data");
+
+        let tmp = tempfile::tempdir().unwrap();
+        Image::open(&image.image)
+            .unwrap()
+            .unpack(tmp.path())
+            .unwrap();
+
+        let contents = fs::read(tmp.path().join("inline")).unwrap();
+        assert_eq!(&contents[..60], &[b'a'; 60]);
+        assert_eq!(contents.len(), 70);
+    }
+}
@@ -0,0 +1,523 @@
+//! Materializing filesystem trees read by the native extractors.
+//!
+//! [`TreeWriter`] creates files, directories, links and special files under a root directory
+//! and tries to apply the ownership and mode stored in the image. When that is not possible
+//! (for example `chown`/`mknod` while running unprivileged) the original attributes are still
+//! kept in a [`Manifest`] written next to the tree, which [`crate::archive::tar_fs`] consults
+//! when building the archive. Every entry is charged against the extraction [`quota`] before
+//! it is written, and writing fails once the extractor has to stop for Ctrl-C or `--timeout`.
+//!
+//! Images are free to contain a symlink and a directory of the same name, so nothing written
+//! ever goes through a symlink: parents are checked one component at a time, and files and
+//! directories are opened with `O_NOFOLLOW`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, symlink, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::native::{self, invalid_data};
+use super::quota;
+
+/// Suffix of the manifest file written next to an extracted tree.
+pub const MANIFEST_SUFFIX: &str = ".xfs-manifest.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+    Char,
+    Block,
+    Fifo,
+    Socket,
+}
+
+/// Ownership and permissions of an entry as stored in the filesystem image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attrs {
+    /// Permission bits, including setuid/setgid/sticky (no file type bits)
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Attrs {
+    pub fn new(mode: u32, uid: u32, gid: u32) -> Self {
+        Self {
+            mode: mode & 0o7777,
+            uid,
+            gid,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    #[serde(flatten)]
+    pub attrs: Attrs,
+    /// `(major, minor)` for character and block devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rdev: Option<(u32, u32)>,
+    /// Link target for symlinks and hardlinks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Extended attributes, with hex-encoded values. Only recorded, never applied on disk.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+    /// Whether the entry exists on disk with the attributes above
+    pub applied: bool,
+}
+
+/// Original attributes of every entry in an extracted tree, keyed by `/`-rooted path.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: String,
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn path_for(root: &Path) -> PathBuf {
+        let mut name = root.file_name().unwrap_or_default().to_os_string();
+        name.push(MANIFEST_SUFFIX);
+        root.with_file_name(name)
+    }
+
+    /// Find the manifest covering `dir`, which may be the extracted root itself or any
+    /// directory inside it. Only a manifest next to a root directly in `extract_dir`, the
+    /// extractor's output directory, is trusted: one anywhere else came out of an image or was
+    /// written by another extractor. Returns the root the manifest paths are relative to.
+    pub fn find(extract_dir: &Path, dir: &Path) -> Option<(PathBuf, Manifest)> {
+        let name = dir.strip_prefix(extract_dir).ok()?.components().next()?;
+        let root = extract_dir.join(name);
+
+        let path = Self::path_for(&root);
+        if !fs::symlink_metadata(&path).ok()?.is_file() {
+            return None;
+        }
+        let manifest = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+
+        Some((root, manifest))
+    }
+
+    /// Look up the entry for `path`, given relative to the manifest root.
+    pub fn get(&self, path: &Path) -> Option<&ManifestEntry> {
+        self.entries.get(&manifest_key(path))
+    }
+}
+
+fn manifest_key(rel: &Path) -> String {
+    format!("/{}", rel.display())
+}
+
+/// Validate a single path component read from an image.
+pub fn safe_name(name: &[u8]) -> io::Result<&OsStr> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') || name.contains(&0)
+    {
+        return Err(invalid_data(format!(
+            "unsafe file name {:?}",
+            String::from_utf8_lossy(name)
+        )));
+    }
+
+    Ok(OsStr::from_bytes(name))
+}
+
+fn is_privileged() -> bool {
+    nix::unistd::geteuid().is_root()
+}
+
+pub struct TreeWriter {
+    root: PathBuf,
+    privileged: bool,
+    manifest: Manifest,
+    dir_modes: Vec<(PathBuf, u32)>,
+    inodes: HashMap<u64, PathBuf>,
+    count: usize,
+}
+
+impl TreeWriter {
+    pub fn new(root: &Path, format: &str) -> io::Result<Self> {
+        fs::create_dir_all(root)?;
+
+        Ok(Self {
+            root: root.to_owned(),
+            privileged: is_privileged(),
+            manifest: Manifest {
+                format: format.to_owned(),
+                entries: BTreeMap::new(),
+            },
+            dir_modes: Vec::new(),
+            inodes: HashMap::new(),
+            count: 0,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn host_path(&self, rel: &Path) -> PathBuf {
+        self.root.join(rel)
+    }
+
+    /// Account for writing `rel`, failing if that would exceed a quota or the extractor has
+    /// been told to stop
+    fn charge(&self, rel: &Path, bytes: u64) -> io::Result<()> {
+        native::check_stop()?;
+        quota::charge(&self.host_path(rel), bytes)
+    }
+
+    /// Create the directory `rel` and any missing parents, one component at a time from the
+    /// root, failing if any of them exists as a symlink or anything else but a directory.
+    fn create_dirs(&self, rel: &Path) -> io::Result<PathBuf> {
+        let mut path = self.root.clone();
+
+        for component in rel.components() {
+            let Component::Normal(name) = component else {
+                return Err(invalid_data(format!("unsafe path {}", rel.display())));
+            };
+            path.push(name);
+
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => {}
+                Ok(_) => {
+                    return Err(invalid_data(format!(
+                        "{} is not a directory",
+                        manifest_key(path.strip_prefix(&self.root).unwrap_or(&path))
+                    )))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&path)?,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Host path for a new non-directory entry, with its parent created and any existing
+    /// entry of the same name removed (later entries win, as in archive formats).
+    fn prepare(&self, rel: &Path) -> io::Result<PathBuf> {
+        let (Some(parent), Some(name)) = (rel.parent(), rel.file_name()) else {
+            return Err(invalid_data(format!("unsafe path {}", rel.display())));
+        };
+        let path = self.create_dirs(parent)?.join(name);
+
+        // Unlinking never follows a symlink, and fails on a directory
+        let _ = fs::remove_file(&path);
+
+        Ok(path)
+    }
+
+    fn record(
+        &mut self,
+        rel: &Path,
+        kind: EntryKind,
+        attrs: Attrs,
+        rdev: Option<(u32, u32)>,
+        target: Option<String>,
+        applied: bool,
+    ) {
+        self.count += 1;
+        self.manifest.entries.insert(
+            manifest_key(rel),
+            ManifestEntry {
+                kind,
+                attrs,
+                rdev,
+                target,
+                xattrs: BTreeMap::new(),
+                applied,
+            },
+        );
+    }
+
+    fn chown(&self, path: &Path, attrs: Attrs) -> bool {
+        self.privileged && lchown(path, Some(attrs.uid), Some(attrs.gid)).is_ok()
+    }
+
+    /// Remember `rel` as the first path of `inode` so later entries for the same inode become
+    /// hardlinks. Returns the existing path if the inode was already written.
+    pub fn link_target(&mut self, inode: u64, rel: &Path) -> Option<PathBuf> {
+        match self.inodes.get(&inode) {
+            Some(existing) => Some(existing.clone()),
+            None => {
+                self.inodes.insert(inode, rel.to_owned());
+                None
+            }
+        }
+    }
+
+    pub fn dir(&mut self, rel: &Path, attrs: Attrs) -> io::Result<()> {
+        self.charge(rel, 0)?;
+        let path = self.create_dirs(rel)?;
+
+        let owned = self.chown(&path, attrs);
+
+        // Applied once all children are written, so read-only directories stay writable
+        self.dir_modes.push((path, attrs.mode));
+        self.record(rel, EntryKind::Dir, attrs, None, None, owned);
+
+        Ok(())
+    }
+
+    pub fn file(&mut self, rel: &Path, attrs: Attrs, data: &[u8]) -> io::Result<()> {
+        self.charge(rel, data.len() as u64)?;
+        self.write_file(rel, attrs, data)
+    }
+
+    /// Write a regular file whose contents, declared to be `size` bytes, are produced by
+    /// `read`. The declared size is charged before `read` runs, so an entry claiming to be huge
+    /// fails the quota before it is decompressed.
+    pub fn file_with(
+        &mut self,
+        rel: &Path,
+        attrs: Attrs,
+        size: u64,
+        read: impl FnOnce() -> io::Result<Vec<u8>>,
+    ) -> io::Result<()> {
+        self.charge(rel, size)?;
+
+        let data = read()?;
+        if data.len() as u64 > size {
+            return Err(invalid_data(format!(
+                "{} is larger than its declared size",
+                rel.display()
+            )));
+        }
+
+        self.write_file(rel, attrs, &data)
+    }
+
+    fn write_file(&mut self, rel: &Path, attrs: Attrs, data: &[u8]) -> io::Result<()> {
+        let path = self.prepare(rel)?;
+
+        // The name was just unlinked, so creating it exclusively fails rather than following
+        // whatever was put there instead
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)?;
+        file.write_all(data)?;
+
+        let owned = self.chown(&path, attrs);
+
+        // chown clears setuid/setgid, so permissions are set afterwards. Unprivileged runs keep
+        // the file readable so it can still be archived; the manifest holds the real mode.
+        let mode = if self.privileged {
+            attrs.mode
+        } else {
+            attrs.mode | 0o600
+        };
+        file.set_permissions(Permissions::from_mode(mode))?;
+
+        self.record(rel, EntryKind::File, attrs, None, None, owned);
+
+        Ok(())
+    }
+
+    pub fn symlink(&mut self, rel: &Path, attrs: Attrs, target: &[u8]) -> io::Result<()> {
+        self.charge(rel, 0)?;
+        let path = self.prepare(rel)?;
+
+        let target = OsStr::from_bytes(target);
+        symlink(target, &path)?;
+
+        let owned = self.chown(&path, attrs);
+        let target = Some(target.to_string_lossy().into_owned());
+        self.record(rel, EntryKind::Symlink, attrs, None, target, owned);
+
+        Ok(())
+    }
+
+    /// Create `rel` as a hardlink to the previously written `existing`.
+    pub fn hardlink(&mut self, rel: &Path, existing: &Path) -> io::Result<()> {
+        self.charge(rel, 0)?;
+        let path = self.prepare(rel)?;
+
+        let existing_key = manifest_key(existing);
+        let Some(original) = self.manifest.entries.get(&existing_key).cloned() else {
+            return Err(invalid_data(format!(
+                "hardlink to unknown entry {existing_key}"
+            )));
+        };
+
+        // Only regular files can be hardlinked portably; special files and symlinks are
+        // recreated instead so they still carry their attributes.
+        let applied = match original.kind {
+            EntryKind::File | EntryKind::Hardlink => {
+                fs::hard_link(self.host_path(existing), &path).is_ok()
+            }
+            _ => false,
+        };
+
+        self.record(
+            rel,
+            EntryKind::Hardlink,
+            original.attrs,
+            original.rdev,
+            Some(existing_key),
+            applied,
+        );
+
+        Ok(())
+    }
+
+    /// Create a device node, FIFO or socket. Device nodes are only created when running
+    /// privileged; otherwise they exist solely in the manifest.
+    pub fn special(
+        &mut self,
+        rel: &Path,
+        kind: EntryKind,
+        attrs: Attrs,
+        rdev: Option<(u32, u32)>,
+    ) -> io::Result<()> {
+        self.charge(rel, 0)?;
+        let path = self.prepare(rel)?;
+
+        let file_type = match kind {
+            EntryKind::Char => libc::S_IFCHR,
+            EntryKind::Block => libc::S_IFBLK,
+            EntryKind::Fifo => libc::S_IFIFO,
+            EntryKind::Socket => libc::S_IFSOCK,
+            _ => return Err(invalid_data(format!("{kind:?} is not a special file"))),
+        };
+
+        let created = if matches!(kind, EntryKind::Char | EntryKind::Block) && !self.privileged {
+            false
+        } else {
+            let (major, minor) = rdev.unwrap_or((0, 0));
+            let dev = libc::makedev(major, minor);
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+            // SAFETY: c_path is a valid NUL-terminated string for the duration of the call
+            unsafe { libc::mknod(c_path.as_ptr(), file_type | (attrs.mode & 0o777), dev) == 0 }
+        };
+
+        let owned = created && self.chown(&path, attrs);
+        self.record(rel, kind, attrs, rdev, None, owned);
+
+        Ok(())
+    }
+
+    /// Record the extended attributes of the already written entry `rel`.
+    pub fn xattrs(&mut self, rel: &Path, xattrs: &[(String, Vec<u8>)]) {
+        if let Some(entry) = self.manifest.entries.get_mut(&manifest_key(rel)) {
+            entry.xattrs = xattrs
+                .iter()
+                .map(|(name, value)| {
+                    let hex = value.iter().map(|byte| format!("{byte:02x}")).collect();
+                    (name.clone(), hex)
+                })
+                .collect();
+        }
+    }
+
+    /// Apply deferred directory permissions and write the manifest. Returns the number of
+    /// entries written.
+    pub fn finish(mut self) -> io::Result<usize> {
+        // Deepest directories first so parents stay traversable until the end
+        self.dir_modes
+            .sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+
+        for (path, mode) in &self.dir_modes {
+            let dir = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+                .open(path)?;
+
+            // Keep extracted directories accessible to the (possibly unprivileged) user that
+            // has to archive them
+            dir.set_permissions(Permissions::from_mode(mode | 0o700))?;
+        }
+
+        let json = serde_json::to_vec(&self.manifest).map_err(io::Error::other)?;
+        fs::write(Manifest::path_for(&self.root), json)?;
+
+        Ok(self.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_nothing_through_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outside = tmp.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        fs::set_permissions(&outside, Permissions::from_mode(0o750)).unwrap();
+
+        let mut tree = TreeWriter::new(&tmp.path().join("root"), "test").unwrap();
+        let attrs = Attrs::new(0o777, 0, 0);
+        tree.symlink(Path::new("lib"), attrs, outside.as_os_str().as_bytes())
+            .unwrap();
+
+        assert!(tree.file(Path::new("lib/evil"), attrs, b"x").is_err());
+        assert!(tree.dir(Path::new("lib"), attrs).is_err());
+        assert!(tree.dir(Path::new("lib/sub"), attrs).is_err());
+        tree.finish().unwrap();
+
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        let mode = fs::metadata(&outside).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o750);
+    }
+
+    #[test]
+    fn later_entries_replace_earlier_ones() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("root");
+
+        let mut tree = TreeWriter::new(&root, "test").unwrap();
+        let attrs = Attrs::new(0o644, 0, 0);
+        tree.symlink(Path::new("a"), attrs, b"/etc/passwd").unwrap();
+        tree.file(Path::new("a"), attrs, b"contents").unwrap();
+        tree.dir(Path::new("d/e"), Attrs::new(0o500, 0, 0)).unwrap();
+        tree.file(Path::new("d/e/f"), attrs, b"").unwrap();
+        assert_eq!(tree.finish().unwrap(), 4);
+
+        assert_eq!(fs::read(root.join("a")).unwrap(), b"contents");
+        let mode = fs::metadata(root.join("d/e")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let (manifest_root, manifest) = Manifest::find(tmp.path(), &root.join("d")).unwrap();
+        assert_eq!(manifest_root, root);
+        assert_eq!(manifest.get(Path::new("d/e")).unwrap().attrs.mode, 0o500);
+    }
+
+    #[test]
+    fn manifests_are_only_trusted_next_to_unpacked_roots() {
+        let tmp = tempfile::tempdir().unwrap();
+        let extract_dir = tmp.path().join("out");
+        let root = extract_dir.join("root");
+        let attrs = Attrs::new(0o755, 0, 0);
+
+        let mut tree = TreeWriter::new(&root, "test").unwrap();
+        tree.dir(Path::new("d"), attrs).unwrap();
+        tree.finish().unwrap();
+
+        // As if the image contained manifests of its own
+        let mut nested = TreeWriter::new(&root.join("d/nested"), "test").unwrap();
+        nested
+            .dir(Path::new("bin"), Attrs::new(0o4755, 0, 0))
+            .unwrap();
+        nested.finish().unwrap();
+        assert!(root.join("d/nested.xfs-manifest.json").is_file());
+
+        let nested_dir = root.join("d/nested/bin");
+        let (manifest_root, _) = Manifest::find(&extract_dir, &nested_dir).unwrap();
+        assert_eq!(manifest_root, root);
+        assert!(Manifest::find(&root, &nested_dir).is_none());
+        assert!(Manifest::find(&tmp.path().join("elsewhere"), &nested_dir).is_none());
+    }
+}
@@ -0,0 +1,724 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::compression::{self, Codec};
+use super::fstree::{safe_name, Attrs, EntryKind, TreeWriter};
+use super::native::{self, crc32_le, invalid_data, Endian, Reader, Unpacked};
+use super::{ExtractError, ExtractStatus, Extractor};
+
+const MAGIC_LE: &[u8] = &[0x85, 0x19];
+const MAGIC_BE: &[u8] = &[0x19, 0x85];
+const NODE_MAGIC: u16 = 0x1985;
+
+const HEADER_LEN: usize = 12;
+const DIRENT_LEN: usize = 40;
+const INODE_LEN: usize = 68;
+
+const NODETYPE_DIRENT: u16 = 0xe001;
+const NODETYPE_INODE: u16 = 0xe002;
+const NODETYPE_CLEANMARKER: u16 = 0x2003;
+const NODETYPE_PADDING: u16 = 0x2004;
+/// Cleared when a node has been superseded (obsoleted) in place
+const NODE_ACCURATE: u16 = 0x2000;
+const FEATURE_MASK: u16 = 0xc000;
+const FEATURE_INCOMPAT: u16 = 0xc000;
+
+const COMPR_NONE: u8 = 0x00;
+const COMPR_ZERO: u8 = 0x01;
+const COMPR_RTIME: u8 = 0x02;
+const COMPR_RUBINMIPS: u8 = 0x03;
+const COMPR_COPY: u8 = 0x04;
+const COMPR_DYNRUBIN: u8 = 0x05;
+const COMPR_ZLIB: u8 = 0x06;
+const COMPR_LZO: u8 = 0x07;
+const COMPR_LZMA: u8 = 0x08;
+
+/// Fixed LZMA parameters of the JFFS2 lzma compressor (lc=0, lp=0, pb=0)
+const LZMA_DICT_SIZE: u32 = 0x2000;
+
+const ROOT_INO: u32 = 1;
+
+/// Erase block size assumed for the end-of-filesystem heuristic when the image has no
+/// cleanmarkers to derive it from
+const DEFAULT_ERASE_SIZE: usize = 64 * 1024;
+
+const MAX_FILE_SIZE: usize = 1 << 30;
+
+/// In-process reader for JFFS2 images of either endianness
+pub struct Jffs2Extractor;
+
+impl Extractor for Jffs2Extractor {
+    fn name(&self) -> &'static str {
+        "jffs2"
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        _verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        native::extract_all(
+            self.name(),
+            in_file,
+            extract_dir,
+            log_file,
+            &[MAGIC_LE, MAGIC_BE],
+            |data, offset, out_dir| {
+                let Some(scan) = Scan::run(&data[offset..]) else {
+                    return Ok(None);
+                };
+
+                let len = scan.len;
+                let (entries, warnings) = scan.unpack(out_dir)?;
+
+                Ok(Some(Unpacked {
+                    len,
+                    entries,
+                    warnings,
+                }))
+            },
+        )
+    }
+}
+
+/// Parse a node header at `pos`, returning the node type and total length if the magic and
+/// header CRC check out.
+fn node_header(data: &[u8], pos: usize, endian: Endian) -> Option<(u16, usize)> {
+    let header = data.get(pos..pos + HEADER_LEN)?;
+
+    let magic = endian.u16(&header[0..2]);
+    let nodetype = endian.u16(&header[2..4]);
+    let totlen = endian.u32(&header[4..8]) as usize;
+    let hdr_crc = endian.u32(&header[8..12]);
+
+    if magic != NODE_MAGIC || crc32_le(0, &header[..8]) != hdr_crc {
+        return None;
+    }
+
+    if totlen < HEADER_LEN || data.len() - pos < totlen {
+        return None;
+    }
+
+    Some((nodetype, totlen))
+}
+
+#[derive(Debug)]
+struct Dirent<'a> {
+    version: u32,
+    ino: u32,
+    name: &'a [u8],
+}
+
+#[derive(Debug)]
+struct DataNode<'a> {
+    version: u32,
+    mode: u32,
+    uid: u16,
+    gid: u16,
+    isize: u32,
+    offset: u32,
+    dsize: u32,
+    compr: u8,
+    data: &'a [u8],
+}
+
+impl DataNode<'_> {
+    fn attrs(&self) -> Attrs {
+        Attrs::new(self.mode, u32::from(self.uid), u32::from(self.gid))
+    }
+
+    fn kind(&self) -> Option<EntryKind> {
+        match self.mode & libc::S_IFMT {
+            libc::S_IFDIR => Some(EntryKind::Dir),
+            libc::S_IFREG => Some(EntryKind::File),
+            libc::S_IFLNK => Some(EntryKind::Symlink),
+            libc::S_IFCHR => Some(EntryKind::Char),
+            libc::S_IFBLK => Some(EntryKind::Block),
+            libc::S_IFIFO => Some(EntryKind::Fifo),
+            libc::S_IFSOCK => Some(EntryKind::Socket),
+            _ => None,
+        }
+    }
+
+    fn decompress(&self) -> io::Result<Vec<u8>> {
+        let dsize = self.dsize as usize;
+
+        let mut out = match self.compr {
+            COMPR_NONE | COMPR_COPY => self.data.to_vec(),
+            COMPR_ZERO => vec![0; dsize],
+            COMPR_RTIME => rtime(self.data, dsize)?,
+            COMPR_ZLIB => Codec::Zlib.decompress(self.data, dsize)?,
+            COMPR_LZO => Codec::Lzo.decompress(self.data, dsize)?,
+            COMPR_LZMA => compression::lzma_raw(self.data, 0, 0, 0, LZMA_DICT_SIZE, dsize)?,
+            COMPR_RUBINMIPS | COMPR_DYNRUBIN => {
+                return Err(invalid_data("rubin compression is not supported"))
+            }
+            compr => return Err(invalid_data(format!("unknown compression type {compr:#x}"))),
+        };
+
+        out.resize(dsize, 0);
+        Ok(out)
+    }
+}
+
+/// Decompress the JFFS2 "rtime" run-length format
+fn rtime(data: &[u8], dsize: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(dsize);
+    let mut positions = [0usize; 256];
+    let mut input = data.chunks_exact(2);
+
+    while out.len() < dsize {
+        let Some(&[value, repeat]) = input.next() else {
+            return Err(invalid_data("rtime data ends early"));
+        };
+
+        out.push(value);
+
+        let back = positions[value as usize];
+        positions[value as usize] = out.len();
+
+        // Byte by byte, since the copy may overlap its own output
+        let repeat = (repeat as usize).min(dsize - out.len());
+        for i in back..back + repeat {
+            out.push(out[i]);
+        }
+    }
+
+    Ok(out)
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    nodes: usize,
+    bad_crc: usize,
+    obsolete: usize,
+    unknown_incompat: usize,
+}
+
+struct Scan<'a> {
+    endian: Endian,
+    /// Bytes of the input covered by the filesystem
+    len: usize,
+    erase_size: Option<usize>,
+    inodes: HashMap<u32, Vec<DataNode<'a>>>,
+    /// Latest directory entry for each `(parent inode, name)`
+    dirents: HashMap<(u32, &'a [u8]), Dirent<'a>>,
+    stats: Stats,
+}
+
+impl<'a> Scan<'a> {
+    /// Walk the nodes starting at the beginning of `data`, which is expected to hold a node
+    fn run(data: &'a [u8]) -> Option<Self> {
+        let endian = if data.starts_with(MAGIC_LE) {
+            Endian::Little
+        } else {
+            Endian::Big
+        };
+
+        node_header(data, 0, endian)?;
+
+        let mut scan = Self {
+            endian,
+            len: 0,
+            erase_size: None,
+            inodes: HashMap::new(),
+            dirents: HashMap::new(),
+            stats: Stats::default(),
+        };
+
+        let mut pos = 0;
+        let mut garbage = 0;
+
+        while pos + HEADER_LEN <= data.len() {
+            if data[pos..pos + 4] == [0xff; 4] {
+                // Erased flash
+                pos += 4;
+                continue;
+            }
+
+            let Some((nodetype, totlen)) = node_header(data, pos, endian) else {
+                // Stop once the data no longer looks like part of the same partition: more
+                // than an erase block without a valid node
+                garbage += 4;
+                if garbage > scan.erase_size.unwrap_or(DEFAULT_ERASE_SIZE) {
+                    break;
+                }
+
+                pos += 4;
+                continue;
+            };
+
+            scan.node(&data[pos..pos + totlen], nodetype, pos);
+
+            pos = (pos + totlen).next_multiple_of(4);
+            scan.len = pos.min(data.len());
+            garbage = 0;
+        }
+
+        if scan.inodes.is_empty() && scan.dirents.is_empty() {
+            return None;
+        }
+
+        // Whole erase blocks belong to the filesystem
+        if let Some(erase_size) = scan.erase_size {
+            scan.len = scan.len.next_multiple_of(erase_size).min(data.len());
+        }
+
+        Some(scan)
+    }
+
+    fn node(&mut self, node: &'a [u8], nodetype: u16, pos: usize) {
+        self.stats.nodes += 1;
+
+        if nodetype & NODE_ACCURATE == 0 {
+            self.stats.obsolete += 1;
+            return;
+        }
+
+        let result = match nodetype {
+            NODETYPE_DIRENT => self.dirent(node),
+            NODETYPE_INODE => self.inode(node),
+            NODETYPE_CLEANMARKER => {
+                self.cleanmarker(pos);
+                Ok(())
+            }
+            NODETYPE_PADDING => Ok(()),
+            _ if nodetype & FEATURE_MASK == FEATURE_INCOMPAT => {
+                self.stats.unknown_incompat += 1;
+                Ok(())
+            }
+            // Summary, xattr and other compatible nodes carry nothing needed for the tree
+            _ => Ok(()),
+        };
+
+        if result.is_err() {
+            self.stats.bad_crc += 1;
+        }
+    }
+
+    /// Cleanmarkers sit at the start of every erase block, so the erase block size is the
+    /// largest power of two dividing all of their offsets
+    fn cleanmarker(&mut self, pos: usize) {
+        if pos == 0 {
+            return;
+        }
+
+        let size = 1 << pos.trailing_zeros();
+        if size >= 4096 {
+            self.erase_size = Some(self.erase_size.map_or(size, |current| current.min(size)));
+        }
+    }
+
+    fn dirent(&mut self, node: &'a [u8]) -> io::Result<()> {
+        let mut r = Reader::at(node, HEADER_LEN, self.endian);
+        let pino = r.u32()?;
+        let version = r.u32()?;
+        let ino = r.u32()?;
+        let _mctime = r.u32()?;
+        let nsize = r.u8()? as usize;
+        let _type = r.u8()?;
+        r.skip(2)?;
+        let node_crc = r.u32()?;
+        let name_crc = r.u32()?;
+        let name = r.bytes(nsize)?;
+
+        if crc32_le(0, &node[..DIRENT_LEN - 8]) != node_crc || crc32_le(0, name) != name_crc {
+            return Err(invalid_data("bad dirent CRC"));
+        }
+
+        let key = (pino, name);
+        if self
+            .dirents
+            .get(&key)
+            .is_none_or(|existing| existing.version < version)
+        {
+            self.dirents.insert(key, Dirent { version, ino, name });
+        }
+
+        Ok(())
+    }
+
+    fn inode(&mut self, node: &'a [u8]) -> io::Result<()> {
+        let mut r = Reader::at(node, HEADER_LEN, self.endian);
+        let ino = r.u32()?;
+        let version = r.u32()?;
+        let mode = r.u32()?;
+        let uid = r.u16()?;
+        let gid = r.u16()?;
+        let isize = r.u32()?;
+        r.skip(12)?;
+        let offset = r.u32()?;
+        let csize = r.u32()?;
+        let dsize = r.u32()?;
+        let compr = r.u8()?;
+        let _usercompr = r.u8()?;
+        let _flags = r.u16()?;
+        let data_crc = r.u32()?;
+        let node_crc = r.u32()?;
+        let data = r.bytes(csize as usize)?;
+
+        if crc32_le(0, &node[..INODE_LEN - 8]) != node_crc || crc32_le(0, data) != data_crc {
+            return Err(invalid_data("bad inode CRC"));
+        }
+
+        self.inodes.entry(ino).or_default().push(DataNode {
+            version,
+            mode,
+            uid,
+            gid,
+            isize,
+            offset,
+            dsize,
+            compr,
+            data,
+        });
+
+        Ok(())
+    }
+
+    /// Replay the data nodes of a file in version order
+    fn contents(nodes: &[DataNode]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+
+        for node in nodes {
+            let start = node.offset as usize;
+            let end = start + node.dsize as usize;
+            if end > MAX_FILE_SIZE || node.isize as usize > MAX_FILE_SIZE {
+                return Err(invalid_data(format!(
+                    "file too large ({} bytes)",
+                    node.isize
+                )));
+            }
+
+            if node.dsize > 0 {
+                let data = node.decompress()?;
+                if out.len() < end {
+                    out.resize(end, 0);
+                }
+                out[start..end].copy_from_slice(&data);
+            }
+
+            // Every node records the file size at the time it was written
+            out.resize(node.isize as usize, 0);
+        }
+
+        Ok(out)
+    }
+
+    fn unpack(mut self, out_dir: &Path) -> io::Result<(usize, Vec<String>)> {
+        let mut warnings = Vec::new();
+
+        for nodes in self.inodes.values_mut() {
+            nodes.sort_by_key(|node| node.version);
+        }
+
+        let mut children: HashMap<u32, BTreeMap<&[u8], u32>> = HashMap::new();
+        for (&(pino, _), dirent) in &self.dirents {
+            // A dirent pointing at inode 0 records an unlink
+            if dirent.ino != 0 {
+                children
+                    .entry(pino)
+                    .or_default()
+                    .insert(dirent.name, dirent.ino);
+            }
+        }
+
+        let root_attrs = self
+            .inodes
+            .get(&ROOT_INO)
+            .and_then(|nodes| nodes.last())
+            .map_or(Attrs::new(0o755, 0, 0), DataNode::attrs);
+
+        let mut tree = TreeWriter::new(out_dir, "jffs2")?;
+        tree.dir(Path::new(""), root_attrs)?;
+
+        let mut visited = HashSet::from([ROOT_INO]);
+        let mut pending = vec![(PathBuf::new(), ROOT_INO)];
+
+        while let Some((dir, dir_ino)) = pending.pop() {
+            for (&name, &ino) in children.get(&dir_ino).into_iter().flatten() {
+                let name = match safe_name(name) {
+                    Ok(name) => name,
+                    Err(err) => {
+                        warnings.push(format!("skipping entry in /{}: {err}", dir.display()));
+                        continue;
+                    }
+                };
+                let rel = dir.join(name);
+
+                let Some(nodes) = self.inodes.get(&ino).filter(|nodes| !nodes.is_empty()) else {
+                    warnings.push(format!(
+                        "/{}: no valid nodes for inode {ino}",
+                        rel.display()
+                    ));
+                    continue;
+                };
+                let latest = nodes.last().unwrap();
+
+                if latest.kind() == Some(EntryKind::Dir) {
+                    if !visited.insert(ino) {
+                        warnings.push(format!("directory loop at /{}", rel.display()));
+                        continue;
+                    }
+
+                    tree.dir(&rel, latest.attrs())?;
+                    pending.push((rel, ino));
+                    continue;
+                }
+
+                if let Some(existing) = tree.link_target(u64::from(ino), &rel) {
+                    tree.hardlink(&rel, &existing)?;
+                    continue;
+                }
+
+                if let Err(err) = self.unpack_entry(&mut tree, &rel, nodes) {
+                    warnings.push(format!("skipping /{}: {err}", rel.display()));
+                }
+            }
+        }
+
+        let stats = &self.stats;
+        if stats.bad_crc > 0 {
+            warnings.push(format!(
+                "{} of {} nodes had bad CRCs and were ignored",
+                stats.bad_crc, stats.nodes
+            ));
+        }
+        if stats.unknown_incompat > 0 {
+            warnings.push(format!(
+                "{} nodes of unknown incompatible types were ignored",
+                stats.unknown_incompat
+            ));
+        }
+
+        log::debug!(
+            "jffs2: {} image, {} nodes ({} obsolete), erase block size {}",
+            self.endian.name(),
+            stats.nodes,
+            stats.obsolete,
+            self.erase_size
+                .map_or("unknown".to_owned(), |size| format!("{size:#x}"))
+        );
+
+        Ok((tree.finish()?, warnings))
+    }
+
+    fn unpack_entry(
+        &self,
+        tree: &mut TreeWriter,
+        rel: &Path,
+        nodes: &[DataNode],
+    ) -> io::Result<()> {
+        let latest = nodes.last().unwrap();
+        let attrs = latest.attrs();
+
+        match latest.kind() {
+            Some(EntryKind::File) => tree.file_with(rel, attrs, u64::from(latest.isize), || {
+                Self::contents(nodes)
+            }),
+            Some(EntryKind::Symlink) => tree.symlink(rel, attrs, &Self::contents(nodes)?),
+            Some(kind @ (EntryKind::Char | EntryKind::Block)) => {
+                let data = latest.decompress()?;
+                let rdev = match data.len() {
+                    2 => {
+                        let old = u32::from(self.endian.u16(&data));
+                        ((old >> 8) & 0xff, old & 0xff)
+                    }
+                    4 => {
+                        let new = self.endian.u32(&data);
+                        ((new & 0xfff00) >> 8, (new & 0xff) | ((new >> 12) & 0xfff00))
+                    }
+                    len => return Err(invalid_data(format!("device number of {len} bytes"))),
+                };
+
+                tree.special(rel, kind, attrs, Some(rdev))
+            }
+            Some(kind @ (EntryKind::Fifo | EntryKind::Socket)) => {
+                tree.special(rel, kind, attrs, None)
+            }
+            _ => Err(invalid_data(format!("unknown mode {:#o}", latest.mode))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    /// Builds node sequences of either endianness
+    struct Builder {
+        endian: Endian,
+        image: Vec<u8>,
+    }
+
+    impl Builder {
+        fn new(endian: Endian) -> Self {
+            Self {
+                endian,
+                image: Vec::new(),
+            }
+        }
+
+        fn fields(&self, out: &mut Vec<u8>, fields: &[(u32, usize)]) {
+            for &(value, len) in fields {
+                match self.endian {
+                    Endian::Little => out.extend(&value.to_le_bytes()[..len]),
+                    Endian::Big => out.extend(&value.to_be_bytes()[4 - len..]),
+                }
+            }
+        }
+
+        /// Append a node: the header, the fixed `body` fields, the data CRC if there is one,
+        /// the node CRC over the header and body, then `tail`
+        fn node(
+            &mut self,
+            nodetype: u16,
+            body: &[(u32, usize)],
+            data_crc: Option<u32>,
+            tail: &[u8],
+        ) {
+            let body_len: usize = body.iter().map(|(_, len)| len).sum();
+            let crcs = if data_crc.is_some() { 8 } else { 4 };
+            let totlen = HEADER_LEN + body_len + crcs + tail.len();
+
+            let mut node = Vec::new();
+            self.fields(
+                &mut node,
+                &[(u32::from(NODE_MAGIC), 2), (u32::from(nodetype), 2)],
+            );
+            self.fields(&mut node, &[(totlen as u32, 4)]);
+            let hdr_crc = crc32_le(0, &node);
+            self.fields(&mut node, &[(hdr_crc, 4)]);
+            self.fields(&mut node, body);
+            let node_crc = crc32_le(0, &node);
+            if let Some(data_crc) = data_crc {
+                self.fields(&mut node, &[(data_crc, 4)]);
+            }
+            self.fields(&mut node, &[(node_crc, 4)]);
+            node.extend_from_slice(tail);
+            node.resize(node.len().next_multiple_of(4), 0xff);
+
+            self.image.extend(node);
+        }
+
+        fn dirent(&mut self, pino: u32, version: u32, ino: u32, name: &str) {
+            let body = [
+                (pino, 4),
+                (version, 4),
+                (ino, 4),
+                (0, 4),
+                (name.len() as u32, 1),
+                (0, 3),
+            ];
+            let mut tail = Vec::new();
+            self.fields(&mut tail, &[(crc32_le(0, name.as_bytes()), 4)]);
+            tail.extend(name.as_bytes());
+            self.node(NODETYPE_DIRENT, &body, None, &tail);
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn inode(
+            &mut self,
+            ino: u32,
+            version: u32,
+            mode: u32,
+            isize: u32,
+            offset: u32,
+            compr: u8,
+            dsize: u32,
+            data: &[u8],
+        ) {
+            let body = [
+                (ino, 4),
+                (version, 4),
+                (mode, 4),
+                (0, 4),
+                (isize, 4),
+                (0, 4),
+                (0, 4),
+                (0, 4),
+                (offset, 4),
+                (data.len() as u32, 4),
+                (dsize, 4),
+                (u32::from(compr), 1),
+                (0, 3),
+            ];
+            self.node(NODETYPE_INODE, &body, Some(crc32_le(0, data)), data);
+        }
+    }
+
+    fn unpack(image: &[u8]) -> (tempfile::TempDir, Vec<String>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let scan = Scan::run(image).unwrap();
+        assert_eq!(scan.len, image.len());
+        let (_, warnings) = scan.unpack(tmp.path()).unwrap();
+        (tmp, warnings)
+    }
+
+    #[test]
+    fn replays_nodes_in_version_order() {
+        for endian in [Endian::Little, Endian::Big] {
+            let mut image = Builder::new(endian);
+            let file = libc::S_IFREG | 0o644;
+
+            image.inode(1, 1, libc::S_IFDIR | 0o755, 0, 0, COMPR_NONE, 0, b"");
+            image.dirent(1, 1, 2, "file");
+            image.inode(2, 2, file, 4, 0, COMPR_NONE, 4, b"new!");
+            image.inode(2, 1, file, 12, 0, COMPR_NONE, 12, b"old contents");
+            image.dirent(1, 1, 3, "gone");
+            image.inode(3, 1, file, 0, 0, COMPR_NONE, 0, b"");
+            image.dirent(1, 2, 0, "gone");
+            image.dirent(1, 1, 4, "link");
+            image.inode(4, 1, libc::S_IFLNK | 0o777, 4, 0, COMPR_NONE, 4, b"file");
+
+            let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+            zlib.write_all(&[b'z'; 100]).unwrap();
+            image.dirent(1, 1, 5, "zeros");
+            image.inode(5, 1, file, 200, 0, COMPR_ZLIB, 100, &zlib.finish().unwrap());
+            image.inode(5, 2, file, 200, 100, COMPR_RTIME, 100, &[b'r', 99]);
+
+            let (tmp, warnings) = unpack(&image.image);
+            assert!(warnings.is_empty(), "{warnings:?}");
+
+            assert_eq!(fs::read(tmp.path().join("file")).unwrap(), b"new!");
+            assert!(!tmp.path().join("gone").exists());
+            assert_eq!(
+                fs::read_link(tmp.path().join("link")).unwrap(),
+                Path::new("file")
+            );
+
+            let zeros = fs::read(tmp.path().join("zeros")).unwrap();
+            assert_eq!(&zeros[..100], &[b'z'; 100]);
+            assert_eq!(&zeros[100..], &[b'r'; 100]);
+        }
+    }
+
+    #[test]
+    fn ignores_nodes_with_bad_crcs() {
+        let mut image = Builder::new(Endian::Little);
+        image.dirent(1, 1, 2, "file");
+        image.inode(2, 1, libc::S_IFREG | 0o644, 4, 0, COMPR_NONE, 4, b"good");
+        image.inode(2, 2, libc::S_IFREG | 0o644, 4, 0, COMPR_NONE, 4, b"evil");
+
+        // Corrupt the data of the second version
+        let last = image.image.len() - 4;
+        image.image[last] = b'E';
+
+        // Obsolete nodes are skipped even when they are intact
+        image.node(
+            NODETYPE_INODE & !NODE_ACCURATE,
+            &[(2, 4), (3, 4)],
+            None,
+            b"",
+        );
+
+        let (tmp, warnings) = unpack(&image.image);
+        assert_eq!(fs::read(tmp.path().join("file")).unwrap(), b"good");
+        assert_eq!(warnings, ["1 of 4 nodes had bad CRCs and were ignored"]);
+    }
+}
@@ -0,0 +1,614 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::fstree::{safe_name, Attrs, EntryKind, TreeWriter};
+use super::native::{self, invalid_data, Endian, Unpacked};
+use super::{ExtractError, ExtractStatus, Extractor};
+
+const V1_MAGIC: u16 = 0x137f;
+const V1_MAGIC_30: u16 = 0x138f;
+const V2_MAGIC: u16 = 0x2468;
+const V2_MAGIC_30: u16 = 0x2478;
+
+const MAGICS: &[&[u8]] = &[
+    &[0x7f, 0x13],
+    &[0x13, 0x7f],
+    &[0x8f, 0x13],
+    &[0x13, 0x8f],
+    &[0x68, 0x24],
+    &[0x24, 0x68],
+    &[0x78, 0x24],
+    &[0x24, 0x78],
+];
+
+const BLOCK_SIZE: usize = 1024;
+/// The superblock is the second block; the first is left for a boot loader
+const SUPERBLOCK_OFFSET: usize = BLOCK_SIZE;
+const MAGIC_OFFSET: usize = SUPERBLOCK_OFFSET + 16;
+
+const ROOT_INODE: u32 = 1;
+const DIRECT_ZONES: usize = 7;
+const MAX_FILE_SIZE: usize = 1 << 30;
+
+/// In-process reader for minix v1 and v2 filesystems of either endianness, with 14 or 30
+/// character file names
+pub struct MinixExtractor;
+
+impl Extractor for MinixExtractor {
+    fn name(&self) -> &'static str {
+        "minix"
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        _verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        native::extract_all_at(
+            self.name(),
+            in_file,
+            extract_dir,
+            log_file,
+            MAGICS,
+            MAGIC_OFFSET,
+            |data, offset, out_dir| {
+                let Some(fs) = Fs::open(&data[offset..]) else {
+                    return Ok(None);
+                };
+
+                let mut warnings = Vec::new();
+                if fs.data.len() < fs.len {
+                    warnings.push(format!(
+                        "image is truncated ({} of {} bytes)",
+                        fs.data.len(),
+                        fs.len
+                    ));
+                }
+
+                let entries = fs.unpack(out_dir, &mut warnings)?;
+
+                Ok(Some(Unpacked {
+                    len: fs.data.len(),
+                    entries,
+                    warnings,
+                }))
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    fn inode_size(self) -> usize {
+        match self {
+            Version::V1 => 32,
+            Version::V2 => 64,
+        }
+    }
+
+    /// Size of a zone number, both in inodes and in indirect blocks
+    fn zone_size(self) -> usize {
+        match self {
+            Version::V1 => 2,
+            Version::V2 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Inode {
+    mode: u32,
+    nlinks: u32,
+    attrs: Attrs,
+    size: usize,
+    zones: Vec<u32>,
+}
+
+impl Inode {
+    fn kind(&self) -> Option<EntryKind> {
+        match self.mode & libc::S_IFMT {
+            libc::S_IFDIR => Some(EntryKind::Dir),
+            libc::S_IFREG => Some(EntryKind::File),
+            libc::S_IFLNK => Some(EntryKind::Symlink),
+            libc::S_IFCHR => Some(EntryKind::Char),
+            libc::S_IFBLK => Some(EntryKind::Block),
+            libc::S_IFIFO => Some(EntryKind::Fifo),
+            libc::S_IFSOCK => Some(EntryKind::Socket),
+            _ => None,
+        }
+    }
+}
+
+struct Fs<'a> {
+    data: &'a [u8],
+    /// Size of the filesystem according to the superblock
+    len: usize,
+    endian: Endian,
+    version: Version,
+    name_len: usize,
+    inode_count: u32,
+    inode_table: usize,
+}
+
+impl<'a> Fs<'a> {
+    /// Parse and sanity check the superblock, returning `None` if there isn't a valid one
+    fn open(data: &'a [u8]) -> Option<Self> {
+        let sb = data.get(SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + 24)?;
+
+        let (endian, magic) = [Endian::Little, Endian::Big]
+            .into_iter()
+            .map(|endian| (endian, endian.u16(&sb[16..18])))
+            .find(|(_, magic)| [V1_MAGIC, V1_MAGIC_30, V2_MAGIC, V2_MAGIC_30].contains(magic))?;
+
+        let (version, name_len) = match magic {
+            V1_MAGIC => (Version::V1, 14),
+            V1_MAGIC_30 => (Version::V1, 30),
+            V2_MAGIC => (Version::V2, 14),
+            _ => (Version::V2, 30),
+        };
+
+        let inode_count = endian.u16(&sb[0..2]) as usize;
+        let imap_blocks = endian.u16(&sb[4..6]) as usize;
+        let zmap_blocks = endian.u16(&sb[6..8]) as usize;
+        let first_data_zone = endian.u16(&sb[8..10]) as usize;
+        let log_zone_size = endian.u16(&sb[10..12]);
+        let state = endian.u16(&sb[18..20]);
+        let zones = match version {
+            Version::V1 => endian.u16(&sb[2..4]) as usize,
+            Version::V2 => endian.u32(&sb[20..24]) as usize,
+        };
+
+        let inode_blocks = (inode_count * version.inode_size()).div_ceil(BLOCK_SIZE);
+        let inode_table = 2 + imap_blocks + zmap_blocks;
+
+        // A two byte magic matches all over the place, so check that the layout adds up
+        let valid = inode_count > 0
+            && imap_blocks > 0
+            && zmap_blocks > 0
+            && imap_blocks * BLOCK_SIZE * 8 > inode_count
+            && first_data_zone >= inode_table + inode_blocks
+            && zones > first_data_zone
+            && log_zone_size == 0
+            && state & !0x3 == 0;
+        if !valid {
+            return None;
+        }
+
+        let len = zones * BLOCK_SIZE;
+
+        Some(Self {
+            data: &data[..data.len().min(len)],
+            len,
+            endian,
+            version,
+            name_len,
+            inode_count: inode_count as u32,
+            inode_table: inode_table * BLOCK_SIZE,
+        })
+    }
+
+    fn block(&self, zone: u32) -> io::Result<&'a [u8]> {
+        let start = zone as usize * BLOCK_SIZE;
+        self.data
+            .get(start..start + BLOCK_SIZE)
+            .ok_or_else(|| invalid_data(format!("zone {zone} out of bounds")))
+    }
+
+    fn zone_number(&self, raw: &[u8]) -> u32 {
+        match self.version {
+            Version::V1 => u32::from(self.endian.u16(raw)),
+            Version::V2 => self.endian.u32(raw),
+        }
+    }
+
+    fn read_inode(&self, ino: u32) -> io::Result<Inode> {
+        if ino == 0 || ino > self.inode_count {
+            return Err(invalid_data(format!("inode {ino} out of range")));
+        }
+
+        let size = self.version.inode_size();
+        let start = self.inode_table + (ino as usize - 1) * size;
+        let raw = self
+            .data
+            .get(start..start + size)
+            .ok_or_else(|| invalid_data(format!("inode {ino} out of bounds")))?;
+        let e = self.endian;
+
+        let (mode, nlinks, uid, gid, size, zones) = match self.version {
+            Version::V1 => (
+                e.u16(&raw[0..2]),
+                u32::from(raw[13]),
+                e.u16(&raw[2..4]),
+                u16::from(raw[12]),
+                e.u32(&raw[4..8]),
+                &raw[14..32],
+            ),
+            Version::V2 => (
+                e.u16(&raw[0..2]),
+                u32::from(e.u16(&raw[2..4])),
+                e.u16(&raw[4..6]),
+                e.u16(&raw[6..8]),
+                e.u32(&raw[8..12]),
+                &raw[24..64],
+            ),
+        };
+
+        Ok(Inode {
+            mode: u32::from(mode),
+            nlinks,
+            attrs: Attrs::new(u32::from(mode), u32::from(uid), u32::from(gid)),
+            size: size as usize,
+            zones: zones
+                .chunks_exact(self.version.zone_size())
+                .map(|raw| self.zone_number(raw))
+                .collect(),
+        })
+    }
+
+    /// Zone holding block `index` of a file, or 0 for a hole
+    fn map_block(&self, inode: &Inode, index: usize) -> io::Result<u32> {
+        if index < DIRECT_ZONES {
+            return Ok(inode.zones[index]);
+        }
+
+        let per_block = BLOCK_SIZE / self.version.zone_size();
+        let mut index = index - DIRECT_ZONES;
+        let mut span = per_block;
+
+        // Single, double and (v2 only) triple indirect zones
+        for (depth, &zone) in inode.zones[DIRECT_ZONES..].iter().enumerate() {
+            if index >= span {
+                index -= span;
+                span *= per_block;
+                continue;
+            }
+
+            let mut zone = zone;
+            for level in (0..=depth).rev() {
+                if zone == 0 {
+                    return Ok(0);
+                }
+
+                let slot = index / per_block.pow(level as u32) % per_block;
+                let size = self.version.zone_size();
+                zone = self.zone_number(&self.block(zone)?[slot * size..(slot + 1) * size]);
+            }
+
+            return Ok(zone);
+        }
+
+        Err(invalid_data(format!(
+            "block {index} beyond the largest file size"
+        )))
+    }
+
+    fn file_contents(&self, inode: &Inode) -> io::Result<Vec<u8>> {
+        if inode.size > MAX_FILE_SIZE {
+            return Err(invalid_data(format!(
+                "file too large ({} bytes)",
+                inode.size
+            )));
+        }
+
+        let mut out = vec![0; inode.size];
+        for (index, chunk) in out.chunks_mut(BLOCK_SIZE).enumerate() {
+            match self.map_block(inode, index)? {
+                0 => {}
+                zone => chunk.copy_from_slice(&self.block(zone)?[..chunk.len()]),
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn read_dir(&self, dir: &Inode) -> io::Result<Vec<(Vec<u8>, u32)>> {
+        let contents = self.file_contents(dir)?;
+        let entry_size = self.name_len + 2;
+
+        Ok(contents
+            .chunks_exact(entry_size)
+            .filter_map(|entry| {
+                let ino = u32::from(self.endian.u16(&entry[0..2]));
+                let name = &entry[2..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+
+                (ino != 0 && name != b"." && name != b"..").then(|| (name.to_vec(), ino))
+            })
+            .collect())
+    }
+
+    fn unpack_entry(&self, tree: &mut TreeWriter, rel: &Path, inode: &Inode) -> io::Result<()> {
+        match inode.kind() {
+            Some(EntryKind::File) => tree.file_with(rel, inode.attrs, inode.size as u64, || {
+                self.file_contents(inode)
+            }),
+            Some(EntryKind::Symlink) => tree.symlink(rel, inode.attrs, &self.file_contents(inode)?),
+            Some(kind @ (EntryKind::Char | EntryKind::Block)) => {
+                let dev = inode.zones[0];
+                tree.special(
+                    rel,
+                    kind,
+                    inode.attrs,
+                    Some(((dev >> 8) & 0xff, dev & 0xff)),
+                )
+            }
+            Some(kind @ (EntryKind::Fifo | EntryKind::Socket)) => {
+                tree.special(rel, kind, inode.attrs, None)
+            }
+            _ => Err(invalid_data(format!("unknown mode {:#o}", inode.mode))),
+        }
+    }
+
+    fn unpack(&self, out_dir: &Path, warnings: &mut Vec<String>) -> io::Result<usize> {
+        let root = self.read_inode(ROOT_INODE)?;
+        if root.kind() != Some(EntryKind::Dir) {
+            return Err(invalid_data("root inode is not a directory"));
+        }
+
+        log::debug!(
+            "minix: {:?}, {} endian, {} character names, {} bytes",
+            self.version,
+            self.endian.name(),
+            self.name_len,
+            self.len
+        );
+
+        let mut tree = TreeWriter::new(out_dir, "minix")?;
+        tree.dir(Path::new(""), root.attrs)?;
+
+        let mut visited = HashSet::from([ROOT_INODE]);
+        let mut pending = vec![(PathBuf::new(), root)];
+
+        while let Some((dir, dir_inode)) = pending.pop() {
+            let entries = match self.read_dir(&dir_inode) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warnings.push(format!("cannot read directory /{}: {err}", dir.display()));
+                    continue;
+                }
+            };
+
+            for (name, ino) in entries {
+                let name = match safe_name(&name) {
+                    Ok(name) => name,
+                    Err(err) => {
+                        warnings.push(format!("skipping entry in /{}: {err}", dir.display()));
+                        continue;
+                    }
+                };
+                let rel = dir.join(name);
+
+                let inode = match self.read_inode(ino) {
+                    Ok(inode) => inode,
+                    Err(err) => {
+                        warnings.push(format!("skipping /{}: {err}", rel.display()));
+                        continue;
+                    }
+                };
+
+                if inode.kind() == Some(EntryKind::Dir) {
+                    if !visited.insert(ino) {
+                        warnings.push(format!("directory loop at /{}", rel.display()));
+                        continue;
+                    }
+
+                    tree.dir(&rel, inode.attrs)?;
+                    pending.push((rel, inode));
+                } else if let Some(existing) = (inode.nlinks > 1)
+                    .then(|| tree.link_target(u64::from(ino), &rel))
+                    .flatten()
+                {
+                    tree.hardlink(&rel, &existing)?;
+                } else if let Err(err) = self.unpack_entry(&mut tree, &rel, &inode) {
+                    warnings.push(format!("skipping /{}: {err}", rel.display()));
+                }
+            }
+        }
+
+        tree.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    const ZONES: usize = 16;
+    const INODE_TABLE: usize = 4;
+    const ROOT_ZONE: u32 = 5;
+    const DIR_ZONE: u32 = 6;
+    const FILE_ZONE: u32 = 7;
+    const LINK_ZONE: u32 = 8;
+    const INDIRECT_ZONE: u32 = 9;
+    const BIG_ZONES: [u32; 2] = [10, 11];
+
+    struct Builder {
+        endian: Endian,
+        version: Version,
+        name_len: usize,
+        image: Vec<u8>,
+    }
+
+    impl Builder {
+        fn put(&mut self, offset: usize, value: u32, len: usize) {
+            let bytes = match self.endian {
+                Endian::Little => value.to_le_bytes()[..len].to_vec(),
+                Endian::Big => value.to_be_bytes()[4 - len..].to_vec(),
+            };
+            self.image[offset..offset + len].copy_from_slice(&bytes);
+        }
+
+        fn inode(&mut self, ino: usize, mode: u32, nlinks: u32, size: usize, zones: &[u32]) {
+            let start = INODE_TABLE * BLOCK_SIZE + (ino - 1) * self.version.inode_size();
+            let zone_size = self.version.zone_size();
+
+            self.put(start, mode, 2);
+            let zones_start = match self.version {
+                Version::V1 => {
+                    self.put(start + 4, size as u32, 4);
+                    self.put(start + 13, nlinks, 1);
+                    start + 14
+                }
+                Version::V2 => {
+                    self.put(start + 2, nlinks, 2);
+                    self.put(start + 8, size as u32, 4);
+                    start + 24
+                }
+            };
+            for (i, &zone) in zones.iter().enumerate() {
+                self.put(zones_start + i * zone_size, zone, zone_size);
+            }
+        }
+
+        /// Write a directory into `zone`, returning its size
+        fn dir(&mut self, zone: u32, entries: &[(u32, &str)]) -> usize {
+            let entry_size = self.name_len + 2;
+            for (i, &(ino, name)) in entries.iter().enumerate() {
+                let start = zone as usize * BLOCK_SIZE + i * entry_size;
+                self.put(start, ino, 2);
+                self.image[start + 2..start + 2 + name.len()].copy_from_slice(name.as_bytes());
+            }
+            entries.len() * entry_size
+        }
+
+        fn data(&mut self, zone: u32, data: &[u8]) {
+            let start = zone as usize * BLOCK_SIZE;
+            self.image[start..start + data.len()].copy_from_slice(data);
+        }
+
+        /// A filesystem holding a file with a hardlink, a symlink, a sparse file reaching into
+        /// the indirect zone, a directory looping back to the root and an entry with a bad
+        /// inode number
+        fn build(endian: Endian, version: Version, magic: u16, name_len: usize) -> Vec<u8> {
+            let mut fs = Self {
+                endian,
+                version,
+                name_len,
+                image: vec![0; ZONES * BLOCK_SIZE],
+            };
+
+            let sb = SUPERBLOCK_OFFSET;
+            fs.put(sb, 16, 2);
+            fs.put(sb + 2, ZONES as u32, 2);
+            fs.put(sb + 4, 1, 2);
+            fs.put(sb + 6, 1, 2);
+            fs.put(sb + 8, ROOT_ZONE, 2);
+            fs.put(sb + 16, u32::from(magic), 2);
+            fs.put(sb + 18, 1, 2);
+            fs.put(sb + 20, ZONES as u32, 4);
+
+            let root = fs.dir(
+                ROOT_ZONE,
+                &[
+                    (1, "."),
+                    (1, ".."),
+                    (2, "file"),
+                    (2, "hard"),
+                    (3, "link"),
+                    (4, "dir"),
+                    (5, "big"),
+                    (99, "bad"),
+                ],
+            );
+            fs.inode(1, libc::S_IFDIR | 0o755, 3, root, &[ROOT_ZONE]);
+
+            fs.data(FILE_ZONE, b"hello minix\n");
+            fs.inode(2, libc::S_IFREG | 0o644, 2, 12, &[FILE_ZONE]);
+
+            fs.data(LINK_ZONE, b"file");
+            fs.inode(3, libc::S_IFLNK | 0o777, 1, 4, &[LINK_ZONE]);
+
+            let dir = fs.dir(DIR_ZONE, &[(4, "."), (1, ".."), (1, "loop")]);
+            fs.inode(4, libc::S_IFDIR | 0o755, 2, dir, &[DIR_ZONE]);
+
+            // Seven holes, then two blocks mapped through the indirect zone
+            let zone_size = version.zone_size();
+            for (slot, zone) in BIG_ZONES.into_iter().enumerate() {
+                fs.put(
+                    INDIRECT_ZONE as usize * BLOCK_SIZE + slot * zone_size,
+                    zone,
+                    zone_size,
+                );
+                fs.data(zone, &[b'0' + slot as u8; BLOCK_SIZE]);
+            }
+            let mut zones = [0; DIRECT_ZONES + 1];
+            zones[DIRECT_ZONES] = INDIRECT_ZONE;
+            fs.inode(
+                5,
+                libc::S_IFREG | 0o600,
+                1,
+                (DIRECT_ZONES + 1) * BLOCK_SIZE + 10,
+                &zones,
+            );
+
+            fs.image
+        }
+    }
+
+    #[test]
+    fn unpacks_each_variant() {
+        for (endian, version, magic, name_len) in [
+            (Endian::Little, Version::V1, V1_MAGIC, 14),
+            (Endian::Big, Version::V1, V1_MAGIC_30, 30),
+            (Endian::Little, Version::V2, V2_MAGIC_30, 30),
+            (Endian::Big, Version::V2, V2_MAGIC, 14),
+        ] {
+            let image = Builder::build(endian, version, magic, name_len);
+            let fs = Fs::open(&image).unwrap();
+            assert_eq!((fs.version, fs.name_len), (version, name_len));
+
+            let tmp = tempfile::tempdir().unwrap();
+            let mut warnings = Vec::new();
+            fs.unpack(tmp.path(), &mut warnings).unwrap();
+            warnings.sort();
+            assert_eq!(
+                warnings,
+                [
+                    "directory loop at /dir/loop",
+                    "skipping /bad: inode 99 out of range"
+                ]
+            );
+
+            let file = tmp.path().join("file");
+            assert_eq!(fs::read(&file).unwrap(), b"hello minix\n");
+            assert_eq!(
+                fs::metadata(tmp.path().join("hard")).unwrap().ino(),
+                fs::metadata(&file).unwrap().ino()
+            );
+            assert_eq!(
+                fs::read_link(tmp.path().join("link")).unwrap(),
+                Path::new("file")
+            );
+
+            let big = fs::read(tmp.path().join("big")).unwrap();
+            assert_eq!(big.len(), (DIRECT_ZONES + 1) * BLOCK_SIZE + 10);
+            assert!(big[..DIRECT_ZONES * BLOCK_SIZE]
+                .iter()
+                .all(|&byte| byte == 0));
+            assert!(big[DIRECT_ZONES * BLOCK_SIZE..][..BLOCK_SIZE]
+                .iter()
+                .all(|&byte| byte == b'0'));
+            assert_eq!(&big[(DIRECT_ZONES + 1) * BLOCK_SIZE..], b"1111111111");
+        }
+    }
+
+    #[test]
+    fn rejects_inconsistent_superblocks() {
+        let mut image = Builder::build(Endian::Little, Version::V1, V1_MAGIC, 14);
+        assert!(Fs::open(&image).is_some());
+
+        // Data zones overlapping the inode table
+        image[SUPERBLOCK_OFFSET + 8] = INODE_TABLE as u8;
+        assert!(Fs::open(&image).is_none());
+    }
+}
@@ -1,43 +1,105 @@
+use std::fmt;
 use std::io;
-use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
-use std::process::Output;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::process::Command;
+use std::sync::RwLock;
 use std::time::Duration;
 
+use serde::Serialize;
 use thiserror::Error;
 
 mod binwalk;
 mod binwalk3;
+pub mod compression;
+mod cpio;
+mod cramfs;
+mod extfs;
+pub mod fstree;
+mod jffs2;
+mod minix;
+pub mod native;
+pub mod plugin;
+pub mod quota;
+mod romfs;
+pub mod sandbox;
+mod squashfs;
+pub mod supervisor;
+mod ubi;
+mod ubifs;
 mod unblob;
+pub mod vendor;
+mod yaffs;
+
+use sandbox::Workspace;
+use supervisor::Finished;
+use {
+    binwalk::BinwalkExtractor, binwalk3::Binwalk3Extractor, cpio::CpioExtractor,
+    cramfs::CramfsExtractor, extfs::ExtfsExtractor, jffs2::Jffs2Extractor,
+    minix::MinixExtractor, romfs::RomfsExtractor, squashfs::SquashfsExtractor,
+    ubi::UbiExtractor, unblob::UnblobExtractor, vendor::VendorExtractor, yaffs::YaffsExtractor,
+};
+
+pub static ALL_EXTRACTORS: &[&dyn Extractor] = &[
+    &BinwalkExtractor,
+    &Binwalk3Extractor,
+    &UnblobExtractor,
+    &SquashfsExtractor,
+    &CpioExtractor,
+    &ExtfsExtractor,
+    &Jffs2Extractor,
+    &UbiExtractor,
+    &CramfsExtractor,
+    &RomfsExtractor,
+    &MinixExtractor,
+    &YaffsExtractor,
+    &VendorExtractor,
+];
+
+static TIMEOUT: RwLock<Duration> = RwLock::new(Duration::MAX);
+
+pub fn set_timeout(timeout: Duration) {
+    *TIMEOUT.write().unwrap() = timeout;
+}
+
+fn get_timeout() -> Duration {
+    *TIMEOUT.read().unwrap()
+}
 
-use {binwalk::BinwalkExtractor, binwalk3::Binwalk3Extractor, unblob::UnblobExtractor};
+/// Extractors loaded from a config file at runtime, see [`plugin`]
+static PLUGINS: RwLock<Vec<&'static dyn Extractor>> = RwLock::new(Vec::new());
 
-pub static ALL_EXTRACTORS: &[&dyn Extractor] =
-    &[&BinwalkExtractor, &Binwalk3Extractor, &UnblobExtractor];
+/// Make `plugin` available to [`get_extractor`], replacing any plugin of the same name.
+/// Built-in extractors cannot be replaced.
+pub fn register_plugin(plugin: plugin::PluginExtractor) -> Result<(), String> {
+    if ALL_EXTRACTORS.iter().any(|extractor| extractor.name() == plugin.name) {
+        return Err(format!("{:?} is a built-in extractor", plugin.name));
+    }
 
-static TIMEOUT_SECS: AtomicU64 = AtomicU64::new(u64::MAX);
+    let mut plugins = PLUGINS.write().unwrap();
+    plugins.retain(|existing| existing.name() != plugin.name);
+    plugins.push(Box::leak(Box::new(plugin)));
 
-pub fn set_timeout(secs: u64) {
-    TIMEOUT_SECS.store(secs, Ordering::Relaxed);
+    Ok(())
 }
 
-fn get_timeout() -> Duration {
-    Duration::from_secs(TIMEOUT_SECS.load(Ordering::Relaxed))
+fn plugins() -> Vec<&'static dyn Extractor> {
+    PLUGINS.read().unwrap().clone()
 }
 
 pub fn all_extractor_names() -> impl Iterator<Item = &'static str> {
-    ALL_EXTRACTORS.into_iter().map(|extractor| extractor.name())
+    ALL_EXTRACTORS
+        .iter()
+        .copied()
+        .chain(plugins())
+        .map(|extractor| extractor.name())
 }
 
 pub fn get_extractor(name: &str) -> Option<&'static dyn Extractor> {
-    for &extractor in ALL_EXTRACTORS {
-        if extractor.name() == name {
-            return Some(extractor);
-        }
-    }
-
-    None
+    ALL_EXTRACTORS
+        .iter()
+        .copied()
+        .chain(plugins())
+        .find(|extractor| extractor.name() == name)
 }
 
 #[derive(Error, Debug)]
@@ -45,11 +107,45 @@ pub enum ExtractError {
     #[error("An I/O error occurred while attempting to extract ({0})")]
     Io(#[from] io::Error),
 
-    #[error("Extraction process was killed with signal {0:?}")]
-    Killed(Option<i32>),
+    #[error("Extraction was interrupted")]
+    Interrupted,
 
     #[error("Extraction process exited with code {0}")]
     Failed(i32),
+
+    #[error("No {0} filesystem was found in the input")]
+    NotFound(&'static str),
+}
+
+/// How an extraction that produced output ended. Anything but `Completed` means the output
+/// may be partial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(into = "crate::results::Status")]
+pub enum ExtractStatus {
+    Completed,
+    /// The extractor ran past `--timeout` and was killed
+    TimedOut,
+    /// The extractor was killed by the given signal, such as from a resource limit
+    Killed(i32),
+    /// The extractor was stopped for writing more than its [`quota`] allows
+    ExceededQuota,
+}
+
+impl ExtractStatus {
+    pub fn is_partial(self) -> bool {
+        self != ExtractStatus::Completed
+    }
+}
+
+impl fmt::Display for ExtractStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractStatus::Completed => f.write_str("completed"),
+            ExtractStatus::TimedOut => f.write_str("timed out"),
+            ExtractStatus::Killed(signal) => write!(f, "killed by signal {signal}"),
+            ExtractStatus::ExceededQuota => f.write_str("exceeded quota"),
+        }
+    }
 }
 
 pub trait Extractor: Sync {
@@ -61,34 +157,50 @@ pub trait Extractor: Sync {
         extract_dir: &Path,
         log_file: &Path,
         verbose: bool,
-    ) -> Result<(), ExtractError>;
+    ) -> Result<ExtractStatus, ExtractError>;
 
-    fn cmd_output_to_result(&self, output: Output, timed_out: bool, verbose: bool) -> Result<(), ExtractError> {
-        if output.status.success() {
-            Ok(())
-        } else {
-            if let Some(code) = output.status.code() {
-                log::error!("{} exited with error code {}. Run with --loud to see more output", self.name(), code);
+    /// Run an external extraction command on `workspace` under the [`supervisor`] with the
+    /// global timeout
+    fn run_command(&self, command: Command, workspace: &Workspace, verbose: bool) -> Result<ExtractStatus, ExtractError> {
+        let finished = supervisor::run(command, workspace, Some(get_timeout()))?;
+
+        self.process_result(finished, 0, verbose)
+    }
 
-                if verbose {
-                    if !output.stdout.is_empty() {
-                        if let Ok(stdout_str) = String::from_utf8(output.stdout) {
-                            log::error!("{} stdout: {}", self.name(), stdout_str.trim());
-                        }
-                    }
-
-                    if !output.stderr.is_empty() {
-                        if let Ok(stderr_str) = String::from_utf8(output.stderr) {
-                            log::error!("{} stderr: {}", self.name(), stderr_str.trim());
-                        }
-                    }
+    fn process_result(&self, finished: Finished, success_code: i32, verbose: bool) -> Result<ExtractStatus, ExtractError> {
+        let log_output = |finished: &Finished| {
+            if verbose {
+                if !finished.stdout.is_empty() {
+                    log::error!("{} stdout: {}", self.name(), String::from_utf8_lossy(&finished.stdout).trim());
                 }
 
-                Err(ExtractError::Failed(code))
-            } else if timed_out {
-                Ok(())
+                if !finished.stderr.is_empty() {
+                    log::error!("{} stderr: {}", self.name(), String::from_utf8_lossy(&finished.stderr).trim());
+                }
+            }
+        };
+
+        if finished.interrupted {
+            Err(ExtractError::Interrupted)
+        } else if finished.exceeded_quota {
+            log::warn!("{} exceeded its extraction quota and was stopped, its output may be incomplete", self.name());
+            Ok(ExtractStatus::ExceededQuota)
+        } else if finished.timed_out {
+            log::warn!("{} timed out, its output may be incomplete. Use `--timeout` to let it run longer.", self.name());
+            Ok(ExtractStatus::TimedOut)
+        } else if let Some(signal) = finished.signal() {
+            log::warn!("{} was killed with signal {}, its output may be incomplete", self.name(), signal);
+            log_output(&finished);
+            Ok(ExtractStatus::Killed(signal))
+        } else {
+            let code = finished.status.code().unwrap_or(-1);
+            if code == success_code {
+                Ok(ExtractStatus::Completed)
             } else {
-                Err(ExtractError::Killed(output.status.signal()))
+                log::error!("{} exited with error code {}. Run with --loud to see more output", self.name(), code);
+                log_output(&finished);
+
+                Err(ExtractError::Failed(code))
             }
         }
     }
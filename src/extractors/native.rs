@@ -0,0 +1,579 @@
+//! Helpers shared by the in-process (native) extractors.
+//!
+//! The native extractors read the whole firmware image into memory, scan it for the magic
+//! numbers of the format they understand, and unpack every valid instance into its own
+//! directory under the scratch directory (`<format>-<offset>`). The image is read once and
+//! shared by all of them while they run.
+
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::time::Instant;
+
+use super::{get_timeout, quota, supervisor, ExtractError, ExtractStatus};
+
+/// Byte order of an on-disk structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    pub fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        match self {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn u64(self, bytes: &[u8]) -> u64 {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&bytes[..8]);
+        match self {
+            Endian::Little => u64::from_le_bytes(buf),
+            Endian::Big => u64::from_be_bytes(buf),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Endian::Little => "le",
+            Endian::Big => "be",
+        }
+    }
+}
+
+/// Bounds-checked cursor over an on-disk structure.
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    endian: Endian,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8], endian: Endian) -> Self {
+        Self {
+            data,
+            pos: 0,
+            endian,
+        }
+    }
+
+    pub fn at(data: &'a [u8], pos: usize, endian: Endian) -> Self {
+        Self { data, pos, endian }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.pos)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| truncated(self.pos, len))?;
+
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    pub fn skip(&mut self, len: usize) -> io::Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(self.endian.u16(self.bytes(2)?))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(self.endian.u32(self.bytes(4)?))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(self.endian.u64(self.bytes(8)?))
+    }
+}
+
+fn truncated(pos: usize, len: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("structure at {pos:#x} (+{len}) runs past the end of the image"),
+    )
+}
+
+/// Shorthand for an [`io::Error`] describing corrupt or unsupported on-disk data.
+pub fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Inputs read, kept for as long as an extractor still holds them
+static INPUTS: Mutex<Vec<(PathBuf, Weak<[u8]>)>> = Mutex::new(Vec::new());
+
+/// Read the input file for an in-process extractor. Extractors running on the same file at
+/// the same time share a single copy of it.
+pub fn read_input(in_file: &Path) -> io::Result<Arc<[u8]>> {
+    // Held while reading, so extractors starting together wait for the first read
+    let mut inputs = INPUTS.lock().unwrap();
+    inputs.retain(|(_, data)| data.strong_count() > 0);
+
+    let shared = inputs.iter().find(|(path, _)| path == in_file);
+    if let Some(data) = shared.and_then(|(_, data)| data.upgrade()) {
+        return Ok(data);
+    }
+
+    let data: Arc<[u8]> = fs::read(in_file)?.into();
+    inputs.push((in_file.to_owned(), Arc::downgrade(&data)));
+
+    Ok(data)
+}
+
+/// Offsets of every occurrence of any of `magics` in `data`, in ascending order.
+pub fn find_magic(data: &[u8], magics: &[&[u8]]) -> Vec<usize> {
+    let Some(min_len) = magics.iter().map(|magic| magic.len()).min() else {
+        return Vec::new();
+    };
+
+    (0..data.len().saturating_sub(min_len - 1))
+        .filter(|&offset| magics.iter().any(|magic| data[offset..].starts_with(magic)))
+        .collect()
+}
+
+/// CRC-32 as computed by the Linux `crc32_le`: reflected 0xedb88320 polynomial, caller-chosen
+/// seed and no final inversion. JFFS2 seeds it with 0, UBI with 0xffffffff.
+pub fn crc32_le(seed: u32, data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    data.iter().fold(seed, |crc, &byte| {
+        TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+thread_local! {
+    /// When the native extractor running on this thread runs past `--timeout`
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+
+    /// Whether a parser is running on this thread, whose panics are reported as errors
+    static IN_PARSER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sets the deadline checked by [`check_stop`] on this thread for as long as it is alive.
+pub struct Deadline {
+    previous: Option<Instant>,
+}
+
+impl Deadline {
+    pub fn set(deadline: Option<Instant>) -> Self {
+        Self {
+            previous: DEADLINE.replace(deadline),
+        }
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        DEADLINE.set(self.previous);
+    }
+}
+
+/// Fail if the native extractor running on this thread has to stop, because of Ctrl-C or
+/// because it ran past its [`Deadline`]. Parsers hit this between entries through
+/// [`TreeWriter`](super::fstree::TreeWriter).
+pub fn check_stop() -> io::Result<()> {
+    if supervisor::interrupted() {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "extraction was interrupted",
+        ));
+    }
+    if DEADLINE
+        .get()
+        .is_some_and(|deadline| Instant::now() >= deadline)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "extraction timed out",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Run the parser `unpack`, turning a panic on a malformed image into an error. The panic is
+/// not printed, as stderr may be carrying the progress stream.
+fn catch_panic<T>(unpack: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    static QUIET_HOOK: Once = Once::new();
+    QUIET_HOOK.call_once(|| {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !IN_PARSER.get() {
+                default(info);
+            }
+        }));
+    });
+
+    let was_in_parser = IN_PARSER.replace(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(unpack));
+    IN_PARSER.set(was_in_parser);
+
+    result.unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        Err(invalid_data(format!("parser panicked: {message}")))
+    })
+}
+
+/// Directory a native extractor unpacks the filesystem found at `offset` into.
+pub fn output_dir(extract_dir: &Path, format: &str, offset: usize) -> PathBuf {
+    extract_dir.join(format!("{format}-{offset:#x}"))
+}
+
+/// Summary of one filesystem unpacked by a native extractor.
+#[derive(Debug, Clone, Default)]
+pub struct Unpacked {
+    /// Number of bytes of the input the filesystem occupies
+    pub len: usize,
+    /// Number of entries written
+    pub entries: usize,
+    /// Problems worth reporting in the extractor log, such as skipped or damaged entries
+    pub warnings: Vec<String>,
+}
+
+/// Scan `in_file` for `magics` and unpack every valid filesystem found.
+///
+/// `unpack` is called with the input data, the offset of a magic match, and the directory
+/// the filesystem should be written to. It returns `Ok(None)` when the data at that offset
+/// turns out not to be a valid filesystem, in which case nothing should have been written.
+/// Matches inside an already unpacked filesystem are skipped. Scanning stops once the
+/// extraction [`quota`] is exceeded, on Ctrl-C, or once `--timeout` has passed; the latter two
+/// are also checked between the entries of a filesystem, see [`check_stop`].
+pub fn extract_all<F>(
+    format: &'static str,
+    in_file: &Path,
+    extract_dir: &Path,
+    log_file: &Path,
+    magics: &[&[u8]],
+    unpack: F,
+) -> Result<ExtractStatus, ExtractError>
+where
+    F: FnMut(&[u8], usize, &Path) -> io::Result<Option<Unpacked>>,
+{
+    extract_all_at(format, in_file, extract_dir, log_file, magics, 0, unpack)
+}
+
+/// Like [`extract_all`], for formats whose magic is stored `magic_offset` bytes into the
+/// filesystem rather than at its start. `unpack` is given the start of the filesystem.
+pub fn extract_all_at<F>(
+    format: &'static str,
+    in_file: &Path,
+    extract_dir: &Path,
+    log_file: &Path,
+    magics: &[&[u8]],
+    magic_offset: usize,
+    mut unpack: F,
+) -> Result<ExtractStatus, ExtractError>
+where
+    F: FnMut(&[u8], usize, &Path) -> io::Result<Option<Unpacked>>,
+{
+    let _deadline = Deadline::set(Instant::now().checked_add(get_timeout()));
+    let data = read_input(in_file)?;
+    let mut log = String::new();
+    let mut unpacked_count = 0;
+    let mut next_offset = 0;
+    let mut stopped = None;
+
+    for magic_pos in find_magic(&data, magics) {
+        let Some(offset) = magic_pos.checked_sub(magic_offset) else {
+            continue;
+        };
+
+        if offset < next_offset {
+            continue;
+        }
+
+        if let Err(err) = check_stop() {
+            log.push_str(&format!("{err}, stopping\n"));
+            stopped = Some(err.kind());
+            break;
+        }
+
+        let out_dir = output_dir(extract_dir, format, offset);
+        let result = catch_panic(|| unpack(&data, offset, &out_dir));
+
+        match result {
+            Ok(Some(unpacked)) => {
+                let line = format!(
+                    "{format} at {offset:#x}: {} bytes, {} entries -> {}",
+                    unpacked.len,
+                    unpacked.entries,
+                    out_dir.display()
+                );
+                log::info!("{line}");
+                log.push_str(&line);
+                log.push('\n');
+
+                for warning in &unpacked.warnings {
+                    log::warn!("{format} at {offset:#x}: {warning}");
+                    log.push_str(&format!("  {warning}\n"));
+                }
+
+                unpacked_count += 1;
+                next_offset = offset + unpacked.len.max(1);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                let line = format!("{format} at {offset:#x}: {err}");
+                log::warn!("{line}");
+                log.push_str(&line);
+                log.push('\n');
+            }
+        }
+
+        if quota::exceeded(extract_dir) {
+            log.push_str("extraction quota exceeded, stopping\n");
+            break;
+        }
+    }
+
+    // The last filesystem may have been cut short
+    let stopped = stopped.or_else(|| check_stop().err().map(|err| err.kind()));
+
+    if !log.is_empty() {
+        fs::write(log_file, log)?;
+    }
+
+    if stopped == Some(io::ErrorKind::Interrupted) {
+        return Err(ExtractError::Interrupted);
+    }
+
+    if quota::exceeded(extract_dir) {
+        log::warn!(
+            "{format} exceeded its extraction quota and was stopped, its output may be incomplete"
+        );
+        return Ok(ExtractStatus::ExceededQuota);
+    }
+
+    if stopped == Some(io::ErrorKind::TimedOut) {
+        log::warn!("{format} timed out, its output may be incomplete. Use `--timeout` to let it run longer.");
+        return Ok(ExtractStatus::TimedOut);
+    }
+
+    if unpacked_count == 0 {
+        return Err(ExtractError::NotFound(format));
+    }
+
+    Ok(ExtractStatus::Completed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extractors::fstree::{Attrs, TreeWriter};
+
+    #[test]
+    fn reader_reports_truncation() {
+        let mut r = Reader::new(&[1, 0, 0, 0, 2], Endian::Little);
+        assert_eq!(r.u32().unwrap(), 1);
+        assert_eq!(r.remaining(), 1);
+
+        let err = r.u16().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(r.pos(), 4);
+
+        assert!(Reader::at(&[0; 4], 2, Endian::Big)
+            .bytes(usize::MAX)
+            .is_err());
+    }
+
+    #[test]
+    fn read_input_shares_one_copy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let in_file = tmp.path().join("image.bin");
+        fs::write(&in_file, b"image").unwrap();
+
+        let first = read_input(&in_file).unwrap();
+        let second = read_input(&in_file).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // Read again once nobody holds it
+        drop((first, second));
+        fs::write(&in_file, b"changed").unwrap();
+        assert_eq!(&*read_input(&in_file).unwrap(), b"changed");
+    }
+
+    #[test]
+    fn extract_all_skips_nested_matches_and_survives_panics() {
+        let tmp = tempfile::tempdir().unwrap();
+        let in_file = tmp.path().join("image.bin");
+        let log_file = tmp.path().join("fs.log");
+        fs::write(&in_file, b"FS..FS..FS..FS..").unwrap();
+
+        let mut calls = Vec::new();
+        let status = extract_all(
+            "fs",
+            &in_file,
+            tmp.path(),
+            &log_file,
+            &[b"FS"],
+            |_, offset, _| {
+                calls.push(offset);
+                match offset {
+                    // Covers the match at 4
+                    0 => Ok(Some(Unpacked {
+                        len: 6,
+                        entries: 1,
+                        warnings: vec!["damaged".to_owned()],
+                    })),
+                    8 => panic!("bad image"),
+                    _ => Ok(None),
+                }
+            },
+        )
+        .unwrap();
+
+        assert_eq!(status, ExtractStatus::Completed);
+        assert_eq!(calls, [0, 8, 12]);
+
+        let log = fs::read_to_string(&log_file).unwrap();
+        let dir = output_dir(tmp.path(), "fs", 0);
+        assert_eq!(
+            log,
+            format!(
+                "fs at 0x0: 6 bytes, 1 entries -> {}\n  damaged\nfs at 0x8: parser panicked: bad image\n",
+                dir.display()
+            )
+        );
+    }
+
+    #[test]
+    fn tree_writer_stops_at_the_deadline() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut tree = TreeWriter::new(tmp.path(), "test").unwrap();
+        let attrs = Attrs::new(libc::S_IFREG | 0o644, 0, 0);
+        tree.file(Path::new("before"), attrs, b"").unwrap();
+
+        {
+            let _deadline = Deadline::set(Some(Instant::now()));
+            let err = tree.file(Path::new("after"), attrs, b"").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        }
+
+        tree.file(Path::new("later"), attrs, b"").unwrap();
+        assert!(!tmp.path().join("after").exists());
+    }
+
+    #[test]
+    fn extract_all_stops_at_the_quota_before_decompressing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let in_file = tmp.path().join("image.bin");
+        let log_file = tmp.path().join("fs.log");
+        let extract_dir = tmp.path().join("out");
+        fs::write(&in_file, b"FS..FS..").unwrap();
+
+        let _lock = quota::tests::QUOTAS_LOCK.lock().unwrap();
+        let quota = quota::Quota {
+            bytes: Some(1024),
+            files: None,
+        };
+        quota::set_quotas(
+            quota::Quota::default(),
+            [("quota-test".to_owned(), quota)].into(),
+        );
+        quota::track("quota-test", &extract_dir);
+
+        let mut calls = 0;
+        let status = extract_all(
+            "fs",
+            &in_file,
+            &extract_dir,
+            &log_file,
+            &[b"FS"],
+            |_, _, out_dir| {
+                calls += 1;
+                let mut tree = TreeWriter::new(out_dir, "test")?;
+                let attrs = Attrs::new(libc::S_IFREG | 0o644, 0, 0);
+                let err = tree
+                    .file_with(Path::new("bomb"), attrs, 1 << 40, || {
+                        unreachable!("decompressed")
+                    })
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+                Err(err)
+            },
+        )
+        .unwrap();
+
+        assert_eq!(status, ExtractStatus::ExceededQuota);
+        assert_eq!(calls, 1);
+        assert!(fs::read_to_string(&log_file)
+            .unwrap()
+            .ends_with("extraction quota exceeded, stopping\n"));
+        quota::untrack(&extract_dir);
+    }
+
+    #[test]
+    fn extract_all_reports_nothing_found() {
+        let tmp = tempfile::tempdir().unwrap();
+        let in_file = tmp.path().join("image.bin");
+        let log_file = tmp.path().join("fs.log");
+        fs::write(&in_file, b"..FS").unwrap();
+
+        let result = extract_all_at(
+            "fs",
+            &in_file,
+            tmp.path(),
+            &log_file,
+            &[b"FS"],
+            4,
+            |_, _, _| unreachable!("the magic is too close to the start"),
+        );
+
+        assert!(matches!(result, Err(ExtractError::NotFound("fs"))));
+        assert!(!log_file.exists());
+    }
+}
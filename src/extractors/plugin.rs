@@ -0,0 +1,202 @@
+//! External extractors declared in a config file rather than compiled in.
+//!
+//! The config is a JSON file listing one entry per extractor:
+//!
+//! ```json
+//! {
+//!     "extractors": [
+//!         {
+//!             "name": "acme",
+//!             "command": "/opt/acme/unpack",
+//!             "args": ["--in", "{input}", "--out", "{outdir}", "--log", "{log}"],
+//!             "success_code": 0,
+//!             "timeout": true
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! `{input}`, `{outdir}` and `{log}` in the command and its arguments are replaced with the
+//! firmware path, the directory to extract into and the log file path.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use super::sandbox::Workspace;
+use super::{get_timeout, supervisor, ExtractError, ExtractStatus, Extractor};
+use serde::Deserialize;
+
+/// Top-level layout of an extractor config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    extractors: Vec<PluginEntry>,
+}
+
+/// One extractor as declared in the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PluginEntry {
+    name: String,
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    success_code: i32,
+    #[serde(default = "default_timeout")]
+    timeout: bool,
+}
+
+fn default_timeout() -> bool {
+    true
+}
+
+/// Extractors declared in a config file.
+#[derive(Debug, Clone)]
+pub struct PluginConfig {
+    pub extractors: Vec<PluginExtractor>,
+}
+
+impl PluginConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let config: ConfigFile = serde_json::from_str(&contents).map_err(|e| e.to_string())?;
+
+        let extractors = config
+            .extractors
+            .into_iter()
+            .map(|entry| {
+                // Names end up in --extractors lists and in file and directory names
+                let valid = !entry.name.is_empty()
+                    && entry
+                        .name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                if !valid {
+                    return Err(format!("invalid extractor name {:?}", entry.name));
+                }
+                if entry.command.is_empty() {
+                    return Err(format!("extractor {:?} has no command", entry.name));
+                }
+
+                Ok(PluginExtractor {
+                    // Extractor names are `'static`, and plugins live for the rest of the
+                    // program anyway
+                    name: Box::leak(entry.name.into_boxed_str()),
+                    command: entry.command,
+                    args: entry.args,
+                    success_code: entry.success_code,
+                    timeout: entry.timeout,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { extractors })
+    }
+}
+
+/// An extractor that runs an external command.
+#[derive(Debug, Clone)]
+pub struct PluginExtractor {
+    /// Name used with `--extractors` and in the results
+    pub name: &'static str,
+    /// Program to run
+    pub command: String,
+    /// Arguments, with `{input}`, `{outdir}` and `{log}` placeholders
+    pub args: Vec<String>,
+    /// Exit code that indicates success
+    pub success_code: i32,
+    /// Whether the command is killed after `--timeout` seconds. Extractors that enforce their
+    /// own time limit can opt out.
+    pub timeout: bool,
+}
+
+/// Replace the placeholders in `template`
+fn expand(template: &str, in_file: &Path, extract_dir: &Path, log_file: &Path) -> OsString {
+    let placeholders: [(&str, &OsStr); 3] = [
+        ("{input}", in_file.as_os_str()),
+        ("{outdir}", extract_dir.as_os_str()),
+        ("{log}", log_file.as_os_str()),
+    ];
+
+    let mut expanded = OsString::new();
+    let mut rest = template;
+    while !rest.is_empty() {
+        if let Some((placeholder, value)) = placeholders
+            .iter()
+            .find(|(placeholder, _)| rest.starts_with(placeholder))
+        {
+            expanded.push(value);
+            rest = &rest[placeholder.len()..];
+        } else {
+            let len = rest.chars().next().map_or(1, char::len_utf8);
+            expanded.push(&rest[..len]);
+            rest = &rest[len..];
+        }
+    }
+
+    expanded
+}
+
+impl Extractor for PluginExtractor {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        let mut command = Command::new(expand(&self.command, in_file, extract_dir, log_file));
+        command.args(
+            self.args
+                .iter()
+                .map(|arg| expand(arg, in_file, extract_dir, log_file)),
+        );
+
+        let workspace = Workspace {
+            input: in_file,
+            extract_dir,
+            log_file,
+        };
+        let timeout = self.timeout.then(get_timeout);
+        let finished = supervisor::run(command, &workspace, timeout)?;
+
+        self.process_result(finished, self.success_code, verbose)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str) -> Result<PluginConfig, String> {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("extractors.json");
+        let config = serde_json::json!({
+            "extractors": [{ "name": name, "command": "/bin/true" }]
+        });
+        fs::write(&path, config.to_string()).unwrap();
+        PluginConfig::load(&path)
+    }
+
+    #[test]
+    fn accepts_plain_names() {
+        let config = load("acme_v2-unpack").unwrap();
+        assert_eq!(config.extractors[0].name, "acme_v2-unpack");
+        assert!(config.extractors[0].timeout);
+    }
+
+    #[test]
+    fn rejects_names_unfit_for_paths() {
+        for name in ["", ".", "..", "a/b", "a,b", "acme ", "ac\u{e9}me"] {
+            let err = load(name).unwrap_err();
+            assert_eq!(err, format!("invalid extractor name {name:?}"));
+        }
+    }
+}
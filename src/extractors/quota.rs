@@ -0,0 +1,195 @@
+//! Disk usage quotas for extraction output, against decompression bombs.
+//!
+//! Every extractor's output directory is tracked while it runs: external extractors by
+//! re-measuring the directory from the [`supervisor`](super::supervisor) poll loop, native ones
+//! by charging each entry [`TreeWriter`](super::fstree::TreeWriter) writes. An extractor that
+//! goes over its own quota, or pushes the total of all extractors over the global quota, is
+//! stopped and reported as [`ExtractStatus::ExceededQuota`](super::ExtractStatus). Directories
+//! are only tracked while their extractor runs, so a later job starts from nothing.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use walkdir::WalkDir;
+
+/// Limits on what an extractor may write. `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Total size of the files written, in bytes
+    pub bytes: Option<u64>,
+    /// Number of files, directories and other entries written
+    pub files: Option<u64>,
+}
+
+impl Quota {
+    fn allows(&self, usage: Usage) -> bool {
+        self.bytes.is_none_or(|bytes| usage.bytes <= bytes)
+            && self.files.is_none_or(|files| usage.files <= files)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    bytes: u64,
+    files: u64,
+}
+
+#[derive(Debug, Default)]
+struct Quotas {
+    global: Quota,
+    per_extractor: BTreeMap<String, Quota>,
+}
+
+/// An output directory being tracked
+#[derive(Debug)]
+struct Tracked {
+    dir: PathBuf,
+    quota: Quota,
+    usage: Usage,
+    exceeded: bool,
+}
+
+static QUOTAS: RwLock<Quotas> = RwLock::new(Quotas {
+    global: Quota {
+        bytes: None,
+        files: None,
+    },
+    per_extractor: BTreeMap::new(),
+});
+
+static TRACKED: Mutex<Vec<Tracked>> = Mutex::new(Vec::new());
+
+/// Set the `global` quota on everything extracted, and quotas for individual extractors.
+pub fn set_quotas(global: Quota, per_extractor: BTreeMap<String, Quota>) {
+    *QUOTAS.write().unwrap() = Quotas {
+        global,
+        per_extractor,
+    };
+}
+
+/// Start tracking `dir` as the output directory of `extractor`.
+pub fn track(extractor: &str, dir: &Path) {
+    let quota = QUOTAS
+        .read()
+        .unwrap()
+        .per_extractor
+        .get(extractor)
+        .copied()
+        .unwrap_or_default();
+
+    let mut tracked = TRACKED.lock().unwrap();
+    tracked.retain(|entry| entry.dir != dir);
+    tracked.push(Tracked {
+        dir: dir.to_owned(),
+        quota,
+        usage: Usage::default(),
+        exceeded: false,
+    });
+}
+
+/// Stop tracking `dir` once its extractor is done, so it no longer counts against the global
+/// quota.
+pub fn untrack(dir: &Path) {
+    TRACKED.lock().unwrap().retain(|entry| entry.dir != dir);
+}
+
+/// Update the usage of the tracked directory containing `path` with `update`, and check it
+/// against the quotas. Returns whether a quota is exceeded.
+fn update(path: &Path, update: impl FnOnce(&mut Usage)) -> bool {
+    let global = QUOTAS.read().unwrap().global;
+    let mut tracked = TRACKED.lock().unwrap();
+
+    let Some(index) = tracked
+        .iter()
+        .position(|entry| path.starts_with(&entry.dir))
+    else {
+        return false;
+    };
+    update(&mut tracked[index].usage);
+
+    let total = tracked.iter().fold(Usage::default(), |total, entry| Usage {
+        bytes: total.bytes + entry.usage.bytes,
+        files: total.files + entry.usage.files,
+    });
+
+    let entry = &mut tracked[index];
+    entry.exceeded |= !entry.quota.allows(entry.usage) || !global.allows(total);
+    entry.exceeded
+}
+
+/// Re-measure the tracked directory `dir` from disk. Returns whether a quota is exceeded.
+pub fn measure(dir: &Path) -> bool {
+    let mut usage = Usage::default();
+    for entry in WalkDir::new(dir).min_depth(1).into_iter().flatten() {
+        usage.files += 1;
+        if let Ok(metadata) = entry.metadata() {
+            if metadata.is_file() {
+                usage.bytes += metadata.len();
+            }
+        }
+    }
+
+    update(dir, |tracked| *tracked = usage)
+}
+
+/// Account for an entry of `bytes` about to be written at `path`, failing if that would
+/// exceed a quota.
+pub fn charge(path: &Path, bytes: u64) -> io::Result<()> {
+    let exceeded = update(path, |usage| {
+        usage.bytes += bytes;
+        usage.files += 1;
+    });
+
+    if exceeded {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            "extraction quota exceeded",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether the tracked directory `dir` has gone over a quota.
+pub fn exceeded(dir: &Path) -> bool {
+    TRACKED
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|entry| entry.dir == dir && entry.exceeded)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Held by tests that set the quotas, which are global
+    pub(crate) static QUOTAS_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn finished_jobs_do_not_count_against_later_ones() {
+        let _lock = QUOTAS_LOCK.lock().unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let global = Quota {
+            bytes: Some(100),
+            files: None,
+        };
+
+        for job in ["first", "second"] {
+            set_quotas(global, BTreeMap::new());
+            let dir = tmp.path().join(job);
+            track("squashfs", &dir);
+
+            charge(&dir.join("a"), 80).unwrap();
+            assert!(charge(&dir.join("b"), 80).is_err(), "{job}");
+            assert!(exceeded(&dir));
+
+            untrack(&dir);
+            assert!(!exceeded(&dir));
+        }
+
+        set_quotas(Quota::default(), BTreeMap::new());
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::fstree::{safe_name, Attrs, EntryKind, TreeWriter};
+use super::native::{self, invalid_data, Endian, Unpacked};
+use super::{ExtractError, ExtractStatus, Extractor};
+
+const MAGIC: &[u8] = b"-rom1fs-";
+
+/// Everything in a ROMFS image is aligned to 16 bytes
+const ALIGN: usize = 16;
+const HEADER_LEN: usize = 16;
+const MAX_NAME_LEN: usize = 4096;
+/// Number of leading bytes covered by the superblock checksum
+const CHECKSUM_LEN: usize = 512;
+
+const TYPE_HARDLINK: u32 = 0;
+const TYPE_DIR: u32 = 1;
+const TYPE_FILE: u32 = 2;
+const TYPE_SYMLINK: u32 = 3;
+const TYPE_BLOCK: u32 = 4;
+const TYPE_CHAR: u32 = 5;
+const TYPE_SOCKET: u32 = 6;
+const TYPE_FIFO: u32 = 7;
+const TYPE_MASK: u32 = 0x7;
+const EXEC_FLAG: u32 = 0x8;
+
+/// Hardlinks can point at hardlinks; give up after this many hops
+const MAX_LINK_DEPTH: usize = 32;
+
+const BE: Endian = Endian::Big;
+
+/// In-process reader for ROMFS images. ROMFS does not store ownership, so everything is owned
+/// by root, with permissions derived from the entry type the way the kernel does.
+pub struct RomfsExtractor;
+
+impl Extractor for RomfsExtractor {
+    fn name(&self) -> &'static str {
+        "romfs"
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        _verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        native::extract_all(
+            self.name(),
+            in_file,
+            extract_dir,
+            log_file,
+            &[MAGIC],
+            |data, offset, out_dir| unpack_image(&data[offset..], out_dir),
+        )
+    }
+}
+
+/// Unpack the image at the start of `data`. Returns `None` if there isn't an intact superblock.
+fn unpack_image(data: &[u8], out_dir: &Path) -> io::Result<Option<Unpacked>> {
+    let Some(len) = data
+        .get(8..12)
+        .map(|size| BE.u32(size) as usize)
+        .filter(|&len| len >= ALIGN * 2 && len <= data.len())
+    else {
+        return Ok(None);
+    };
+
+    // The kernel refuses images with a bad checksum, and so do we: the magic also appears as a
+    // plain string in kernels with ROMFS support
+    if checksum(&data[..len.min(CHECKSUM_LEN)]) != 0 {
+        return Ok(None);
+    }
+
+    let image = Image { data: &data[..len] };
+    let mut warnings = Vec::new();
+
+    // The root directory's header follows the volume name
+    let (name, root) = image.name(16)?;
+    log::debug!(
+        "romfs: volume {:?}, {len} bytes",
+        String::from_utf8_lossy(name)
+    );
+
+    let entries = image.unpack(image.header(root)?, out_dir, &mut warnings)?;
+
+    Ok(Some(Unpacked {
+        len,
+        entries,
+        warnings,
+    }))
+}
+
+/// Sum of the big-endian words in `data`, which is zero for an intact superblock
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks_exact(4)
+        .fold(0u32, |sum, word| sum.wrapping_add(BE.u32(word)))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header<'a> {
+    offset: usize,
+    next: usize,
+    kind: u32,
+    exec: bool,
+    spec: u32,
+    size: usize,
+    name: &'a [u8],
+    data_offset: usize,
+}
+
+impl Header<'_> {
+    /// Permissions as the kernel reports them
+    fn attrs(&self) -> Attrs {
+        let mode = match self.kind {
+            TYPE_DIR => libc::S_IFDIR | 0o644,
+            TYPE_FILE => libc::S_IFREG | 0o644,
+            TYPE_SYMLINK => libc::S_IFLNK | 0o777,
+            TYPE_BLOCK => libc::S_IFBLK | 0o600,
+            TYPE_CHAR => libc::S_IFCHR | 0o600,
+            TYPE_SOCKET => libc::S_IFSOCK | 0o644,
+            _ => libc::S_IFIFO | 0o644,
+        };
+
+        let exec = if self.exec && matches!(self.kind, TYPE_DIR | TYPE_FILE) {
+            0o111
+        } else {
+            0
+        };
+
+        Attrs::new(mode | exec, 0, 0)
+    }
+}
+
+struct Image<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Image<'a> {
+    /// The NUL-terminated name at `offset`, and the aligned offset following it
+    fn name(&self, offset: usize) -> io::Result<(&'a [u8], usize)> {
+        let rest = self
+            .data
+            .get(offset..)
+            .ok_or_else(|| invalid_data(format!("name at {offset:#x} out of bounds")))?;
+        let len = rest
+            .iter()
+            .take(MAX_NAME_LEN)
+            .position(|&byte| byte == 0)
+            .ok_or_else(|| invalid_data(format!("unterminated name at {offset:#x}")))?;
+
+        Ok((&rest[..len], (offset + len + 1).next_multiple_of(ALIGN)))
+    }
+
+    fn header(&self, offset: usize) -> io::Result<Header<'a>> {
+        let raw = self
+            .data
+            .get(offset..offset + HEADER_LEN)
+            .filter(|_| offset.is_multiple_of(ALIGN))
+            .ok_or_else(|| invalid_data(format!("bad file header offset {offset:#x}")))?;
+
+        let next = BE.u32(&raw[0..4]);
+        let (name, data_offset) = self.name(offset + HEADER_LEN)?;
+
+        Ok(Header {
+            offset,
+            next: (next & !(ALIGN as u32 - 1)) as usize,
+            kind: next & TYPE_MASK,
+            exec: next & EXEC_FLAG != 0,
+            spec: BE.u32(&raw[4..8]),
+            size: BE.u32(&raw[8..12]) as usize,
+            name,
+            data_offset,
+        })
+    }
+
+    fn contents(&self, header: &Header) -> io::Result<&'a [u8]> {
+        self.data
+            .get(header.data_offset..header.data_offset + header.size)
+            .ok_or_else(|| invalid_data("file data out of bounds"))
+    }
+
+    /// Entries of the directory whose first entry is at `first`, following the chain of
+    /// `next` pointers
+    fn read_dir(&self, first: usize) -> io::Result<Vec<Header<'a>>> {
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        let mut offset = first;
+
+        while offset != 0 {
+            if !seen.insert(offset) {
+                return Err(invalid_data(format!("entry chain loops at {offset:#x}")));
+            }
+
+            let header = self.header(offset)?;
+            offset = header.next;
+            entries.push(header);
+        }
+
+        Ok(entries)
+    }
+
+    /// Follow a hardlink to the header it refers to
+    fn resolve(&self, header: Header<'a>) -> io::Result<Header<'a>> {
+        let mut header = header;
+        for _ in 0..MAX_LINK_DEPTH {
+            if header.kind != TYPE_HARDLINK {
+                return Ok(header);
+            }
+            header = self.header(header.spec as usize)?;
+        }
+
+        Err(invalid_data("too many levels of hardlinks"))
+    }
+
+    fn unpack_entry(&self, tree: &mut TreeWriter, rel: &Path, header: &Header) -> io::Result<()> {
+        let attrs = header.attrs();
+        match header.kind {
+            TYPE_FILE => tree.file(rel, attrs, self.contents(header)?),
+            TYPE_SYMLINK => tree.symlink(rel, attrs, self.contents(header)?),
+            TYPE_BLOCK | TYPE_CHAR => {
+                let kind = if header.kind == TYPE_BLOCK {
+                    EntryKind::Block
+                } else {
+                    EntryKind::Char
+                };
+                tree.special(
+                    rel,
+                    kind,
+                    attrs,
+                    Some((header.spec >> 16, header.spec & 0xffff)),
+                )
+            }
+            TYPE_SOCKET => tree.special(rel, EntryKind::Socket, attrs, None),
+            TYPE_FIFO => tree.special(rel, EntryKind::Fifo, attrs, None),
+            kind => Err(invalid_data(format!("unexpected entry type {kind}"))),
+        }
+    }
+
+    fn unpack(
+        &self,
+        root: Header,
+        out_dir: &Path,
+        warnings: &mut Vec<String>,
+    ) -> io::Result<usize> {
+        if root.kind != TYPE_DIR {
+            return Err(invalid_data("root entry is not a directory"));
+        }
+
+        let mut tree = TreeWriter::new(out_dir, "romfs")?;
+        tree.dir(Path::new(""), root.attrs())?;
+
+        let mut visited = HashSet::from([root.offset]);
+        let mut pending = vec![(PathBuf::new(), root.spec as usize)];
+
+        while let Some((dir, first)) = pending.pop() {
+            let entries = match self.read_dir(first) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warnings.push(format!("cannot read directory /{}: {err}", dir.display()));
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                if entry.name == b"." || entry.name == b".." {
+                    continue;
+                }
+
+                let name = match safe_name(entry.name) {
+                    Ok(name) => name,
+                    Err(err) => {
+                        warnings.push(format!("skipping entry in /{}: {err}", dir.display()));
+                        continue;
+                    }
+                };
+                let rel = dir.join(name);
+
+                let header = match self.resolve(entry) {
+                    Ok(header) => header,
+                    Err(err) => {
+                        warnings.push(format!("skipping /{}: {err}", rel.display()));
+                        continue;
+                    }
+                };
+
+                if header.kind == TYPE_DIR {
+                    if !visited.insert(header.offset) {
+                        warnings.push(format!("skipping directory hardlink /{}", rel.display()));
+                        continue;
+                    }
+
+                    tree.dir(&rel, header.attrs())?;
+                    pending.push((rel, header.spec as usize));
+                } else if let Some(existing) = tree.link_target(header.offset as u64, &rel) {
+                    tree.hardlink(&rel, &existing)?;
+                } else if let Err(err) = self.unpack_entry(&mut tree, &rel, &header) {
+                    warnings.push(format!("skipping /{}: {err}", rel.display()));
+                }
+            }
+        }
+
+        tree.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    fn set_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn pad_name(image: &mut Vec<u8>, name: &str) {
+        image.extend(name.as_bytes());
+        image.resize((image.len() + 1).next_multiple_of(ALIGN), 0);
+    }
+
+    /// Append an entry with no successor, returning its offset
+    fn entry(image: &mut Vec<u8>, next: u32, spec: usize, name: &str, data: &[u8]) -> usize {
+        let offset = image.len();
+        for word in [next, spec as u32, data.len() as u32, 0] {
+            image.extend(word.to_be_bytes());
+        }
+        pad_name(image, name);
+        image.extend(data);
+        image.resize(image.len().next_multiple_of(ALIGN), 0);
+        offset
+    }
+
+    /// Point the entry at `offset` to its successor in the directory
+    fn chain(image: &mut [u8], offset: usize, next: usize) {
+        let flags = BE.u32(&image[offset..offset + 4]);
+        set_u32(image, offset, flags | next as u32);
+    }
+
+    /// Fill in the size and the superblock checksum
+    fn seal(image: &mut [u8]) {
+        set_u32(image, 8, image.len() as u32);
+        set_u32(image, 12, 0);
+        let sum = checksum(&image[..image.len().min(CHECKSUM_LEN)]);
+        set_u32(image, 12, sum.wrapping_neg());
+    }
+
+    /// An image with an executable, a hardlink to it, a symlink and a directory holding a
+    /// hardlink back to the root. Returns the image and the offset of that directory's entry.
+    fn image() -> (Vec<u8>, usize) {
+        let mut image = MAGIC.to_vec();
+        image.resize(HEADER_LEN, 0);
+        pad_name(&mut image, "volume");
+
+        let root = entry(&mut image, TYPE_DIR, 0, ".", b"");
+        let file = entry(&mut image, TYPE_FILE | EXEC_FLAG, 0, "file", b"#!/bin/sh\n");
+        let hard = entry(&mut image, TYPE_HARDLINK, file, "hard", b"");
+        let link = entry(&mut image, TYPE_SYMLINK, 0, "link", b"file");
+        let dir = entry(&mut image, TYPE_DIR, 0, "dir", b"");
+        let up = entry(&mut image, TYPE_HARDLINK, root, "up", b"");
+
+        set_u32(&mut image, root + 4, file as u32);
+        chain(&mut image, file, hard);
+        chain(&mut image, hard, link);
+        chain(&mut image, link, dir);
+        set_u32(&mut image, dir + 4, up as u32);
+
+        seal(&mut image);
+        (image, up)
+    }
+
+    #[test]
+    fn unpacks_tree() {
+        let (image, _) = image();
+        let tmp = tempfile::tempdir().unwrap();
+        let unpacked = unpack_image(&image, tmp.path()).unwrap().unwrap();
+        assert_eq!(unpacked.len, image.len());
+        assert_eq!(unpacked.warnings, ["skipping directory hardlink /dir/up"]);
+
+        let file = tmp.path().join("file");
+        assert_eq!(fs::read(&file).unwrap(), b"#!/bin/sh\n");
+        assert_eq!(fs::metadata(&file).unwrap().mode() & 0o777, 0o755);
+        assert_eq!(
+            fs::metadata(tmp.path().join("hard")).unwrap().ino(),
+            fs::metadata(&file).unwrap().ino()
+        );
+        assert_eq!(
+            fs::read_link(tmp.path().join("link")).unwrap(),
+            Path::new("file")
+        );
+        assert!(tmp.path().join("dir").is_dir());
+    }
+
+    #[test]
+    fn rejects_bad_checksums_and_entry_loops() {
+        let (mut image, up) = image();
+        let tmp = tempfile::tempdir().unwrap();
+
+        image[HEADER_LEN] ^= 1;
+        assert!(unpack_image(&image, tmp.path()).unwrap().is_none());
+        image[HEADER_LEN] ^= 1;
+
+        chain(&mut image, up, up);
+        seal(&mut image);
+        let unpacked = unpack_image(&image, tmp.path()).unwrap().unwrap();
+        assert_eq!(
+            unpacked.warnings,
+            [format!(
+                "cannot read directory /dir: entry chain loops at {up:#x}"
+            )]
+        );
+        assert!(tmp.path().join("file").is_file());
+    }
+}
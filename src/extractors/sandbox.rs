@@ -0,0 +1,621 @@
+//! Optional namespace sandbox for external extractor processes.
+//!
+//! With `--sandbox`, each external extractor runs in fresh unprivileged user, mount, PID and
+//! network namespaces. Its root is an empty tmpfs holding read-only bind mounts of the system
+//! directories (plus any `--sandbox-allow` paths) and of the input file, a writable bind mount
+//! of its scratch directory and log file, a few device nodes, a private `/tmp` and a `/proc`
+//! showing only its own processes. It has no network, and a seccomp filter stops it from
+//! undoing any of that (mounting, creating or entering namespaces, tracing other processes,
+//! loading kernel code).
+//!
+//! Everything is prepared in the parent as a [`Plan`], because the child may not allocate
+//! between `fork` and `exec`.
+
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{self, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use tempfile::TempDir;
+
+/// Directories every extractor needs to run at all, mounted read-only when present
+const SYSTEM_DIRS: &[&str] = &[
+    "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/libx32", "/usr", "/etc", "/opt",
+];
+const DEVICES: &[&str] = &[
+    "/dev/null",
+    "/dev/zero",
+    "/dev/full",
+    "/dev/random",
+    "/dev/urandom",
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static EXTRA_READ_ONLY: RwLock<Vec<PathBuf>> = RwLock::new(Vec::new());
+
+/// Run external extractors sandboxed from now on, additionally exposing `read_only` (e.g. a
+/// Python installation in a home directory).
+pub fn enable(read_only: Vec<PathBuf>) {
+    *EXTRA_READ_ONLY.write().unwrap() = read_only;
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Run external extractors without the sandbox from now on.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The files an extractor run works on: all a sandboxed extractor gets to see besides the
+/// system directories.
+#[derive(Debug, Clone, Copy)]
+pub struct Workspace<'a> {
+    pub input: &'a Path,
+    pub extract_dir: &'a Path,
+    pub log_file: &'a Path,
+}
+
+/// One step of building the sandbox root, with paths already inside the new root
+#[derive(Debug)]
+enum Step {
+    Mkdir(CString),
+    /// Create a file to mount over, unless it already exists (possibly on a read-only mount)
+    Touch(CString),
+    Symlink {
+        target: CString,
+        link: CString,
+    },
+    Bind {
+        source: CString,
+        target: CString,
+        /// Flags to remount read-only with, `None` to leave the mount writable
+        read_only: Option<libc::c_ulong>,
+        /// Failure is not fatal
+        optional: bool,
+    },
+    Tmpfs(CString),
+    /// A procfs for the new PID namespace, if the kernel allows mounting one
+    Proc(CString),
+}
+
+/// Everything needed to build the sandbox in the child.
+#[derive(Debug)]
+pub struct Plan {
+    /// Empty directory the new root is mounted on; removed when the plan is dropped
+    _root_dir: TempDir,
+    root: CString,
+    uid_map: CString,
+    gid_map: CString,
+    steps: Vec<Step>,
+    cwd: CString,
+    filter: Vec<libc::sock_filter>,
+}
+
+fn cstring(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains NUL"))
+}
+
+/// Mount flags that must be kept when remounting a bind of `path`, as unprivileged users
+/// cannot clear them
+fn locked_flags(path: &Path) -> io::Result<libc::c_ulong> {
+    let path = cstring(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut flags = libc::MS_RDONLY;
+    for (st, ms) in [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & st != 0 {
+            flags |= ms;
+        }
+    }
+
+    Ok(flags)
+}
+
+impl Plan {
+    pub fn new(workspace: &Workspace) -> io::Result<Self> {
+        let root_dir = tempfile::Builder::new().prefix("xfs-sandbox").tempdir()?;
+        let root = root_dir.path().to_path_buf();
+        let cwd = std::env::current_dir()?;
+        let inside = |path: &Path| -> io::Result<CString> {
+            let mut bytes = root.as_os_str().as_bytes().to_vec();
+            bytes.extend_from_slice(path.as_os_str().as_bytes());
+            cstring(&bytes)
+        };
+
+        let mut plan = Plan {
+            root: cstring(root.as_os_str().as_bytes())?,
+            uid_map: CString::new(format!("{0} {0} 1", unsafe { libc::geteuid() })).unwrap(),
+            gid_map: CString::new(format!("{0} {0} 1", unsafe { libc::getegid() })).unwrap(),
+            steps: Vec::new(),
+            cwd: cstring(cwd.as_os_str().as_bytes())?,
+            filter: seccomp_filter()?,
+            _root_dir: root_dir,
+        };
+        let mut made = Vec::new();
+        let mut mkdir_all = |steps: &mut Vec<Step>, dir: &Path| -> io::Result<()> {
+            for ancestor in dir.ancestors().collect::<Vec<_>>().into_iter().rev() {
+                if ancestor != Path::new("/") && !made.contains(&ancestor.to_path_buf()) {
+                    made.push(ancestor.to_path_buf());
+                    steps.push(Step::Mkdir(inside(ancestor)?));
+                }
+            }
+            Ok(())
+        };
+
+        // First, so that anything mounted below them stays visible
+        for tmpfs in ["/tmp", "/dev/shm"].map(Path::new) {
+            mkdir_all(&mut plan.steps, tmpfs)?;
+            plan.steps.push(Step::Tmpfs(inside(tmpfs)?));
+        }
+
+        let extra = EXTRA_READ_ONLY.read().unwrap().clone();
+        let read_only_dirs = SYSTEM_DIRS.iter().map(PathBuf::from).chain(extra);
+        for dir in read_only_dirs {
+            let dir = path::absolute(dir)?;
+            let Ok(meta) = fs::symlink_metadata(&dir) else {
+                continue;
+            };
+
+            mkdir_all(&mut plan.steps, dir.parent().unwrap_or(Path::new("/")))?;
+            if meta.file_type().is_symlink() {
+                plan.steps.push(Step::Symlink {
+                    target: cstring(fs::read_link(&dir)?.as_os_str().as_bytes())?,
+                    link: inside(&dir)?,
+                });
+                continue;
+            }
+
+            if meta.is_dir() {
+                mkdir_all(&mut plan.steps, &dir)?;
+            } else {
+                plan.steps.push(Step::Touch(inside(&dir)?));
+            }
+            plan.steps.push(Step::Bind {
+                source: cstring(dir.as_os_str().as_bytes())?,
+                target: inside(&dir)?,
+                read_only: Some(locked_flags(&dir)?),
+                optional: false,
+            });
+        }
+
+        mkdir_all(&mut plan.steps, Path::new("/dev"))?;
+        for device in DEVICES.iter().map(Path::new).filter(|dev| dev.exists()) {
+            plan.steps.push(Step::Touch(inside(device)?));
+            plan.steps.push(Step::Bind {
+                source: cstring(device.as_os_str().as_bytes())?,
+                target: inside(device)?,
+                read_only: None,
+                optional: false,
+            });
+        }
+        mkdir_all(&mut plan.steps, Path::new("/proc"))?;
+        plan.steps.push(Step::Proc(inside(Path::new("/proc"))?));
+
+        // The log file is written by the extractor, so it has to exist to be bind mounted
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(workspace.log_file)?;
+
+        let input = path::absolute(workspace.input)?;
+        let log_file = path::absolute(workspace.log_file)?;
+        let extract_dir = path::absolute(workspace.extract_dir)?;
+        for (file, read_only) in [(&input, Some(locked_flags(&input)?)), (&log_file, None)] {
+            mkdir_all(&mut plan.steps, file.parent().unwrap_or(Path::new("/")))?;
+            plan.steps.push(Step::Touch(inside(file)?));
+            plan.steps.push(Step::Bind {
+                source: cstring(file.as_os_str().as_bytes())?,
+                target: inside(file)?,
+                read_only,
+                optional: false,
+            });
+        }
+        mkdir_all(&mut plan.steps, &extract_dir)?;
+        plan.steps.push(Step::Bind {
+            source: cstring(extract_dir.as_os_str().as_bytes())?,
+            target: inside(&extract_dir)?,
+            read_only: None,
+            optional: false,
+        });
+
+        // Keep relative paths working
+        mkdir_all(&mut plan.steps, &cwd)?;
+
+        Ok(plan)
+    }
+
+    /// Enter the sandbox. Runs in the forked child, so only async-signal-safe calls.
+    ///
+    /// Only children join a new PID namespace, so this forks: the extractor continues in the
+    /// child as PID 1 of the namespace, while this process waits for it and exits the same
+    /// way. Both stay in the process group the supervisor kills.
+    pub fn apply(&self) -> io::Result<()> {
+        unsafe {
+            check(libc::unshare(
+                libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWNET,
+            ))?;
+
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", self.uid_map.as_bytes())?;
+            write_file(c"/proc/self/gid_map", self.gid_map.as_bytes())?;
+
+            let pid = libc::fork();
+            check(pid)?;
+            if pid > 0 {
+                wait_and_exit(pid);
+            }
+
+            self.enter()
+        }
+    }
+
+    /// Build the new root and switch to it, in the first process of the new namespaces
+    unsafe fn enter(&self) -> io::Result<()> {
+        unsafe {
+            let none = std::ptr::null();
+            check(libc::mount(
+                none,
+                c"/".as_ptr(),
+                none,
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            ))?;
+            check(libc::mount(
+                c"tmpfs".as_ptr(),
+                self.root.as_ptr(),
+                c"tmpfs".as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                c"mode=0755".as_ptr().cast(),
+            ))?;
+
+            for step in &self.steps {
+                match step {
+                    Step::Mkdir(path) => {
+                        if libc::mkdir(path.as_ptr(), 0o755) < 0
+                            && *libc::__errno_location() != libc::EEXIST
+                        {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Step::Touch(path) => {
+                        let fd = libc::open(path.as_ptr(), libc::O_CREAT | libc::O_RDONLY, 0o644);
+                        check(fd)?;
+                        libc::close(fd);
+                    }
+                    Step::Symlink { target, link } => {
+                        check(libc::symlink(target.as_ptr(), link.as_ptr()))?;
+                    }
+                    Step::Bind {
+                        source,
+                        target,
+                        read_only,
+                        optional,
+                    } => {
+                        let bound = check(libc::mount(
+                            source.as_ptr(),
+                            target.as_ptr(),
+                            none,
+                            libc::MS_BIND | libc::MS_REC,
+                            std::ptr::null(),
+                        ))
+                        .and_then(|_| match read_only {
+                            Some(flags) => check(libc::mount(
+                                none,
+                                target.as_ptr(),
+                                none,
+                                libc::MS_BIND | libc::MS_REMOUNT | flags,
+                                std::ptr::null(),
+                            )),
+                            None => Ok(()),
+                        });
+
+                        if !optional {
+                            bound?;
+                        }
+                    }
+                    Step::Tmpfs(path) => {
+                        check(libc::mount(
+                            c"tmpfs".as_ptr(),
+                            path.as_ptr(),
+                            c"tmpfs".as_ptr(),
+                            libc::MS_NOSUID | libc::MS_NODEV,
+                            c"mode=1777".as_ptr().cast(),
+                        ))?;
+                    }
+                    Step::Proc(path) => {
+                        // Refused when parts of the host's /proc are hidden, as in many
+                        // containers; the extractor then goes without
+                        libc::mount(
+                            c"proc".as_ptr(),
+                            path.as_ptr(),
+                            c"proc".as_ptr(),
+                            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                            std::ptr::null(),
+                        );
+                    }
+                }
+            }
+
+            // Swap the new root in and drop the old one
+            check(libc::chdir(self.root.as_ptr()))?;
+            check(
+                libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as libc::c_int,
+            )?;
+            check(libc::umount2(c".".as_ptr(), libc::MNT_DETACH))?;
+            check(libc::mount(
+                none,
+                c"/".as_ptr(),
+                none,
+                libc::MS_BIND
+                    | libc::MS_REMOUNT
+                    | libc::MS_RDONLY
+                    | libc::MS_NOSUID
+                    | libc::MS_NODEV,
+                std::ptr::null(),
+            ))?;
+            if libc::chdir(self.cwd.as_ptr()) < 0 {
+                check(libc::chdir(c"/".as_ptr()))?;
+            }
+
+            let program = libc::sock_fprog {
+                len: self.filter.len() as libc::c_ushort,
+                filter: self.filter.as_ptr().cast_mut(),
+            };
+            check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            check(libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &program as *const libc::sock_fprog,
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Wait for `pid` and exit with its status. Runs between `fork` and `exec`.
+unsafe fn wait_and_exit(pid: libc::pid_t) -> ! {
+    // Let go of every inherited descriptor, which includes the pipe `Command::spawn` reads
+    // until `exec`: only the child's copy should keep it open
+    if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) != 0 {
+        let mut limit: libc::rlimit = mem::zeroed();
+        libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+        for fd in 3..limit.rlim_cur.min(65536) as libc::c_int {
+            libc::close(fd);
+        }
+    }
+
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) < 0 {
+        if *libc::__errno_location() != libc::EINTR {
+            libc::_exit(127);
+        }
+    }
+
+    if libc::WIFSIGNALED(status) {
+        let signal = libc::WTERMSIG(status);
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+        libc::_exit(128 + signal);
+    }
+    libc::_exit(libc::WEXITSTATUS(status))
+}
+
+unsafe fn write_file(path: &std::ffi::CStr, contents: &[u8]) -> io::Result<()> {
+    let fd = libc::open(path.as_ptr(), libc::O_WRONLY);
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+    libc::close(fd);
+    if written != contents.len() as isize {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscalls that could be used to get out of the sandbox or at the kernel, which fail with
+/// `EPERM` instead
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_fsopen,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fspick,
+    libc::SYS_move_mount,
+    libc::SYS_open_tree,
+    libc::SYS_mount_setattr,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_reboot,
+    libc::SYS_acct,
+];
+
+/// `clone` flags creating namespaces
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const NAMESPACE_FLAGS: libc::c_int = libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | 0x80; // CLONE_NEWTIME
+
+/// Deny [`DENIED_SYSCALLS`], `clone` with [`NAMESPACE_FLAGS`], and everything from a foreign
+/// syscall ABI. `clone3` takes its flags in memory the filter cannot read, so it reports
+/// `ENOSYS` to make the C library fall back to `clone`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp_filter() -> io::Result<Vec<libc::sock_filter>> {
+    const LD_ABS_W: u16 = 0x20;
+    const JEQ_K: u16 = 0x15;
+    const JGE_K: u16 = 0x35;
+    const JSET_K: u16 = 0x45;
+    const RET_K: u16 = 0x06;
+    const RET_ALLOW: u32 = 0x7fff_0000;
+    const RET_EPERM: u32 = 0x0005_0000 | libc::EPERM as u32;
+    const RET_ENOSYS: u32 = 0x0005_0000 | libc::ENOSYS as u32;
+    const RET_KILL: u32 = 0x8000_0000;
+    /// x32 syscalls on x86_64 have this bit set
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    let stmt = |code, k| libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code, k, jt, jf| libc::sock_filter { code, jt, jf, k };
+
+    let mut filter = vec![
+        // seccomp_data.arch
+        stmt(LD_ABS_W, 4),
+        jump(JEQ_K, AUDIT_ARCH, 1, 0),
+        stmt(RET_K, RET_KILL),
+        // seccomp_data.nr
+        stmt(LD_ABS_W, 0),
+    ];
+    if cfg!(target_arch = "x86_64") {
+        filter.push(jump(JGE_K, X32_SYSCALL_BIT, 0, 1));
+        filter.push(stmt(RET_K, RET_EPERM));
+    }
+    for &nr in DENIED_SYSCALLS {
+        filter.push(jump(JEQ_K, nr as u32, 0, 1));
+        filter.push(stmt(RET_K, RET_EPERM));
+    }
+    filter.extend([
+        jump(JEQ_K, libc::SYS_clone3 as u32, 0, 1),
+        stmt(RET_K, RET_ENOSYS),
+        jump(JEQ_K, libc::SYS_clone as u32, 0, 3),
+        // The low half of seccomp_data.args[0], the flags on both architectures
+        stmt(LD_ABS_W, 16),
+        jump(JSET_K, NAMESPACE_FLAGS as u32, 0, 1),
+        stmt(RET_K, RET_EPERM),
+    ]);
+    filter.push(stmt(RET_K, RET_ALLOW));
+
+    Ok(filter)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp_filter() -> io::Result<Vec<libc::sock_filter>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the sandbox is not supported on this architecture",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    /// Whether this process may create user namespaces at all
+    fn user_namespaces_available() -> bool {
+        let mut command = Command::new("true");
+        unsafe { command.pre_exec(|| check(libc::unshare(libc::CLONE_NEWUSER))) };
+        command.status().is_ok_and(|status| status.success())
+    }
+
+    #[test]
+    fn confines_the_extractor() {
+        if !user_namespaces_available() {
+            eprintln!("skipping: user namespaces are not available");
+            return;
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("image.bin");
+        let extract_dir = tmp.path().join("extract");
+        fs::write(&input, b"firmware").unwrap();
+        fs::create_dir(&extract_dir).unwrap();
+        let workspace = Workspace {
+            input: &input,
+            extract_dir: &extract_dir,
+            log_file: &tmp.path().join("extract.log"),
+        };
+
+        let script = format!(
+            "echo $$
+            ls /proc | grep -c '^[0-9]'
+            echo out > {extract}/out
+            echo out 2>/dev/null > {input} || echo input is read-only
+            unshare -U true 2>/dev/null && echo escaped
+            true",
+            extract = extract_dir.display(),
+            input = input.display(),
+        );
+
+        let plan = Plan::new(&workspace).unwrap();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        unsafe { command.pre_exec(move || plan.apply()) };
+        let output = command.output().unwrap();
+        assert!(output.status.success(), "{output:?}");
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<_> = stdout.lines().collect();
+
+        // PID 1 of its own namespace, which is all its /proc shows (if it could mount one)
+        assert_eq!(lines[0], "1");
+        assert!(lines[1].parse::<usize>().unwrap() < 5, "{stdout}");
+        assert_eq!(lines[2..], ["input is read-only"]);
+
+        assert_eq!(fs::read(extract_dir.join("out")).unwrap(), b"out\n");
+        assert_eq!(fs::read(&input).unwrap(), b"firmware");
+    }
+}
@@ -0,0 +1,925 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::compression::Codec;
+use super::fstree::{safe_name, Attrs, EntryKind, TreeWriter};
+use super::native::{self, invalid_data, Endian, Reader, Unpacked};
+use super::{ExtractError, ExtractStatus, Extractor};
+
+const MAGIC_LE: &[u8] = b"hsqs";
+const MAGIC_BE: &[u8] = b"sqsh";
+
+const SUPERBLOCK_SIZE: usize = 96;
+const METADATA_SIZE: usize = 8192;
+
+const NO_FRAGMENT: u32 = 0xffff_ffff;
+const BLOCK_UNCOMPRESSED: u32 = 1 << 24;
+const METADATA_UNCOMPRESSED: u16 = 1 << 15;
+
+/// Largest file unpacked; sparse blocks let a small image declare files of any size
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// In-process reader for SquashFS 4.x images
+pub struct SquashfsExtractor;
+
+impl Extractor for SquashfsExtractor {
+    fn name(&self) -> &'static str {
+        "squashfs"
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        _verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        native::extract_all(
+            self.name(),
+            in_file,
+            extract_dir,
+            log_file,
+            &[MAGIC_LE, MAGIC_BE],
+            |data, offset, out_dir| {
+                let Some(mut image) = Image::open(&data[offset..]) else {
+                    return Ok(None);
+                };
+
+                let entries = image.unpack(out_dir)?;
+
+                Ok(Some(Unpacked {
+                    len: image.data.len(),
+                    entries,
+                    warnings: Vec::new(),
+                }))
+            },
+        )
+    }
+}
+
+/// Unpack the SquashFS image at the start of `data`, such as one stored in a UBI volume.
+/// Returns `None` if `data` does not start with a valid superblock.
+pub(super) fn unpack_image(data: &[u8], out_dir: &Path) -> io::Result<Option<usize>> {
+    match Image::open(data) {
+        Some(mut image) => image.unpack(out_dir).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug)]
+struct Superblock {
+    block_size: u32,
+    fragment_count: u32,
+    codec: Codec,
+    id_count: u16,
+    root_inode: u64,
+    bytes_used: u64,
+    id_table: u64,
+    inode_table: u64,
+    directory_table: u64,
+    fragment_table: u64,
+}
+
+impl Superblock {
+    fn parse(data: &[u8]) -> Option<(Self, Endian)> {
+        let endian = if data.starts_with(MAGIC_LE) {
+            Endian::Little
+        } else if data.starts_with(MAGIC_BE) {
+            Endian::Big
+        } else {
+            return None;
+        };
+
+        let mut r = Reader::at(data.get(..SUPERBLOCK_SIZE)?, 4, endian);
+
+        let _inode_count = r.u32().ok()?;
+        let _mod_time = r.u32().ok()?;
+        let block_size = r.u32().ok()?;
+        let fragment_count = r.u32().ok()?;
+        let compressor = r.u16().ok()?;
+        let block_log = r.u16().ok()?;
+        let _flags = r.u16().ok()?;
+        let id_count = r.u16().ok()?;
+        let version = (r.u16().ok()?, r.u16().ok()?);
+        let root_inode = r.u64().ok()?;
+        let bytes_used = r.u64().ok()?;
+        let id_table = r.u64().ok()?;
+        let _xattr_table = r.u64().ok()?;
+        let inode_table = r.u64().ok()?;
+        let directory_table = r.u64().ok()?;
+        let fragment_table = r.u64().ok()?;
+
+        let codec = match compressor {
+            1 => Codec::Zlib,
+            2 => Codec::Lzma,
+            3 => Codec::Lzo,
+            4 => Codec::Xz,
+            5 => Codec::Lz4,
+            6 => Codec::Zstd,
+            _ => return None,
+        };
+
+        let valid = version == (4, 0)
+            && block_size.is_power_of_two()
+            && (4096..=1 << 20).contains(&block_size)
+            && u32::from(block_log) == block_size.trailing_zeros()
+            && bytes_used >= SUPERBLOCK_SIZE as u64
+            && bytes_used <= data.len() as u64
+            && inode_table < directory_table
+            && directory_table <= bytes_used
+            && id_table < bytes_used;
+
+        if !valid {
+            return None;
+        }
+
+        Some((
+            Self {
+                block_size,
+                fragment_count,
+                codec,
+                id_count,
+                root_inode,
+                bytes_used,
+                id_table,
+                inode_table,
+                directory_table,
+                fragment_table,
+            },
+            endian,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Fragment {
+    start: u64,
+    size: u32,
+}
+
+#[derive(Debug)]
+enum InodeKind {
+    Dir {
+        block: u32,
+        offset: u16,
+        size: u32,
+    },
+    File {
+        blocks_start: u64,
+        size: u64,
+        fragment: u32,
+        fragment_offset: u32,
+        block_sizes: Vec<u32>,
+    },
+    Symlink(Vec<u8>),
+    Special {
+        kind: EntryKind,
+        rdev: Option<(u32, u32)>,
+    },
+}
+
+#[derive(Debug)]
+struct Inode {
+    number: u32,
+    attrs: Attrs,
+    kind: InodeKind,
+}
+
+struct DirEntry {
+    name: Vec<u8>,
+    inode_block: u32,
+    inode_offset: u16,
+}
+
+/// Position inside one of the metadata tables: the byte offset of a metadata block from
+/// the start of the image, and an offset within its decompressed contents.
+#[derive(Debug, Clone, Copy)]
+struct MetaPos {
+    block: u64,
+    offset: usize,
+}
+
+struct Image<'a> {
+    data: &'a [u8],
+    endian: Endian,
+    sb: Superblock,
+    ids: Vec<u32>,
+    fragments: Vec<Fragment>,
+    /// Decompressed metadata blocks, along with the position of the following block
+    metadata: HashMap<u64, (Vec<u8>, u64)>,
+    fragment_blocks: HashMap<u32, Vec<u8>>,
+}
+
+impl<'a> Image<'a> {
+    fn open(data: &'a [u8]) -> Option<Self> {
+        let (sb, endian) = Superblock::parse(data)?;
+
+        Some(Self {
+            data: &data[..sb.bytes_used as usize],
+            endian,
+            sb,
+            ids: Vec::new(),
+            fragments: Vec::new(),
+            metadata: HashMap::new(),
+            fragment_blocks: HashMap::new(),
+        })
+    }
+
+    fn decompress(&self, raw: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        self.sb.codec.decompress(raw, max_len).map_err(|err| {
+            invalid_data(format!(
+                "{} decompression failed: {err}",
+                self.sb.codec.name()
+            ))
+        })
+    }
+
+    fn slice(&self, start: u64, len: usize) -> io::Result<&'a [u8]> {
+        usize::try_from(start)
+            .ok()
+            .and_then(|start| self.data.get(start..start.checked_add(len)?))
+            .ok_or_else(|| invalid_data(format!("block at {start:#x} (+{len}) is out of bounds")))
+    }
+
+    fn metadata_block(&mut self, pos: u64) -> io::Result<&(Vec<u8>, u64)> {
+        if !self.metadata.contains_key(&pos) {
+            let header = self.endian.u16(self.slice(pos, 2)?);
+            let len = (header & !METADATA_UNCOMPRESSED) as usize;
+            let raw = self.slice(pos + 2, len)?;
+
+            let block = if header & METADATA_UNCOMPRESSED != 0 {
+                raw.to_vec()
+            } else {
+                self.decompress(raw, METADATA_SIZE)?
+            };
+
+            self.metadata.insert(pos, (block, pos + 2 + len as u64));
+        }
+
+        Ok(&self.metadata[&pos])
+    }
+
+    fn read_metadata(&mut self, pos: &mut MetaPos, len: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(len);
+
+        while out.len() < len {
+            let (block, next) = self.metadata_block(pos.block)?;
+            let available = block.get(pos.offset..).ok_or_else(|| {
+                invalid_data(format!("metadata offset {} out of range", pos.offset))
+            })?;
+
+            if available.is_empty() {
+                if block.is_empty() {
+                    return Err(invalid_data("empty metadata block"));
+                }
+
+                *pos = MetaPos {
+                    block: *next,
+                    offset: 0,
+                };
+                continue;
+            }
+
+            let take = available.len().min(len - out.len());
+            out.extend_from_slice(&available[..take]);
+            pos.offset += take;
+        }
+
+        Ok(out)
+    }
+
+    /// Read a table indexed by a list of metadata block pointers (ids, fragments)
+    fn read_lookup_table(
+        &mut self,
+        start: u64,
+        entries: usize,
+        entry_size: usize,
+    ) -> io::Result<Vec<u8>> {
+        let total = entries * entry_size;
+        let block_count = total.div_ceil(METADATA_SIZE);
+        let pointers = self.slice(start, block_count * 8)?;
+
+        let mut out = Vec::with_capacity(total);
+        for pointer in pointers.chunks_exact(8) {
+            let mut pos = MetaPos {
+                block: self.endian.u64(pointer),
+                offset: 0,
+            };
+            let len = (total - out.len()).min(METADATA_SIZE);
+            out.extend(self.read_metadata(&mut pos, len)?);
+        }
+
+        Ok(out)
+    }
+
+    fn load_tables(&mut self) -> io::Result<()> {
+        let ids = self.read_lookup_table(self.sb.id_table, self.sb.id_count as usize, 4)?;
+        self.ids = ids.chunks_exact(4).map(|id| self.endian.u32(id)).collect();
+
+        if self.sb.fragment_count > 0 && self.sb.fragment_table < self.sb.bytes_used {
+            let table = self.read_lookup_table(
+                self.sb.fragment_table,
+                self.sb.fragment_count as usize,
+                16,
+            )?;
+
+            self.fragments = table
+                .chunks_exact(16)
+                .map(|entry| Fragment {
+                    start: self.endian.u64(&entry[0..8]),
+                    size: self.endian.u32(&entry[8..12]),
+                })
+                .collect();
+        }
+
+        Ok(())
+    }
+
+    fn id(&self, index: u16) -> io::Result<u32> {
+        self.ids
+            .get(index as usize)
+            .copied()
+            .ok_or_else(|| invalid_data(format!("id index {index} out of range")))
+    }
+
+    fn read_u32s(&mut self, pos: &mut MetaPos, count: usize) -> io::Result<Vec<u32>> {
+        let bytes = self.read_metadata(pos, count * 4)?;
+        Ok(bytes.chunks_exact(4).map(|b| self.endian.u32(b)).collect())
+    }
+
+    fn read_inode(&mut self, block: u32, offset: u16) -> io::Result<Inode> {
+        let mut pos = MetaPos {
+            block: self.sb.inode_table + u64::from(block),
+            offset: offset as usize,
+        };
+
+        let header = self.read_metadata(&mut pos, 16)?;
+        let mut r = Reader::new(&header, self.endian);
+        let inode_type = r.u16()?;
+        let mode = r.u16()?;
+        let uid = self.id(r.u16()?)?;
+        let gid = self.id(r.u16()?)?;
+        let _mtime = r.u32()?;
+        let number = r.u32()?;
+
+        let attrs = Attrs::new(u32::from(mode), uid, gid);
+
+        let fixed_len = match inode_type {
+            1 => 16,
+            2 => 16,
+            3 => 8,
+            4 | 5 => 8,
+            6 | 7 => 4,
+            8 => 24,
+            9 => 40,
+            10 => 8,
+            11 | 12 => 12,
+            13 | 14 => 8,
+            _ => return Err(invalid_data(format!("unknown inode type {inode_type}"))),
+        };
+
+        let body = self.read_metadata(&mut pos, fixed_len)?;
+        let mut r = Reader::new(&body, self.endian);
+
+        let kind = match inode_type {
+            1 => {
+                let block = r.u32()?;
+                let _link_count = r.u32()?;
+                let size = u32::from(r.u16()?);
+                let offset = r.u16()?;
+                InodeKind::Dir {
+                    block,
+                    offset,
+                    size,
+                }
+            }
+            8 => {
+                let _link_count = r.u32()?;
+                let size = r.u32()?;
+                let block = r.u32()?;
+                let _parent = r.u32()?;
+                let _index_count = r.u16()?;
+                let offset = r.u16()?;
+                InodeKind::Dir {
+                    block,
+                    offset,
+                    size,
+                }
+            }
+            2 | 9 => {
+                let (blocks_start, fragment, fragment_offset, size) = if inode_type == 2 {
+                    let blocks_start = u64::from(r.u32()?);
+                    let fragment = r.u32()?;
+                    let fragment_offset = r.u32()?;
+                    let size = u64::from(r.u32()?);
+                    (blocks_start, fragment, fragment_offset, size)
+                } else {
+                    let blocks_start = r.u64()?;
+                    let size = r.u64()?;
+                    let _sparse = r.u64()?;
+                    let _link_count = r.u32()?;
+                    let fragment = r.u32()?;
+                    let fragment_offset = r.u32()?;
+                    (blocks_start, fragment, fragment_offset, size)
+                };
+
+                let block_size = u64::from(self.sb.block_size);
+                let block_count = if fragment == NO_FRAGMENT {
+                    size.div_ceil(block_size)
+                } else {
+                    size / block_size
+                };
+
+                if size > MAX_FILE_SIZE || block_count * 4 > self.data.len() as u64 {
+                    return Err(invalid_data(format!("file size {size} is implausible")));
+                }
+
+                let block_sizes = self.read_u32s(&mut pos, block_count as usize)?;
+
+                InodeKind::File {
+                    blocks_start,
+                    size,
+                    fragment,
+                    fragment_offset,
+                    block_sizes,
+                }
+            }
+            3 | 10 => {
+                let _link_count = r.u32()?;
+                let target_len = r.u32()? as usize;
+                if target_len > 4096 {
+                    return Err(invalid_data(format!(
+                        "symlink target of {target_len} bytes"
+                    )));
+                }
+                InodeKind::Symlink(self.read_metadata(&mut pos, target_len)?)
+            }
+            4 | 5 | 11 | 12 => {
+                let _link_count = r.u32()?;
+                let dev = r.u32()?;
+                let major = (dev >> 8) & 0xfff;
+                let minor = (dev & 0xff) | ((dev >> 12) & 0xfff00);
+                let kind = if matches!(inode_type, 4 | 11) {
+                    EntryKind::Block
+                } else {
+                    EntryKind::Char
+                };
+                InodeKind::Special {
+                    kind,
+                    rdev: Some((major, minor)),
+                }
+            }
+            _ => {
+                let kind = if matches!(inode_type, 6 | 13) {
+                    EntryKind::Fifo
+                } else {
+                    EntryKind::Socket
+                };
+                InodeKind::Special { kind, rdev: None }
+            }
+        };
+
+        Ok(Inode {
+            number,
+            attrs,
+            kind,
+        })
+    }
+
+    fn read_dir(&mut self, block: u32, offset: u16, size: u32) -> io::Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+
+        // The stored size includes 3 bytes for the implicit `.` and `..` entries
+        let mut remaining = (size as usize).saturating_sub(3);
+        let mut pos = MetaPos {
+            block: self.sb.directory_table + u64::from(block),
+            offset: offset as usize,
+        };
+
+        while remaining >= 12 {
+            let header = self.read_metadata(&mut pos, 12)?;
+            let mut r = Reader::new(&header, self.endian);
+            let count = r.u32()? as usize + 1;
+            let inode_block = r.u32()?;
+            let _inode_base = r.u32()?;
+            remaining -= 12;
+
+            if count > 256 {
+                return Err(invalid_data(format!(
+                    "directory header with {count} entries"
+                )));
+            }
+
+            for _ in 0..count {
+                let entry = self.read_metadata(&mut pos, 8)?;
+                let mut r = Reader::new(&entry, self.endian);
+                let inode_offset = r.u16()?;
+                let _inode_delta = r.u16()?;
+                let _entry_type = r.u16()?;
+                let name_len = r.u16()? as usize + 1;
+
+                let name = self.read_metadata(&mut pos, name_len)?;
+                remaining = remaining.saturating_sub(8 + name_len);
+
+                entries.push(DirEntry {
+                    name,
+                    inode_block,
+                    inode_offset,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn fragment_block(&mut self, index: u32) -> io::Result<&[u8]> {
+        if !self.fragment_blocks.contains_key(&index) {
+            let fragment = *self
+                .fragments
+                .get(index as usize)
+                .ok_or_else(|| invalid_data(format!("fragment {index} out of range")))?;
+
+            let len = (fragment.size & !BLOCK_UNCOMPRESSED) as usize;
+            let raw = self.slice(fragment.start, len)?;
+            let block = if fragment.size & BLOCK_UNCOMPRESSED != 0 {
+                raw.to_vec()
+            } else {
+                self.decompress(raw, self.sb.block_size as usize)?
+            };
+
+            self.fragment_blocks.insert(index, block);
+        }
+
+        Ok(&self.fragment_blocks[&index])
+    }
+
+    fn read_file(
+        &mut self,
+        blocks_start: u64,
+        size: u64,
+        fragment: u32,
+        fragment_offset: u32,
+        block_sizes: &[u32],
+    ) -> io::Result<Vec<u8>> {
+        let size = size as usize;
+        let block_size = self.sb.block_size as usize;
+        let mut out = Vec::with_capacity(size.min(self.data.len() * 4));
+        let mut pos = blocks_start;
+
+        for &stored in block_sizes {
+            let expected = block_size.min(size.saturating_sub(out.len()));
+            let len = (stored & !BLOCK_UNCOMPRESSED) as usize;
+
+            if len == 0 {
+                // Sparse block
+                out.resize(out.len() + expected, 0);
+                continue;
+            }
+
+            let raw = self.slice(pos, len)?;
+            pos += len as u64;
+
+            // A corrupt block may hold more than a block's worth, which is dropped
+            let block = if stored & BLOCK_UNCOMPRESSED != 0 {
+                Cow::Borrowed(raw)
+            } else {
+                Cow::Owned(self.decompress(raw, block_size)?)
+            };
+            out.extend_from_slice(&block[..block.len().min(expected)]);
+        }
+
+        if fragment != NO_FRAGMENT && out.len() < size {
+            let tail = size - out.len();
+            let start = fragment_offset as usize;
+            let block = self.fragment_block(fragment)?;
+            let tail = block
+                .get(start..start + tail)
+                .ok_or_else(|| invalid_data(format!("fragment {fragment} is too short")))?;
+            out.extend_from_slice(tail);
+        }
+
+        out.resize(size, 0);
+
+        Ok(out)
+    }
+
+    fn unpack(&mut self, out_dir: &Path) -> io::Result<usize> {
+        self.load_tables()?;
+
+        let root = self.read_inode((self.sb.root_inode >> 16) as u32, self.sb.root_inode as u16)?;
+        let InodeKind::Dir {
+            block,
+            offset,
+            size,
+        } = root.kind
+        else {
+            return Err(invalid_data("root inode is not a directory"));
+        };
+
+        let mut tree = TreeWriter::new(out_dir, "squashfs")?;
+        tree.dir(Path::new(""), root.attrs)?;
+
+        let mut visited = HashSet::from([root.number]);
+        let mut pending: Vec<(PathBuf, u32, u16, u32)> =
+            vec![(PathBuf::new(), block, offset, size)];
+
+        while let Some((dir, block, offset, size)) = pending.pop() {
+            for entry in self.read_dir(block, offset, size)? {
+                let name = match safe_name(&entry.name) {
+                    Ok(name) => name,
+                    Err(err) => {
+                        log::warn!("squashfs: skipping entry in /{}: {err}", dir.display());
+                        continue;
+                    }
+                };
+                let rel = dir.join(name);
+
+                let inode = self.read_inode(entry.inode_block, entry.inode_offset)?;
+
+                if let InodeKind::Dir {
+                    block,
+                    offset,
+                    size,
+                } = inode.kind
+                {
+                    if !visited.insert(inode.number) {
+                        log::warn!("squashfs: directory loop at /{}", rel.display());
+                        continue;
+                    }
+
+                    tree.dir(&rel, inode.attrs)?;
+                    pending.push((rel, block, offset, size));
+                    continue;
+                }
+
+                if let Some(existing) = tree.link_target(u64::from(inode.number), &rel) {
+                    tree.hardlink(&rel, &existing)?;
+                    continue;
+                }
+
+                match inode.kind {
+                    InodeKind::File {
+                        blocks_start,
+                        size,
+                        fragment,
+                        fragment_offset,
+                        ref block_sizes,
+                    } => {
+                        tree.file_with(&rel, inode.attrs, size, || {
+                            self.read_file(
+                                blocks_start,
+                                size,
+                                fragment,
+                                fragment_offset,
+                                block_sizes,
+                            )
+                        })?;
+                    }
+                    InodeKind::Symlink(ref target) => tree.symlink(&rel, inode.attrs, target)?,
+                    InodeKind::Special { kind, rdev } => {
+                        tree.special(&rel, kind, inode.attrs, rdev)?
+                    }
+                    InodeKind::Dir { .. } => unreachable!(),
+                }
+            }
+        }
+
+        log::debug!(
+            "squashfs: {} image, {} compression",
+            self.endian.name(),
+            self.sb.codec.name()
+        );
+
+        tree.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Builds images with uncompressed metadata and data blocks
+    struct Builder {
+        block_log: u16,
+        data: Vec<u8>,
+        inodes: Vec<u8>,
+        dirs: Vec<u8>,
+        count: u32,
+    }
+
+    impl Builder {
+        fn new(block_log: u16) -> Self {
+            Self {
+                block_log,
+                data: Vec::new(),
+                inodes: Vec::new(),
+                dirs: Vec::new(),
+                count: 0,
+            }
+        }
+
+        /// Append a data block, returning its position and size field
+        fn block(&mut self, contents: &[u8]) -> (u32, u32) {
+            let start = (SUPERBLOCK_SIZE + self.data.len()) as u32;
+            self.data.extend_from_slice(contents);
+            (start, contents.len() as u32 | BLOCK_UNCOMPRESSED)
+        }
+
+        /// Append an inode, returning its position in the inode table
+        fn inode(&mut self, inode_type: u16, mode: u16, body: &[u8]) -> usize {
+            self.count += 1;
+            let pos = self.inodes.len();
+            for field in [inode_type, mode, 0, 0] {
+                self.inodes.extend(field.to_le_bytes());
+            }
+            self.inodes.extend(0u32.to_le_bytes());
+            self.inodes.extend(self.count.to_le_bytes());
+            self.inodes.extend_from_slice(body);
+            pos
+        }
+
+        fn file(&mut self, start: u32, size: u32, block_sizes: &[u32]) -> usize {
+            let mut body = Vec::new();
+            for field in [start, NO_FRAGMENT, 0, size] {
+                body.extend(field.to_le_bytes());
+            }
+            for size in block_sizes {
+                body.extend(size.to_le_bytes());
+            }
+            self.inode(2, 0o644, &body)
+        }
+
+        fn symlink(&mut self, target: &str) -> usize {
+            let mut body = Vec::new();
+            body.extend(1u32.to_le_bytes());
+            body.extend((target.len() as u32).to_le_bytes());
+            body.extend(target.as_bytes());
+            self.inode(3, 0o777, &body)
+        }
+
+        /// Append a directory listing of inodes in the first inode metadata block and its
+        /// inode
+        fn dir(&mut self, entries: &[(&str, usize)]) -> usize {
+            let start = self.dirs.len();
+            for field in [entries.len() as u32 - 1, 0, 0] {
+                self.dirs.extend(field.to_le_bytes());
+            }
+            for (name, pos) in entries {
+                for field in [*pos as u16, 0, 1, name.len() as u16 - 1] {
+                    self.dirs.extend(field.to_le_bytes());
+                }
+                self.dirs.extend(name.as_bytes());
+            }
+
+            let mut body = Vec::new();
+            body.extend(0u32.to_le_bytes());
+            body.extend(2u32.to_le_bytes());
+            body.extend(((self.dirs.len() - start + 3) as u16).to_le_bytes());
+            body.extend((start as u16).to_le_bytes());
+            body.extend(0u32.to_le_bytes());
+            self.inode(1, 0o755, &body)
+        }
+
+        fn metadata(stream: &[u8]) -> Vec<u8> {
+            let mut out = Vec::new();
+            for chunk in stream.chunks(METADATA_SIZE) {
+                out.extend((chunk.len() as u16 | METADATA_UNCOMPRESSED).to_le_bytes());
+                out.extend_from_slice(chunk);
+            }
+            out
+        }
+
+        fn build(self, root: usize) -> Vec<u8> {
+            let inode_table = SUPERBLOCK_SIZE + self.data.len();
+            let inodes = Self::metadata(&self.inodes);
+            let directory_table = inode_table + inodes.len();
+            let dirs = Self::metadata(&self.dirs);
+            let ids = Self::metadata(&0u32.to_le_bytes());
+            let id_block = directory_table + dirs.len();
+            let id_table = id_block + ids.len();
+            let bytes_used = id_table + 8;
+
+            let root_ref = ((root / METADATA_SIZE * (METADATA_SIZE + 2)) << 16) as u64
+                | (root % METADATA_SIZE) as u64;
+
+            let mut image = MAGIC_LE.to_vec();
+            image.extend(self.count.to_le_bytes());
+            image.extend(0u32.to_le_bytes());
+            image.extend((1u32 << self.block_log).to_le_bytes());
+            image.extend(0u32.to_le_bytes());
+            for field in [1, self.block_log, 0, 1, 4, 0] {
+                image.extend(field.to_le_bytes());
+            }
+            for field in [
+                root_ref,
+                bytes_used as u64,
+                id_table as u64,
+                u64::MAX,
+                inode_table as u64,
+                directory_table as u64,
+                u64::MAX,
+                u64::MAX,
+            ] {
+                image.extend(field.to_le_bytes());
+            }
+
+            image.extend(self.data);
+            image.extend(inodes);
+            image.extend(dirs);
+            image.extend(ids);
+            image.extend((id_block as u64).to_le_bytes());
+            image
+        }
+    }
+
+    #[test]
+    fn unpacks_files_dirs_and_symlinks() {
+        let mut image = Builder::new(12);
+        let (hello_start, hello) = image.block(b"hello world");
+        let (passwd_start, passwd) = image.block(b"root:x:0:0::/root:/bin/sh\n");
+        let passwd = image.file(passwd_start, 26, &[passwd]);
+        let etc = image.dir(&[("passwd", passwd)]);
+        let hello = image.file(hello_start, 11, &[hello]);
+        let link = image.symlink("hello");
+        let root = image.dir(&[("etc", etc), ("hello", hello), ("link", link)]);
+        let image = image.build(root);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let out = tmp.path().join("root");
+        assert_eq!(unpack_image(&image, &out).unwrap(), Some(5));
+
+        assert_eq!(fs::read(out.join("hello")).unwrap(), b"hello world");
+        assert_eq!(fs::read_link(out.join("link")).unwrap(), Path::new("hello"));
+        assert!(fs::read_to_string(out.join("etc/passwd"))
+            .unwrap()
+            .starts_with("root:"));
+        assert!(unpack_image(&image[1..], &out).unwrap().is_none());
+    }
+
+    #[test]
+    fn clamps_oversized_uncompressed_blocks() {
+        let mut image = Builder::new(12);
+        let (start, first) = image.block(&[b'a'; 8000]);
+        let (_, second) = image.block(&[b'b'; 904]);
+        let file = image.file(start, 5000, &[first, second]);
+        let root = image.dir(&[("file", file)]);
+        let image = image.build(root);
+
+        let tmp = tempfile::tempdir().unwrap();
+        unpack_image(&image, tmp.path()).unwrap();
+
+        let contents = fs::read(tmp.path().join("file")).unwrap();
+        assert_eq!(contents.len(), 5000);
+        assert!(contents[..4096].iter().all(|&b| b == b'a'));
+        assert!(contents[4096..].iter().all(|&b| b == b'b'));
+    }
+
+    #[test]
+    fn extracts_images_found_anywhere_in_the_input() {
+        let mut image = Builder::new(12);
+        let (start, block) = image.block(b"x");
+        let file = image.file(start, 1, &[block]);
+        let root = image.dir(&[("file", file)]);
+        let image = image.build(root);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let firmware = tmp.path().join("firmware.bin");
+        let mut contents = vec![0xff; 0x100];
+        contents.extend_from_slice(&image);
+        contents.extend_from_slice(b"sqsh trailing garbage");
+        fs::write(&firmware, contents).unwrap();
+
+        let extract_dir = tmp.path().join("out");
+        let log_file = tmp.path().join("squashfs.log");
+        let status = SquashfsExtractor
+            .extract(&firmware, &extract_dir, &log_file, false)
+            .unwrap();
+        assert_eq!(status, ExtractStatus::Completed);
+        assert_eq!(
+            fs::read(extract_dir.join("squashfs-0x100/file")).unwrap(),
+            b"x"
+        );
+        assert!(fs::read_to_string(&log_file)
+            .unwrap()
+            .contains("squashfs at 0x100"));
+
+        fs::write(&firmware, b"nothing to see here").unwrap();
+        let result = SquashfsExtractor.extract(&firmware, &extract_dir, &log_file, false);
+        assert!(matches!(result, Err(ExtractError::NotFound("squashfs"))));
+    }
+
+    #[test]
+    fn rejects_huge_sparse_files() {
+        // 2 GiB of sparse 1 MiB blocks, described in 8 KiB
+        let mut image = Builder::new(20);
+        let file = image.file(SUPERBLOCK_SIZE as u32, 1 << 31, &[0; 2048]);
+        let root = image.dir(&[("sparse", file)]);
+        let image = image.build(root);
+
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(unpack_image(&image, tmp.path()).is_err());
+    }
+}
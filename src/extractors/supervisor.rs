@@ -0,0 +1,330 @@
+//! Supervision of the external processes run by extractors.
+//!
+//! Every child is started in its own process group so that it, and anything it spawns, can be
+//! killed as a unit when it times out, when the user presses Ctrl-C, or once it has exited
+//! (so stray grandchildren cannot keep the output pipes open). The group is always killed
+//! before the child is reaped, while its id cannot have been reused. stdout and stderr are
+//! drained on separate threads while waiting, so a chatty child can never block on a full
+//! pipe.
+//! With `--sandbox`, the child is also confined to its [`Workspace`], and its output directory
+//! is measured against the [`quota`]s as it runs.
+
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::quota;
+use super::sandbox::{self, Plan, Workspace};
+
+/// How often a waiting supervisor checks for Ctrl-C
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for the rest of the output once the process group is gone. A descendant
+/// that moved to a process group of its own can hold the pipes open indefinitely.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// The output directory is measured against the quotas at most this many times as often as
+/// it takes to walk it, to keep the overhead small on large trees
+const MEASURE_BACKOFF: u32 = 10;
+
+/// Resource limits applied to every child process. `None` leaves the limit unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Address space, in bytes
+    pub memory: Option<u64>,
+    /// CPU time, in seconds
+    pub cpu: Option<u64>,
+    /// Size of any single file written, in bytes
+    pub file_size: Option<u64>,
+}
+
+static LIMITS: RwLock<Limits> = RwLock::new(Limits {
+    memory: None,
+    cpu: None,
+    file_size: None,
+});
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_limits(limits: Limits) {
+    *LIMITS.write().unwrap() = limits;
+}
+
+/// Whether Ctrl-C (or SIGTERM) has been received since [`install_interrupt_handler`]
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    // A second Ctrl-C gives up on a clean shutdown
+    if INTERRUPTED.swap(true, Ordering::Relaxed) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Catch Ctrl-C and SIGTERM so running children are killed and the run stops cleanly. As
+/// children run in their own process groups, the terminal's SIGINT would not reach them.
+pub fn install_interrupt_handler() {
+    let handler = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// A child process that has finished, one way or another.
+#[derive(Debug)]
+pub struct Finished {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The child was killed because it ran past its timeout
+    pub timed_out: bool,
+    /// The child was killed because of Ctrl-C
+    pub interrupted: bool,
+    /// The child wrote more than its quota allows, and was killed if still running
+    pub exceeded_quota: bool,
+}
+
+impl Finished {
+    /// The signal that terminated the child, other than one sent by the supervisor
+    pub fn signal(&self) -> Option<i32> {
+        if self.timed_out || self.interrupted || self.exceeded_quota {
+            None
+        } else {
+            self.status.signal()
+        }
+    }
+}
+
+fn kill_group(child: &Child) {
+    // The child leads its process group, so the group id is its pid, which stays reserved
+    // until the child is reaped. ESRCH once the whole group is gone is expected.
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+}
+
+/// Wait up to `timeout` for the child to exit, without reaping it. Returns whether it exited.
+fn wait_exit(child: &Child, timeout: Duration) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    let mut sleep = Duration::from_millis(1);
+
+    loop {
+        // SAFETY: siginfo_t is plain data, and waitid only writes to it
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, child.id(), &mut info, flags) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        // With WNOHANG, si_pid is left zero while the child is still running
+        if unsafe { info.si_pid() } != 0 {
+            return Ok(true);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(sleep.min(deadline - now));
+        sleep = (sleep * 2).min(POLL_INTERVAL);
+    }
+}
+
+/// Output of a pipe, read on its own thread
+struct Drain {
+    output: Arc<Mutex<Vec<u8>>>,
+    done: mpsc::Receiver<()>,
+}
+
+impl Drain {
+    fn start(pipe: Option<impl Read + Send + 'static>) -> Self {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let (done_tx, done) = mpsc::channel();
+
+        let buf = output.clone();
+        thread::spawn(move || {
+            if let Some(mut pipe) = pipe {
+                let mut chunk = [0; 8192];
+                while let Ok(len @ 1..) = pipe.read(&mut chunk) {
+                    buf.lock().unwrap().extend_from_slice(&chunk[..len]);
+                }
+            }
+            let _ = done_tx.send(());
+        });
+
+        Self { output, done }
+    }
+
+    /// Everything read until the pipe closed, or until `deadline` if it stays open
+    fn finish(self, deadline: Instant) -> Vec<u8> {
+        let _ = self
+            .done
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()));
+        mem::take(&mut *self.output.lock().unwrap())
+    }
+}
+
+/// Run `command` on the files of `workspace` to completion, killing its process group if it is
+/// still running after `timeout`.
+pub fn run(
+    mut command: Command,
+    workspace: &Workspace,
+    timeout: Option<Duration>,
+) -> io::Result<Finished> {
+    let limits = *LIMITS.read().unwrap();
+    let plan = match sandbox::enabled() {
+        true => Some(Arc::new(Plan::new(workspace)?)),
+        false => None,
+    };
+
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+
+    // SAFETY: setrlimit and the calls made by `Plan::apply` are async-signal-safe, and the
+    // closure does not allocate
+    let child_plan = plan.clone();
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, limit) in [
+                (libc::RLIMIT_AS, limits.memory),
+                (libc::RLIMIT_CPU, limits.cpu),
+                (libc::RLIMIT_FSIZE, limits.file_size),
+            ] {
+                if let Some(limit) = limit {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit as libc::rlim_t,
+                        rlim_max: limit as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+            match &child_plan {
+                Some(plan) => plan.apply(),
+                None => Ok(()),
+            }
+        });
+    }
+    if plan.is_some() {
+        command.env("HOME", "/tmp").env("TMPDIR", "/tmp");
+    }
+
+    let mut child = command.spawn().map_err(|e| match plan {
+        Some(_) if e.raw_os_error() != Some(libc::ENOENT) => io::Error::new(
+            e.kind(),
+            format!("could not start sandboxed extractor (are unprivileged user namespaces enabled?): {e}"),
+        ),
+        _ => e,
+    })?;
+    let stdout = Drain::start(child.stdout.take());
+    let stderr = Drain::start(child.stderr.take());
+
+    let start = Instant::now();
+    let mut next_measure = start;
+    let mut timed_out = false;
+    let mut interrupted = false;
+    let mut exceeded_quota = false;
+
+    loop {
+        if wait_exit(&child, POLL_INTERVAL)? {
+            break;
+        }
+
+        interrupted = self::interrupted();
+        timed_out = timeout.is_some_and(|timeout| start.elapsed() >= timeout);
+        if Instant::now() >= next_measure {
+            let measure_start = Instant::now();
+            exceeded_quota = quota::measure(workspace.extract_dir);
+            next_measure = Instant::now() + measure_start.elapsed() * MEASURE_BACKOFF;
+        }
+        if interrupted || timed_out || exceeded_quota {
+            break;
+        }
+    }
+
+    // Stop the child if it is still running, and take down anything it left running, which
+    // would otherwise hold the pipes open
+    kill_group(&child);
+    let status = child.wait()?;
+    exceeded_quota = quota::measure(workspace.extract_dir);
+
+    let drain_deadline = Instant::now() + DRAIN_TIMEOUT;
+    Ok(Finished {
+        status,
+        stdout: stdout.finish(drain_deadline),
+        stderr: stderr.finish(drain_deadline),
+        timed_out,
+        interrupted,
+        exceeded_quota,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn run_script(script: &str, timeout: Option<Duration>) -> Finished {
+        let tmp = tempfile::tempdir().unwrap();
+        let workspace = Workspace {
+            input: &tmp.path().join("image.bin"),
+            extract_dir: tmp.path(),
+            log_file: &tmp.path().join("extract.log"),
+        };
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        run(command, &workspace, timeout).unwrap()
+    }
+
+    /// Whether `pid` has exited, whether or not it has been reaped yet
+    fn is_dead(pid: &str) -> bool {
+        let stat = fs::read_to_string(Path::new("/proc").join(pid).join("stat"));
+        stat.map_or(true, |stat| {
+            stat.rsplit(')')
+                .next()
+                .is_some_and(|rest| rest.trim_start().starts_with('Z'))
+        })
+    }
+
+    #[test]
+    fn collects_output_and_exit_status() {
+        let finished = run_script("echo out; echo err >&2; exit 3", None);
+        assert_eq!(finished.status.code(), Some(3));
+        assert_eq!(finished.stdout, b"out\n");
+        assert_eq!(finished.stderr, b"err\n");
+        assert!(!finished.timed_out && !finished.interrupted && !finished.exceeded_quota);
+    }
+
+    #[test]
+    fn timeout_kills_the_process_group() {
+        let start = Instant::now();
+        let finished = run_script("sleep 60 & echo $!; wait", Some(Duration::from_millis(300)));
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        assert!(finished.timed_out);
+        assert_eq!(finished.status.signal(), Some(libc::SIGKILL));
+        assert_eq!(finished.signal(), None);
+
+        // The background sleep was in the same group
+        let grandchild = String::from_utf8(finished.stdout).unwrap();
+        let grandchild = grandchild.trim();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !is_dead(grandchild) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(is_dead(grandchild), "process {grandchild} survived");
+    }
+}
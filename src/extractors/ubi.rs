@@ -0,0 +1,494 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::fstree::safe_name;
+use super::native::{self, crc32_le, Endian, Unpacked};
+use super::ubifs::{self, Volume};
+use super::{quota, squashfs, ExtractError, ExtractStatus, Extractor};
+
+const EC_MAGIC: &[u8] = b"UBI#";
+const VID_MAGIC: &[u8] = b"UBI!";
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+
+const HEADER_LEN: usize = 64;
+const CRC32_INIT: u32 = 0xffff_ffff;
+
+/// Volume IDs from here on are internal to UBI; the first is the layout volume holding the
+/// volume table
+const LAYOUT_VOLUME_ID: u32 = 0x7fff_efff;
+const VTBL_RECORD_LEN: usize = 172;
+const MAX_VOLUMES: usize = 128;
+
+const VID_STATIC: u8 = 2;
+
+const MIN_PEB_SIZE: usize = 4 * 1024;
+const MAX_PEB_SIZE: usize = 4 * 1024 * 1024;
+
+const BE: Endian = Endian::Big;
+
+/// In-process reader for UBI images and the UBIFS volumes inside them (as well as bare UBIFS
+/// images). Each volume is unpacked into its own directory.
+pub struct UbiExtractor;
+
+impl Extractor for UbiExtractor {
+    fn name(&self) -> &'static str {
+        "ubi"
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        _verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        native::extract_all(
+            self.name(),
+            in_file,
+            extract_dir,
+            log_file,
+            &[EC_MAGIC, ubifs::NODE_MAGIC],
+            |data, offset, out_dir| {
+                let data = &data[offset..];
+
+                if data.starts_with(EC_MAGIC) {
+                    let Some(image) = Image::open(data) else {
+                        return Ok(None);
+                    };
+
+                    return image.unpack(out_dir).map(Some);
+                }
+
+                // A bare UBIFS image, as produced by mkfs.ubifs before ubinize
+                let Some((leb_size, leb_count)) = ubifs::image_geometry(data) else {
+                    return Ok(None);
+                };
+
+                let len = data.len().min(leb_size * leb_count);
+                let volume = Volume {
+                    lebs: data[..len].chunks(leb_size).map(Some).collect(),
+                };
+
+                let mut warnings = Vec::new();
+                let entries = ubifs::unpack(&volume, out_dir, &mut warnings)?;
+
+                Ok(Some(Unpacked {
+                    len,
+                    entries,
+                    warnings,
+                }))
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EcHeader {
+    vid_hdr_offset: usize,
+    data_offset: usize,
+}
+
+impl EcHeader {
+    fn parse(peb: &[u8]) -> Option<Self> {
+        let header = peb
+            .get(..HEADER_LEN)
+            .filter(|header| header.starts_with(EC_MAGIC))?;
+        if crc32_le(CRC32_INIT, &header[..60]) != BE.u32(&header[60..64]) {
+            return None;
+        }
+
+        Some(Self {
+            vid_hdr_offset: BE.u32(&header[16..20]) as usize,
+            data_offset: BE.u32(&header[20..24]) as usize,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct VidHeader {
+    vol_type: u8,
+    copy_flag: bool,
+    vol_id: u32,
+    lnum: u32,
+    data_size: usize,
+    data_pad: usize,
+    data_crc: u32,
+    sqnum: u64,
+}
+
+impl VidHeader {
+    fn parse(peb: &[u8], ec: EcHeader) -> Option<Self> {
+        let header = peb
+            .get(ec.vid_hdr_offset..ec.vid_hdr_offset.checked_add(HEADER_LEN)?)
+            .filter(|header| header.starts_with(VID_MAGIC))?;
+        if crc32_le(CRC32_INIT, &header[..60]) != BE.u32(&header[60..64]) {
+            return None;
+        }
+
+        Some(Self {
+            vol_type: header[5],
+            copy_flag: header[6] != 0,
+            vol_id: BE.u32(&header[8..12]),
+            lnum: BE.u32(&header[12..16]),
+            data_size: BE.u32(&header[20..24]) as usize,
+            data_pad: BE.u32(&header[28..32]) as usize,
+            data_crc: BE.u32(&header[32..36]),
+            sqnum: BE.u64(&header[40..48]),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct VolumeInfo {
+    name: String,
+    update_marker: bool,
+    /// Upper bound on the volume's LEB numbers
+    reserved_pebs: usize,
+}
+
+struct Image<'a> {
+    data: &'a [u8],
+    peb_size: usize,
+    /// Contents of the newest copy of every LEB, by volume and LEB number
+    lebs: HashMap<u32, BTreeMap<u32, (u64, &'a [u8])>>,
+    /// LEB size of every volume: what is left of a PEB after the headers and the volume's
+    /// alignment padding
+    leb_sizes: HashMap<u32, usize>,
+    static_volumes: Vec<u32>,
+}
+
+impl<'a> Image<'a> {
+    fn open(data: &'a [u8]) -> Option<Self> {
+        EcHeader::parse(data)?;
+
+        // The PEB size is the distance to the next erase counter header
+        let peb_size = (MIN_PEB_SIZE.trailing_zeros()..=MAX_PEB_SIZE.trailing_zeros())
+            .map(|shift| 1 << shift)
+            .find(|&size| data.get(size..).and_then(EcHeader::parse).is_some())?;
+
+        let mut image = Self {
+            data,
+            peb_size,
+            lebs: HashMap::new(),
+            leb_sizes: HashMap::new(),
+            static_volumes: Vec::new(),
+        };
+
+        let mut len = 0;
+        for peb in data.chunks_exact(peb_size) {
+            match EcHeader::parse(peb) {
+                Some(ec) => image.add_peb(peb, ec),
+                // Erased blocks are part of the image, anything else ends it
+                None if peb[..HEADER_LEN].iter().all(|&byte| byte == 0xff) => {}
+                None => break,
+            }
+
+            len += peb_size;
+        }
+
+        image.data = &data[..len];
+
+        Some(image)
+    }
+
+    fn add_peb(&mut self, peb: &'a [u8], ec: EcHeader) {
+        let Some(vid) = VidHeader::parse(peb, ec) else {
+            return;
+        };
+
+        let end = self.peb_size.saturating_sub(vid.data_pad);
+        let Some(mut contents) = peb.get(ec.data_offset..end) else {
+            return;
+        };
+        self.leb_sizes.insert(vid.vol_id, contents.len());
+
+        if vid.vol_type == VID_STATIC {
+            contents = &contents[..vid.data_size.min(contents.len())];
+            if !self.static_volumes.contains(&vid.vol_id) {
+                self.static_volumes.push(vid.vol_id);
+            }
+        }
+
+        // Copies made by wear-leveling carry a data CRC, which tells whether the copy finished
+        if vid.copy_flag
+            && contents
+                .get(..vid.data_size)
+                .is_none_or(|copied| crc32_le(CRC32_INIT, copied) != vid.data_crc)
+        {
+            return;
+        }
+
+        let volume = self.lebs.entry(vid.vol_id).or_default();
+        if volume
+            .get(&vid.lnum)
+            .is_none_or(|(sqnum, _)| *sqnum < vid.sqnum)
+        {
+            volume.insert(vid.lnum, (vid.sqnum, contents));
+        }
+    }
+
+    /// Parse the volume table stored in the layout volume
+    fn volume_table(&self) -> BTreeMap<u32, VolumeInfo> {
+        let mut table = BTreeMap::new();
+        let Some(layout) = self.lebs.get(&LAYOUT_VOLUME_ID) else {
+            return table;
+        };
+
+        // The layout volume holds two copies of the table; use the first intact one
+        for (_, leb) in layout.values() {
+            for (vol_id, record) in leb
+                .chunks_exact(VTBL_RECORD_LEN)
+                .take(MAX_VOLUMES)
+                .enumerate()
+            {
+                let reserved_pebs = BE.u32(&record[0..4]);
+                if reserved_pebs == 0
+                    || crc32_le(CRC32_INIT, &record[..168]) != BE.u32(&record[168..172])
+                {
+                    continue;
+                }
+
+                let name_len = (BE.u16(&record[14..16]) as usize).min(128);
+                let name = String::from_utf8_lossy(&record[16..16 + name_len]).into_owned();
+
+                table.entry(vol_id as u32).or_insert(VolumeInfo {
+                    name,
+                    update_marker: record[13] != 0,
+                    reserved_pebs: reserved_pebs as usize,
+                });
+            }
+
+            if !table.is_empty() {
+                break;
+            }
+        }
+
+        table
+    }
+
+    fn unpack(&self, out_dir: &Path) -> io::Result<Unpacked> {
+        fs::create_dir_all(out_dir)?;
+
+        let mut table = self.volume_table();
+        let mut warnings = Vec::new();
+        let mut entries = 0;
+
+        if table.is_empty() {
+            warnings.push("no volume table found, volumes are named by ID".to_owned());
+        }
+
+        // Volumes with data but without a table record still get extracted
+        let peb_count = self.data.len() / self.peb_size;
+        for &vol_id in self
+            .lebs
+            .keys()
+            .filter(|&&vol_id| vol_id < LAYOUT_VOLUME_ID)
+        {
+            table.entry(vol_id).or_insert_with(|| VolumeInfo {
+                name: format!("volume-{vol_id}"),
+                update_marker: false,
+                reserved_pebs: peb_count,
+            });
+        }
+
+        for (vol_id, info) in &table {
+            let name = match safe_name(info.name.as_bytes()) {
+                Ok(name) => name.to_string_lossy().into_owned(),
+                Err(_) => format!("volume-{vol_id}"),
+            };
+
+            if info.update_marker {
+                warnings.push(format!(
+                    "volume {name}: update was interrupted, contents may be incomplete"
+                ));
+            }
+
+            let Some(lebs) = self.lebs.get(vol_id) else {
+                log::debug!("ubi: volume {name} is empty");
+                continue;
+            };
+
+            // LEB numbers come from the VID headers; a volume can't have more LEBs than PEBs
+            let leb_limit = info.reserved_pebs.min(peb_count);
+            if let Some(lnum) = lebs.keys().find(|&&lnum| lnum as usize >= leb_limit) {
+                warnings.push(format!(
+                    "volume {name}: ignoring LEBs from {lnum} on, past the {leb_limit} the volume can hold"
+                ));
+            }
+
+            match self.unpack_volume(
+                *vol_id,
+                lebs,
+                leb_limit,
+                &out_dir.join(&name),
+                &mut warnings,
+            ) {
+                Ok(count) => entries += count,
+                Err(err) => warnings.push(format!("volume {name}: {err}")),
+            }
+        }
+
+        log::debug!(
+            "ubi: {} PEBs of {:#x} bytes, {} volumes",
+            self.data.len() / self.peb_size,
+            self.peb_size,
+            table.len()
+        );
+
+        Ok(Unpacked {
+            len: self.data.len(),
+            entries,
+            warnings,
+        })
+    }
+
+    /// Unpack a volume into `dir` if it holds a filesystem we can read, or else save its raw
+    /// contents as `dir.bin`. Returns the number of entries written.
+    fn unpack_volume(
+        &self,
+        vol_id: u32,
+        lebs: &BTreeMap<u32, (u64, &'a [u8])>,
+        leb_limit: usize,
+        dir: &Path,
+        warnings: &mut Vec<String>,
+    ) -> io::Result<usize> {
+        let leb_count = lebs
+            .keys()
+            .rfind(|&&lnum| (lnum as usize) < leb_limit)
+            .map_or(0, |&last| last as usize + 1);
+        let volume = Volume {
+            lebs: (0..leb_count as u32)
+                .map(|lnum| lebs.get(&lnum).map(|&(_, leb)| leb))
+                .collect(),
+        };
+
+        if volume
+            .lebs
+            .first()
+            .copied()
+            .flatten()
+            .is_some_and(ubifs::is_ubifs)
+        {
+            return ubifs::unpack(&volume, dir, warnings);
+        }
+
+        // Unmapped LEBs of dynamic volumes read as erased flash
+        let leb_size = self.leb_sizes[&vol_id];
+        let mut contents = Vec::new();
+        for leb in &volume.lebs {
+            match leb {
+                Some(leb) => contents.extend_from_slice(leb),
+                None if !self.static_volumes.contains(&vol_id) => {
+                    contents.resize(contents.len() + leb_size, 0xff)
+                }
+                None => {}
+            }
+        }
+
+        if contents.starts_with(SQUASHFS_MAGIC) {
+            if let Some(count) = squashfs::unpack_image(&contents, dir)? {
+                return Ok(count);
+            }
+        }
+
+        let mut raw = dir.as_os_str().to_owned();
+        raw.push(".bin");
+        quota::charge(Path::new(&raw), contents.len() as u64)?;
+        fs::write(raw, contents)?;
+
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEB_SIZE: usize = MIN_PEB_SIZE;
+    const VID_HDR_OFFSET: usize = 64;
+    const DATA_OFFSET: usize = 128;
+
+    fn header(magic: &[u8], fields: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut header = vec![0; HEADER_LEN];
+        header[..4].copy_from_slice(magic);
+        for &(offset, value) in fields {
+            header[offset..offset + value.len()].copy_from_slice(value);
+        }
+        let crc = crc32_le(CRC32_INIT, &header[..60]);
+        header[60..].copy_from_slice(&crc.to_be_bytes());
+        header
+    }
+
+    /// A PEB holding LEB `lnum` of a dynamic volume
+    fn peb(vol_id: u32, lnum: u32, data_pad: usize, sqnum: u64, contents: &[u8]) -> Vec<u8> {
+        let mut peb = vec![0xff; PEB_SIZE];
+
+        let ec = header(
+            EC_MAGIC,
+            &[
+                (16, &(VID_HDR_OFFSET as u32).to_be_bytes()),
+                (20, &(DATA_OFFSET as u32).to_be_bytes()),
+            ],
+        );
+        peb[..HEADER_LEN].copy_from_slice(&ec);
+
+        let vid = header(
+            VID_MAGIC,
+            &[
+                (5, &[1]),
+                (8, &vol_id.to_be_bytes()),
+                (12, &lnum.to_be_bytes()),
+                (28, &(data_pad as u32).to_be_bytes()),
+                (40, &sqnum.to_be_bytes()),
+            ],
+        );
+        peb[VID_HDR_OFFSET..VID_HDR_OFFSET + HEADER_LEN].copy_from_slice(&vid);
+
+        peb[DATA_OFFSET..DATA_OFFSET + contents.len()].copy_from_slice(contents);
+        peb
+    }
+
+    fn volume_table(name: &str, reserved_pebs: u32) -> Vec<u8> {
+        let mut record = vec![0; VTBL_RECORD_LEN];
+        record[0..4].copy_from_slice(&reserved_pebs.to_be_bytes());
+        record[14..16].copy_from_slice(&(name.len() as u16).to_be_bytes());
+        record[16..16 + name.len()].copy_from_slice(name.as_bytes());
+        let crc = crc32_le(CRC32_INIT, &record[..168]);
+        record[168..].copy_from_slice(&crc.to_be_bytes());
+        record
+    }
+
+    #[test]
+    fn fills_unmapped_lebs_and_bounds_lnums() {
+        let data_pad = 8;
+        let leb_size = PEB_SIZE - DATA_OFFSET - data_pad;
+
+        let image = [
+            peb(LAYOUT_VOLUME_ID, 0, 0, 1, &volume_table("rootfs", 3)),
+            peb(0, 0, data_pad, 2, &vec![b'a'; leb_size]),
+            peb(0, 2, data_pad, 3, &vec![b'c'; leb_size]),
+            // A LEB number far past what the volume could hold
+            peb(0, 0x00ff_ffff, data_pad, 4, b"bogus"),
+        ]
+        .concat();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let unpacked = Image::open(&image).unwrap().unpack(tmp.path()).unwrap();
+        assert_eq!(unpacked.len, image.len());
+        assert!(
+            unpacked.warnings[0].starts_with("volume rootfs: ignoring LEBs from 16777215 on"),
+            "{:?}",
+            unpacked.warnings
+        );
+
+        let contents = fs::read(tmp.path().join("rootfs.bin")).unwrap();
+        assert_eq!(contents.len(), 3 * leb_size);
+        assert!(contents[..leb_size].iter().all(|&byte| byte == b'a'));
+        assert!(contents[leb_size..2 * leb_size]
+            .iter()
+            .all(|&byte| byte == 0xff));
+        assert!(contents[2 * leb_size..].iter().all(|&byte| byte == b'c'));
+    }
+}
@@ -0,0 +1,656 @@
+//! UBIFS reader used by the UBI extractor, both for UBIFS volumes inside a UBI image and for
+//! bare UBIFS images.
+//!
+//! The committed state is read by walking the index B-tree from the root recorded in the
+//! master node; nodes written to the journal since that commit are then replayed on top.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::compression::Codec;
+use super::fstree::{safe_name, Attrs, EntryKind, TreeWriter};
+use super::native::{crc32_le, invalid_data, Endian};
+
+pub const NODE_MAGIC: &[u8] = &[0x31, 0x18, 0x10, 0x06];
+
+const COMMON_HEADER_LEN: usize = 24;
+const CRC32_INIT: u32 = 0xffff_ffff;
+/// Nodes are aligned to 8 bytes within a LEB
+const NODE_ALIGN: usize = 8;
+const PADDING_BYTE: u8 = 0xce;
+
+const INO_NODE: u8 = 0;
+const DATA_NODE: u8 = 1;
+const DENT_NODE: u8 = 2;
+const XENT_NODE: u8 = 3;
+const PAD_NODE: u8 = 5;
+const SB_NODE: u8 = 6;
+const MST_NODE: u8 = 7;
+const REF_NODE: u8 = 8;
+const IDX_NODE: u8 = 9;
+const CS_NODE: u8 = 10;
+
+const INO_NODE_LEN: usize = 160;
+const DENT_NODE_LEN: usize = 56;
+const DATA_NODE_LEN: usize = 48;
+const IDX_NODE_LEN: usize = 28;
+/// Index branch: LEB number, offset, length and an 8 byte key
+const BRANCH_LEN: usize = 20;
+
+const DATA_KEY: u32 = 1;
+const KEY_TYPE_SHIFT: u32 = 29;
+
+const SB_LNUM: usize = 0;
+const MST_LNUM: usize = 1;
+const LOG_LNUM: usize = 3;
+
+const ROOT_INO: u64 = 1;
+const BLOCK_SIZE: usize = 4096;
+const MAX_INDEX_LEVEL: u16 = 64;
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+const COMPR_NONE: u16 = 0;
+const COMPR_LZO: u16 = 1;
+const COMPR_ZLIB: u16 = 2;
+const COMPR_ZSTD: u16 = 3;
+
+const LE: Endian = Endian::Little;
+
+/// Logical erase blocks of a volume. Unmapped LEBs are `None`.
+pub struct Volume<'a> {
+    pub lebs: Vec<Option<&'a [u8]>>,
+}
+
+/// Whether `data` (the first LEB of a volume, or a bare image) starts with a UBIFS superblock
+pub fn is_ubifs(data: &[u8]) -> bool {
+    parse_node(data, 0).is_some_and(|node| node.node_type == SB_NODE)
+}
+
+/// LEB size and count recorded in the superblock at the start of a bare UBIFS image
+pub fn image_geometry(data: &[u8]) -> Option<(usize, usize)> {
+    let node = parse_node(data, 0).filter(|node| node.node_type == SB_NODE)?;
+    let leb_size = LE.u32(node.data.get(36..40)?) as usize;
+    let leb_count = LE.u32(node.data.get(40..44)?) as usize;
+
+    (leb_size >= 4096 && leb_size.is_multiple_of(NODE_ALIGN) && leb_count > 0)
+        .then_some((leb_size, leb_count))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node<'a> {
+    node_type: u8,
+    sqnum: u64,
+    /// The whole node, including the common header
+    data: &'a [u8],
+}
+
+/// Parse the node at `offs` of a LEB, checking its magic, length and CRC
+fn parse_node(leb: &[u8], offs: usize) -> Option<Node<'_>> {
+    let header = leb.get(offs..offs.checked_add(COMMON_HEADER_LEN)?)?;
+    if !header.starts_with(NODE_MAGIC) {
+        return None;
+    }
+
+    let crc = LE.u32(&header[4..8]);
+    let sqnum = LE.u64(&header[8..16]);
+    let len = LE.u32(&header[16..20]) as usize;
+    let node_type = header[20];
+
+    let data = leb
+        .get(offs..offs.checked_add(len)?)
+        .filter(|_| len >= COMMON_HEADER_LEN)?;
+    if crc32_le(CRC32_INIT, &data[8..]) != crc {
+        return None;
+    }
+
+    Some(Node {
+        node_type,
+        sqnum,
+        data,
+    })
+}
+
+/// Inode number, key type and remaining key bits of the key stored at the start of a node body
+fn node_key(node: &Node) -> io::Result<(u64, u32, u32)> {
+    let key = node
+        .data
+        .get(COMMON_HEADER_LEN..COMMON_HEADER_LEN + 8)
+        .ok_or_else(|| invalid_data("node too short for its key"))?;
+    let inum = u64::from(LE.u32(&key[0..4]));
+    let value = LE.u32(&key[4..8]);
+    Ok((
+        inum,
+        value >> KEY_TYPE_SHIFT,
+        value & ((1 << KEY_TYPE_SHIFT) - 1),
+    ))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Inode<'a> {
+    size: u64,
+    nlink: u32,
+    attrs: Attrs,
+    mode: u32,
+    /// Symlink target, device number or extended attribute value
+    data: &'a [u8],
+}
+
+impl Inode<'_> {
+    fn parse<'a>(node: &Node<'a>) -> io::Result<Inode<'a>> {
+        let body = node.data;
+        if body.len() < INO_NODE_LEN {
+            return Err(invalid_data("truncated inode node"));
+        }
+
+        let data_len = LE.u32(&body[112..116]) as usize;
+        let mode = LE.u32(&body[104..108]);
+
+        Ok(Inode {
+            size: LE.u64(&body[48..56]),
+            nlink: LE.u32(&body[92..96]),
+            attrs: Attrs::new(mode, LE.u32(&body[96..100]), LE.u32(&body[100..104])),
+            mode,
+            data: body
+                .get(INO_NODE_LEN..INO_NODE_LEN + data_len)
+                .ok_or_else(|| invalid_data("inode data out of bounds"))?,
+        })
+    }
+
+    fn kind(&self) -> Option<EntryKind> {
+        match self.mode & libc::S_IFMT {
+            libc::S_IFDIR => Some(EntryKind::Dir),
+            libc::S_IFREG => Some(EntryKind::File),
+            libc::S_IFLNK => Some(EntryKind::Symlink),
+            libc::S_IFCHR => Some(EntryKind::Char),
+            libc::S_IFBLK => Some(EntryKind::Block),
+            libc::S_IFIFO => Some(EntryKind::Fifo),
+            libc::S_IFSOCK => Some(EntryKind::Socket),
+            _ => None,
+        }
+    }
+}
+
+/// Latest version of every indexed node, keyed the way UBIFS keys them
+#[derive(Default)]
+struct Entries<'a> {
+    inodes: HashMap<u64, (u64, Inode<'a>)>,
+    data: HashMap<(u64, u32), (u64, Node<'a>)>,
+    /// Directory entries by `(parent, name)`; a target of 0 records a deletion
+    dents: HashMap<(u64, &'a [u8]), (u64, u64)>,
+    /// Extended attribute entries by `(host, name)`, pointing at the inode holding the value
+    xents: HashMap<(u64, &'a [u8]), (u64, u64)>,
+}
+
+impl<'a> Entries<'a> {
+    fn apply(&mut self, node: Node<'a>) -> io::Result<()> {
+        let sqnum = node.sqnum;
+
+        match node.node_type {
+            INO_NODE => {
+                let (inum, _, _) = node_key(&node)?;
+                let inode = Inode::parse(&node)?;
+                if self.inodes.get(&inum).is_none_or(|(seen, _)| *seen < sqnum) {
+                    self.inodes.insert(inum, (sqnum, inode));
+                }
+            }
+            DATA_NODE => {
+                let (inum, key_type, block) = node_key(&node)?;
+                if key_type != DATA_KEY || node.data.len() < DATA_NODE_LEN {
+                    return Err(invalid_data("malformed data node"));
+                }
+                if self
+                    .data
+                    .get(&(inum, block))
+                    .is_none_or(|(seen, _)| *seen < sqnum)
+                {
+                    self.data.insert((inum, block), (sqnum, node));
+                }
+            }
+            DENT_NODE | XENT_NODE => {
+                let (parent, _, _) = node_key(&node)?;
+                let body = node.data;
+                if body.len() < DENT_NODE_LEN {
+                    return Err(invalid_data("truncated directory entry node"));
+                }
+
+                let target = LE.u64(&body[40..48]);
+                let name_len = LE.u16(&body[50..52]) as usize;
+                let name = body
+                    .get(DENT_NODE_LEN..DENT_NODE_LEN + name_len)
+                    .ok_or_else(|| invalid_data("directory entry name out of bounds"))?;
+
+                let map = if node.node_type == DENT_NODE {
+                    &mut self.dents
+                } else {
+                    &mut self.xents
+                };
+                if map
+                    .get(&(parent, name))
+                    .is_none_or(|(seen, _)| *seen < sqnum)
+                {
+                    map.insert((parent, name), (sqnum, target));
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+struct Fs<'v, 'a> {
+    volume: &'v Volume<'a>,
+    log_lebs: usize,
+    entries: Entries<'a>,
+    warnings: Vec<String>,
+}
+
+impl<'a> Fs<'_, 'a> {
+    fn leb(&self, lnum: usize) -> io::Result<&'a [u8]> {
+        self.volume
+            .lebs
+            .get(lnum)
+            .copied()
+            .flatten()
+            .ok_or_else(|| invalid_data(format!("LEB {lnum} is not mapped")))
+    }
+
+    fn node(&self, lnum: usize, offs: usize) -> io::Result<Node<'a>> {
+        parse_node(self.leb(lnum)?, offs)
+            .ok_or_else(|| invalid_data(format!("no valid node at LEB {lnum}:{offs:#x}")))
+    }
+
+    /// Valid nodes written sequentially from `offs` of a LEB, up to free space or corruption
+    fn scan_leb(&self, lnum: usize, mut offs: usize) -> Vec<Node<'a>> {
+        let Ok(leb) = self.leb(lnum) else {
+            return Vec::new();
+        };
+
+        let mut nodes = Vec::new();
+        loop {
+            // Gaps too small for a padding node are filled with padding bytes instead
+            while leb.get(offs) == Some(&PADDING_BYTE) {
+                offs += 1;
+            }
+
+            let Some(node) = parse_node(leb, offs) else {
+                break;
+            };
+
+            let mut len = node.data.len();
+            if node.node_type == PAD_NODE && len >= COMMON_HEADER_LEN + 4 {
+                len += LE.u32(&node.data[24..28]) as usize;
+            }
+
+            nodes.push(node);
+            offs = (offs + len).next_multiple_of(NODE_ALIGN);
+        }
+
+        nodes
+    }
+
+    /// The latest valid master node, from either of the two master LEBs
+    fn master(&self) -> io::Result<Node<'a>> {
+        (MST_LNUM..=MST_LNUM + 1)
+            .flat_map(|lnum| self.scan_leb(lnum, 0))
+            .filter(|node| node.node_type == MST_NODE && node.data.len() >= 60)
+            .max_by_key(|node| node.sqnum)
+            .ok_or_else(|| invalid_data("no valid master node"))
+    }
+
+    fn walk_index(&mut self, root_lnum: usize, root_offs: usize) -> io::Result<()> {
+        let mut pending = vec![(root_lnum, root_offs, None)];
+        let mut visited = HashSet::new();
+        let mut errors = 0;
+
+        while let Some((lnum, offs, expected_level)) = pending.pop() {
+            if !visited.insert((lnum, offs)) {
+                continue;
+            }
+
+            let node = self.node(lnum, offs)?;
+            if node.node_type != IDX_NODE || node.data.len() < IDX_NODE_LEN {
+                return Err(invalid_data(format!(
+                    "expected index node at LEB {lnum}:{offs:#x}"
+                )));
+            }
+
+            let child_count = LE.u16(&node.data[24..26]) as usize;
+            let level = LE.u16(&node.data[26..28]);
+            if level > MAX_INDEX_LEVEL || expected_level.is_some_and(|expected| expected != level) {
+                return Err(invalid_data(format!(
+                    "bad index level {level} at LEB {lnum}:{offs:#x}"
+                )));
+            }
+
+            let branches = node
+                .data
+                .get(IDX_NODE_LEN..IDX_NODE_LEN + child_count * BRANCH_LEN)
+                .ok_or_else(|| invalid_data("index node branches out of bounds"))?;
+
+            for branch in branches.chunks_exact(BRANCH_LEN) {
+                let child_lnum = LE.u32(&branch[0..4]) as usize;
+                let child_offs = LE.u32(&branch[4..8]) as usize;
+
+                if level > 0 {
+                    pending.push((child_lnum, child_offs, Some(level - 1)));
+                    continue;
+                }
+
+                let applied = self
+                    .node(child_lnum, child_offs)
+                    .and_then(|leaf| self.entries.apply(leaf));
+                if applied.is_err() {
+                    errors += 1;
+                }
+            }
+        }
+
+        if errors > 0 {
+            self.warnings
+                .push(format!("{errors} indexed nodes could not be read"));
+        }
+
+        Ok(())
+    }
+
+    /// Apply the nodes written to journal buds since the last commit. The log starts with the
+    /// commit start node at `log_lnum` and lists the buds in reference nodes.
+    fn replay_journal(&mut self, log_lnum: usize) -> usize {
+        let Some(commit_start) = self
+            .scan_leb(log_lnum, 0)
+            .first()
+            .copied()
+            .filter(|node| node.node_type == CS_NODE)
+        else {
+            self.warnings
+                .push("journal log has no commit start node, not replayed".to_owned());
+            return 0;
+        };
+
+        let log_end = LOG_LNUM + self.log_lebs;
+        let mut replayed = 0;
+        let mut lnum = log_lnum;
+
+        for _ in 0..self.log_lebs {
+            let log_nodes = self.scan_leb(lnum, 0);
+            let is_current = |node: &Node| node.sqnum >= commit_start.sqnum;
+
+            if log_nodes.is_empty() || !log_nodes.iter().all(is_current) {
+                break;
+            }
+
+            for reference in log_nodes.iter().filter(|node| node.node_type == REF_NODE) {
+                let Some(body) = reference.data.get(24..32) else {
+                    continue;
+                };
+                let bud_lnum = LE.u32(&body[0..4]) as usize;
+                let bud_offs = LE.u32(&body[4..8]) as usize;
+
+                for node in self.scan_leb(bud_lnum, bud_offs) {
+                    if node.sqnum > commit_start.sqnum && self.entries.apply(node).is_ok() {
+                        replayed += 1;
+                    }
+                }
+            }
+
+            lnum = if lnum + 1 >= log_end {
+                LOG_LNUM
+            } else {
+                lnum + 1
+            };
+        }
+
+        replayed
+    }
+
+    fn file_contents(&self, inum: u64, inode: &Inode) -> io::Result<Vec<u8>> {
+        if inode.size > MAX_FILE_SIZE {
+            return Err(invalid_data(format!(
+                "file too large ({} bytes)",
+                inode.size
+            )));
+        }
+
+        let size = inode.size as usize;
+        let mut out = vec![0; size];
+
+        for (block, start) in (0..size).step_by(BLOCK_SIZE).enumerate() {
+            // Missing blocks are holes
+            let Some((_, node)) = self.entries.data.get(&(inum, block as u32)) else {
+                continue;
+            };
+
+            let body = node.data;
+            let len = (LE.u32(&body[40..44]) as usize).min(BLOCK_SIZE);
+            let compressed = &body[DATA_NODE_LEN..];
+
+            let data = match LE.u16(&body[44..46]) {
+                COMPR_NONE => compressed.to_vec(),
+                COMPR_LZO => Codec::Lzo.decompress(compressed, len)?,
+                COMPR_ZLIB => Codec::Deflate.decompress(compressed, len)?,
+                COMPR_ZSTD => Codec::Zstd.decompress(compressed, len)?,
+                compr => return Err(invalid_data(format!("unknown compression type {compr}"))),
+            };
+
+            let end = size.min(start + data.len().min(len));
+            out[start..end].copy_from_slice(&data[..end - start]);
+        }
+
+        Ok(out)
+    }
+
+    fn xattrs(
+        &self,
+        inum: u64,
+        xattrs: &HashMap<u64, Vec<(&'a [u8], u64)>>,
+    ) -> Vec<(String, Vec<u8>)> {
+        xattrs
+            .get(&inum)
+            .into_iter()
+            .flatten()
+            .filter_map(|&(name, value_inum)| {
+                let (_, value) = self.entries.inodes.get(&value_inum)?;
+                Some((
+                    String::from_utf8_lossy(name).into_owned(),
+                    value.data.to_vec(),
+                ))
+            })
+            .collect()
+    }
+
+    fn unpack_entry(
+        &self,
+        tree: &mut TreeWriter,
+        rel: &Path,
+        inum: u64,
+        inode: &Inode,
+    ) -> io::Result<()> {
+        match inode.kind() {
+            Some(EntryKind::File) => tree.file_with(rel, inode.attrs, inode.size, || {
+                self.file_contents(inum, inode)
+            }),
+            Some(EntryKind::Symlink) => tree.symlink(rel, inode.attrs, inode.data),
+            Some(kind @ (EntryKind::Char | EntryKind::Block)) => {
+                // Either a 32 bit or a 64 bit ("huge") encoding of the same new-style number
+                let dev = match inode.data.len() {
+                    4 | 8 => LE.u32(inode.data),
+                    len => return Err(invalid_data(format!("device number of {len} bytes"))),
+                };
+                let rdev = ((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00));
+
+                tree.special(rel, kind, inode.attrs, Some(rdev))
+            }
+            Some(kind @ (EntryKind::Fifo | EntryKind::Socket)) => {
+                tree.special(rel, kind, inode.attrs, None)
+            }
+            _ => Err(invalid_data(format!("unknown mode {:#o}", inode.mode))),
+        }
+    }
+
+    fn write_tree(&mut self, out_dir: &Path) -> io::Result<usize> {
+        let mut children: HashMap<u64, BTreeMap<&[u8], u64>> = HashMap::new();
+        for (&(parent, name), &(_, target)) in &self.entries.dents {
+            if target != 0 {
+                children.entry(parent).or_default().insert(name, target);
+            }
+        }
+
+        let mut xattrs: HashMap<u64, Vec<(&[u8], u64)>> = HashMap::new();
+        for (&(host, name), &(_, target)) in &self.entries.xents {
+            if target != 0 {
+                xattrs.entry(host).or_default().push((name, target));
+            }
+        }
+
+        let Some(&(_, root)) = self.entries.inodes.get(&ROOT_INO) else {
+            return Err(invalid_data("root inode not found"));
+        };
+
+        let mut tree = TreeWriter::new(out_dir, "ubifs")?;
+        tree.dir(Path::new(""), root.attrs)?;
+        tree.xattrs(Path::new(""), &self.xattrs(ROOT_INO, &xattrs));
+
+        let mut visited = HashSet::from([ROOT_INO]);
+        let mut pending = vec![(PathBuf::new(), ROOT_INO)];
+        let mut warnings = Vec::new();
+
+        while let Some((dir, dir_inum)) = pending.pop() {
+            for (&name, &inum) in children.get(&dir_inum).into_iter().flatten() {
+                let name = match safe_name(name) {
+                    Ok(name) => name,
+                    Err(err) => {
+                        warnings.push(format!("skipping entry in /{}: {err}", dir.display()));
+                        continue;
+                    }
+                };
+                let rel = dir.join(name);
+
+                let Some(&(_, inode)) = self
+                    .entries
+                    .inodes
+                    .get(&inum)
+                    .filter(|(_, inode)| inode.nlink > 0)
+                else {
+                    warnings.push(format!("/{}: inode {inum} not found", rel.display()));
+                    continue;
+                };
+
+                if inode.kind() == Some(EntryKind::Dir) {
+                    if !visited.insert(inum) {
+                        warnings.push(format!("directory loop at /{}", rel.display()));
+                        continue;
+                    }
+
+                    tree.dir(&rel, inode.attrs)?;
+                    pending.push((rel.clone(), inum));
+                } else if let Some(existing) = tree.link_target(inum, &rel) {
+                    tree.hardlink(&rel, &existing)?;
+                    continue;
+                } else if let Err(err) = self.unpack_entry(&mut tree, &rel, inum, &inode) {
+                    warnings.push(format!("skipping /{}: {err}", rel.display()));
+                    continue;
+                }
+
+                let entry_xattrs = self.xattrs(inum, &xattrs);
+                if !entry_xattrs.is_empty() {
+                    tree.xattrs(&rel, &entry_xattrs);
+                }
+            }
+        }
+
+        self.warnings.extend(warnings);
+        tree.finish()
+    }
+}
+
+/// Unpack the UBIFS filesystem in `volume` into `out_dir`. Problems that did not prevent
+/// extraction are appended to `warnings`.
+pub fn unpack(volume: &Volume, out_dir: &Path, warnings: &mut Vec<String>) -> io::Result<usize> {
+    let first_leb = volume
+        .lebs
+        .get(SB_LNUM)
+        .copied()
+        .flatten()
+        .unwrap_or_default();
+    let sb = parse_node(first_leb, 0)
+        .filter(|node| node.node_type == SB_NODE && node.data.len() >= 86)
+        .ok_or_else(|| invalid_data("no UBIFS superblock"))?;
+
+    let key_format = sb.data[27];
+    if key_format != 0 {
+        return Err(invalid_data(format!("unsupported key format {key_format}")));
+    }
+
+    let mut fs = Fs {
+        volume,
+        log_lebs: LE.u32(&sb.data[56..60]) as usize,
+        entries: Entries::default(),
+        warnings: Vec::new(),
+    };
+
+    let master = fs.master()?;
+    let log_lnum = LE.u32(&master.data[44..48]) as usize;
+    let root_lnum = LE.u32(&master.data[48..52]) as usize;
+    let root_offs = LE.u32(&master.data[52..56]) as usize;
+
+    fs.walk_index(root_lnum, root_offs)?;
+    let replayed = fs.replay_journal(log_lnum);
+
+    log::debug!(
+        "ubifs: {} inodes, {} journal nodes replayed, default compression {}",
+        fs.entries.inodes.len(),
+        replayed,
+        LE.u16(&sb.data[84..86])
+    );
+
+    let entries = fs.write_tree(out_dir)?;
+    warnings.append(&mut fs.warnings);
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A node with a valid header and CRC around `body`
+    fn node(node_type: u8, sqnum: u64, body: &[u8]) -> Vec<u8> {
+        let mut node = vec![0; COMMON_HEADER_LEN];
+        node[..4].copy_from_slice(NODE_MAGIC);
+        node[8..16].copy_from_slice(&sqnum.to_le_bytes());
+        node[16..20].copy_from_slice(&((COMMON_HEADER_LEN + body.len()) as u32).to_le_bytes());
+        node[20] = node_type;
+        node.extend_from_slice(body);
+        let crc = crc32_le(CRC32_INIT, &node[8..]);
+        node[4..8].copy_from_slice(&crc.to_le_bytes());
+        node
+    }
+
+    #[test]
+    fn rejects_nodes_too_short_for_a_key() {
+        for node_type in [INO_NODE, DATA_NODE, DENT_NODE, XENT_NODE] {
+            let leb = node(node_type, 1, &[0; 4]);
+            let node = parse_node(&leb, 0).unwrap();
+            assert!(Entries::default().apply(node).is_err());
+        }
+    }
+
+    #[test]
+    fn applies_the_newest_inode() {
+        let mut body = vec![0; INO_NODE_LEN - COMMON_HEADER_LEN];
+        body[..4].copy_from_slice(&ROOT_INO.to_le_bytes()[..4]);
+        body[80..84].copy_from_slice(&(libc::S_IFDIR | 0o755).to_le_bytes());
+
+        let lebs = [node(INO_NODE, 2, &body), node(INO_NODE, 1, &body)];
+        let mut entries = Entries::default();
+        for leb in &lebs {
+            entries.apply(parse_node(leb, 0).unwrap()).unwrap();
+        }
+
+        let (sqnum, inode) = entries.inodes[&ROOT_INO];
+        assert_eq!(sqnum, 2);
+        assert_eq!(inode.kind(), Some(EntryKind::Dir));
+    }
+}
@@ -1,4 +1,5 @@
-use super::{ExtractError, Extractor};
+use super::sandbox::Workspace;
+use super::{ExtractError, ExtractStatus, Extractor};
 use std::path::Path;
 use std::process::Command;
 
@@ -15,16 +16,21 @@ impl Extractor for UnblobExtractor {
         extract_dir: &Path,
         log_file: &Path,
         verbose: bool,
-    ) -> Result<(), ExtractError> {
-        let output = Command::new("unblob")
+    ) -> Result<ExtractStatus, ExtractError> {
+        let mut command = Command::new("unblob");
+        command
             .arg(in_file)
             .arg("-e")
             .arg(extract_dir)
             .arg("--log")
             .arg(log_file)
-            .args(&["--entropy-depth", "1"])
-            .output()?;
+            .args(["--entropy-depth", "1"]);
 
-        self.cmd_output_to_result(output, false, verbose)
+        let workspace = Workspace {
+            input: in_file,
+            extract_dir,
+            log_file,
+        };
+        self.run_command(command, &workspace, verbose)
     }
 }
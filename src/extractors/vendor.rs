@@ -0,0 +1,953 @@
+//! Recognition of vendor firmware containers.
+//!
+//! Many firmware images wrap the kernel and root filesystem in a small vendor header that
+//! records where each payload starts, what it is called, where it gets loaded and a checksum.
+//! [`scan`] finds and validates those headers so they can be reported in `xfs_results.json`
+//! before any extractor runs, and [`VendorExtractor`] carves every payload into its own
+//! named file in the scratch directory.
+
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use md5::Md5;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use super::native::{self, crc32_le, Endian};
+use super::{ExtractError, ExtractStatus, Extractor};
+
+const UIMAGE_MAGIC: &[u8] = &[0x27, 0x05, 0x19, 0x56];
+const FDT_MAGIC: &[u8] = &[0xd0, 0x0d, 0xfe, 0xed];
+const TRX_MAGIC: &[u8] = b"HDR0";
+const TPLINK_V1_MAGIC: &[u8] = &[0, 0, 0, 1];
+const TPLINK_V2_MAGIC: &[u8] = &[0, 0, 0, 2];
+const CHK_MAGIC: &[u8] = &[0x2a, 0x23, 0x24, 0x5e];
+const SERCOMM_MAGIC: &[u8] = b"Ser\0";
+const SEAMA_MAGIC: &[u8] = &[0x5e, 0xa3, 0xa4, 0x17];
+
+const MAGICS: &[&[u8]] = &[
+    UIMAGE_MAGIC,
+    FDT_MAGIC,
+    TRX_MAGIC,
+    TPLINK_V1_MAGIC,
+    TPLINK_V2_MAGIC,
+    CHK_MAGIC,
+    SERCOMM_MAGIC,
+    SEAMA_MAGIC,
+];
+
+const BE: Endian = Endian::Big;
+const LE: Endian = Endian::Little;
+
+/// A vendor container found in the input.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct VendorHeader {
+    /// Container format: `uimage`, `fit`, `trx`, `tplink`, `chk`, `sercomm` or `seama`
+    pub format: &'static str,
+    /// Offset of the header in the input
+    pub offset: usize,
+    /// Number of bytes of the input covered by the header and its payloads
+    pub size: usize,
+    /// Length of the header itself
+    pub header_size: usize,
+    /// Image name, firmware version, board id or metadata, depending on the format
+    pub name: Option<String>,
+    /// Whether the checksum over the header (or over the whole image, for formats that only
+    /// have one) matches. `None` when the format has no such checksum.
+    pub checksum_valid: Option<bool>,
+    pub partitions: Vec<Partition>,
+}
+
+/// One payload described by a vendor header.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Partition {
+    pub name: String,
+    /// Offset of the payload in the input
+    pub offset: usize,
+    pub size: usize,
+    pub load_address: Option<u64>,
+    pub entry_point: Option<u64>,
+    /// Compression recorded in the header, if the format records one
+    pub compression: Option<String>,
+    /// Whether the checksum covering this payload matches. `None` when there is none.
+    pub checksum_valid: Option<bool>,
+    /// What the payload looks like, going by its leading magic number
+    pub content: Option<&'static str>,
+}
+
+impl Partition {
+    fn new(name: impl Into<String>, data: &[u8], offset: usize, size: usize) -> Self {
+        Self {
+            name: name.into(),
+            offset,
+            size,
+            load_address: None,
+            entry_point: None,
+            compression: None,
+            checksum_valid: None,
+            content: sniff(&data[offset..offset + size]),
+        }
+    }
+
+    /// File name the payload is carved to
+    fn file_name(&self) -> String {
+        let stem: String = self
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let stem = stem.trim_matches('.');
+        let stem = if stem.is_empty() { "payload" } else { stem };
+
+        format!("{stem}.{}", self.content.unwrap_or("bin"))
+    }
+}
+
+/// Carves the payloads of every vendor container into `<format>-<offset>/<name>.<content>`.
+pub struct VendorExtractor;
+
+impl Extractor for VendorExtractor {
+    fn name(&self) -> &'static str {
+        "vendor"
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        _verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        let data = native::read_input(in_file)?;
+        let headers = scan(&data);
+        let mut log = String::new();
+
+        for header in &headers {
+            let out_dir = native::output_dir(extract_dir, header.format, header.offset);
+            carve(&data, header, &out_dir)?;
+
+            let line = format!(
+                "{} at {:#x}: {} bytes, {} partitions -> {}",
+                header.format,
+                header.offset,
+                header.size,
+                header.partitions.len(),
+                out_dir.display()
+            );
+            log::info!("{line}");
+            log.push_str(&line);
+            log.push('\n');
+
+            if header.checksum_valid == Some(false) {
+                log.push_str("  header checksum mismatch\n");
+            }
+            for partition in &header.partitions {
+                if partition.checksum_valid == Some(false) {
+                    log.push_str(&format!("  {}: checksum mismatch\n", partition.name));
+                }
+            }
+        }
+
+        if !log.is_empty() {
+            fs::write(log_file, log)?;
+        }
+
+        if headers.is_empty() {
+            return Err(ExtractError::NotFound(self.name()));
+        }
+
+        Ok(ExtractStatus::Completed)
+    }
+}
+
+fn carve(data: &[u8], header: &VendorHeader, out_dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(out_dir)?;
+
+    for (i, partition) in header.partitions.iter().enumerate() {
+        let mut path = out_dir.join(partition.file_name());
+        if path.exists() {
+            path = out_dir.join(format!("{i}-{}", partition.file_name()));
+        }
+
+        fs::write(
+            path,
+            &data[partition.offset..partition.offset + partition.size],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Read `in_file` and [`scan`] it.
+pub fn scan_file(in_file: &Path) -> io::Result<Vec<VendorHeader>> {
+    Ok(scan(&native::read_input(in_file)?))
+}
+
+/// Every valid vendor container header in `data`, in order of offset.
+///
+/// Containers nested in the payload of another (a TRX inside a Netgear CHK, a uImage kernel
+/// inside a TP-Link image) are reported as well; only matches inside a header are skipped.
+pub fn scan(data: &[u8]) -> Vec<VendorHeader> {
+    let mut headers = Vec::new();
+    let mut header_end = 0;
+
+    for offset in native::find_magic(data, MAGICS) {
+        if offset < header_end {
+            continue;
+        }
+
+        let header = panic::catch_unwind(AssertUnwindSafe(|| parse(data, offset)))
+            .ok()
+            .flatten();
+
+        if let Some(header) = header {
+            header_end = offset + header.header_size;
+            headers.push(header);
+        }
+    }
+
+    headers
+}
+
+fn parse(data: &[u8], offset: usize) -> Option<VendorHeader> {
+    match data.get(offset..offset + 4)? {
+        UIMAGE_MAGIC => parse_uimage(data, offset),
+        FDT_MAGIC => parse_fit(data, offset),
+        TRX_MAGIC => parse_trx(data, offset),
+        TPLINK_V1_MAGIC | TPLINK_V2_MAGIC => parse_tplink(data, offset),
+        CHK_MAGIC => parse_chk(data, offset),
+        SERCOMM_MAGIC => parse_sercomm(data, offset),
+        SEAMA_MAGIC => parse_seama(data, offset),
+        _ => None,
+    }
+}
+
+/// Standard (zlib) CRC-32
+fn crc32(data: &[u8]) -> u32 {
+    crc32_le(!0, data) ^ !0
+}
+
+/// A NUL-terminated string made only of printable ASCII
+fn c_string(bytes: &[u8]) -> Option<String> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let bytes = &bytes[..len];
+
+    bytes
+        .iter()
+        .all(|&b| (0x20..0x7f).contains(&b))
+        .then(|| String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Payload types, going by their leading magic number
+const CONTENT_MAGICS: &[(&[u8], &str)] = &[
+    (b"hsqs", "squashfs"),
+    (b"sqsh", "squashfs"),
+    (&[0x45, 0x3d, 0xcd, 0x28], "cramfs"),
+    (&[0x28, 0xcd, 0x3d, 0x45], "cramfs"),
+    (&[0x85, 0x19], "jffs2"),
+    (&[0x19, 0x85], "jffs2"),
+    (b"UBI#", "ubi"),
+    (&[0x31, 0x18, 0x10, 0x06], "ubifs"),
+    (b"-rom1fs-", "romfs"),
+    (b"07070", "cpio"),
+    (UIMAGE_MAGIC, "uimage"),
+    (FDT_MAGIC, "dtb"),
+    (TRX_MAGIC, "trx"),
+    (b"\x7fELF", "elf"),
+    (&[0x1f, 0x8b], "gz"),
+    (&[0xfd, b'7', b'z', b'X', b'Z', 0], "xz"),
+    (&[0x28, 0xb5, 0x2f, 0xfd], "zst"),
+    (b"BZh", "bz2"),
+    (b"\x89LZO", "lzo"),
+    (&[0x5d, 0, 0], "lzma"),
+];
+
+fn sniff(payload: &[u8]) -> Option<&'static str> {
+    if payload.get(1080..1082) == Some(&[0x53, 0xef]) {
+        return Some("ext");
+    }
+
+    CONTENT_MAGICS
+        .iter()
+        .find(|(magic, _)| payload.starts_with(magic))
+        .map(|&(_, content)| content)
+}
+
+fn is_filesystem(content: Option<&str>) -> bool {
+    matches!(
+        content,
+        Some("squashfs" | "cramfs" | "jffs2" | "ubi" | "ubifs" | "romfs" | "cpio" | "ext")
+    )
+}
+
+const UIMAGE_HEADER_LEN: usize = 64;
+const UIMAGE_TYPE_MULTI: u8 = 4;
+const UIMAGE_COMPRESSION: &[&str] = &["none", "gzip", "bzip2", "lzma", "lzo", "lz4", "zstd"];
+
+fn uimage_type(kind: u8) -> &'static str {
+    match kind {
+        1 => "standalone",
+        2 => "kernel",
+        3 => "ramdisk",
+        4 => "multi",
+        5 => "firmware",
+        6 => "script",
+        7 => "filesystem",
+        8 => "flat_dt",
+        _ => "image",
+    }
+}
+
+/// U-Boot legacy image: a 64 byte big-endian header followed by the payload. Multi-file
+/// images start their payload with a zero-terminated table of image sizes.
+fn parse_uimage(data: &[u8], offset: usize) -> Option<VendorHeader> {
+    let header = data.get(offset..offset + UIMAGE_HEADER_LEN)?;
+    let size = BE.u32(&header[12..16]) as usize;
+    let load = BE.u32(&header[16..20]);
+    let entry = BE.u32(&header[20..24]);
+    let (os, arch, kind, compression) = (header[28], header[29], header[30], header[31]);
+
+    if os == 0 || os >= 64 || arch == 0 || arch >= 64 || kind == 0 || kind >= 64 {
+        return None;
+    }
+    let compression = UIMAGE_COMPRESSION.get(compression as usize)?;
+    let name = c_string(&header[32..])?;
+
+    let data_start = offset + UIMAGE_HEADER_LEN;
+    let payload = data.get(data_start..data_start.checked_add(size)?)?;
+
+    let mut zeroed = header.to_vec();
+    zeroed[4..8].fill(0);
+    let header_valid = crc32(&zeroed) == BE.u32(&header[4..8]);
+    let data_valid = crc32(payload) == BE.u32(&header[24..28]);
+
+    let mut partitions = Vec::new();
+    let mut header_size = UIMAGE_HEADER_LEN;
+
+    if kind == UIMAGE_TYPE_MULTI {
+        let sizes: Vec<usize> = payload
+            .chunks_exact(4)
+            .map(|size| BE.u32(size) as usize)
+            .take_while(|&size| size != 0)
+            .collect();
+
+        header_size += (sizes.len() + 1) * 4;
+        let mut pos = offset + header_size;
+        for (i, &size) in sizes.iter().enumerate() {
+            if pos.checked_add(size)? > data_start + payload.len() {
+                return None;
+            }
+
+            let mut partition = Partition::new(format!("multi-{i}"), data, pos, size);
+            partition.compression = Some(compression.to_string());
+            partition.checksum_valid = Some(data_valid);
+            partitions.push(partition);
+
+            pos += size.next_multiple_of(4);
+        }
+    } else {
+        let mut partition = Partition::new(uimage_type(kind), data, data_start, size);
+        partition.load_address = Some(load.into());
+        partition.entry_point = Some(entry.into());
+        partition.compression = Some(compression.to_string());
+        partition.checksum_valid = Some(data_valid);
+        partitions.push(partition);
+    }
+
+    Some(VendorHeader {
+        format: "uimage",
+        offset,
+        size: UIMAGE_HEADER_LEN + size,
+        header_size,
+        name: Some(name).filter(|name| !name.is_empty()),
+        checksum_valid: Some(header_valid),
+        partitions,
+    })
+}
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_MAX_DEPTH: usize = 16;
+
+struct FdtNode<'a> {
+    name: &'a [u8],
+    props: Vec<(&'a [u8], &'a [u8])>,
+    children: Vec<FdtNode<'a>>,
+}
+
+impl<'a> FdtNode<'a> {
+    fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props
+            .iter()
+            .find(|(prop, _)| *prop == name.as_bytes())
+            .map(|&(_, value)| value)
+    }
+
+    fn string(&self, name: &str) -> Option<String> {
+        self.prop(name).and_then(c_string)
+    }
+
+    /// A `u32` or `u64` cell property
+    fn number(&self, name: &str) -> Option<u64> {
+        let value = self.prop(name)?;
+        match value.len() {
+            4 => Some(BE.u32(value).into()),
+            8 => Some(BE.u64(value)),
+            _ => None,
+        }
+    }
+
+    fn child(&self, name: &str) -> Option<&FdtNode<'a>> {
+        self.children
+            .iter()
+            .find(|child| child.name == name.as_bytes())
+    }
+}
+
+/// Parser for the structure block of a flattened device tree
+struct Fdt<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    fn token(&self, pos: &mut usize) -> Option<u32> {
+        let token = BE.u32(self.structs.get(*pos..*pos + 4)?);
+        *pos += 4;
+        Some(token)
+    }
+
+    fn node(&self, pos: &mut usize, depth: usize) -> Option<FdtNode<'a>> {
+        if depth > FDT_MAX_DEPTH {
+            return None;
+        }
+
+        let rest = self.structs.get(*pos..)?;
+        let name = &rest[..rest.iter().position(|&b| b == 0)?];
+        *pos = (*pos + name.len() + 1).next_multiple_of(4);
+
+        let mut node = FdtNode {
+            name,
+            props: Vec::new(),
+            children: Vec::new(),
+        };
+
+        loop {
+            match self.token(pos)? {
+                FDT_BEGIN_NODE => node.children.push(self.node(pos, depth + 1)?),
+                FDT_END_NODE => return Some(node),
+                FDT_PROP => {
+                    let len = self.token(pos)? as usize;
+                    let name_offset = self.token(pos)? as usize;
+                    let value = self.structs.get(*pos..pos.checked_add(len)?)?;
+                    *pos = (*pos + len).next_multiple_of(4);
+
+                    let name = self.strings.get(name_offset..)?;
+                    let name = &name[..name.iter().position(|&b| b == 0)?];
+                    node.props.push((name, value));
+                }
+                FDT_NOP => {}
+                _ => return None,
+            }
+        }
+    }
+
+    fn root(&self) -> Option<FdtNode<'a>> {
+        let mut pos = 0;
+        loop {
+            match self.token(&mut pos)? {
+                FDT_NOP => {}
+                FDT_BEGIN_NODE => return self.node(&mut pos, 0),
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Whether the `hash*` subnodes of a FIT image node match its data. `None` when there are no
+/// hashes of a supported algorithm.
+fn fit_hashes_valid(image: &FdtNode, payload: &[u8]) -> Option<bool> {
+    let mut valid = None;
+
+    for hash in image
+        .children
+        .iter()
+        .filter(|child| child.name.starts_with(b"hash"))
+    {
+        let Some(value) = hash.prop("value") else {
+            continue;
+        };
+
+        let matches = match hash.string("algo")?.as_str() {
+            "crc32" => value == crc32(payload).to_be_bytes(),
+            "md5" => value == Md5::digest(payload).as_slice(),
+            "sha1" => value == Sha1::digest(payload).as_slice(),
+            "sha256" => value == Sha256::digest(payload).as_slice(),
+            _ => continue,
+        };
+
+        valid = Some(valid.unwrap_or(true) && matches);
+    }
+
+    valid
+}
+
+/// U-Boot FIT image: a device tree whose `/images` node describes each payload, stored either
+/// inline in a `data` property or after the tree (`data-offset`/`data-position`). Plain device
+/// trees have no `/images` node and are ignored.
+fn parse_fit(data: &[u8], offset: usize) -> Option<VendorHeader> {
+    let header = data.get(offset..offset + 40)?;
+    let total_size = BE.u32(&header[4..8]) as usize;
+    let structs_offset = BE.u32(&header[8..12]) as usize;
+    let strings_offset = BE.u32(&header[12..16]) as usize;
+    let version = BE.u32(&header[20..24]);
+    let strings_size = BE.u32(&header[32..36]) as usize;
+    let structs_size = BE.u32(&header[36..40]) as usize;
+
+    if version < 17 {
+        return None;
+    }
+
+    let blob = data.get(offset..offset.checked_add(total_size)?)?;
+    let fdt = Fdt {
+        structs: blob.get(structs_offset..structs_offset.checked_add(structs_size)?)?,
+        strings: blob.get(strings_offset..strings_offset.checked_add(strings_size)?)?,
+    };
+
+    let root = fdt.root()?;
+    let images = root.child("images")?;
+    let external_base = offset + total_size.next_multiple_of(4);
+    let mut end = offset + total_size;
+    let mut partitions = Vec::new();
+
+    for image in &images.children {
+        let (start, size) = if let Some(inline) = image.prop("data") {
+            (
+                inline.as_ptr() as usize - data.as_ptr() as usize,
+                inline.len(),
+            )
+        } else {
+            let size = image.number("data-size")? as usize;
+            if let Some(position) = image.number("data-position") {
+                (offset.checked_add(position as usize)?, size)
+            } else {
+                (
+                    external_base.checked_add(image.number("data-offset")? as usize)?,
+                    size,
+                )
+            }
+        };
+
+        let Some(payload) = data.get(start..start.saturating_add(size)) else {
+            continue;
+        };
+        end = end.max(start + size);
+
+        let mut partition = Partition::new(String::from_utf8_lossy(image.name), data, start, size);
+        partition.load_address = image.number("load");
+        partition.entry_point = image.number("entry");
+        partition.compression = image.string("compression");
+        partition.checksum_valid = fit_hashes_valid(image, payload);
+        partitions.push(partition);
+    }
+
+    if partitions.is_empty() {
+        return None;
+    }
+
+    Some(VendorHeader {
+        format: "fit",
+        offset,
+        size: end - offset,
+        header_size: total_size,
+        name: root.string("description"),
+        checksum_valid: None,
+        partitions,
+    })
+}
+
+/// Broadcom TRX: little-endian header with a CRC over everything after the CRC field and up to
+/// four partition offsets.
+///
+/// TRX does not name its partitions, so they are named by role: a filesystem is the rootfs,
+/// the partition before it is the kernel and anything before that is the loader.
+fn parse_trx(data: &[u8], offset: usize) -> Option<VendorHeader> {
+    let header = data.get(offset..offset + 28)?;
+    let len = LE.u32(&header[4..8]) as usize;
+    let crc = LE.u32(&header[8..12]);
+    let (count, header_size) = match LE.u32(&header[12..16]) >> 16 {
+        1 => (3, 28),
+        2 => (4, 32),
+        _ => return None,
+    };
+
+    let image = data.get(offset..offset.checked_add(len)?)?;
+    if len < header_size {
+        return None;
+    }
+
+    let offsets: Vec<usize> = image[16..16 + count * 4]
+        .chunks_exact(4)
+        .map(|part| LE.u32(part) as usize)
+        .take_while(|&part| part != 0)
+        .collect();
+
+    if offsets.is_empty()
+        || offsets[0] < header_size
+        || offsets.windows(2).any(|pair| pair[0] >= pair[1])
+        || offsets[offsets.len() - 1] >= len
+    {
+        return None;
+    }
+
+    let mut partitions: Vec<_> = offsets
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = offsets.get(i + 1).copied().unwrap_or(len);
+            Partition::new(format!("partition{i}"), data, offset + start, end - start)
+        })
+        .collect();
+
+    let rootfs = partitions
+        .iter()
+        .position(|partition| is_filesystem(partition.content));
+    let kernel = rootfs.unwrap_or(partitions.len()).checked_sub(1);
+    for (i, partition) in partitions.iter_mut().enumerate() {
+        if Some(i) == rootfs {
+            partition.name = "rootfs".into();
+        } else if Some(i) == kernel {
+            partition.name = "kernel".into();
+        } else if kernel == Some(1) && i == 0 {
+            partition.name = "loader".into();
+        } else if kernel.is_some_and(|kernel| i < kernel) {
+            partition.name = format!("loader{i}");
+        }
+    }
+
+    Some(VendorHeader {
+        format: "trx",
+        offset,
+        size: len,
+        header_size,
+        name: None,
+        checksum_valid: Some(crc32_le(!0, &image[12..]) == crc),
+        partitions,
+    })
+}
+
+const TPLINK_HEADER_LEN: usize = 512;
+const TPLINK_MD5_SALTS: &[[u8; 16]] = &[
+    [
+        0xdc, 0xd7, 0x3a, 0xa5, 0xc3, 0x95, 0x98, 0xfb, 0xdd, 0xf9, 0xe7, 0xf4, 0x0e, 0xae, 0x47,
+        0x38,
+    ],
+    [
+        0x8c, 0xef, 0x33, 0x5b, 0xd5, 0xc5, 0xce, 0xfa, 0xa7, 0x9c, 0x28, 0xda, 0xb2, 0xe9, 0x0f,
+        0x42,
+    ],
+];
+
+/// TP-Link v1 and v2 firmware: a 512 byte big-endian header with the kernel, rootfs and
+/// bootloader offsets, and an MD5 over the whole image with a fixed salt in place of the
+/// digest.
+fn parse_tplink(data: &[u8], offset: usize) -> Option<VendorHeader> {
+    let header = data.get(offset..offset + TPLINK_HEADER_LEN)?;
+
+    let (name, md5_offset, fields) = match BE.u32(&header[0..4]) {
+        1 => {
+            let vendor = c_string(&header[4..28])?;
+            let version = c_string(&header[28..64])?;
+            (format!("{vendor} {version}"), 0x4c, 0x74)
+        }
+        2 => (c_string(&header[4..52])?, 0x40, 0x68),
+        _ => return None,
+    };
+
+    let field = |i: usize| BE.u32(&header[fields + i * 4..]) as usize;
+    let (kernel_la, kernel_ep, fw_length) = (field(0), field(1), field(2));
+    let parts = [
+        ("kernel", field(3), field(4)),
+        ("rootfs", field(5), field(6)),
+        ("bootloader", field(7), field(8)),
+    ];
+
+    // The kernel always follows the header directly
+    if name.trim().is_empty() || parts[0].1 != TPLINK_HEADER_LEN || parts[0].2 == 0 {
+        return None;
+    }
+
+    let mut partitions = Vec::new();
+    let mut end = TPLINK_HEADER_LEN;
+    for (part, start, size) in parts {
+        if size == 0 {
+            continue;
+        }
+
+        let part_end = start.checked_add(size)?;
+        if start < TPLINK_HEADER_LEN || offset.checked_add(part_end)? > data.len() {
+            return None;
+        }
+        end = end.max(part_end);
+
+        let mut partition = Partition::new(part, data, offset + start, size);
+        if part == "kernel" {
+            partition.load_address = Some(kernel_la as u64);
+            partition.entry_point = Some(kernel_ep as u64);
+        }
+        partitions.push(partition);
+    }
+
+    // The digest covers the image as written, which is either the length in the header or
+    // just the partitions when the image is not padded to the full flash size
+    let size = fw_length.min(data.len() - offset).max(end);
+    let digest = &header[md5_offset..md5_offset + 16];
+    let checksum_valid = [size, end].iter().any(|&len| {
+        TPLINK_MD5_SALTS.iter().any(|salt| {
+            let mut salted = header.to_vec();
+            salted[md5_offset..md5_offset + 16].copy_from_slice(salt);
+
+            let mut md5 = Md5::new();
+            md5.update(&salted);
+            md5.update(&data[offset + TPLINK_HEADER_LEN..offset + len]);
+            md5.finalize().as_slice() == digest
+        })
+    });
+
+    Some(VendorHeader {
+        format: "tplink",
+        offset,
+        size,
+        header_size: TPLINK_HEADER_LEN,
+        name: Some(name),
+        checksum_valid: Some(checksum_valid),
+        partitions,
+    })
+}
+
+/// Fletcher-like checksum used by Netgear's CHK format
+#[derive(Default)]
+struct NetgearChecksum {
+    c0: u32,
+    c1: u32,
+}
+
+impl NetgearChecksum {
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.c0 = self.c0.wrapping_add(byte.into());
+            self.c1 = self.c1.wrapping_add(self.c0);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        let fold = |c: u32| {
+            let b = (c & 0xffff) + (c >> 16);
+            ((b >> 16) + b) & 0xffff
+        };
+
+        (fold(self.c1) << 16) | fold(self.c0)
+    }
+
+    fn of(parts: &[&[u8]]) -> u32 {
+        let mut checksum = Self::default();
+        for part in parts {
+            checksum.update(part);
+        }
+        checksum.finish()
+    }
+}
+
+const CHK_FIXED_LEN: usize = 40;
+const CHK_MAX_HEADER_LEN: usize = 1024;
+
+/// Netgear CHK: big-endian header with a board id, kernel and rootfs lengths, and checksums
+/// over the header, each payload and both payloads together.
+fn parse_chk(data: &[u8], offset: usize) -> Option<VendorHeader> {
+    let fixed = data.get(offset..offset + CHK_FIXED_LEN)?;
+    let header_len = BE.u32(&fixed[4..8]) as usize;
+    if !(CHK_FIXED_LEN..=CHK_MAX_HEADER_LEN).contains(&header_len) {
+        return None;
+    }
+
+    let header = data.get(offset..offset + header_len)?;
+    let board_id = c_string(&header[CHK_FIXED_LEN..]).filter(|id| !id.is_empty())?;
+    let kernel_len = BE.u32(&header[24..28]) as usize;
+    let rootfs_len = BE.u32(&header[28..32]) as usize;
+
+    let kernel_start = offset + header_len;
+    let rootfs_start = kernel_start.checked_add(kernel_len)?;
+    let kernel = data.get(kernel_start..rootfs_start)?;
+    let rootfs = data.get(rootfs_start..rootfs_start.checked_add(rootfs_len)?)?;
+
+    let mut zeroed = header.to_vec();
+    zeroed[36..40].fill(0);
+    let header_valid = NetgearChecksum::of(&[&zeroed]) == BE.u32(&header[36..40]);
+    let image_valid = NetgearChecksum::of(&[kernel, rootfs]) == BE.u32(&header[32..36]);
+
+    let mut partitions = Vec::new();
+    for (name, start, payload, checksum) in [
+        ("kernel", kernel_start, kernel, BE.u32(&header[16..20])),
+        ("rootfs", rootfs_start, rootfs, BE.u32(&header[20..24])),
+    ] {
+        if payload.is_empty() {
+            continue;
+        }
+
+        let mut partition = Partition::new(name, data, start, payload.len());
+        partition.checksum_valid = Some(NetgearChecksum::of(&[payload]) == checksum);
+        partitions.push(partition);
+    }
+
+    Some(VendorHeader {
+        format: "chk",
+        offset,
+        size: header_len + kernel_len + rootfs_len,
+        header_size: header_len,
+        name: Some(board_id),
+        checksum_valid: Some(header_valid && image_valid),
+        partitions,
+    })
+}
+
+const SERCOMM_FIXED_LEN: usize = 0x34;
+const SERCOMM_MAX_HEADER_LEN: usize = 0x1000;
+
+/// Sercomm kernel header: little-endian, with the flash offset, length and CRC of the kernel
+/// and the rootfs. The header CRC must match, as the magic is short and the layout loose.
+fn parse_sercomm(data: &[u8], offset: usize) -> Option<VendorHeader> {
+    let fixed = data.get(offset..offset + SERCOMM_FIXED_LEN)?;
+    let header_len = LE.u32(&fixed[4..8]) as usize;
+    if !(SERCOMM_FIXED_LEN..=SERCOMM_MAX_HEADER_LEN).contains(&header_len) {
+        return None;
+    }
+
+    let header = data.get(offset..offset + header_len)?;
+    let mut zeroed = header.to_vec();
+    zeroed[8..12].fill(0);
+    if crc32(&zeroed) != LE.u32(&header[8..12]) {
+        return None;
+    }
+
+    let field = |at: usize| LE.u32(&header[at..]) as usize;
+    let (kernel_flash, kernel_len, kernel_crc) = (field(0x10), field(0x14), field(0x18) as u32);
+    let (rootfs_flash, rootfs_len, rootfs_crc) = (field(0x28), field(0x2c), field(0x30) as u32);
+
+    let kernel_start = offset + header_len;
+    let kernel_end = kernel_start.checked_add(kernel_len)?;
+    let kernel = data.get(kernel_start..kernel_end)?;
+
+    let mut kernel_part = Partition::new("kernel", data, kernel_start, kernel_len);
+    kernel_part.checksum_valid = Some(crc32(kernel) == kernel_crc);
+    let mut partitions = vec![kernel_part];
+    let mut end = kernel_end;
+
+    // The rootfs is located by flash offset, which is relative either to the header or to the
+    // kernel, or it simply follows the kernel; prefer whichever candidate has a matching CRC
+    if rootfs_len != 0 {
+        let distance = rootfs_flash.checked_sub(kernel_flash);
+        let candidates: Vec<usize> = [
+            distance.and_then(|distance| offset.checked_add(distance)),
+            distance.and_then(|distance| kernel_start.checked_add(distance)),
+            Some(kernel_end),
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|&start| start >= kernel_end && start.saturating_add(rootfs_len) <= data.len())
+        .collect();
+
+        let start = candidates
+            .iter()
+            .copied()
+            .find(|&start| crc32(&data[start..start + rootfs_len]) == rootfs_crc)
+            .or(candidates.first().copied());
+
+        if let Some(start) = start {
+            let mut rootfs = Partition::new("rootfs", data, start, rootfs_len);
+            rootfs.checksum_valid = Some(crc32(&data[start..start + rootfs_len]) == rootfs_crc);
+            partitions.push(rootfs);
+            end = start + rootfs_len;
+        }
+    }
+
+    Some(VendorHeader {
+        format: "sercomm",
+        offset,
+        size: end - offset,
+        header_size: header_len,
+        name: None,
+        checksum_valid: Some(true),
+        partitions,
+    })
+}
+
+const SEAMA_FIXED_LEN: usize = 12;
+const SEAMA_DIGEST_LEN: usize = 16;
+
+/// D-Link SEAMA: big-endian header, an MD5 of the image, NUL-separated `key=value` metadata
+/// and the image. A header with an image size of zero carries no digest and wraps the SEAMA
+/// images that follow it.
+fn parse_seama(data: &[u8], offset: usize) -> Option<VendorHeader> {
+    let fixed = data.get(offset..offset + SEAMA_FIXED_LEN)?;
+    let reserved = BE.u16(&fixed[4..6]);
+    let meta_size = BE.u16(&fixed[6..8]) as usize;
+    let size = BE.u32(&fixed[8..12]) as usize;
+
+    if reserved != 0 {
+        return None;
+    }
+
+    let digest_len = if size == 0 { 0 } else { SEAMA_DIGEST_LEN };
+    let meta_start = offset + SEAMA_FIXED_LEN + digest_len;
+    let header_size = SEAMA_FIXED_LEN + digest_len + meta_size;
+
+    let metadata = data
+        .get(meta_start..meta_start + meta_size)?
+        .split(|&b| b == 0)
+        .filter(|entry| !entry.is_empty())
+        .map(c_string)
+        .collect::<Option<Vec<_>>>()?;
+    if metadata.is_empty() {
+        return None;
+    }
+
+    let image_start = offset + header_size;
+    let image = data.get(image_start..image_start.checked_add(size)?)?;
+
+    let mut partitions = Vec::new();
+    let mut checksum_valid = None;
+    if size != 0 {
+        let digest = &data[offset + SEAMA_FIXED_LEN..meta_start];
+        checksum_valid = Some(Md5::digest(image).as_slice() == digest);
+
+        let name = metadata
+            .iter()
+            .find_map(|entry| entry.strip_prefix("type="))
+            .unwrap_or("image");
+        let mut partition = Partition::new(name, data, image_start, size);
+        partition.checksum_valid = checksum_valid;
+        partitions.push(partition);
+    }
+
+    Some(VendorHeader {
+        format: "seama",
+        offset,
+        size: header_size + size,
+        header_size,
+        name: Some(metadata.join(", ")),
+        checksum_valid,
+        partitions,
+    })
+}
@@ -0,0 +1,864 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::fstree::{safe_name, Attrs, EntryKind, TreeWriter};
+use super::native::{self, invalid_data, Endian, Unpacked};
+use super::{ExtractError, ExtractStatus, Extractor};
+
+/// Object headers of objects directly in the root directory start with the object type and
+/// the root's object ID. Images are written root first, so one of these starts every image.
+const MAGICS: &[&[u8]] = &[
+    &[1, 0, 0, 0, 1, 0, 0, 0],
+    &[2, 0, 0, 0, 1, 0, 0, 0],
+    &[3, 0, 0, 0, 1, 0, 0, 0],
+    &[4, 0, 0, 0, 1, 0, 0, 0],
+    &[5, 0, 0, 0, 1, 0, 0, 0],
+    &[0, 0, 0, 1, 0, 0, 0, 1],
+    &[0, 0, 0, 2, 0, 0, 0, 1],
+    &[0, 0, 0, 3, 0, 0, 0, 1],
+    &[0, 0, 0, 4, 0, 0, 0, 1],
+    &[0, 0, 0, 5, 0, 0, 0, 1],
+];
+
+/// Page sizes and the spare (OOB) sizes seen with them. A spare size of zero means the dump has
+/// no OOB data, which only works with inband tags.
+const GEOMETRIES: &[(usize, &[usize])] = &[
+    (512, &[16, 0]),
+    (1024, &[32, 0]),
+    (2048, &[64, 0]),
+    (4096, &[128, 224, 256, 0]),
+    (8192, &[256, 436, 448, 640, 0]),
+    (16384, &[512, 1280, 0]),
+];
+
+/// Offsets within the spare area where YAFFS2 tags are commonly found: at the start when
+/// written by mkyaffs2image, after the bad block marker when placed by the MTD layer
+const TAG_OFFSETS: &[usize] = &[0, 2, 4, 16];
+/// Number of chunks looked at when choosing a layout
+const PROBE_CHUNKS: usize = 64;
+
+const OBJ_HEADER_LEN: usize = 512;
+const PACKED_TAGS2_LEN: usize = 16;
+const YAFFS1_PAGE: usize = 512;
+
+const OBJECT_FILE: u32 = 1;
+const OBJECT_SYMLINK: u32 = 2;
+const OBJECT_DIR: u32 = 3;
+const OBJECT_HARDLINK: u32 = 4;
+const OBJECT_SPECIAL: u32 = 5;
+
+const ROOT_ID: u32 = 1;
+const LOST_AND_FOUND_ID: u32 = 2;
+const UNLINKED_ID: u32 = 3;
+const DELETED_ID: u32 = 4;
+
+const LOWEST_SEQUENCE: u32 = 0x0000_1000;
+const HIGHEST_SEQUENCE: u32 = 0xefff_ff00;
+const CHECKPOINT_SEQUENCE: u32 = 0x21;
+
+/// Set in the chunk ID of headers whose tags summarise the header, in which case the chunk ID
+/// holds the parent and the top bits of the object ID hold the object type
+const EXTRA_HEADER_INFO_FLAG: u32 = 0x8000_0000;
+const EXTRA_OBJECT_TYPE_SHIFT: u32 = 28;
+const MAX_CHUNK_ID: u32 = 0xf_ffff;
+
+const MAX_HARDLINK_DEPTH: usize = 32;
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// In-process reader for YAFFS2 (and YAFFS1) NAND images. The page size, spare area size, tag
+/// format (YAFFS1 tags, YAFFS2 tags in the spare area or inband tags) and endianness are
+/// probed from the image.
+pub struct YaffsExtractor;
+
+impl Extractor for YaffsExtractor {
+    fn name(&self) -> &'static str {
+        "yaffs"
+    }
+
+    fn extract(
+        &self,
+        in_file: &Path,
+        extract_dir: &Path,
+        log_file: &Path,
+        _verbose: bool,
+    ) -> Result<ExtractStatus, ExtractError> {
+        native::extract_all(
+            self.name(),
+            in_file,
+            extract_dir,
+            log_file,
+            MAGICS,
+            |data, offset, out_dir| {
+                let Some(layout) = Layout::probe(data, offset) else {
+                    return Ok(None);
+                };
+
+                let scan = Scan::run(data, offset, layout);
+                log::debug!(
+                    "yaffs: {layout} at {:#x}, {} chunks",
+                    scan.start,
+                    scan.chunks
+                );
+
+                let mut warnings = scan.warnings.clone();
+                let entries = scan.unpack(out_dir, &mut warnings)?;
+
+                Ok(Some(Unpacked {
+                    len: scan.end - offset,
+                    entries,
+                    warnings,
+                }))
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TagFormat {
+    /// 8 bytes of tags spread over the 16 byte spare area of a 512 byte page
+    Yaffs1,
+    /// Packed YAFFS2 tags at an offset in the spare area
+    Yaffs2 { offset: usize },
+    /// Packed YAFFS2 tags at the end of each page, shortening the data area
+    Inband,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    page: usize,
+    spare: usize,
+    tags: TagFormat,
+    endian: Endian,
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tags = match self.tags {
+            TagFormat::Yaffs1 => "yaffs1 tags".to_owned(),
+            TagFormat::Yaffs2 { offset } => format!("yaffs2 tags at spare offset {offset}"),
+            TagFormat::Inband => "inband tags".to_owned(),
+        };
+        write!(
+            f,
+            "{}+{} byte pages, {tags}, {} endian",
+            self.page,
+            self.spare,
+            self.endian.name()
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Tags {
+    obj_id: u32,
+    /// 0 for object headers, otherwise the 1-based index of the data chunk in the file
+    chunk_id: u32,
+    n_bytes: usize,
+    /// Orders chunks with the same ID: the YAFFS2 block sequence number, or the YAFFS1 serial
+    serial: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    Valid(Tags),
+    /// Erased, deleted, in a bad block or holding checkpoint data
+    Skipped,
+    Invalid,
+}
+
+impl Layout {
+    fn stride(&self) -> usize {
+        self.page + self.spare
+    }
+
+    fn data_len(&self) -> usize {
+        match self.tags {
+            TagFormat::Inband => self.page - PACKED_TAGS2_LEN,
+            _ => self.page,
+        }
+    }
+
+    fn candidates() -> impl Iterator<Item = Layout> {
+        GEOMETRIES.iter().flat_map(|&(page, spares)| {
+            spares.iter().flat_map(move |&spare| {
+                let formats: Vec<TagFormat> = if spare == 0 {
+                    vec![TagFormat::Inband]
+                } else if page == YAFFS1_PAGE && spare == 16 {
+                    vec![
+                        TagFormat::Yaffs1,
+                        TagFormat::Yaffs2 { offset: 0 },
+                        TagFormat::Inband,
+                    ]
+                } else {
+                    TAG_OFFSETS
+                        .iter()
+                        .filter(|&&offset| offset + PACKED_TAGS2_LEN <= spare)
+                        .map(|&offset| TagFormat::Yaffs2 { offset })
+                        .chain([TagFormat::Inband])
+                        .collect()
+                };
+
+                formats.into_iter().flat_map(move |tags| {
+                    [Endian::Little, Endian::Big].map(|endian| Layout {
+                        page,
+                        spare,
+                        tags,
+                        endian,
+                    })
+                })
+            })
+        })
+    }
+
+    /// Pick the layout under which the most chunks from `offset` on parse consistently. The
+    /// chunk at `offset` must be an object header.
+    fn probe(data: &[u8], offset: usize) -> Option<Layout> {
+        let mut best: Option<(usize, Layout)> = None;
+
+        for layout in Self::candidates() {
+            let Some(Chunk::Valid(first)) = layout.chunk(data, offset) else {
+                continue;
+            };
+            if first.chunk_id != 0 || layout.header(data, offset, first.obj_id).is_none() {
+                continue;
+            }
+
+            let score = (0..PROBE_CHUNKS)
+                .map(|index| layout.chunk(data, offset + index * layout.stride()))
+                .take_while(|chunk| matches!(chunk, Some(Chunk::Valid(_) | Chunk::Skipped)))
+                .filter(|chunk| matches!(chunk, Some(Chunk::Valid(_))))
+                .count();
+
+            if best.is_none_or(|(best_score, _)| score > best_score) {
+                best = Some((score, layout));
+            }
+        }
+
+        best.map(|(_, layout)| layout)
+    }
+
+    /// Parse the tags of the chunk at `pos`, or `None` past the end of the data
+    fn chunk(&self, data: &[u8], pos: usize) -> Option<Chunk> {
+        let chunk = data.get(pos..pos + self.stride())?;
+        let (page, spare) = chunk.split_at(self.page);
+
+        Some(match self.tags {
+            TagFormat::Yaffs1 => self.yaffs1_tags(spare),
+            TagFormat::Yaffs2 { offset } => {
+                // A bad block marker, when the tags leave room for one
+                if offset > 0 && spare[0] != 0xff {
+                    return Some(Chunk::Skipped);
+                }
+                self.yaffs2_tags(&spare[offset..offset + PACKED_TAGS2_LEN])
+            }
+            TagFormat::Inband => self.yaffs2_tags(&page[self.data_len()..]),
+        })
+    }
+
+    fn yaffs2_tags(&self, raw: &[u8]) -> Chunk {
+        if raw.iter().all(|&byte| byte == 0xff) {
+            return Chunk::Skipped;
+        }
+
+        let e = self.endian;
+        let seq = e.u32(&raw[0..4]);
+        let mut obj_id = e.u32(&raw[4..8]);
+        let mut chunk_id = e.u32(&raw[8..12]);
+        let mut n_bytes = e.u32(&raw[12..16]) as usize;
+
+        if seq == CHECKPOINT_SEQUENCE {
+            return Chunk::Skipped;
+        }
+        if !(LOWEST_SEQUENCE..=HIGHEST_SEQUENCE).contains(&seq) {
+            return Chunk::Invalid;
+        }
+
+        if chunk_id & EXTRA_HEADER_INFO_FLAG != 0 {
+            let obj_type = obj_id >> EXTRA_OBJECT_TYPE_SHIFT;
+            if !(OBJECT_FILE..=OBJECT_SPECIAL).contains(&obj_type) {
+                return Chunk::Invalid;
+            }
+
+            obj_id &= (1 << EXTRA_OBJECT_TYPE_SHIFT) - 1;
+            chunk_id = 0;
+            n_bytes = 0;
+        }
+
+        if obj_id == 0 || chunk_id > MAX_CHUNK_ID || n_bytes > self.data_len() {
+            return Chunk::Invalid;
+        }
+
+        Chunk::Valid(Tags {
+            obj_id,
+            chunk_id,
+            n_bytes,
+            serial: seq,
+        })
+    }
+
+    fn yaffs1_tags(&self, spare: &[u8]) -> Chunk {
+        let page_status = spare[4];
+        let block_status = spare[5];
+        if page_status != 0xff || block_status != 0xff {
+            return Chunk::Skipped;
+        }
+
+        let raw = [
+            spare[0], spare[1], spare[2], spare[3], spare[6], spare[7], spare[11], spare[12],
+        ];
+        if raw.iter().all(|&byte| byte == 0xff) {
+            return Chunk::Skipped;
+        }
+
+        // Bitfields are allocated from the least significant bit on little-endian machines and
+        // from the most significant bit on big-endian ones
+        let value = self.endian.u64(&raw);
+        let (chunk_id, serial, n_bytes_lsb, obj_id, n_bytes_msb) = match self.endian {
+            Endian::Little => (
+                value & 0xf_ffff,
+                (value >> 20) & 0x3,
+                (value >> 22) & 0x3ff,
+                (value >> 32) & 0x3_ffff,
+                value >> 62,
+            ),
+            Endian::Big => (
+                value >> 44,
+                (value >> 42) & 0x3,
+                (value >> 32) & 0x3ff,
+                (value >> 14) & 0x3_ffff,
+                value & 0x3,
+            ),
+        };
+
+        let n_bytes = (n_bytes_lsb | (n_bytes_msb << 10)) as usize;
+        if obj_id == 0 || n_bytes > YAFFS1_PAGE {
+            return Chunk::Invalid;
+        }
+
+        Chunk::Valid(Tags {
+            obj_id: obj_id as u32,
+            chunk_id: chunk_id as u32,
+            n_bytes,
+            serial: serial as u32,
+        })
+    }
+
+    /// Whether the chunk at `pos` has valid tags and, for a header, a valid object header
+    fn is_intact(&self, data: &[u8], pos: usize) -> bool {
+        match self.chunk(data, pos) {
+            Some(Chunk::Valid(tags)) => {
+                tags.chunk_id != 0 || self.header(data, pos, tags.obj_id).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Parse the object header in the chunk at `pos`
+    fn header<'a>(&self, data: &'a [u8], pos: usize, obj_id: u32) -> Option<Header<'a>> {
+        Header::parse(data.get(pos..pos + OBJ_HEADER_LEN)?, obj_id, self.endian)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header<'a> {
+    obj_type: u32,
+    parent: u32,
+    name: &'a [u8],
+    mode: u32,
+    attrs: Attrs,
+    size: u64,
+    equiv_id: u32,
+    alias: &'a [u8],
+    rdev: u32,
+}
+
+fn c_string(raw: &[u8]) -> &[u8] {
+    &raw[..raw.iter().position(|&byte| byte == 0).unwrap_or(raw.len())]
+}
+
+impl<'a> Header<'a> {
+    fn parse(raw: &'a [u8], obj_id: u32, endian: Endian) -> Option<Self> {
+        let e = endian;
+        let obj_type = e.u32(&raw[0..4]);
+        let parent = e.u32(&raw[4..8]);
+        if !(OBJECT_FILE..=OBJECT_SPECIAL).contains(&obj_type) || parent == obj_id {
+            return None;
+        }
+
+        let size_low = e.u32(&raw[292..296]);
+        let size_high = e.u32(&raw[496..500]);
+        let size = if size_high == u32::MAX {
+            u64::from(size_low)
+        } else {
+            u64::from(size_low) | (u64::from(size_high) << 32)
+        };
+
+        let mode = e.u32(&raw[268..272]);
+
+        Some(Self {
+            obj_type,
+            parent,
+            name: c_string(&raw[10..266]),
+            mode,
+            attrs: Attrs::new(mode, e.u32(&raw[272..276]), e.u32(&raw[276..280])),
+            size,
+            equiv_id: e.u32(&raw[296..300]),
+            alias: c_string(&raw[300..460]),
+            rdev: e.u32(&raw[460..464]),
+        })
+    }
+}
+
+/// Block sequence number (or YAFFS1 serial) and position of a chunk, which order its copies
+type Order = (u32, usize);
+
+/// Newest copy of every chunk in an image
+struct Scan<'a> {
+    data: &'a [u8],
+    layout: Layout,
+    start: usize,
+    end: usize,
+    chunks: usize,
+    /// Object headers by object ID
+    headers: HashMap<u32, (Order, Header<'a>)>,
+    /// Data chunks by object ID and chunk ID
+    file_data: HashMap<u32, BTreeMap<u32, (Order, &'a [u8])>>,
+    warnings: Vec<String>,
+}
+
+impl<'a> Scan<'a> {
+    fn run(data: &'a [u8], offset: usize, layout: Layout) -> Self {
+        let stride = layout.stride();
+
+        // The first object in the root directory need not be in the first chunk
+        let mut start = offset;
+        while start >= stride && layout.is_intact(data, start - stride) {
+            start -= stride;
+        }
+
+        let mut scan = Self {
+            data,
+            layout,
+            start,
+            end: offset + stride,
+            chunks: 0,
+            headers: HashMap::new(),
+            file_data: HashMap::new(),
+            warnings: Vec::new(),
+        };
+
+        let mut bad_headers = 0;
+        let mut pos = start;
+        while let Some(chunk) = layout.chunk(data, pos) {
+            match chunk {
+                Chunk::Valid(tags) => {
+                    if !scan.add(pos, tags) {
+                        bad_headers += 1;
+                    }
+                    scan.chunks += 1;
+                    scan.end = pos + stride;
+                }
+                Chunk::Skipped => {}
+                Chunk::Invalid => break,
+            }
+            pos += stride;
+        }
+
+        if bad_headers > 0 {
+            scan.warnings
+                .push(format!("{bad_headers} damaged object headers were skipped"));
+        }
+
+        scan
+    }
+
+    /// Whether `new` supersedes `old`. YAFFS2 orders by block sequence number and then by
+    /// position, YAFFS1 by a two bit serial number that is incremented on every rewrite.
+    fn is_newer(&self, new: Order, old: Order) -> bool {
+        match self.layout.tags {
+            TagFormat::Yaffs1 => new.0 == (old.0 + 1) % 4 || (new.0 == old.0 && new.1 > old.1),
+            _ => new > old,
+        }
+    }
+
+    /// Record a chunk, returning false if it is a header that does not parse
+    fn add(&mut self, pos: usize, tags: Tags) -> bool {
+        let order = (tags.serial, pos);
+
+        if tags.chunk_id == 0 {
+            let Some(header) = self.layout.header(self.data, pos, tags.obj_id) else {
+                return false;
+            };
+
+            let newer = match self.headers.get(&tags.obj_id) {
+                Some(&(old, _)) => self.is_newer(order, old),
+                None => true,
+            };
+            if newer {
+                self.headers.insert(tags.obj_id, (order, header));
+            }
+        } else {
+            let contents = &self.data[pos..pos + tags.n_bytes];
+
+            let newer = match self
+                .file_data
+                .get(&tags.obj_id)
+                .and_then(|chunks| chunks.get(&tags.chunk_id))
+            {
+                Some(&(old, _)) => self.is_newer(order, old),
+                None => true,
+            };
+            if newer {
+                self.file_data
+                    .entry(tags.obj_id)
+                    .or_default()
+                    .insert(tags.chunk_id, (order, contents));
+            }
+        }
+
+        true
+    }
+
+    fn file_contents(&self, obj_id: u32, header: &Header) -> io::Result<Vec<u8>> {
+        if header.size > MAX_FILE_SIZE {
+            return Err(invalid_data(format!(
+                "file too large ({} bytes)",
+                header.size
+            )));
+        }
+
+        let size = header.size as usize;
+        let chunk_len = self.layout.data_len();
+        let mut out = vec![0; size];
+
+        for (&chunk_id, &(_, contents)) in self.file_data.get(&obj_id).into_iter().flatten() {
+            let start = (chunk_id as usize - 1) * chunk_len;
+            if start >= size {
+                // Left over from before the file was truncated
+                continue;
+            }
+
+            let end = size.min(start + contents.len());
+            out[start..end].copy_from_slice(&contents[..end - start]);
+        }
+
+        Ok(out)
+    }
+
+    /// Follow hardlinks to the object they refer to
+    fn resolve(&self, obj_id: u32, header: Header<'a>) -> io::Result<(u32, Header<'a>)> {
+        let (mut obj_id, mut header) = (obj_id, header);
+        for _ in 0..MAX_HARDLINK_DEPTH {
+            if header.obj_type != OBJECT_HARDLINK {
+                return Ok((obj_id, header));
+            }
+
+            obj_id = header.equiv_id;
+            header = self
+                .headers
+                .get(&obj_id)
+                .map(|&(_, header)| header)
+                .ok_or_else(|| invalid_data(format!("hardlink to missing object {obj_id}")))?;
+        }
+
+        Err(invalid_data("too many levels of hardlinks"))
+    }
+
+    fn unpack_entry(
+        &self,
+        tree: &mut TreeWriter,
+        rel: &Path,
+        obj_id: u32,
+        header: &Header,
+    ) -> io::Result<()> {
+        match header.obj_type {
+            OBJECT_FILE => tree.file_with(rel, header.attrs, header.size, || {
+                self.file_contents(obj_id, header)
+            }),
+            OBJECT_SYMLINK => tree.symlink(rel, header.attrs, header.alias),
+            _ => {
+                let kind = match header.mode & libc::S_IFMT {
+                    libc::S_IFCHR => EntryKind::Char,
+                    libc::S_IFBLK => EntryKind::Block,
+                    libc::S_IFIFO => EntryKind::Fifo,
+                    libc::S_IFSOCK => EntryKind::Socket,
+                    mode => return Err(invalid_data(format!("unknown special mode {mode:#o}"))),
+                };
+                let dev = header.rdev;
+                let rdev = matches!(kind, EntryKind::Char | EntryKind::Block)
+                    .then_some(((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00)));
+
+                tree.special(rel, kind, header.attrs, rdev)
+            }
+        }
+    }
+
+    fn unpack(&self, out_dir: &Path, warnings: &mut Vec<String>) -> io::Result<usize> {
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (&obj_id, &(_, header)) in &self.headers {
+            if !matches!(header.parent, UNLINKED_ID | DELETED_ID) {
+                children.entry(header.parent).or_default().push(obj_id);
+            }
+        }
+        for siblings in children.values_mut() {
+            siblings.sort_unstable();
+        }
+
+        // The root and lost+found directories normally have no header of their own
+        let root_attrs = self
+            .headers
+            .get(&ROOT_ID)
+            .map_or(Attrs::new(libc::S_IFDIR | 0o755, 0, 0), |(_, header)| {
+                header.attrs
+            });
+
+        let mut tree = TreeWriter::new(out_dir, "yaffs")?;
+        tree.dir(Path::new(""), root_attrs)?;
+
+        let mut pending = vec![(PathBuf::new(), ROOT_ID)];
+        if children.contains_key(&LOST_AND_FOUND_ID) {
+            let rel = PathBuf::from("lost+found");
+            tree.dir(&rel, Attrs::new(libc::S_IFDIR | 0o700, 0, 0))?;
+            pending.push((rel, LOST_AND_FOUND_ID));
+        }
+
+        let mut visited = HashSet::from([ROOT_ID, LOST_AND_FOUND_ID]);
+
+        while let Some((dir, dir_id)) = pending.pop() {
+            for &obj_id in children.get(&dir_id).into_iter().flatten() {
+                let (_, entry) = self.headers[&obj_id];
+
+                let name = match safe_name(entry.name) {
+                    Ok(name) => name,
+                    Err(err) => {
+                        warnings.push(format!("skipping entry in /{}: {err}", dir.display()));
+                        continue;
+                    }
+                };
+                let rel = dir.join(name);
+
+                let (target_id, header) = match self.resolve(obj_id, entry) {
+                    Ok(resolved) => resolved,
+                    Err(err) => {
+                        warnings.push(format!("skipping /{}: {err}", rel.display()));
+                        continue;
+                    }
+                };
+
+                if header.obj_type == OBJECT_DIR {
+                    if target_id != obj_id || !visited.insert(obj_id) {
+                        warnings.push(format!("skipping directory hardlink /{}", rel.display()));
+                        continue;
+                    }
+
+                    tree.dir(&rel, header.attrs)?;
+                    pending.push((rel, obj_id));
+                } else if let Some(existing) = tree.link_target(u64::from(target_id), &rel) {
+                    tree.hardlink(&rel, &existing)?;
+                } else if let Err(err) = self.unpack_entry(&mut tree, &rel, target_id, &header) {
+                    warnings.push(format!("skipping /{}: {err}", rel.display()));
+                }
+            }
+        }
+
+        let orphans = self
+            .headers
+            .values()
+            .filter(|(_, header)| {
+                !matches!(header.parent, UNLINKED_ID | DELETED_ID)
+                    && !visited.contains(&header.parent)
+            })
+            .count();
+        if orphans > 0 {
+            warnings.push(format!("{orphans} objects are not reachable from the root"));
+        }
+
+        tree.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    const SEQUENCE: u32 = LOWEST_SEQUENCE;
+
+    struct Builder {
+        layout: Layout,
+        image: Vec<u8>,
+    }
+
+    impl Builder {
+        fn new(layout: Layout) -> Self {
+            Self {
+                layout,
+                image: Vec::new(),
+            }
+        }
+
+        fn words(&self, words: &[u32]) -> Vec<u8> {
+            words
+                .iter()
+                .flat_map(|&word| match self.layout.endian {
+                    Endian::Little => word.to_le_bytes(),
+                    Endian::Big => word.to_be_bytes(),
+                })
+                .collect()
+        }
+
+        fn chunk(&mut self, seq: u32, obj_id: u32, chunk_id: u32, data: &[u8]) {
+            let layout = self.layout;
+            let mut chunk = vec![0xff; layout.stride()];
+            chunk[..data.len()].copy_from_slice(data);
+
+            // Headers written by mkyaffs2image leave the byte count at zero
+            let n_bytes = if chunk_id == 0 { 0 } else { data.len() as u32 };
+            let tags = self.words(&[seq, obj_id, chunk_id, n_bytes]);
+            let tags_at = match layout.tags {
+                TagFormat::Yaffs2 { offset } => layout.page + offset,
+                _ => layout.data_len(),
+            };
+            chunk[tags_at..tags_at + PACKED_TAGS2_LEN].copy_from_slice(&tags);
+
+            self.image.extend(chunk);
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn header(
+            &mut self,
+            seq: u32,
+            obj_id: u32,
+            obj_type: u32,
+            parent: u32,
+            name: &str,
+            mode: u32,
+            size: u32,
+            equiv_id: u32,
+            alias: &str,
+        ) {
+            let mut header = vec![0; OBJ_HEADER_LEN];
+            header[0..8].copy_from_slice(&self.words(&[obj_type, parent]));
+            header[10..10 + name.len()].copy_from_slice(name.as_bytes());
+            header[268..272].copy_from_slice(&self.words(&[mode]));
+            header[292..300].copy_from_slice(&self.words(&[size, equiv_id]));
+            header[300..300 + alias.len()].copy_from_slice(alias.as_bytes());
+            header[496..500].copy_from_slice(&self.words(&[u32::MAX]));
+
+            self.chunk(seq, obj_id, 0, &header);
+        }
+
+        /// A file rewritten in a later block, a hardlink and a symlink to it in a subdirectory,
+        /// a deleted file, an orphan and a damaged header
+        fn build(layout: Layout) -> Vec<u8> {
+            let mut fs = Self::new(layout);
+            let file = libc::S_IFREG | 0o644;
+
+            fs.header(SEQUENCE, 257, OBJECT_FILE, ROOT_ID, "file", file, 12, 0, "");
+            fs.chunk(SEQUENCE, 257, 1, b"stale data!!");
+            fs.header(
+                SEQUENCE,
+                258,
+                OBJECT_DIR,
+                ROOT_ID,
+                "dir",
+                libc::S_IFDIR | 0o755,
+                0,
+                0,
+                "",
+            );
+            fs.header(
+                SEQUENCE,
+                259,
+                OBJECT_SYMLINK,
+                258,
+                "link",
+                libc::S_IFLNK | 0o777,
+                0,
+                0,
+                "../file",
+            );
+            fs.header(
+                SEQUENCE,
+                260,
+                OBJECT_HARDLINK,
+                258,
+                "hard",
+                file,
+                0,
+                257,
+                "",
+            );
+            fs.header(
+                SEQUENCE,
+                261,
+                OBJECT_FILE,
+                DELETED_ID,
+                "deleted",
+                file,
+                0,
+                0,
+                "",
+            );
+            fs.header(SEQUENCE, 262, OBJECT_FILE, 999, "orphan", file, 0, 0, "");
+            fs.header(SEQUENCE, 263, 9, ROOT_ID, "damaged", file, 0, 0, "");
+            fs.chunk(SEQUENCE + 1, 257, 1, b"hello yaffs\n");
+
+            fs.image
+        }
+    }
+
+    fn unpack(image: &[u8]) -> (Layout, tempfile::TempDir, Vec<String>) {
+        let layout = Layout::probe(image, 0).unwrap();
+        let scan = Scan::run(image, 0, layout);
+        assert_eq!(scan.end, image.len());
+
+        let tmp = tempfile::tempdir().unwrap();
+        let mut warnings = scan.warnings.clone();
+        scan.unpack(tmp.path(), &mut warnings).unwrap();
+        (layout, tmp, warnings)
+    }
+
+    #[test]
+    fn probes_layout_and_unpacks() {
+        for (page, spare, tags, endian) in [
+            (2048, 64, TagFormat::Yaffs2 { offset: 0 }, Endian::Little),
+            (2048, 64, TagFormat::Yaffs2 { offset: 2 }, Endian::Big),
+            (4096, 0, TagFormat::Inband, Endian::Little),
+        ] {
+            let layout = Layout {
+                page,
+                spare,
+                tags,
+                endian,
+            };
+            let (probed, tmp, warnings) = unpack(&Builder::build(layout));
+            assert_eq!(
+                (probed.page, probed.spare, probed.tags, probed.endian.name()),
+                (page, spare, tags, endian.name())
+            );
+            assert_eq!(
+                warnings,
+                [
+                    "1 damaged object headers were skipped",
+                    "1 objects are not reachable from the root",
+                ]
+            );
+
+            let file = tmp.path().join("file");
+            assert_eq!(fs::read(&file).unwrap(), b"hello yaffs\n");
+            assert_eq!(
+                fs::metadata(tmp.path().join("dir/hard")).unwrap().ino(),
+                fs::metadata(&file).unwrap().ino()
+            );
+            assert_eq!(
+                fs::read_link(tmp.path().join("dir/link")).unwrap(),
+                Path::new("../file")
+            );
+            assert!(!tmp.path().join("deleted").exists());
+            assert!(!tmp.path().join("damaged").exists());
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! The library entry point: describe an extraction with [`ExtractionJob`] and [`run`] it.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use fw2tar::ExtractionJob;
+//!
+//! let extraction = ExtractionJob::new("firmware.bin")
+//!     .output_dir("out")
+//!     .extractors(["squashfs", "unblob"])
+//!     .timeout(Duration::from_secs(120))
+//!     .progress(|event: &fw2tar::progress::Event| eprintln!("{event:?}"))
+//!     .run()?;
+//!
+//! println!("{:?}", extraction.results.preferred_extractor);
+//! # Ok::<(), fw2tar::Fw2tarError>(())
+//! ```
+//!
+//! Resource limits, quotas, the sandbox and extractor plugins are process-wide, so jobs that
+//! run at the same time in one process should agree on them.
+//!
+//! [`run`]: ExtractionJob::run
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::analysis::scoring::ScoringProfile;
+use crate::args::Args;
+use crate::extractors::quota::Quota;
+use crate::extractors::supervisor::Limits;
+use crate::progress::{Event, EventKind, Progress, Silent};
+use crate::results::Results;
+use crate::{BestExtractor, Fw2tarError};
+
+/// What a finished job produced.
+#[derive(Debug)]
+pub struct Extraction {
+    pub best: BestExtractor,
+    /// Directory all outputs were written to
+    pub output_dir: PathBuf,
+    /// The selected rootfs archive, `None` if no extractor found a rootfs
+    pub rootfs_archive: Option<PathBuf>,
+    /// Everything written to `xfs_results.json`
+    pub results: Results,
+}
+
+/// An extraction of one firmware image, configured with the builder methods and started
+/// with [`run`](ExtractionJob::run).
+pub struct ExtractionJob {
+    pub(crate) firmware: PathBuf,
+    pub(crate) output_dir: Option<PathBuf>,
+    pub(crate) extractors: Option<Vec<String>>,
+    pub(crate) extractor_config: Option<PathBuf>,
+    pub(crate) scoring: ScoringProfile,
+    pub(crate) scoring_profile: Option<PathBuf>,
+    pub(crate) keep_scratch: bool,
+    pub(crate) copy_rootfs: bool,
+    pub(crate) keep_candidates: bool,
+    pub(crate) primary_limit: usize,
+    pub(crate) secondary_limit: usize,
+    pub(crate) force: bool,
+    pub(crate) logs: bool,
+    pub(crate) log_devices: bool,
+    pub(crate) verbose: bool,
+    pub(crate) timeout: Duration,
+    pub(crate) limits: Limits,
+    pub(crate) sandbox: Option<Vec<PathBuf>>,
+    pub(crate) quota: Quota,
+    pub(crate) extractor_quotas: BTreeMap<String, Quota>,
+    progress: Box<dyn Progress>,
+}
+
+impl fmt::Debug for ExtractionJob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtractionJob")
+            .field("firmware", &self.firmware)
+            .field("output_dir", &self.output_dir)
+            .field("extractors", &self.extractors)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ExtractionJob {
+    /// Extract `firmware` with every extractor into the current directory, with the same
+    /// defaults as the `xfs` command line.
+    pub fn new(firmware: impl Into<PathBuf>) -> Self {
+        Self {
+            firmware: firmware.into(),
+            output_dir: None,
+            extractors: None,
+            extractor_config: None,
+            scoring: ScoringProfile::default(),
+            scoring_profile: None,
+            keep_scratch: true,
+            copy_rootfs: false,
+            keep_candidates: false,
+            primary_limit: 1,
+            secondary_limit: 0,
+            force: false,
+            logs: false,
+            log_devices: false,
+            verbose: false,
+            timeout: Duration::from_secs(20),
+            limits: Limits::default(),
+            sandbox: None,
+            quota: Quota::default(),
+            extractor_quotas: BTreeMap::new(),
+            progress: Box::new(Silent),
+        }
+    }
+
+    /// The job the `xfs` command line describes, without progress reporting.
+    pub fn from_args(args: &Args) -> Self {
+        let mut job = Self::new(&args.firmware)
+            .keep_scratch(!args.no_scratch)
+            .copy_rootfs(args.copy_rootfs)
+            .keep_candidates(args.keep_candidates)
+            .primary_limit(args.primary_limit)
+            .secondary_limit(args.secondary_limit)
+            .force(args.force)
+            .logs(args.logs)
+            .log_devices(args.log_devices)
+            .verbose(args.loud)
+            .timeout(Duration::from_secs(args.timeout))
+            .limits(Limits {
+                memory: args.memory_limit.map(|mib| mib << 20),
+                cpu: args.cpu_limit,
+                file_size: args.file_size_limit.map(|mib| mib << 20),
+            })
+            .quota(Quota {
+                bytes: args.quota_size.map(|mib| mib << 20),
+                files: args.quota_files,
+            });
+
+        job.output_dir = args.output.clone();
+        job.extractors = args
+            .extractors
+            .as_ref()
+            .map(|extractors| extractors.split(',').map(String::from).collect());
+        job.extractor_config = args.extractor_config.clone();
+        job.scoring_profile = args.scoring_profile.clone();
+        job.sandbox = args.sandbox.then(|| args.sandbox_allow.clone());
+        job.extractor_quotas = args.extractor_quota.iter().cloned().collect();
+
+        job
+    }
+
+    /// Directory to write all outputs to, instead of the current directory
+    pub fn output_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.output_dir = Some(dir.into());
+        self
+    }
+
+    /// Only run the named extractors, instead of all of them
+    pub fn extractors<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.extractors = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Load additional external extractors from a plugin config file
+    pub fn extractor_config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.extractor_config = Some(path.into());
+        self
+    }
+
+    /// Rank rootfs candidates with `profile` instead of the default weights
+    pub fn scoring(mut self, profile: ScoringProfile) -> Self {
+        self.scoring = profile;
+        self.scoring_profile = None;
+        self
+    }
+
+    /// Load the scoring profile from a JSON file when the job runs, see
+    /// [`scoring`](crate::analysis::scoring)
+    pub fn scoring_profile(mut self, path: impl Into<PathBuf>) -> Self {
+        self.scoring_profile = Some(path.into());
+        self
+    }
+
+    /// Keep every extractor's output in `xfs-extract/` (the default)
+    pub fn keep_scratch(mut self, keep: bool) -> Self {
+        self.keep_scratch = keep;
+        self
+    }
+
+    /// Copy the selected rootfs out to `rootfs/`
+    pub fn copy_rootfs(mut self, copy: bool) -> Self {
+        self.copy_rootfs = copy;
+        self
+    }
+
+    /// Keep the archives of the extractors that were not selected
+    pub fn keep_candidates(mut self, keep: bool) -> Self {
+        self.keep_candidates = keep;
+        self
+    }
+
+    /// Maximum number of root filesystems to archive per extractor
+    pub fn primary_limit(mut self, limit: usize) -> Self {
+        self.primary_limit = limit;
+        self
+    }
+
+    /// Maximum number of non-root filesystems to archive per extractor
+    pub fn secondary_limit(mut self, limit: usize) -> Self {
+        self.secondary_limit = limit;
+        self
+    }
+
+    /// Replace the outputs of a previous run instead of failing
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Write a log file per extractor to the output directory
+    pub fn logs(mut self, logs: bool) -> Self {
+        self.logs = logs;
+        self
+    }
+
+    /// Write the device nodes left out of the archives to `devices.log`
+    pub fn log_devices(mut self, log_devices: bool) -> Self {
+        self.log_devices = log_devices;
+        self
+    }
+
+    /// Log the output of external extractors
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// How long each external extractor may run
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Resource limits for external extractor processes
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Run external extractors in a namespace sandbox, exposing `read_only` paths on top of
+    /// the system directories
+    pub fn sandbox(mut self, read_only: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.sandbox = Some(read_only.into_iter().collect());
+        self
+    }
+
+    /// Quota on everything written by all extractors together
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// Quota on what the named extractor writes
+    pub fn extractor_quota(mut self, extractor: impl Into<String>, quota: Quota) -> Self {
+        self.extractor_quotas.insert(extractor.into(), quota);
+        self
+    }
+
+    /// Report progress to `progress` instead of discarding it
+    pub fn progress(mut self, progress: impl Progress + 'static) -> Self {
+        self.progress = Box::new(progress);
+        self
+    }
+
+    pub fn firmware(&self) -> &Path {
+        &self.firmware
+    }
+
+    pub(crate) fn emit(&self, extractor: Option<&'static str>, kind: EventKind) {
+        self.progress.event(&Event::new(extractor, kind));
+    }
+
+    /// Run every extractor and write the outputs. Nothing is written to the output directory
+    /// unless the job succeeds.
+    pub fn run(&self) -> Result<Extraction, Fw2tarError> {
+        self.emit(
+            None,
+            EventKind::JobStarted {
+                input: self.firmware.display().to_string(),
+            },
+        );
+
+        let extraction = crate::run(self);
+        match &extraction {
+            Ok(extraction) => self.emit(
+                extraction.best.name(),
+                EventKind::JobFinished {
+                    rootfs_archive: extraction.results.rootfs_archive.clone(),
+                },
+            ),
+            Err(e) => self.emit(
+                None,
+                EventKind::JobFailed {
+                    error: e.to_string(),
+                },
+            ),
+        }
+
+        extraction
+    }
+}
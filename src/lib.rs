@@ -1,23 +1,35 @@
 pub mod analysis;
 pub mod archive;
 pub mod args;
+pub mod copy;
 mod error;
 pub mod extractors;
+pub mod job;
 pub mod metadata;
+pub mod output;
+pub mod progress;
+pub mod results;
 
-use analysis::{extract_and_process, ExtractionResult};
+use analysis::scoring::ScoringProfile;
+use analysis::{extract_and_process, ExtractionResult, CANDIDATES_DIR};
+use extractors::plugin::PluginConfig;
+use extractors::supervisor;
 pub use error::Fw2tarError;
+pub use job::{Extraction, ExtractionJob};
 use metadata::Metadata;
 
-use std::cmp::Reverse;
-use std::collections::HashSet;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Mutex;
+use std::time::Instant;
 use std::{env, fs, thread};
-use serde_json::json;
 
-use crate::analysis::copy_dir_all;
+use crate::copy::CopyReport;
+use crate::output::Transaction;
+use crate::progress::{EventKind, Stage};
+use crate::results::{Candidate, ExtractorResult, Input, Results, SecondaryFilesystem, SCHEMA_VERSION};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BestExtractor {
     Best(&'static str),
     Only(&'static str),
@@ -25,17 +37,45 @@ pub enum BestExtractor {
     None,
 }
 
-pub fn main(args: args::Args) -> Result<(BestExtractor, PathBuf), Fw2tarError> {
-    if !args.firmware.is_file() {
-        if args.firmware.exists() {
-            return Err(Fw2tarError::FirmwareNotAFile(args.firmware));
+impl BestExtractor {
+    /// Name of the extractor the rootfs was taken from, if any
+    pub fn name(&self) -> Option<&'static str> {
+        match *self {
+            BestExtractor::Best(name) | BestExtractor::Only(name) | BestExtractor::Identical(name) => Some(name),
+            BestExtractor::None => None,
+        }
+    }
+}
+
+fn run(job: &ExtractionJob) -> Result<Extraction, Fw2tarError> {
+    let start_time = Instant::now();
+
+    if !job.firmware.is_file() {
+        if job.firmware.exists() {
+            return Err(Fw2tarError::FirmwareNotAFile(job.firmware.clone()));
         } else {
-            return Err(Fw2tarError::FirmwareDoesNotExist(args.firmware));
+            return Err(Fw2tarError::FirmwareDoesNotExist(job.firmware.clone()));
+        }
+    }
+
+    let scoring = match &job.scoring_profile {
+        Some(path) => ScoringProfile::load(path)
+            .map_err(|e| Fw2tarError::InvalidScoringProfile(path.clone(), e))?,
+        None => job.scoring.clone(),
+    };
+
+    if let Some(config_path) = &job.extractor_config {
+        let config = PluginConfig::load(config_path)
+            .map_err(|e| Fw2tarError::InvalidExtractorConfig(config_path.clone(), e))?;
+
+        for plugin in config.extractors {
+            extractors::register_plugin(plugin)
+                .map_err(|e| Fw2tarError::InvalidExtractorConfig(config_path.clone(), e))?;
         }
     }
 
     // Determine output directory - default to current directory
-    let output_dir = args.output.clone().unwrap_or_else(|| env::current_dir().unwrap());
+    let output_dir = job.output_dir.clone().unwrap_or_else(|| env::current_dir().unwrap());
     
     // Ensure output directory exists
     if !output_dir.exists() {
@@ -43,58 +83,74 @@ pub fn main(args: args::Args) -> Result<(BestExtractor, PathBuf), Fw2tarError> {
     }
 
     // Extract base filename from firmware (for future use)
-    let _firmware_base = if let Some(stem) = args.firmware.file_stem() {
+    let _firmware_base = if let Some(stem) = job.firmware.file_stem() {
         stem.to_string_lossy().to_string()
     } else {
-        args.firmware.file_name().unwrap().to_string_lossy().to_string()
+        job.firmware.file_name().unwrap().to_string_lossy().to_string()
     };
 
+    // Hold the output directory for the whole run. Everything is built in a staging directory
+    // inside it and only moved into place once complete.
+    let transaction = Transaction::begin(&output_dir)?;
+
+    // Refuse to clobber a previous run's output unless forced, in which case it is replaced
+    // when the run is committed
+    for existing in ["rootfs.tar.gz", "xfs-extract"].map(|name| output_dir.join(name)) {
+        if existing.exists() && !job.force {
+            return Err(Fw2tarError::OutputExists(existing));
+        }
+    }
+
+    let final_output_dir = output_dir;
+    let output_dir = transaction.staging_dir().to_owned();
+
     // Set up output paths
     let selected_output_path = output_dir.join("rootfs.tar.gz");
     let extract_dir_path = output_dir.join("xfs-extract");
     let rootfs_dir_path = output_dir.join("rootfs");
 
-    // Check if either rootfs.tar.gz or xfs-extract directory already exist
-    if (selected_output_path.exists() || extract_dir_path.exists()) && !args.force {
-        // Return error with the path that exists (prioritize rootfs.tar.gz if both exist)
-        if selected_output_path.exists() {
-            return Err(Fw2tarError::OutputExists(selected_output_path));
-        } else {
-            return Err(Fw2tarError::OutputExists(extract_dir_path));
-        }
-    }
-    
-    // If --force is specified, remove existing files/directories
-    if args.force {
-        // Remove rootfs.tar.gz if it exists
-        if selected_output_path.exists() {
-            fs::remove_file(&selected_output_path)?;
-        }
-        
-        // Remove xfs-extract directory if it exists
-        if extract_dir_path.exists() {
-            fs::remove_dir_all(&extract_dir_path)?;
-        }
-        
-        // Remove xfs_results.json if it exists
-        let results_json_path = output_dir.join("xfs_results.json");
-        if results_json_path.exists() {
-            fs::remove_file(&results_json_path)?;
-        }
-    }
+    // Candidate archives are staged here until the best extractor is known
+    let candidates_dir = output_dir.join(CANDIDATES_DIR);
+    fs::create_dir_all(&candidates_dir)?;
 
+    let input = Input::hash(&job.firmware)?;
     let metadata = Metadata {
-        input_hash: analysis::sha1_file(&args.firmware).unwrap_or_default(),
-        file: args.firmware.display().to_string(),
+        input_hash: input.sha1.clone(),
+        file: job.firmware.display().to_string(),
         fw2tar_command: env::args().collect(),
     };
 
-    extractors::set_timeout(args.timeout);
+    // Identify vendor containers up front so their layout is reported whichever extractor wins
+    let vendor_headers = extractors::vendor::scan_file(&job.firmware).unwrap_or_else(|e| {
+        log::warn!("Failed to scan for vendor headers: {e}");
+        Vec::new()
+    });
+
+    for header in &vendor_headers {
+        log::info!(
+            "{} header at {:#x} ({} partitions)",
+            header.format,
+            header.offset,
+            header.partitions.len()
+        );
+    }
+
+    extractors::set_timeout(job.timeout);
+    extractors::supervisor::set_limits(job.limits);
+    match &job.sandbox {
+        Some(read_only) => extractors::sandbox::enable(read_only.clone()),
+        None => extractors::sandbox::disable(),
+    }
+    extractors::quota::set_quotas(job.quota, job.extractor_quotas.clone());
+    for name in job.extractor_quotas.keys() {
+        if extractors::get_extractor(name).is_none() {
+            return Err(Fw2tarError::InvalidExtractor(name.clone()));
+        }
+    }
 
-    let extractors: Vec<_> = args
+    let extractors: Vec<_> = job
         .extractors
-        .clone() // Clone to avoid partial move
-        .map(|extractors| extractors.split(",").map(String::from).collect())
+        .clone()
         .unwrap_or_else(|| {
             extractors::all_extractor_names()
                 .map(String::from)
@@ -102,10 +158,15 @@ pub fn main(args: args::Args) -> Result<(BestExtractor, PathBuf), Fw2tarError> {
         });
 
     let results: Mutex<Vec<ExtractionResult>> = Mutex::new(Vec::new());
+    let reports: Mutex<BTreeMap<&'static str, ExtractorResult>> = Mutex::new(BTreeMap::new());
+
+    // Temporary extraction directories holding rootfs candidates to copy, without scratch
+    let kept_temp_dirs = Mutex::new(Vec::new());
 
     let removed_devices: Option<Mutex<HashSet<PathBuf>>> =
-        args.log_devices.then(|| Mutex::new(HashSet::new()));
+        job.log_devices.then(|| Mutex::new(HashSet::new()));
 
+    job.emit(None, EventKind::StageStarted { stage: Stage::Extract });
     thread::scope(|threads| -> Result<(), Fw2tarError> {
         for extractor_name in extractors {
             let extractor = extractors::get_extractor(&extractor_name)
@@ -114,19 +175,22 @@ pub fn main(args: args::Args) -> Result<(BestExtractor, PathBuf), Fw2tarError> {
             threads.spawn(|| {
                 if let Err(e) = extract_and_process(
                     extractor,
-                    &args.firmware,
+                    &job.firmware,
                     &output_dir,
                     &extract_dir_path,
-                    !args.no_scratch,
-                    args.copy_rootfs,
+                    job.keep_scratch,
+                    job.copy_rootfs,
                     &rootfs_dir_path,
-                    args.loud,
-                    args.primary_limit,
-                    args.secondary_limit,
+                    &kept_temp_dirs,
+                    job.verbose,
+                    job.primary_limit,
+                    job.secondary_limit,
                     &results,
+                    &reports,
                     &metadata,
                     removed_devices.as_ref(),
-                    &args,
+                    &scoring,
+                    job,
                 ) {
                     log::info!("{} error: {e}", extractor.name());
                 }
@@ -136,6 +200,11 @@ pub fn main(args: args::Args) -> Result<(BestExtractor, PathBuf), Fw2tarError> {
         Ok(())
     })?;
 
+    if supervisor::interrupted() {
+        return Err(Fw2tarError::Interrupted);
+    }
+    job.emit(None, EventKind::StageFinished { stage: Stage::Extract });
+
     if let Some(removed_devices) = removed_devices {
         let mut removed_devices = removed_devices
             .into_inner()
@@ -148,7 +217,7 @@ pub fn main(args: args::Args) -> Result<(BestExtractor, PathBuf), Fw2tarError> {
 
         if removed_devices.is_empty() {
             log::warn!("No device files were found during extraction, skipping writing log");
-        } else if args.logs {
+        } else if job.logs {
             // Only write devices.log if --logs flag is specified
             let devices_log_path = output_dir.join("devices.log");
             fs::write(
@@ -160,163 +229,270 @@ pub fn main(args: args::Args) -> Result<(BestExtractor, PathBuf), Fw2tarError> {
     }
 
     let results = results.lock().unwrap();
-    let mut best_results: Vec<_> = results.iter().filter(|&res| res.index == 0).collect();
+    let mut best_results: Vec<_> = results.iter().filter(|&res| res.primary && res.index == 0).collect();
 
     // Get relative path to extracted files directory
     let relative_extract_dir = "./xfs-extract";
     
+    // The rootfs fields are filled in below once one is found
+    let mut document = Results {
+        schema_version: SCHEMA_VERSION,
+        input,
+        preferred_extractor: None,
+        identified_rootfs: None,
+        rootfs_archive: None,
+        copied_rootfs: None,
+        rootfs_copy: None,
+        extracted_files: relative_extract_dir.to_owned(),
+        extraction_status: None,
+        extractors: reports
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|(name, report)| (name.to_owned(), report))
+            .collect(),
+        candidates: Vec::new(),
+        secondary_filesystems: Vec::new(),
+        architecture: None,
+        vendor_headers,
+        elapsed_seconds: 0.0,
+    };
+
+    let relative_extract_path = |path: &std::path::Path| {
+        format!("./xfs-extract/{}", path.strip_prefix(extract_dir_path.as_path()).unwrap_or(path).display())
+    };
+
     // Create results.json even if no rootfs is found
     if best_results.is_empty() {
-        // Create xfs_results.json with null values for failure case
-        let results_json = json!({
-            "preferred_extractor": null,
-            "identified_rootfs": null,
-            "rootfs_archive": null,
-            "copied_rootfs": null,
-            "extracted_files": relative_extract_dir
-        });
-        
-        // Write the results JSON file
-        let results_json_path = output_dir.join("xfs_results.json");
-        let json_content = serde_json::to_string_pretty(&results_json).unwrap() + "\n";
-        fs::write(&results_json_path, json_content).unwrap();
+        job.emit(None, EventKind::StageStarted { stage: Stage::Output });
+
+        // Secondary filesystems are still kept, from the extractor that unpacked the most files
+        // into them
+        let mut secondaries: Vec<_> = results.iter().filter(|candidate| !candidate.primary).collect();
+        secondaries.sort_by_key(|candidate| (candidate.extractor, candidate.index));
+
+        let mut secondary_files: BTreeMap<&str, usize> = BTreeMap::new();
+        for candidate in &secondaries {
+            *secondary_files.entry(candidate.extractor).or_default() += candidate.num_files;
+        }
+        let secondary_extractor = secondary_files
+            .into_iter()
+            .max_by_key(|&(extractor, files)| (files, extractor == "unblob"))
+            .map(|(extractor, _)| extractor);
+
+        for candidate in secondaries {
+            let i = candidate.index;
+            let name = if Some(candidate.extractor) == secondary_extractor {
+                format!("secondary.{i}.tar.gz")
+            } else if job.keep_candidates {
+                format!("secondary.{}.{i}.tar.gz", candidate.extractor)
+            } else {
+                continue;
+            };
+
+            let archive = output_dir.join(&name);
+            fs::rename(&candidate.path, &archive)?;
+            job.emit(Some(candidate.extractor), EventKind::ArchiveWritten {
+                archive: name.clone(),
+                size: fs::metadata(&archive)?.len(),
+            });
+
+            if Some(candidate.extractor) == secondary_extractor {
+                document.secondary_filesystems.extend(candidate.kind.map(|kind| SecondaryFilesystem {
+                    archive: name,
+                    path: relative_extract_path(&candidate.rootfs_path),
+                    kind,
+                    size: candidate.size,
+                    num_files: candidate.num_files,
+                    archive_hash: candidate.archive_hash.clone(),
+                }));
+            }
+        }
+        document.elapsed_seconds = start_time.elapsed().as_secs_f64();
+        document.write(&output_dir)?;
         
-        return Ok((BestExtractor::None, selected_output_path));
+        fs::remove_dir_all(&candidates_dir)?;
+        transaction.commit()?;
+        job.emit(None, EventKind::StageFinished { stage: Stage::Output });
+
+        return Ok(Extraction {
+            best: BestExtractor::None,
+            output_dir: final_output_dir,
+            rootfs_archive: None,
+            results: document,
+        });
     }
     
-    let result = if best_results.len() == 1 {
-        Ok((BestExtractor::Only(best_results[0].extractor), selected_output_path.clone()))
+    let best = if best_results.len() == 1 {
+        BestExtractor::Only(best_results[0].extractor)
     } else {
-        best_results.sort_by_key(|res| Reverse((res.file_node_count, res.extractor == "unblob")));
+        // Highest score first, then the most complete archive, with unblob breaking ties
+        best_results.sort_by(|a, b| {
+            let score = |res: &ExtractionResult| res.score.as_ref().map_or(f64::MIN, |score| score.total);
+            score(b)
+                .total_cmp(&score(a))
+                .then_with(|| (b.file_node_count, b.extractor == "unblob").cmp(&(a.file_node_count, a.extractor == "unblob")))
+        });
 
-        Ok((BestExtractor::Best(best_results[0].extractor), selected_output_path.clone()))
+        BestExtractor::Best(best_results[0].extractor)
     };
 
     let best_result = best_results[0];
 
-    fs::rename(&best_result.path, &selected_output_path).unwrap();
-    
-    // Print the rootfs path for the best extractor only with relative path
-    let relative_rootfs_path = format!("./xfs-extract/{}", best_result.rootfs_path.strip_prefix(extract_dir_path.as_path()).unwrap_or(&best_result.rootfs_path).display());
-    
-    // Print rootfs path based on mode
-    if args.progress {
-        println!("xfs: [STAGE 3/4] Selecting best extractor: {}", best_result.extractor);
-        println!("xfs: [STAGE 3/4] rootfs found at: {}", relative_rootfs_path);
-    } else {
-        println!("xfs: rootfs found at: {}", relative_rootfs_path);
+    job.emit(None, EventKind::StageStarted { stage: Stage::Select });
+
+    // Promote the best extractor's archives, so the archive always matches the reported
+    // extractor, and keep or discard the others
+    let mut archives = Vec::new();
+    for candidate in results.iter() {
+        let name = if candidate.extractor == best_result.extractor {
+            match (candidate.primary, candidate.index) {
+                (true, 0) => "rootfs.tar.gz".to_owned(),
+                (true, i) => format!("rootfs.{i}.tar.gz"),
+                (false, i) => format!("secondary.{i}.tar.gz"),
+            }
+        } else if job.keep_candidates {
+            match (candidate.primary, candidate.index) {
+                (true, 0) => format!("rootfs.{}.tar.gz", candidate.extractor),
+                (true, i) => format!("rootfs.{}.{i}.tar.gz", candidate.extractor),
+                (false, i) => format!("secondary.{}.{i}.tar.gz", candidate.extractor),
+            }
+        } else {
+            archives.push((candidate, None));
+            continue;
+        };
+
+        let archive = output_dir.join(&name);
+        fs::rename(&candidate.path, &archive)?;
+        job.emit(Some(candidate.extractor), EventKind::ArchiveWritten {
+            archive: name.clone(),
+            size: fs::metadata(&archive)?.len(),
+        });
+        archives.push((candidate, Some(name)));
     }
-    
-    // Create xfs_results.json with the required information
-    let mut results_json = json!({
-        "preferred_extractor": best_result.extractor,
-        "identified_rootfs": relative_rootfs_path,
-        "rootfs_archive": selected_output_path.file_name().unwrap_or_default().to_string_lossy().to_string(),
-        "copied_rootfs": null,
-        "extracted_files": relative_extract_dir
+    fs::remove_dir_all(&candidates_dir)?;
+    archives.sort_by_key(|(candidate, _)| (candidate.extractor, !candidate.primary, candidate.index));
+
+    // Every root filesystem found, with why it was or was not picked, so an alternative can be
+    // picked when the scoring is wrong
+    let best_score = best_result.score.as_ref().expect("root filesystems are scored");
+    document.candidates = archives
+        .iter()
+        .filter(|(candidate, _)| candidate.primary)
+        .map(|(candidate, archive)| {
+            let selected = candidate.extractor == best_result.extractor && candidate.index == 0;
+            let score = candidate.score.clone().unwrap_or_default();
+            let explanation = if selected {
+                format!("selected, with the highest score ({:.2})", score.total)
+            } else {
+                score.explain(best_score)
+            };
+
+            Candidate {
+                extractor: candidate.extractor.to_owned(),
+                index: candidate.index,
+                selected,
+                path: relative_extract_path(&candidate.rootfs_path),
+                size: candidate.size,
+                num_files: candidate.num_files,
+                key_file_count: candidate.key_file_count,
+                executables: candidate.executables,
+                elf_files: candidate.kinds.elf_files(),
+                file_kinds: candidate.kinds,
+                file_node_count: candidate.file_node_count,
+                archive_hash: candidate.archive_hash.clone(),
+                archive: archive.clone(),
+                score,
+                explanation,
+            }
+        })
+        .collect();
+
+    document.secondary_filesystems = archives
+        .iter()
+        .filter(|(candidate, _)| !candidate.primary && candidate.extractor == best_result.extractor)
+        .filter_map(|(candidate, archive)| Some(SecondaryFilesystem {
+            archive: archive.clone()?,
+            path: relative_extract_path(&candidate.rootfs_path),
+            kind: candidate.kind?,
+            size: candidate.size,
+            num_files: candidate.num_files,
+            archive_hash: candidate.archive_hash.clone(),
+        }))
+        .collect();
+
+    // Report the rootfs path for the best extractor only with relative path
+    let relative_rootfs_path = relative_extract_path(&best_result.rootfs_path);
+    job.emit(Some(best_result.extractor), EventKind::RootfsSelected {
+        path: relative_rootfs_path.clone(),
+        status: best_result.status,
     });
-    
-    // Update copied_rootfs field if copy_rootfs is true
-    if args.copy_rootfs {
-        results_json["copied_rootfs"] = json!("./rootfs");
-    }
-    
-    // Write the results JSON file
-    let results_json_path = output_dir.join("xfs_results.json");
-    let json_content = serde_json::to_string_pretty(&results_json).unwrap() + "\n";
-    fs::write(&results_json_path, json_content).unwrap();
+    job.emit(None, EventKind::StageFinished { stage: Stage::Select });
+    job.emit(None, EventKind::StageStarted { stage: Stage::Output });
 
     // If copy_rootfs is specified, copy the rootfs directory from the best extractor
-    if args.copy_rootfs {
+    let rootfs_copy = job.copy_rootfs.then(|| {
         let target_rootfs_dir = rootfs_dir_path;
-        
-        // No debug information needed
-        
+
         // Try to copy the rootfs directory, but handle errors gracefully
-        match (|| -> Result<(), Fw2tarError> {
-            // Remove existing directory if it exists
-            if target_rootfs_dir.exists() {
-                fs::remove_dir_all(&target_rootfs_dir)?;
-            }
-            
-            // Create parent directories if they don't exist
-            if let Some(parent) = target_rootfs_dir.parent() {
-                fs::create_dir_all(parent)?;
-            }
-            
-            // In Docker container, we need to handle paths differently
-            // Instead of trying to copy directly, we'll use a command to copy
-            let source_path = &best_result.rootfs_path;
-            
-            // Check if we're running in a container (common Docker env var)
-            let in_container = env::var("container").is_ok() || Path::new("/.dockerenv").exists();
-            
-            // Create target directory
-            fs::create_dir_all(&target_rootfs_dir)?;
-            
-            if in_container {
-                // Use system cp command which handles Docker volume mounts better
-                // Copy contents of the source directory to the target directory
-                let status = std::process::Command::new("sh")
-                    .arg("-c")
-                    .arg(format!("cp -a {}/* {}", source_path.display(), target_rootfs_dir.display()))
-                    .status()?;
-                
-                if !status.success() {
-                    return Err(Fw2tarError::IoError(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("cp command failed with status: {}", status)
-                    )));
-                }
-            } else {
-                // Not in container, use regular copy
-                // Check if source exists
-                if !source_path.exists() {
-                    return Err(Fw2tarError::IoError(std::io::Error::new(
-                        std::io::ErrorKind::NotFound,
-                        format!("Source rootfs path does not exist: {}", source_path.display())
-                    )));
-                }
-                
-                // Copy the directory contents
-                // Read source directory entries
-                let entries = fs::read_dir(source_path)?;
-                
-                // Copy each entry to the target directory
-                for entry in entries {
-                    let entry = entry?;
-                    let path = entry.path();
-                    let target = target_rootfs_dir.join(entry.file_name());
-                    
-                    if path.is_dir() {
-                        copy_dir_all(&path, &target)?;
-                    } else {
-                        fs::copy(&path, &target)?;
-                    }
-                }
+        match (|| -> Result<CopyReport, Fw2tarError> {
+            // Remove whatever is in the way, without following it if it is a symlink
+            match fs::symlink_metadata(&target_rootfs_dir) {
+                Ok(meta) if meta.is_dir() => fs::remove_dir_all(&target_rootfs_dir)?,
+                Ok(_) => fs::remove_file(&target_rootfs_dir)?,
+                Err(_) => {}
             }
-            
-            Ok(())
+
+            Ok(copy::copy_tree(&best_result.rootfs_path, &best_result.extract_dir, &target_rootfs_dir)?)
         })() {
-            Ok(_) => {
-                if args.progress {
-                    println!("xfs: [STAGE 4/4] Creating output files");
-                    println!("xfs: [STAGE 4/4] rootfs successfully copied to: ./rootfs");
-                } else {
-                    println!("xfs: rootfs successfully copied to: ./rootfs");
+            Ok(report) => {
+                job.emit(None, EventKind::RootfsCopied {
+                    path: "./rootfs".to_owned(),
+                    skipped_devices: report.skipped_devices.len(),
+                });
+
+                if !report.skipped_devices.is_empty() {
+                    log::warn!(
+                        "{} device nodes could not be created in ./rootfs (they are listed in xfs_results.json)",
+                        report.skipped_devices.len()
+                    );
+                }
+                if !report.failed.is_empty() {
+                    log::warn!(
+                        "{} entries could not be copied to ./rootfs (they are listed in xfs_results.json)",
+                        report.failed.len()
+                    );
                 }
+
+                Some(report)
             },
             Err(e) => {
-                if args.progress {
-                    eprintln!("xfs: [STAGE 4/4] Warning: Failed to copy rootfs directory: {}", e);
-                    eprintln!("xfs: [STAGE 4/4] The archive was created successfully, but the rootfs directory couldn't be copied.");
-                } else if args.loud {
-                    eprintln!("xfs: Warning: Failed to copy rootfs directory: {}", e);
-                    eprintln!("xfs: The archive was created successfully, but the rootfs directory couldn't be copied.");
-                }
+                job.emit(None, EventKind::CopyFailed { error: e.to_string() });
+
+                None
             }
         }
-    }
+    }).flatten();
+    drop(kept_temp_dirs);
+
+    // Fill in the selected rootfs
+    document.preferred_extractor = Some(best_result.extractor.to_owned());
+    document.identified_rootfs = Some(relative_rootfs_path);
+    document.rootfs_archive = Some(selected_output_path.file_name().unwrap_or_default().to_string_lossy().to_string());
+    document.copied_rootfs = rootfs_copy.as_ref().map(|_| "./rootfs".to_owned());
+    document.rootfs_copy = rootfs_copy;
+    document.extraction_status = Some(best_result.status.into());
+    document.architecture = best_result.architecture.clone();
+    document.elapsed_seconds = start_time.elapsed().as_secs_f64();
+    document.write(&output_dir)?;
+
+    transaction.commit()?;
+    job.emit(None, EventKind::StageFinished { stage: Stage::Output });
 
-    result
+    Ok(Extraction {
+        best,
+        output_dir: final_output_dir.clone(),
+        rootfs_archive: Some(final_output_dir.join("rootfs.tar.gz")),
+        results: document,
+    })
 }
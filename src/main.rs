@@ -1,68 +1,161 @@
+use std::io::{self, Write};
 use std::process::exit;
 
 use clap::Parser;
 
-use fw2tar::args::Args;
-use fw2tar::BestExtractor;
+use fw2tar::args::{Args, ProgressFormat};
+use fw2tar::progress::{Event, EventKind, Progress, Stage};
+use fw2tar::ExtractionJob;
 
-fn main() {
-    let args = Args::parse();
-
-    if args.loud && std::env::var("XFS_LOG").is_err() {
-        std::env::set_var("XFS_LOG", "debug");
-    }
+/// Renders job events as the `xfs` text output
+struct TextProgress {
+    /// `--progress`: report every stage
+    stages: bool,
+    /// `--loud`: report extraction steps without stage numbers
+    loud: bool,
+}
 
-    pretty_env_logger::init_custom_env("XFS_LOG");
+impl Progress for TextProgress {
+    fn event(&self, event: &Event) {
+        let extractor = event.extractor.unwrap_or_default();
 
-    match fw2tar::main(args.clone()) {
-        Ok((res, _output_path)) => match res {
-            BestExtractor::Best(extractor) => {
-                // Always print the best extractor information
-                println!("xfs: best extractor: {extractor}");
-                
-                
-                // Print archive information
-                if args.progress {
-                    println!("xfs: [STAGE 4/4] rootfs archive created: ./rootfs.tar.gz");
-                    println!("xfs: Process complete");
+        match &event.kind {
+            EventKind::ExtractorStarted if self.stages => {
+                println!("xfs: [STAGE 1/4] {extractor} - extraction: starting...");
+            }
+            EventKind::ExtractorFinished { status } if status.is_partial() => {
+                if self.stages {
+                    println!("xfs: [STAGE 1/4] {extractor} - extraction: {status} (partial) ⚠");
+                } else if self.loud {
+                    println!("xfs: {extractor} - extraction: ⚠ ({status}, partial)");
+                }
+            }
+            EventKind::ExtractorFinished { .. } => {
+                if self.stages {
+                    println!("xfs: [STAGE 1/4] {extractor} - extraction: completed ✓");
+                } else if self.loud {
+                    println!("xfs: {extractor} - extraction: ✓");
+                }
+            }
+            EventKind::ExtractorFailed { .. } => {
+                if self.stages {
+                    println!("xfs: [STAGE 1/4] {extractor} - extraction: failed ✗");
+                } else if self.loud {
+                    println!("xfs: {extractor} - extraction: ✗");
+                }
+            }
+            EventKind::StageStarted { stage: Stage::Identify } if self.stages => {
+                println!("xfs: [STAGE 2/4] {extractor} - identify rootfs: searching...");
+            }
+            EventKind::CandidatesFound { count: 0 } => {
+                println!("\txfs: [STAGE 2/4] {extractor} - identify rootfs: No Linux rootfs found ✗");
+            }
+            EventKind::CandidatesFound { .. } => {
+                if self.stages {
+                    println!("\txfs: [STAGE 2/4] {extractor} - identify rootfs: found ✓");
+                } else if self.loud {
+                    println!("xfs: {extractor} - identify rootfs: ✓");
+                }
+            }
+            EventKind::CandidatesSkipped { count } => {
+                if self.stages {
+                    println!("xfs: [STAGE 2/4] WARNING: skipping {count} filesystems, if files are missing you may need to set --primary-limit higher");
+                } else if self.loud {
+                    println!("xfs: WARNING: skipping {count} filesystems, if files are missing you may need to set --primary-limit higher");
+                }
+            }
+            EventKind::SecondarySkipped { count } => {
+                if self.stages {
+                    println!("xfs: [STAGE 2/4] WARNING: skipping {count} secondary filesystems, you may need to set --secondary-limit higher");
+                } else if self.loud {
+                    println!("xfs: WARNING: skipping {count} secondary filesystems, you may need to set --secondary-limit higher");
+                }
+            }
+            EventKind::RootfsSelected { path, status } => {
+                if self.stages {
+                    println!("xfs: [STAGE 3/4] Selecting best extractor: {extractor}");
+                    println!("xfs: [STAGE 3/4] rootfs found at: {path}");
                 } else {
-                    println!("xfs: rootfs archive: ./rootfs.tar.gz");
+                    println!("xfs: rootfs found at: {path}");
+                }
+
+                if status.is_partial() {
+                    println!("xfs: WARNING: {extractor} {status} before finishing, the rootfs may be incomplete");
                 }
             }
-            BestExtractor::Only(extractor) => {
-                // Always print the best extractor information
-                println!("xfs: best extractor: {extractor}");
-                
-                
-                // Print archive information
-                if args.progress {
-                    println!("xfs: [STAGE 4/4] rootfs archive created: ./rootfs.tar.gz");
-                    println!("xfs: Process complete");
+            EventKind::RootfsCopied { path, .. } => {
+                if self.stages {
+                    println!("xfs: [STAGE 4/4] Creating output files");
+                    println!("xfs: [STAGE 4/4] rootfs successfully copied to: {path}");
                 } else {
-                    println!("xfs: rootfs archive: ./rootfs.tar.gz");
+                    println!("xfs: rootfs successfully copied to: {path}");
+                }
+            }
+            EventKind::CopyFailed { error } => {
+                if self.stages {
+                    eprintln!("xfs: [STAGE 4/4] Warning: Failed to copy rootfs directory: {error}");
+                    eprintln!("xfs: [STAGE 4/4] The archive was created successfully, but the rootfs directory couldn't be copied.");
+                } else if self.loud {
+                    eprintln!("xfs: Warning: Failed to copy rootfs directory: {error}");
+                    eprintln!("xfs: The archive was created successfully, but the rootfs directory couldn't be copied.");
                 }
             }
-            BestExtractor::Identical(extractor) => {
+            EventKind::JobFinished { rootfs_archive: Some(archive) } => {
                 // Always print the best extractor information
                 println!("xfs: best extractor: {extractor}");
-                
-                
+
                 // Print archive information
-                if args.progress {
-                    println!("xfs: [STAGE 4/4] rootfs archive created: ./rootfs.tar.gz");
+                if self.stages {
+                    println!("xfs: [STAGE 4/4] rootfs archive created: ./{archive}");
                     println!("xfs: Process complete");
                 } else {
-                    println!("xfs: rootfs archive: ./rootfs.tar.gz");
+                    println!("xfs: rootfs archive: ./{archive}");
                 }
             }
-            BestExtractor::None => {
+            EventKind::JobFinished { rootfs_archive: None } => {
                 println!("xfs: [STAGE 3/4] No Linux filesystems were found - perhaps RTOS?");
                 println!("xfs: Process complete");
             }
-        },
-        Err(e) => {
-            eprintln!("xfs: {e}");
-            exit(1);
+            _ => {}
+        }
+    }
+}
+
+/// Writes job events to stdout as JSON Lines
+struct JsonProgress;
+
+impl Progress for JsonProgress {
+    fn event(&self, event: &Event) {
+        // Events come from every extractor thread, so write each line under the lock
+        let mut stdout = io::stdout().lock();
+        if let Ok(line) = serde_json::to_string(event) {
+            let _ = writeln!(stdout, "{line}");
+            let _ = stdout.flush();
         }
     }
 }
+
+fn main() {
+    let args = Args::parse();
+
+    if args.loud && std::env::var("XFS_LOG").is_err() {
+        std::env::set_var("XFS_LOG", "debug");
+    }
+
+    pretty_env_logger::init_custom_env("XFS_LOG");
+    fw2tar::extractors::supervisor::install_interrupt_handler();
+
+    let job = ExtractionJob::from_args(&args);
+    let job = match args.progress_format {
+        ProgressFormat::Text => job.progress(TextProgress {
+            stages: args.progress,
+            loud: args.loud,
+        }),
+        ProgressFormat::Json => job.progress(JsonProgress),
+    };
+
+    if let Err(e) = job.run() {
+        eprintln!("xfs: {e}");
+        exit(1);
+    }
+}
@@ -0,0 +1,280 @@
+//! Transactional writes to the output directory.
+//!
+//! A run builds all of its outputs in a hidden staging directory inside the output directory
+//! and only moves them into place once everything succeeded, so a crash, panic or Ctrl-C never
+//! leaves a half-written archive or a stale `xfs_results.json` next to fresh files. A lock file
+//! keeps two runs from targeting the same output directory at once, and a record of what each
+//! run wrote lets the next one clear outputs it does not replace. The previous results are
+//! moved aside before anything else is replaced, so an interrupted commit never looks finished.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::results::RESULTS_FILE;
+use crate::Fw2tarError;
+
+/// Lock file held for the duration of a run. It is never removed, as removing a lock file
+/// that others may already have opened would let two runs hold "the" lock.
+pub const LOCK_FILE: &str = ".xfs.lock";
+
+/// Directory outputs are built in before being moved into place
+pub const STAGING_DIR: &str = ".xfs-staging";
+
+/// Names of the outputs the last committed run wrote, one per line
+pub const OUTPUTS_FILE: &str = ".xfs-outputs";
+
+/// An in-progress run writing to an output directory. Dropping it without calling
+/// [`Transaction::commit`] discards everything staged.
+#[derive(Debug)]
+pub struct Transaction {
+    output_dir: PathBuf,
+    staging_dir: PathBuf,
+    _lock: File,
+    committed: bool,
+}
+
+impl Transaction {
+    /// Lock `output_dir` and set up a fresh staging directory inside it.
+    pub fn begin(output_dir: &Path) -> Result<Self, Fw2tarError> {
+        let lock = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(output_dir.join(LOCK_FILE))?;
+
+        // SAFETY: the descriptor is owned by `lock`, which outlives the call
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.kind() {
+                io::ErrorKind::WouldBlock => Fw2tarError::OutputLocked(output_dir.to_owned()),
+                _ => err.into(),
+            });
+        }
+
+        // Left over from a run that crashed or was killed; nobody else can be using it while
+        // the lock is held
+        let staging_dir = output_dir.join(STAGING_DIR);
+        if fs::symlink_metadata(&staging_dir).is_ok() {
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        fs::create_dir(&staging_dir)?;
+
+        Ok(Self {
+            output_dir: output_dir.to_owned(),
+            staging_dir,
+            _lock: lock,
+            committed: false,
+        })
+    }
+
+    /// Directory to build the outputs in, laid out as they should end up in the output
+    /// directory
+    pub fn staging_dir(&self) -> &Path {
+        &self.staging_dir
+    }
+
+    /// Move everything staged into the output directory, replacing previous outputs of the
+    /// same name and removing those of the previous run that were not written again.
+    pub fn commit(self) -> io::Result<()> {
+        self.commit_with(|from, to| fs::rename(from, to))
+    }
+
+    /// [`Transaction::commit`], moving entries with `rename`
+    fn commit_with(
+        mut self,
+        mut rename: impl FnMut(&Path, &Path) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut entries = fs::read_dir(&self.staging_dir)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        // The results go last, so their presence means the rest of the outputs are complete
+        entries.sort_by_key(|name| name == RESULTS_FILE);
+
+        // Replaced outputs are moved aside rather than deleted first, so the previous output
+        // stays intact until the new one is in place. Files are replaced atomically.
+        let replaced_dir = self.staging_dir.join(".replaced");
+        fs::create_dir(&replaced_dir)?;
+
+        // The previous results go before anything is replaced, so a commit that does not finish
+        // never looks like a finished run
+        let results = self.output_dir.join(RESULTS_FILE);
+        if fs::symlink_metadata(&results).is_ok() {
+            rename(&results, &replaced_dir.join(RESULTS_FILE))?;
+        }
+
+        // Outputs of the previous run. The record is only trusted to name plain entries.
+        let previous = fs::read(self.output_dir.join(OUTPUTS_FILE)).unwrap_or_default();
+        let previous: Vec<&OsStr> = previous
+            .split(|&byte| byte == b'\n')
+            .map(OsStr::from_bytes)
+            .filter(|name| !name.is_empty() && Path::new(name).file_name() == Some(name))
+            .filter(|&name| name != LOCK_FILE && name != STAGING_DIR)
+            .collect();
+
+        // Until the commit is done, the record names the outputs of both runs, so the next run
+        // still clears whatever an interrupted commit left behind
+        let written = entries.iter().map(OsString::as_os_str);
+        self.write_record(previous.iter().copied().chain(written.clone()), &mut rename)?;
+
+        // Outputs of the previous run this one did not write, such as archives for candidates
+        // that are no longer found
+        for &name in &previous {
+            let target = self.output_dir.join(name);
+            if !entries.iter().any(|entry| entry == name) && fs::symlink_metadata(&target).is_ok() {
+                rename(&target, &replaced_dir.join(name))?;
+            }
+        }
+
+        for name in &entries {
+            if name == RESULTS_FILE {
+                self.write_record(written.clone(), &mut rename)?;
+            }
+
+            let staged = self.staging_dir.join(name);
+            let target = self.output_dir.join(name);
+
+            let target_is_dir = fs::symlink_metadata(&target).is_ok_and(|meta| meta.is_dir());
+            if target_is_dir || (staged.is_dir() && fs::symlink_metadata(&target).is_ok()) {
+                rename(&target, &replaced_dir.join(name))?;
+            }
+
+            rename(&staged, &target)?;
+        }
+
+        if !entries.iter().any(|name| name == RESULTS_FILE) {
+            self.write_record(written, &mut rename)?;
+        }
+
+        self.committed = true;
+        fs::remove_dir_all(&self.staging_dir)?;
+
+        Ok(())
+    }
+
+    /// Replace the record of the outputs in the output directory with `names`.
+    fn write_record<'a>(
+        &self,
+        names: impl Iterator<Item = &'a OsStr>,
+        rename: &mut impl FnMut(&Path, &Path) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let record = names.fold(Vec::new(), |mut record, name| {
+            record.extend_from_slice(name.as_bytes());
+            record.push(b'\n');
+            record
+        });
+
+        let staged = self.staging_dir.join(OUTPUTS_FILE);
+        fs::write(&staged, record)?;
+        rename(&staged, &self.output_dir.join(OUTPUTS_FILE))
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_dir_all(&self.staging_dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_replaces_and_clears_previous_outputs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let first = Transaction::begin(dir).unwrap();
+        fs::write(first.staging_dir().join("rootfs.tar.gz"), b"old").unwrap();
+        fs::write(first.staging_dir().join("rootfs.1.tar.gz"), b"old").unwrap();
+        fs::create_dir(first.staging_dir().join("rootfs")).unwrap();
+        fs::write(first.staging_dir().join("unblob.log"), b"old").unwrap();
+        first.commit().unwrap();
+        fs::write(dir.join("notes.txt"), b"mine").unwrap();
+
+        let second = Transaction::begin(dir).unwrap();
+        fs::write(second.staging_dir().join("rootfs.tar.gz"), b"new").unwrap();
+        fs::write(second.staging_dir().join(RESULTS_FILE), b"{}").unwrap();
+        second.commit().unwrap();
+
+        assert_eq!(fs::read(dir.join("rootfs.tar.gz")).unwrap(), b"new");
+        assert!(dir.join(RESULTS_FILE).exists());
+        assert!(!dir.join("rootfs.1.tar.gz").exists());
+        assert!(!dir.join("rootfs").exists());
+        assert!(!dir.join("unblob.log").exists());
+        // Only what a run wrote is cleared
+        assert!(dir.join("notes.txt").exists());
+        assert!(!dir.join(STAGING_DIR).exists());
+    }
+
+    #[test]
+    fn an_unfinished_commit_does_not_look_finished() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let first = Transaction::begin(dir).unwrap();
+        fs::write(first.staging_dir().join("rootfs.tar.gz"), b"old").unwrap();
+        fs::write(first.staging_dir().join("unblob.log"), b"old").unwrap();
+        fs::write(first.staging_dir().join(RESULTS_FILE), b"old").unwrap();
+        first.commit().unwrap();
+
+        let second = Transaction::begin(dir).unwrap();
+        fs::write(second.staging_dir().join("rootfs.tar.gz"), b"new").unwrap();
+        fs::write(second.staging_dir().join("rootfs.1.tar.gz"), b"new").unwrap();
+        fs::write(second.staging_dir().join(RESULTS_FILE), b"new").unwrap();
+        let err = second
+            .commit_with(|from, to| {
+                if to.ends_with("rootfs.tar.gz") {
+                    return Err(io::Error::other("disk on fire"));
+                }
+                fs::rename(from, to)
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "disk on fire");
+        assert!(!dir.join(RESULTS_FILE).exists());
+
+        // The next run clears what either of them left
+        let third = Transaction::begin(dir).unwrap();
+        fs::write(third.staging_dir().join(RESULTS_FILE), b"third").unwrap();
+        third.commit().unwrap();
+
+        assert_eq!(fs::read(dir.join(RESULTS_FILE)).unwrap(), b"third");
+        for name in ["rootfs.tar.gz", "rootfs.1.tar.gz", "unblob.log"] {
+            assert!(!dir.join(name).exists(), "{name}");
+        }
+    }
+
+    #[test]
+    fn dropping_discards_staged_outputs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("rootfs.tar.gz"), b"old").unwrap();
+
+        let transaction = Transaction::begin(dir).unwrap();
+        fs::write(transaction.staging_dir().join("rootfs.tar.gz"), b"new").unwrap();
+        drop(transaction);
+
+        assert_eq!(fs::read(dir.join("rootfs.tar.gz")).unwrap(), b"old");
+        assert!(!dir.join(STAGING_DIR).exists());
+    }
+
+    #[test]
+    fn a_second_run_is_locked_out() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let transaction = Transaction::begin(tmp.path()).unwrap();
+        let err = Transaction::begin(tmp.path()).unwrap_err();
+        assert!(matches!(err, Fw2tarError::OutputLocked(_)));
+        // The staging directory of the run holding the lock is left alone
+        assert!(transaction.staging_dir().exists());
+
+        drop(transaction);
+        Transaction::begin(tmp.path()).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+//! Progress reporting for extraction jobs.
+//!
+//! The library never prints. Instead, an [`ExtractionJob`](crate::ExtractionJob) hands every
+//! step it takes to its [`Progress`] as an [`Event`], from whichever extractor thread the step
+//! happens on. The `xfs` binary renders these as its text output, or writes them out as JSON
+//! Lines with `--progress-format json`.
+//!
+//! The event names and fields are a stable vocabulary: new events and fields may be added,
+//! but existing ones keep their meaning. Consumers should ignore events they do not know.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::extractors::ExtractStatus;
+
+/// Phases of a job. `extract` covers everything that happens on the extractor threads, where
+/// each extractor also reports its own `identify` stage; the rest happens once all of them are
+/// done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Running the extractors
+    Extract,
+    /// Searching one extractor's output for root filesystems and archiving them
+    Identify,
+    /// Picking the rootfs to report and promoting its archives
+    Select,
+    /// Copying the rootfs and writing the results
+    Output,
+}
+
+impl Stage {
+    /// Position of the stage, from 1 to [`Stage::COUNT`]
+    pub fn number(self) -> usize {
+        self as usize + 1
+    }
+
+    pub const COUNT: usize = 4;
+}
+
+/// A step of an extraction job
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// When the step happened, in seconds since the Unix epoch
+    pub timestamp: f64,
+    /// Extractor the step belongs to, if any
+    pub extractor: Option<&'static str>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl Event {
+    pub fn new(extractor: Option<&'static str>, kind: EventKind) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        Self {
+            timestamp,
+            extractor,
+            kind,
+        }
+    }
+}
+
+/// What happened, serialized as the `event` field
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The job started on `input`
+    JobStarted { input: String },
+    /// A stage started, for the job or for one extractor
+    StageStarted { stage: Stage },
+    /// A stage finished, for the job or for one extractor
+    StageFinished { stage: Stage },
+    /// An extractor started running
+    ExtractorStarted,
+    /// An extractor produced output, which is partial unless `status` is `completed`
+    ExtractorFinished { status: ExtractStatus },
+    /// An extractor failed without producing output
+    ExtractorFailed { error: String },
+    /// A root filesystem was found in an extractor's output
+    CandidateFound {
+        index: usize,
+        path: String,
+        size: u64,
+        /// Files with an exec bit
+        executables: usize,
+        /// ELF executables, whatever their mode
+        elf_executables: usize,
+        /// Total of its score, higher being more likely the real rootfs
+        score: f64,
+    },
+    /// The search of an extractor's output found `count` root filesystems, possibly none
+    CandidatesFound { count: usize },
+    /// Root filesystems past `--primary-limit` were not archived
+    CandidatesSkipped { count: usize },
+    /// Secondary filesystems past `--secondary-limit` were not archived
+    SecondarySkipped { count: usize },
+    /// The rootfs to report was picked, `path` being relative to the output directory
+    RootfsSelected { path: String, status: ExtractStatus },
+    /// An archive was written to the output directory as `archive`
+    ArchiveWritten { archive: String, size: u64 },
+    /// The selected rootfs was copied out (`--copy-rootfs`)
+    RootfsCopied {
+        path: String,
+        skipped_devices: usize,
+    },
+    /// Copying out the selected rootfs failed; its archive is still written
+    CopyFailed { error: String },
+    /// The job finished. `rootfs_archive` is `None` if no extractor found a rootfs.
+    JobFinished { rootfs_archive: Option<String> },
+    /// The job failed and wrote no output
+    JobFailed { error: String },
+}
+
+/// Receives the [`Event`]s of a job as they happen.
+pub trait Progress: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Send + Sync> Progress for F {
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Ignores every event
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Silent;
+
+impl Progress for Silent {
+    fn event(&self, _event: &Event) {}
+}
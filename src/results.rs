@@ -0,0 +1,272 @@
+//! The `xfs_results.json` document.
+//!
+//! Other tools parse this file, so these types are its contract: a change that is not
+//! backwards compatible must bump [`SCHEMA_VERSION`]. `xfs_results.schema.json` at the root of
+//! the repository is generated from them; run the tests with `XFS_UPDATE_SCHEMA=1` to
+//! regenerate it after changing them.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+use md5::Md5;
+use schemars::{JsonSchema, Schema};
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::analysis::architecture::Architecture;
+use crate::analysis::directory_executables::FileKinds;
+use crate::analysis::find_secondary_filesystems::SecondaryKind;
+use crate::analysis::safety::SafetyReport;
+use crate::analysis::scoring::Score;
+use crate::copy::CopyReport;
+use crate::extractors::vendor::VendorHeader;
+use crate::extractors::{ExtractError, ExtractStatus};
+
+/// Version of the results document, bumped on every incompatible change
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Name of the results document in the output directory
+pub const RESULTS_FILE: &str = "xfs_results.json";
+
+/// Everything a run found, written to `xfs_results.json` whether or not a rootfs was found.
+/// Paths are relative to the output directory.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Results {
+    /// Version of the schema used by the xfs that wrote the document
+    pub schema_version: u32,
+    pub input: Input,
+    /// Extractor the rootfs was taken from, `null` if none found one
+    pub preferred_extractor: Option<String>,
+    /// Extracted directory of the selected rootfs
+    pub identified_rootfs: Option<String>,
+    /// Archive of the selected rootfs
+    pub rootfs_archive: Option<String>,
+    /// Copy of the selected rootfs, when `--copy-rootfs` was used and the copy succeeded
+    pub copied_rootfs: Option<String>,
+    /// What the rootfs copy could not reproduce
+    pub rootfs_copy: Option<CopyReport>,
+    /// Directory holding the output of every extractor
+    pub extracted_files: String,
+    /// How the preferred extractor's run ended
+    pub extraction_status: Option<Status>,
+    /// How every extractor's run went, by extractor name
+    pub extractors: BTreeMap<String, ExtractorResult>,
+    /// Every rootfs found by any extractor, including the selected one
+    pub candidates: Vec<Candidate>,
+    /// Non-root filesystems archived from the preferred extractor's output
+    pub secondary_filesystems: Vec<SecondaryFilesystem>,
+    /// CPU architecture, ABI and C library of the selected rootfs, `null` if it holds no
+    /// binaries
+    pub architecture: Option<Architecture>,
+    /// Vendor containers found in the input
+    pub vendor_headers: Vec<VendorHeader>,
+    /// Wall clock time of the whole run
+    pub elapsed_seconds: f64,
+}
+
+impl Results {
+    /// Write the document to [`RESULTS_FILE`] in `dir`.
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)? + "\n";
+        fs::write(dir.join(RESULTS_FILE), json)
+    }
+}
+
+/// The firmware image that was extracted
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Input {
+    /// Path as given on the command line
+    pub file: String,
+    pub size: u64,
+    pub md5: String,
+    pub sha1: String,
+    pub sha256: String,
+}
+
+impl Input {
+    /// Describe and hash the file at `path`.
+    pub fn hash(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut md5 = Md5::new();
+        let mut sha1 = Sha1::new();
+        let mut sha256 = Sha256::new();
+        let mut size = 0;
+
+        let mut buf = vec![0; 1 << 20];
+        loop {
+            let len = file.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            md5.update(&buf[..len]);
+            sha1.update(&buf[..len]);
+            sha256.update(&buf[..len]);
+            size += len as u64;
+        }
+
+        Ok(Self {
+            file: path.display().to_string(),
+            size,
+            md5: format!("{:x}", md5.finalize()),
+            sha1: format!("{:x}", sha1.finalize()),
+            sha256: format!("{:x}", sha256.finalize()),
+        })
+    }
+}
+
+/// How an extractor's run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Ran to completion
+    Completed,
+    /// Exited with an error, see `exit_code` and `error`
+    Failed,
+    /// Found nothing it could extract
+    NotFound,
+    /// Killed by a signal, see `signal`
+    Killed,
+    /// Ran past `--timeout` and was killed
+    TimedOut,
+    /// Wrote more than its quota allows and was stopped
+    ExceededQuota,
+}
+
+impl From<ExtractStatus> for Status {
+    fn from(status: ExtractStatus) -> Self {
+        match status {
+            ExtractStatus::Completed => Status::Completed,
+            ExtractStatus::TimedOut => Status::TimedOut,
+            ExtractStatus::Killed(_) => Status::Killed,
+            ExtractStatus::ExceededQuota => Status::ExceededQuota,
+        }
+    }
+}
+
+/// How one extractor's run went
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ExtractorResult {
+    pub status: Status,
+    /// Exit code of an external extractor that failed
+    pub exit_code: Option<i32>,
+    /// Signal an external extractor was killed with
+    pub signal: Option<i32>,
+    /// Why the extractor failed
+    pub error: Option<String>,
+    pub elapsed_seconds: f64,
+    /// Hazards found in the extracted tree, if the extractor produced one
+    pub hazards: Option<SafetyReport>,
+}
+
+impl ExtractorResult {
+    /// An extractor run that produced output, which may be partial
+    pub fn finished(status: ExtractStatus, elapsed: Duration, hazards: SafetyReport) -> Self {
+        Self {
+            status: status.into(),
+            exit_code: None,
+            signal: match status {
+                ExtractStatus::Killed(signal) => Some(signal),
+                _ => None,
+            },
+            error: None,
+            elapsed_seconds: elapsed.as_secs_f64(),
+            hazards: Some(hazards),
+        }
+    }
+
+    /// An extractor run that failed
+    pub fn failed(error: &ExtractError, elapsed: Duration) -> Self {
+        Self {
+            status: match error {
+                ExtractError::NotFound(_) => Status::NotFound,
+                _ => Status::Failed,
+            },
+            exit_code: match error {
+                ExtractError::Failed(code) => Some(*code),
+                _ => None,
+            },
+            signal: None,
+            error: Some(error.to_string()),
+            elapsed_seconds: elapsed.as_secs_f64(),
+            hazards: None,
+        }
+    }
+}
+
+/// A rootfs found by an extractor
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Candidate {
+    pub extractor: String,
+    /// Rank among the extractor's candidates, 0 being its best
+    pub index: usize,
+    /// Whether this is the rootfs xfs picked
+    pub selected: bool,
+    /// Extracted directory
+    pub path: String,
+    /// Total size of its files
+    pub size: u64,
+    pub num_files: usize,
+    /// Number of the key directories and critical files present
+    pub key_file_count: usize,
+    /// Files with an exec bit, which extractors running unprivileged may lose or invent
+    pub executables: usize,
+    /// ELF files of any type
+    pub elf_files: usize,
+    /// Files that hold code, by what their contents say they are
+    pub file_kinds: FileKinds,
+    /// Number of entries in its archive
+    pub file_node_count: usize,
+    /// SHA-1 of its archive
+    pub archive_hash: String,
+    /// Name the archive was kept under, `null` if it was discarded
+    pub archive: Option<String>,
+    /// How it scored, feature by feature. Candidates are ranked by the total, ties going to
+    /// the larger archive.
+    pub score: Score,
+    /// Why it was or was not selected
+    pub explanation: String,
+}
+
+/// A non-root filesystem, such as a configuration partition
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct SecondaryFilesystem {
+    pub archive: String,
+    /// Extracted directory
+    pub path: String,
+    pub kind: SecondaryKind,
+    /// Total size of its files
+    pub size: u64,
+    pub num_files: usize,
+    /// SHA-1 of its archive
+    pub archive_hash: String,
+}
+
+/// JSON Schema of the results document
+pub fn schema() -> Schema {
+    schemars::schema_for!(Results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_file_is_current() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("xfs_results.schema.json");
+        let schema = serde_json::to_string_pretty(&schema()).unwrap() + "\n";
+
+        if std::env::var_os("XFS_UPDATE_SCHEMA").is_some() {
+            fs::write(&path, &schema).unwrap();
+        }
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap_or_default(),
+            schema,
+            "xfs_results.schema.json is out of date, regenerate it with XFS_UPDATE_SCHEMA=1 cargo test"
+        );
+    }
+}
@@ -0,0 +1,53 @@
+//! Runs `make_cpio_symlinks.sh` and checks the native cpio extractor's output with
+//! `check_cpio_result.py`. Skipped when the `cpio` tool is not installed.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use fw2tar::extractors::get_extractor;
+
+/// Directory the archive's `lib` symlink points to, see `make_cpio_symlinks.sh`
+const ESCAPE_TARGET: &str = "/tmp/xfs_cpio_escape_target";
+
+#[test]
+fn cpio_symlinks() {
+    if Command::new("cpio").arg("--version").output().is_err() {
+        eprintln!("skipping, make_cpio_symlinks.sh needs cpio");
+        return;
+    }
+
+    let tests_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let tmp = tempfile::tempdir().unwrap();
+
+    let made = Command::new("bash")
+        .arg(tests_dir.join("make_cpio_symlinks.sh"))
+        .current_dir(tmp.path())
+        .status()
+        .unwrap();
+    assert!(made.success());
+    let archive = tmp.path().join("test_archive.cpio");
+
+    // The symlink target has to exist for an extractor that follows it to write through it
+    fs::create_dir_all(ESCAPE_TARGET).unwrap();
+    let _ = fs::remove_file(Path::new(ESCAPE_TARGET).join("evil"));
+
+    let extract_dir = tmp.path().join("xfs-extract");
+    get_extractor("cpio")
+        .unwrap()
+        .extract(&archive, &extract_dir, &tmp.path().join("cpio.log"), false)
+        .unwrap();
+
+    let checked = Command::new("python3")
+        .arg(tests_dir.join("check_cpio_result.py"))
+        .arg(extract_dir.join("cpio-0x0"))
+        .output()
+        .unwrap();
+    let _ = fs::remove_dir_all(ESCAPE_TARGET);
+
+    let report = String::from_utf8_lossy(&checked.stdout);
+    assert!(
+        report.contains("All files and symlinks are correct"),
+        "{report}"
+    );
+}
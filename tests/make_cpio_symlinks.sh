@@ -35,11 +35,19 @@ ln -s symlink_circular_a "$WORKDIR/bin/symlink_circular_b"
 # 8) Second symlink to busybox
 ln -s busybox "$WORKDIR/bin/symlink_same_dir2"
 
+# 9) Symlink to a directory outside the archive, followed by an entry beneath it that
+#    must not be written through the link
+ESCAPE_TARGET=/tmp/xfs_cpio_escape_target
+mkdir -p "$ESCAPE_TARGET"
+echo "Escaped" > "$ESCAPE_TARGET/evil"
+ln -s "$ESCAPE_TARGET" "$WORKDIR/lib"
+
 # Navigate to the work directory
 cd "$WORKDIR"
 
 # Create the CPIO archive
-find . | cpio -ov --format=newc > test_archive.cpio
+{ find .; echo ./lib/evil; } | cpio -ov --format=newc > test_archive.cpio
+rm -rf "$ESCAPE_TARGET"
 
 # Move the archive to the current directory (assuming it's where the script is run)
 mv test_archive.cpio "$OLDPWD"